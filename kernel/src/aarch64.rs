@@ -31,20 +31,20 @@ pub fn sp_sel() -> u8 {
     (ptr & 1) as u8
 }
 
-/// Returns the core currently executing.
-///
-/// # Safety
-///
-/// This function should only be called when EL is >= 1.
-pub unsafe fn affinity() -> usize {
-    let x: usize;
-    asm!("mrs     $0, mpidr_el1
-          and     $0, $0, #3"
-          : "=r"(x));
+/// Returns the faulting address of the last synchronous exception, if it
+/// had one. `Syndrome::has_fault_address()` says whether it did.
+#[inline(always)]
+pub fn far() -> u64 {
+    let far: u64;
+    unsafe {
+        asm!("mrs $0, FAR_EL1" : "=r"(far));
+    }
 
-    x
+    far
 }
 
+pub use pi::aarch64::{affinity, clean_dcache, clean_invalidate_dcache, sync_icache};
+
 /// A NOOP that won't be optimized out.
 pub fn nop() {
     unsafe {
@@ -59,3 +59,13 @@ pub fn wfi() {
         asm!("wfi" :::: "volatile");
     }
 }
+
+/// Unmasks IRQs on the current core.
+///
+/// # Safety
+///
+/// This function should only be called when EL is >= 1.
+#[inline(always)]
+pub unsafe fn enable_irqs() {
+    asm!("msr DAIFClr, #0b0010" :::: "volatile");
+}
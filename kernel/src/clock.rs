@@ -0,0 +1,159 @@
+//! Wall-clock time.
+//!
+//! UTC is kept as an offset from the generic timer's counter, which starts at
+//! zero when the board powers up. At boot the offset comes from the time
+//! persisted in `CLOCK_FILE` on the SD card; a real-time clock chip, once one
+//! is registered with `set_rtc`, takes precedence. The SD card driver can't
+//! write yet, so the persisted time has to be updated from another machine.
+//!
+//! The clock is also the time source FAT creation and modification times are
+//! stamped from; see `rtc::fat_now`.
+
+use std::io::Read;
+use std::str;
+
+use fs::traits;
+use pi::generic_timer::Instant;
+use pi::i2c::{Bsc, I2c};
+use pi::mutex::Mutex;
+use pi::rtc::{self, DateTime, Ds3231};
+use FILE_SYSTEM;
+
+/// The clock that counts UTC time since the Unix epoch.
+pub const CLOCK_REALTIME: u64 = 0;
+
+/// The clock that counts time since the board powered up and is never set.
+pub const CLOCK_MONOTONIC: u64 = 1;
+
+/// Where the last known time is persisted, as `YYYY-MM-DD HH:MM:SS` or
+/// seconds since the Unix epoch.
+pub const CLOCK_FILE: &str = "/clock.txt";
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// A point in time in seconds and nanoseconds, as `clock_gettime` returns it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timespec {
+    pub secs: u64,
+    pub nanos: u32,
+}
+
+impl Timespec {
+    fn from_nanos(nanos: u64) -> Timespec {
+        Timespec { secs: nanos / NANOS_PER_SEC, nanos: (nanos % NANOS_PER_SEC) as u32 }
+    }
+
+    fn as_nanos(&self) -> u64 {
+        self.secs * NANOS_PER_SEC + self.nanos as u64
+    }
+}
+
+/// A real-time clock chip that keeps time while the board is off.
+pub trait Rtc: Send {
+    /// Returns the time the chip holds, if it holds a valid one.
+    fn read(&mut self) -> Option<DateTime>;
+
+    /// Sets the chip's time. Returns `false` if it couldn't be set.
+    fn set(&mut self, datetime: &DateTime) -> bool;
+}
+
+impl<B: rtc::Bus + Send> Rtc for Ds3231<B> {
+    fn read(&mut self) -> Option<DateTime> {
+        Ds3231::read(self).ok()
+    }
+
+    fn set(&mut self, datetime: &DateTime) -> bool {
+        Ds3231::set(self, datetime).is_ok()
+    }
+}
+
+/// Unix time, in nanoseconds, when the counter was zero.
+static OFFSET: Mutex<u64> = Mutex::new(0);
+
+static RTC: Mutex<Option<Box<Rtc>>> = Mutex::new(None);
+
+/// Returns the time of `clock`, or `None` if there is no such clock.
+pub fn get(clock: u64) -> Option<Timespec> {
+    match clock {
+        CLOCK_REALTIME => Some(now()),
+        CLOCK_MONOTONIC => Some(monotonic()),
+        _ => None
+    }
+}
+
+/// Returns the time since the board powered up.
+pub fn monotonic() -> Timespec {
+    Timespec::from_nanos(Instant::now().as_nanos())
+}
+
+/// Returns the current UTC time since the Unix epoch.
+pub fn now() -> Timespec {
+    Timespec::from_nanos(*OFFSET.lock_irq() + Instant::now().as_nanos())
+}
+
+/// Returns the current UTC date and time.
+pub fn datetime() -> DateTime {
+    DateTime::from_unix(now().secs)
+}
+
+fn set_offset(time: Timespec) {
+    *OFFSET.lock_irq() = time.as_nanos().saturating_sub(Instant::now().as_nanos());
+}
+
+/// Sets the real-time clock chip, if there is one, and then the current UTC
+/// time. Returns `false`, leaving the time unchanged, if the chip couldn't be
+/// set.
+pub fn set(time: Timespec) -> bool {
+    let set = match *RTC.lock() {
+        Some(ref mut rtc) => rtc.set(&DateTime::from_unix(time.secs)),
+        None => true
+    };
+
+    if set {
+        set_offset(time);
+    }
+    set
+}
+
+/// Registers the real-time clock chip `rtc` and sets the time from it if it
+/// holds a valid time. Otherwise the chip is set to the current time.
+pub fn set_rtc(mut rtc: Box<Rtc>) {
+    match rtc.read() {
+        Some(datetime) => set_offset(Timespec { secs: datetime.to_unix(), nanos: 0 }),
+        None => { rtc.set(&datetime()); },
+    }
+    *RTC.lock() = Some(rtc);
+}
+
+/// Looks for a DS3231 on the I2C bus of the 40-pin header and registers it
+/// with `set_rtc` if it answers. Returns `true` if it did.
+pub fn probe_ds3231() -> bool {
+    let mut ds3231 = Ds3231::new(I2c::new(Bsc::Bsc1));
+    match ds3231.read() {
+        // Nothing acknowledged: there's no chip to trust or set.
+        Err(rtc::Error::Bus(_)) => false,
+        _ => {
+            set_rtc(Box::new(ds3231));
+            true
+        }
+    }
+}
+
+/// Makes the clock the file system's time source and sets the time from
+/// `CLOCK_FILE`. Returns the time read, if any.
+pub fn initialize() -> Option<DateTime> {
+    rtc::set_time_source(datetime);
+
+    let mut file = traits::FileSystem::open_file(&FILE_SYSTEM, CLOCK_FILE).ok()?;
+    let mut contents = vec![];
+    file.read_to_end(&mut contents).ok()?;
+
+    let text = str::from_utf8(&contents).ok()?.trim();
+    let datetime = match text.parse::<u64>() {
+        Ok(secs) => DateTime::from_unix(secs),
+        Err(_) => text.parse::<DateTime>().ok()?,
+    };
+
+    set_offset(Timespec { secs: datetime.to_unix(), nanos: 0 });
+    Some(datetime)
+}
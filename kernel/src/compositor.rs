@@ -0,0 +1,379 @@
+//! Sharing the screen between processes.
+//!
+//! Once initialized, the compositor owns the top of the screen, the desktop,
+//! and the console that `kprint!` writes to is confined to the rest. Processes
+//! draw into surfaces: off-screen images placed on the desktop. The compositor
+//! stacks them in z-order and blits the parts that change onto the
+//! framebuffer, outlining the surface with the keyboard focus.
+//!
+//! Console input goes to the focused surface, where its owner reads it with
+//! the `surface_read` system call, or to the console when no surface has
+//! focus. `FOCUS_BYTE` moves the focus up the stack, and from the top surface
+//! back to the console.
+
+use std::cmp;
+use std::collections::VecDeque;
+
+use pi::graphics::{Canvas, Image, Rect, Rgba, Target};
+use pi::mutex::Mutex;
+use pi::screen::SCREEN;
+use process::Id;
+
+/// Type alias for the type of a surface ID.
+pub type SurfaceId = u64;
+
+/// The byte sent by Ctrl-T, which moves the keyboard focus to the next
+/// surface.
+pub const FOCUS_BYTE: u8 = 0x14;
+
+/// The most surfaces a process may have.
+pub const MAX_SURFACES: usize = 8;
+
+/// The most input bytes a surface holds before further ones are dropped.
+const INPUT_SIZE: usize = 256;
+
+const BACKGROUND: Rgba = Rgba::rgb(0x10, 0x20, 0x30);
+const BORDER: Rgba = Rgba::rgb(0x55, 0x55, 0x55);
+const FOCUSED_BORDER: Rgba = Rgba::rgb(0xff, 0xff, 0x55);
+
+/// Errors returned by surface operations. The discriminant is the error value
+/// a system call reports in `x7`.
+#[repr(u64)]
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Error {
+    /// There is no input to read yet. `surface_read` waits and retries
+    /// instead of reporting this error.
+    WouldBlock = 1,
+    /// The surface doesn't exist or belongs to another process.
+    BadSurface = 2,
+    /// The surface is empty or larger than the desktop, or the rectangle
+    /// drawn isn't within the surface.
+    BadSize = 3,
+    /// The process already has `MAX_SURFACES` surfaces.
+    TooMany = 4,
+    /// The compositor hasn't been initialized.
+    NoDesktop = 5,
+}
+
+/// An off-screen image a process draws into, and where it is on the desktop.
+#[derive(Debug)]
+struct Surface {
+    id: SurfaceId,
+    owner: Id,
+    /// The surface's position and size, relative to the desktop.
+    rect: Rect,
+    image: Image,
+    input: VecDeque<u8>,
+}
+
+/// Returns `rect` grown by the one pixel wide border drawn around surfaces.
+fn outline(rect: Rect) -> Rect {
+    Rect::new(rect.x - 1, rect.y - 1, rect.width + 2, rect.height + 2)
+}
+
+/// Returns the smallest rectangle covering both `a` and `b`.
+fn union(a: Rect, b: Rect) -> Rect {
+    let (left, top) = (cmp::min(a.x, b.x), cmp::min(a.y, b.y));
+    let right = cmp::max(a.x as i64 + a.width as i64, b.x as i64 + b.width as i64);
+    let bottom = cmp::max(a.y as i64 + a.height as i64, b.y as i64 + b.height as i64);
+    Rect::new(left, top, (right - left as i64) as u32, (bottom - top as i64) as u32)
+}
+
+/// Returns the position (`x`, `y`) of a `width` by `height` surface clamped
+/// so that the surface is at most just off `area`, keeping the arithmetic on
+/// its rectangle and outline well within `i32`.
+fn clamp_position(area: Rect, x: i64, y: i64, width: u32, height: u32) -> (i32, i32) {
+    let x = cmp::min(cmp::max(x, -(width as i64)), area.width as i64);
+    let y = cmp::min(cmp::max(y, -(height as i64)), area.height as i64);
+    (x as i32, y as i32)
+}
+
+#[derive(Debug)]
+struct Desktop {
+    /// The part of the framebuffer the desktop covers.
+    area: Rect,
+    /// Surfaces from the bottom of the stack to the top.
+    surfaces: Vec<Surface>,
+    /// The surface console input goes to, if not the console.
+    focus: Option<SurfaceId>,
+    last_id: SurfaceId,
+}
+
+impl Desktop {
+    /// Returns the index in the stack of surface `id` if `owner` owns it.
+    fn find(&self, owner: Id, id: SurfaceId) -> Result<usize, Error> {
+        self.surfaces.iter()
+            .position(|surface| surface.id == id && surface.owner == owner)
+            .ok_or(Error::BadSurface)
+    }
+
+    /// Redraws `damage`, a rectangle relative to the desktop: the background,
+    /// then every surface overlapping it from the bottom of the stack up.
+    fn compose(&self, damage: Rect) {
+        let mut screen = SCREEN.lock_irq();
+        {
+            let framebuffer = match screen.framebuffer() {
+                Some(framebuffer) => framebuffer,
+                None => return,
+            };
+
+            let (left, top) = (self.area.x, self.area.y);
+            let mut canvas = Canvas::new(framebuffer);
+            let damage = Rect::new(left + damage.x, top + damage.y, damage.width, damage.height);
+            canvas.set_clip(damage.intersect(&self.area));
+            canvas.clear(BACKGROUND);
+
+            for surface in self.surfaces.iter() {
+                let rect = Rect::new(left + surface.rect.x, top + surface.rect.y,
+                                     surface.rect.width, surface.rect.height);
+                let border = match self.focus == Some(surface.id) {
+                    true => FOCUSED_BORDER,
+                    false => BORDER,
+                };
+                canvas.rect(outline(rect), border);
+                canvas.blit(&surface.image, rect.x, rect.y);
+            }
+        }
+        screen.present();
+    }
+
+    /// Redraws the surface at `index` and its border.
+    fn compose_surface(&self, index: usize) {
+        self.compose(outline(self.surfaces[index].rect));
+    }
+}
+
+/// The compositor for the whole machine.
+#[derive(Debug)]
+pub struct Compositor(Mutex<Option<Desktop>>);
+
+impl Compositor {
+    /// Returns an uninitialized compositor. Until it is initialized, the
+    /// console has the whole screen and no surfaces can be created.
+    pub const fn uninitialized() -> Compositor {
+        Compositor(Mutex::new(None))
+    }
+
+    /// Takes the top `height` pixels of the screen as the desktop, leaving
+    /// the rest to the console. Returns the desktop's width and height, or
+    /// `None` if there is no screen or it isn't taller than `height`.
+    pub fn initialize(&self, height: usize) -> Option<(usize, usize)> {
+        let mut desktop = self.0.lock_irq();
+        let area = {
+            let mut screen = SCREEN.lock_irq();
+            let (width, screen_height) = match screen.framebuffer() {
+                Some(framebuffer) => (framebuffer.width, framebuffer.height),
+                None => return None,
+            };
+            if screen_height <= height {
+                return None;
+            }
+
+            let console_height = (screen_height - height) as u32;
+            screen.set_viewport(Some(Rect::new(0, height as i32, width as u32, console_height)));
+            Rect::new(0, 0, width as u32, height as u32)
+        };
+
+        let new = Desktop { area, surfaces: Vec::new(), focus: None, last_id: 0 };
+        new.compose(Rect::new(0, 0, area.width, area.height));
+        *desktop = Some(new);
+        Some((area.width as usize, area.height as usize))
+    }
+
+    /// Runs `f` on the desktop with the compositor locked.
+    fn critical<F, R>(&self, f: F) -> Result<R, Error>
+        where F: FnOnce(&mut Desktop) -> Result<R, Error>
+    {
+        match self.0.lock_irq().as_mut() {
+            Some(desktop) => f(desktop),
+            None => Err(Error::NoDesktop),
+        }
+    }
+
+    /// Creates a transparent surface for process `owner` at (`x`, `y`) on the
+    /// desktop, on top of the others, and returns its ID. Positions beyond
+    /// the edges of the desktop are clamped to just off them.
+    pub fn create(&self, owner: Id, x: i64, y: i64, width: usize, height: usize)
+        -> Result<SurfaceId, Error>
+    {
+        self.critical(|desktop| {
+            if width == 0 || height == 0
+                || width > desktop.area.width as usize || height > desktop.area.height as usize {
+                return Err(Error::BadSize);
+            }
+            if desktop.surfaces.iter().filter(|s| s.owner == owner).count() >= MAX_SURFACES {
+                return Err(Error::TooMany);
+            }
+
+            desktop.last_id += 1;
+            let id = desktop.last_id;
+            let (width, height) = (width as u32, height as u32);
+            let (x, y) = clamp_position(desktop.area, x, y, width, height);
+            desktop.surfaces.push(Surface {
+                id,
+                owner,
+                rect: Rect::new(x, y, width, height),
+                image: Image::new(width as usize, height as usize),
+                input: VecDeque::new(),
+            });
+            desktop.compose_surface(desktop.surfaces.len() - 1);
+            Ok(id)
+        })
+    }
+
+    /// Destroys surface `id` of process `owner`.
+    pub fn destroy(&self, owner: Id, id: SurfaceId) -> Result<(), Error> {
+        self.critical(|desktop| {
+            let index = desktop.find(owner, id)?;
+            let surface = desktop.surfaces.remove(index);
+            if desktop.focus == Some(id) {
+                desktop.focus = None;
+            }
+            desktop.compose(outline(surface.rect));
+            Ok(())
+        })
+    }
+
+    /// Destroys every surface of process `owner`, which has exited.
+    pub fn remove_process(&self, owner: Id) {
+        let _ = self.critical(|desktop| {
+            while let Some(index) = desktop.surfaces.iter().position(|s| s.owner == owner) {
+                let surface = desktop.surfaces.remove(index);
+                if desktop.focus == Some(surface.id) {
+                    desktop.focus = None;
+                }
+                desktop.compose(outline(surface.rect));
+            }
+            Ok(())
+        });
+    }
+
+    /// Replaces the `width` by `height` pixels at (`x`, `y`) in surface `id`
+    /// of process `owner` with `pixels`, given row by row as `0xAARRGGBB`,
+    /// and shows them.
+    pub fn update(&self, owner: Id, id: SurfaceId, x: usize, y: usize,
+                  width: usize, height: usize, pixels: &[u32]) -> Result<(), Error> {
+        self.critical(|desktop| {
+            let index = desktop.find(owner, id)?;
+            {
+                let image = &mut desktop.surfaces[index].image;
+                let fits = |start: usize, len: usize, limit: usize| {
+                    start.checked_add(len).map_or(false, |end| end <= limit)
+                };
+                let area = width.checked_mul(height);
+                if !fits(x, width, image.width()) || !fits(y, height, image.height())
+                    || area.map_or(true, |area| pixels.len() < area) {
+                    return Err(Error::BadSize);
+                }
+
+                for (row, line) in pixels.chunks(cmp::max(width, 1)).take(height).enumerate() {
+                    for (column, &pixel) in line.iter().enumerate() {
+                        image.set_pixel(x + column, y + row, Rgba::from_argb(pixel));
+                    }
+                }
+            }
+
+            let rect = desktop.surfaces[index].rect;
+            let damage = Rect::new(rect.x + x as i32, rect.y + y as i32,
+                                   width as u32, height as u32);
+            desktop.compose(damage);
+            Ok(())
+        })
+    }
+
+    /// Moves surface `id` of process `owner` to (`x`, `y`) on the desktop,
+    /// clamped as in `create`.
+    pub fn move_to(&self, owner: Id, id: SurfaceId, x: i64, y: i64) -> Result<(), Error> {
+        self.critical(|desktop| {
+            let index = desktop.find(owner, id)?;
+            let old = desktop.surfaces[index].rect;
+            let (x, y) = clamp_position(desktop.area, x, y, old.width, old.height);
+            desktop.surfaces[index].rect = Rect { x, y, ..old };
+            desktop.compose(union(outline(old), outline(desktop.surfaces[index].rect)));
+            Ok(())
+        })
+    }
+
+    /// Raises surface `id` of process `owner` to the top of the stack and
+    /// gives it the keyboard focus.
+    pub fn raise(&self, owner: Id, id: SurfaceId) -> Result<(), Error> {
+        self.critical(|desktop| {
+            let index = desktop.find(owner, id)?;
+            let surface = desktop.surfaces.remove(index);
+            desktop.surfaces.push(surface);
+            Compositor::focus(desktop, Some(id));
+            Ok(())
+        })
+    }
+
+    /// Moves the keyboard focus to `focus`, redrawing the borders of the
+    /// surfaces that lose and gain it.
+    fn focus(desktop: &mut Desktop, focus: Option<SurfaceId>) {
+        let previous = desktop.focus;
+        desktop.focus = focus;
+        for id in previous.into_iter().chain(focus) {
+            if let Some(index) = desktop.surfaces.iter().position(|s| s.id == id) {
+                desktop.compose_surface(index);
+            }
+        }
+    }
+
+    /// Reads input that arrived while surface `id` of process `owner` had
+    /// the keyboard focus into `buf`, returning the number of bytes read.
+    pub fn read(&self, owner: Id, id: SurfaceId, buf: &mut [u8]) -> Result<usize, Error> {
+        self.critical(|desktop| {
+            let index = desktop.find(owner, id)?;
+            let input = &mut desktop.surfaces[index].input;
+            if input.is_empty() && !buf.is_empty() {
+                return Err(Error::WouldBlock);
+            }
+
+            let len = cmp::min(buf.len(), input.len());
+            for (byte, input) in buf.iter_mut().zip(input.drain(..len)) {
+                *byte = input;
+            }
+            Ok(len)
+        })
+    }
+
+    /// Handles a byte of console input. Returns `true` if the byte was taken:
+    /// if it was `FOCUS_BYTE` or a surface has the focus. Otherwise it is
+    /// left for the console.
+    pub fn input(&self, byte: u8) -> bool {
+        self.critical(|desktop| {
+            if byte == FOCUS_BYTE {
+                // Cycle through the surfaces from the bottom up, then the
+                // console.
+                let next = match desktop.focus {
+                    None => desktop.surfaces.first().map(|s| s.id),
+                    Some(id) => desktop.surfaces.iter()
+                        .skip_while(|s| s.id != id)
+                        .nth(1)
+                        .map(|s| s.id),
+                };
+                Compositor::focus(desktop, next);
+                return Ok(true);
+            }
+
+            let focused = desktop.focus;
+            match desktop.surfaces.iter_mut().find(|s| Some(s.id) == focused) {
+                Some(surface) => {
+                    if surface.input.len() < INPUT_SIZE {
+                        surface.input.push_back(byte);
+                    }
+                    Ok(true)
+                }
+                None => Ok(false),
+            }
+        }).unwrap_or(false)
+    }
+
+    /// Returns the process that owns the focused surface, which Ctrl-C
+    /// interrupts instead of the foreground process.
+    pub fn focused_owner(&self) -> Option<Id> {
+        self.critical(|desktop| {
+            let focused = desktop.focus;
+            Ok(desktop.surfaces.iter().find(|s| Some(s.id) == focused).map(|s| s.owner))
+        }).unwrap_or(None)
+    }
+}
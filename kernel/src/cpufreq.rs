@@ -0,0 +1,326 @@
+//! CPU frequency scaling and thermal throttling.
+//!
+//! Every core counts the time it spends waiting for an interrupt with nothing
+//! to run. The governor thread wakes every `SAMPLE_MS`, works out how busy the
+//! busiest core was since it last woke and picks the ARM clock rate according
+//! to the current `Governor`. It also reads the SoC's temperature: while it is
+//! at or above the throttling threshold, the fastest rate allowed drops by
+//! `STEP` each sample, and once the SoC has cooled `HYSTERESIS` below the
+//! threshold it rises again the same way.
+//!
+//! The firmware may change the core clock along with the ARM clock, and the
+//! mini UART's baud rate is derived from the core clock, so every change
+//! recomputes the UART's divider with the console held.
+
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{cmp, fmt};
+
+use aarch64;
+use pi::aarch64::{disable_irqs, restore_irqs};
+use pi::console::{kprintln, CONSOLE};
+use pi::mutex::Mutex;
+use pi::propertytag::{self, Clock, Error};
+use pi::timer;
+use smp::{self, NCORES};
+use syscalls;
+
+/// How often the governor samples the load and temperature.
+const SAMPLE_MS: u32 = 100;
+
+/// The load, in percent, above which the on-demand governor runs the ARM
+/// clock at its fastest. Below it the rate is proportional to the load.
+const UP_THRESHOLD: u32 = 80;
+
+/// The granularity of rate changes, in Hz.
+const STEP: u32 = 100 * 1000 * 1000;
+
+/// The default throttling threshold, in thousandths of a degree Celsius. The
+/// firmware itself throttles at 85°C.
+pub const THROTTLE_TEMPERATURE: u32 = 75 * 1000;
+
+/// How far below the threshold the SoC must cool before throttling eases.
+const HYSTERESIS: u32 = 5 * 1000;
+
+/// Microseconds each core has spent idle since boot.
+static IDLE_US: [AtomicUsize; NCORES] = [
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+];
+
+static CPUFREQ: Mutex<Option<CpuFreq>> = Mutex::new(None);
+
+/// How the ARM clock rate is chosen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Governor {
+    /// Follows the load of the busiest core.
+    OnDemand,
+    /// Always the fastest rate.
+    Performance,
+    /// Always the slowest rate.
+    Powersave,
+    /// A fixed rate in Hz, set by the user.
+    Userspace(u32),
+}
+
+impl Governor {
+    /// Returns the governor called `name`, other than `Userspace`.
+    pub fn from_name(name: &str) -> Option<Governor> {
+        match name {
+            "ondemand" => Some(Governor::OnDemand),
+            "performance" => Some(Governor::Performance),
+            "powersave" => Some(Governor::Powersave),
+            _ => None,
+        }
+    }
+
+    /// Returns the rate this governor picks between `min` and `max` Hz when
+    /// the busiest core is `load` percent busy.
+    fn target(&self, load: u32, min: u32, max: u32) -> u32 {
+        match *self {
+            Governor::Performance => max,
+            Governor::Powersave => min,
+            Governor::Userspace(rate) => rate,
+            Governor::OnDemand if load >= UP_THRESHOLD => max,
+            Governor::OnDemand => {
+                let span = (max - min) as u64 * load as u64 / UP_THRESHOLD as u64;
+                // Round down to a step so small changes in load don't retune.
+                min + span as u32 / STEP * STEP
+            }
+        }
+    }
+}
+
+impl fmt::Display for Governor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Governor::OnDemand => write!(f, "ondemand"),
+            Governor::Performance => write!(f, "performance"),
+            Governor::Powersave => write!(f, "powersave"),
+            Governor::Userspace(rate) => write!(f, "userspace ({}MHz)", rate / 1000 / 1000),
+        }
+    }
+}
+
+/// A snapshot of the governor's state, returned by `status()`.
+#[derive(Debug, Clone, Copy)]
+pub struct Status {
+    pub governor: Governor,
+    /// The ARM clock's rate and limits, in Hz.
+    pub rate: u32,
+    pub min: u32,
+    pub max: u32,
+    /// The fastest rate thermal throttling currently allows, in Hz.
+    pub ceiling: u32,
+    /// The core clock's rate, in Hz.
+    pub core_clock: u32,
+    /// How busy the busiest core was over the last sample, in percent.
+    pub load: u32,
+    /// The SoC's temperature and the throttling threshold, in thousandths of
+    /// a degree Celsius.
+    pub temperature: u32,
+    pub throttle_temperature: u32,
+}
+
+impl Status {
+    pub fn is_throttled(&self) -> bool {
+        self.ceiling < self.max
+    }
+}
+
+struct CpuFreq {
+    status: Status,
+    /// Each core's `IDLE_US` and the time, at the last sample.
+    last_idle: [usize; NCORES],
+    last_sample: u64,
+}
+
+impl CpuFreq {
+    fn new() -> Result<CpuFreq, Error> {
+        let min = propertytag::min_clock_rate(Clock::Arm)?;
+        let max = propertytag::max_clock_rate(Clock::Arm)?;
+        let mut last_idle = [0; NCORES];
+        for (last, idle) in last_idle.iter_mut().zip(IDLE_US.iter()) {
+            *last = idle.load(Ordering::Relaxed);
+        }
+
+        Ok(CpuFreq {
+            status: Status {
+                governor: Governor::OnDemand,
+                rate: propertytag::clock_rate(Clock::Arm)?,
+                min,
+                max,
+                ceiling: max,
+                core_clock: propertytag::clock_rate(Clock::Core)?,
+                load: 0,
+                temperature: propertytag::temperature()?,
+                throttle_temperature: THROTTLE_TEMPERATURE,
+            },
+            last_idle,
+            last_sample: timer::current_time(),
+        })
+    }
+
+    /// Updates the load from the cores' idle time since the last sample.
+    fn sample_load(&mut self) {
+        let now = timer::current_time();
+        let elapsed = cmp::max(now.wrapping_sub(self.last_sample) as usize, 1);
+        self.last_sample = now;
+
+        let mut load = 0;
+        for core in 0..NCORES {
+            let idle = IDLE_US[core].load(Ordering::Relaxed);
+            let idle_delta = idle.wrapping_sub(self.last_idle[core]);
+            self.last_idle[core] = idle;
+            if smp::is_online(core) {
+                let busy = elapsed.saturating_sub(idle_delta);
+                load = cmp::max(load, busy * 100 / elapsed);
+            }
+        }
+        self.status.load = load as u32;
+    }
+
+    /// Updates the temperature and moves the ceiling a step if the SoC is too
+    /// hot or has cooled down.
+    fn sample_temperature(&mut self) -> Result<(), Error> {
+        let status = &mut self.status;
+        status.temperature = propertytag::temperature()?;
+        if status.temperature >= status.throttle_temperature {
+            status.ceiling = cmp::max(status.ceiling.saturating_sub(STEP), status.min);
+        } else if status.temperature + HYSTERESIS < status.throttle_temperature {
+            status.ceiling = cmp::min(status.ceiling.saturating_add(STEP), status.max);
+        }
+        Ok(())
+    }
+
+    /// Sets the ARM clock to what the governor picks, within the ceiling.
+    fn retune(&mut self) -> Result<(), Error> {
+        let status = self.status;
+        let target = status.governor.target(status.load, status.min, status.max);
+        let rate = cmp::max(cmp::min(target, status.ceiling), status.min);
+        if rate != status.rate {
+            self.set_clock_rate(Clock::Arm, rate)?;
+        }
+        Ok(())
+    }
+
+    /// Sets `clock` to `rate` Hz, returning the rate it was set to.
+    fn set_clock_rate(&mut self, clock: Clock, rate: u32) -> Result<u32, Error> {
+        let (rate, core_clock) = change_clock_rate(clock, rate, self.status.core_clock)?;
+        self.status.core_clock = core_clock;
+        if clock == Clock::Arm {
+            self.status.rate = rate;
+        }
+        Ok(rate)
+    }
+}
+
+/// Sets `clock` to `rate` Hz and, if the core clock is no longer `core_clock`
+/// Hz, recomputes the mini UART's divider. Returns the rates `clock` and the
+/// core clock were set to.
+fn change_clock_rate(clock: Clock, rate: u32, core_clock: u32) -> Result<(u32, u32), Error> {
+    // Drain what's queued at the old divider. The console isn't held across
+    // the mailbox exchanges, which can take up to a second: output from
+    // other cores until the divider is fixed up may be garbled.
+    let _ = CONSOLE.lock_irq().flush();
+
+    let rate = propertytag::set_clock_rate(clock, rate, false)?;
+    let new_core_clock = propertytag::clock_rate(Clock::Core)?;
+    if new_core_clock != core_clock {
+        CONSOLE.lock_irq().set_core_clock(new_core_clock);
+    }
+    Ok((rate, new_core_clock))
+}
+
+/// Runs `f` with the governor's state locked. Fails with `NoSuchDevice` if
+/// the governor isn't running.
+fn critical<F, R>(f: F) -> Result<R, Error>
+    where F: FnOnce(&mut CpuFreq) -> Result<R, Error>
+{
+    match *CPUFREQ.lock() {
+        Some(ref mut cpufreq) => f(cpufreq),
+        None => Err(Error::NoSuchDevice),
+    }
+}
+
+/// Waits for an interrupt, counting the time spent as idle for the calling
+/// core. IRQs are masked until the time is counted; a pending IRQ still wakes
+/// the core.
+pub fn idle() {
+    let core = aarch64::affinity();
+    unsafe {
+        let daif = disable_irqs();
+        let start = timer::current_time();
+        aarch64::wfi();
+        let idle = timer::current_time().wrapping_sub(start) as usize;
+        IDLE_US[core].fetch_add(idle, Ordering::Relaxed);
+        restore_irqs(daif);
+    }
+}
+
+/// Returns the governor's state, or `None` if it isn't running.
+pub fn status() -> Option<Status> {
+    CPUFREQ.lock().as_ref().map(|cpufreq| cpufreq.status)
+}
+
+/// Switches to `governor` and retunes the ARM clock right away.
+pub fn set_governor(governor: Governor) -> Result<Status, Error> {
+    critical(|cpufreq| {
+        cpufreq.status.governor = governor;
+        cpufreq.retune()?;
+        Ok(cpufreq.status)
+    })
+}
+
+/// Sets the temperature, in thousandths of a degree Celsius, at which the ARM
+/// clock is throttled.
+pub fn set_throttle_temperature(temperature: u32) -> Result<(), Error> {
+    critical(|cpufreq| {
+        cpufreq.status.throttle_temperature = temperature;
+        Ok(())
+    })
+}
+
+/// Sets `clock` to `rate` Hz and returns the rate it was set to. Setting the
+/// ARM clock switches to the userspace governor, so the rate sticks.
+pub fn set_clock_rate(clock: Clock, rate: u32) -> Result<u32, Error> {
+    if clock == Clock::Arm {
+        return set_governor(Governor::Userspace(rate)).map(|status| status.rate);
+    }
+
+    match *CPUFREQ.lock() {
+        Some(ref mut cpufreq) => cpufreq.set_clock_rate(clock, rate),
+        None => change_clock_rate(clock, rate, 0).map(|(rate, _)| rate),
+    }
+}
+
+/// The governor thread: samples the load and temperature every `SAMPLE_MS`
+/// and retunes the ARM clock. Returns only if the firmware can't report the
+/// ARM clock's limits.
+pub extern "C" fn governor(_: u64) -> u64 {
+    let cpufreq = match CpuFreq::new() {
+        Ok(cpufreq) => cpufreq,
+        Err(e) => {
+            kprintln!("cpufreq: no frequency scaling: {:?}", e);
+            return 1;
+        }
+    };
+
+    // The firmware may have booted with a core clock other than the one the
+    // UART assumed.
+    let core_clock = cpufreq.status.core_clock;
+    CONSOLE.lock_irq().set_core_clock(core_clock);
+    *CPUFREQ.lock() = Some(cpufreq);
+
+    loop {
+        let _ = syscalls::sleep(SAMPLE_MS);
+
+        let result = critical(|cpufreq| {
+            cpufreq.sample_load();
+            cpufreq.sample_temperature()?;
+            cpufreq.retune()
+        });
+        if let Err(e) = result {
+            kprintln!("cpufreq: {:?}", e);
+        }
+    }
+}
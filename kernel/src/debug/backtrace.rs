@@ -0,0 +1,143 @@
+//! Frame-pointer based stack backtraces, symbolized with the kernel's symbol
+//! table.
+//!
+//! Every function compiled with frame pointers pushes a frame record of the
+//! caller's `x29` followed by its return address and points `x29` at it, so
+//! the records form a linked list up the stack. The symbol table is the
+//! output of `nm -n` on the kernel ELF, which the Makefile writes next to the
+//! kernel image; copy it to the root of the SD card to get function names.
+
+use std::io::{self, Read};
+use std::str;
+
+use fs::traits;
+use pi::common::IO_BASE;
+use pi::console::kprintln;
+use pi::mutex::Mutex;
+use traps::TrapFrame;
+use FILE_SYSTEM;
+
+/// Where `load_symbols()` looks for the symbol table by default.
+pub const SYMBOL_FILE: &str = "/kernel.sym";
+
+/// The most frames printed in one backtrace.
+const MAX_FRAMES: usize = 32;
+
+/// A function symbol: its start address and demangled name.
+#[derive(Debug)]
+struct Symbol {
+    addr: u64,
+    name: String,
+}
+
+/// The loaded symbols, sorted by address.
+static SYMBOLS: Mutex<Option<Vec<Symbol>>> = Mutex::new(None);
+
+/// Loads the symbol table at `path`, replacing any loaded before, and returns
+/// the number of function symbols read. Lines that aren't text symbols in
+/// `nm` format are skipped.
+pub fn load_symbols(path: &str) -> io::Result<usize> {
+    let mut file = traits::FileSystem::open_file(&FILE_SYSTEM, path)?;
+    let mut contents = vec![];
+    file.read_to_end(&mut contents)?;
+    let text = str::from_utf8(&contents)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "symbol table isn't UTF-8"))?;
+
+    let mut symbols: Vec<Symbol> = text.lines().filter_map(parse_line).collect();
+    symbols.sort_by_key(|symbol| symbol.addr);
+
+    let count = symbols.len();
+    *SYMBOLS.lock() = Some(symbols);
+    Ok(count)
+}
+
+/// Parses a line of `nm` output such as `0000000000080000 T _start`, keeping
+/// only symbols in the text section.
+fn parse_line(line: &str) -> Option<Symbol> {
+    let mut parts = line.splitn(3, ' ');
+    let addr = u64::from_str_radix(parts.next()?, 16).ok()?;
+    match parts.next()? {
+        "T" | "t" | "W" | "w" => {},
+        _ => return None
+    }
+
+    let name = parts.next()?.trim();
+    if name.is_empty() {
+        return None;
+    }
+
+    Some(Symbol { addr, name: name.to_string() })
+}
+
+/// Prints the backtrace of the caller.
+#[inline(never)]
+pub fn print() {
+    let fp: u64;
+    unsafe { asm!("mov $0, x29" : "=r"(fp)) };
+
+    kprintln!("backtrace:");
+    print_frames(fp, 0);
+}
+
+/// Prints the backtrace of the code that took the exception with trap frame
+/// `tf`, starting from the instruction at `elr`. Addresses in user programs
+/// aren't symbolized.
+pub fn print_trap(tf: &TrapFrame) {
+    kprintln!("backtrace:");
+    print_frame(0, tf.elr);
+    print_frames(tf.x29, 1);
+}
+
+/// Prints a frame for each record in the frame chain starting at `fp`,
+/// numbering them from `first`.
+fn print_frames(mut fp: u64, first: usize) {
+    for n in first..MAX_FRAMES {
+        if !is_valid_frame(fp) {
+            return;
+        }
+
+        let (next, lr) = unsafe {
+            let record = fp as *const u64;
+            (*record, *record.offset(1))
+        };
+
+        if lr == 0 {
+            return;
+        }
+
+        // The return address is the instruction after the call.
+        print_frame(n, lr - 4);
+
+        // Stacks grow down, so callers' records are at higher addresses.
+        if next <= fp {
+            return;
+        }
+        fp = next;
+    }
+
+    kprintln!("  ...");
+}
+
+fn is_valid_frame(fp: u64) -> bool {
+    fp != 0 && fp & 0xf == 0 && fp < IO_BASE as u64
+}
+
+fn print_frame(n: usize, pc: u64) {
+    // The panic may have happened while the symbols were being loaded.
+    let guard = SYMBOLS.try_lock();
+    let symbols = match guard.as_ref().and_then(|symbols| symbols.as_ref()) {
+        Some(symbols) => symbols,
+        None => return kprintln!("  #{:<2} {:#018x}", n, pc),
+    };
+
+    let index = match symbols.binary_search_by_key(&pc, |symbol| symbol.addr) {
+        Ok(index) => Some(index),
+        Err(0) => None,
+        Err(index) => Some(index - 1),
+    };
+
+    match index.map(|index| &symbols[index]) {
+        Some(symbol) => kprintln!("  #{:<2} {:#018x} {}+{:#x}", n, pc, symbol.name, pc - symbol.addr),
+        None => kprintln!("  #{:<2} {:#018x} ??", n, pc),
+    }
+}
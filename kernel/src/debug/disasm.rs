@@ -0,0 +1,231 @@
+//! A minimal A64 disassembler covering the instructions the compiler emits
+//! most often. Anything else is shown as a raw `.word`.
+
+use std::fmt;
+
+const CONDITIONS: [&str; 16] = [
+    "eq", "ne", "hs", "lo", "mi", "pl", "vs", "vc",
+    "hi", "ls", "ge", "lt", "gt", "le", "al", "nv",
+];
+
+/// A decoded instruction at a known address, displayed in assembler syntax.
+#[derive(Debug, Copy, Clone)]
+pub struct Instruction {
+    pub addr: u64,
+    pub word: u32,
+}
+
+impl Instruction {
+    /// Returns the instruction at `addr`.
+    ///
+    /// # Safety
+    ///
+    /// `addr` must be 4-byte aligned and readable.
+    pub unsafe fn read(addr: u64) -> Instruction {
+        Instruction { addr, word: *(addr as *const u32) }
+    }
+
+    fn bits(&self, lo: u32, len: u32) -> u32 {
+        (self.word >> lo) & ((1 << len) - 1)
+    }
+
+    /// Sign-extends the `len`-bit field at `lo`, scaled by `scale`, as an
+    /// offset from this instruction.
+    fn target(&self, lo: u32, len: u32, scale: u32) -> u64 {
+        let shift = 64 - len;
+        let offset = ((self.bits(lo, len) as i64) << shift) >> shift;
+        self.addr.wrapping_add((offset << scale) as u64)
+    }
+}
+
+/// Formats general purpose register `n`, where 31 is `sp` if `sp` is set and
+/// the zero register otherwise.
+struct Reg {
+    n: u32,
+    wide: bool,
+    sp: bool,
+}
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.n, self.wide, self.sp) {
+            (31, true, true) => write!(f, "sp"),
+            (31, false, true) => write!(f, "wsp"),
+            (31, true, false) => write!(f, "xzr"),
+            (31, false, false) => write!(f, "wzr"),
+            (n, true, _) => write!(f, "x{}", n),
+            (n, false, _) => write!(f, "w{}", n),
+        }
+    }
+}
+
+fn x(n: u32) -> Reg { Reg { n, wide: true, sp: false } }
+fn xsp(n: u32) -> Reg { Reg { n, wide: true, sp: true } }
+fn r(n: u32, wide: bool) -> Reg { Reg { n, wide, sp: false } }
+fn rsp(n: u32, wide: bool) -> Reg { Reg { n, wide, sp: true } }
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let w = self.word;
+        let rd = self.bits(0, 5);
+        let rn = self.bits(5, 5);
+        let sf = self.bits(31, 1) == 1;
+
+        match w {
+            0xd503201f => return write!(f, "nop"),
+            0xd503203f => return write!(f, "yield"),
+            0xd503205f => return write!(f, "wfe"),
+            0xd503207f => return write!(f, "wfi"),
+            0xd503209f => return write!(f, "sev"),
+            0xd69f03e0 => return write!(f, "eret"),
+            _ => {}
+        }
+
+        if w & 0xfffffc1f == 0xd65f0000 {
+            return match rn {
+                30 => write!(f, "ret"),
+                rn => write!(f, "ret {}", x(rn)),
+            };
+        }
+
+        if w & 0xfffffc1f == 0xd61f0000 {
+            return write!(f, "br {}", x(rn));
+        }
+
+        if w & 0xfffffc1f == 0xd63f0000 {
+            return write!(f, "blr {}", x(rn));
+        }
+
+        if w & 0xffe0001f == 0xd4000001 {
+            return write!(f, "svc #{:#x}", self.bits(5, 16));
+        }
+
+        if w & 0xffe0001f == 0xd4000002 {
+            return write!(f, "hvc #{:#x}", self.bits(5, 16));
+        }
+
+        if w & 0xffe0001f == 0xd4200000 {
+            return write!(f, "brk #{:#x}", self.bits(5, 16));
+        }
+
+        if w & 0x7c000000 == 0x14000000 {
+            let op = if w >> 31 == 1 { "bl" } else { "b" };
+            return write!(f, "{} {:#x}", op, self.target(0, 26, 2));
+        }
+
+        if w & 0xff000010 == 0x54000000 {
+            let cond = CONDITIONS[self.bits(0, 4) as usize];
+            return write!(f, "b.{} {:#x}", cond, self.target(5, 19, 2));
+        }
+
+        if w & 0x7e000000 == 0x34000000 {
+            let op = if self.bits(24, 1) == 1 { "cbnz" } else { "cbz" };
+            return write!(f, "{} {}, {:#x}", op, r(rd, sf), self.target(5, 19, 2));
+        }
+
+        if w & 0x7e000000 == 0x36000000 {
+            let op = if self.bits(24, 1) == 1 { "tbnz" } else { "tbz" };
+            let bit = self.bits(31, 1) << 5 | self.bits(19, 5);
+            return write!(f, "{} {}, #{}, {:#x}", op, r(rd, bit >= 32), bit, self.target(5, 14, 2));
+        }
+
+        if w & 0x1f000000 == 0x10000000 {
+            let imm = (self.bits(5, 19) << 2 | self.bits(29, 2)) as u64;
+            let signed = ((imm << 43) as i64 >> 43) as u64;
+            return match w >> 31 {
+                0 => write!(f, "adr {}, {:#x}", x(rd), self.addr.wrapping_add(signed)),
+                _ => write!(f, "adrp {}, {:#x}", x(rd),
+                            (self.addr & !0xfff).wrapping_add(signed << 12)),
+            };
+        }
+
+        if w & 0x1f000000 == 0x11000000 {
+            let imm = (self.bits(10, 12) as u64) << (12 * self.bits(22, 1));
+            let sub = self.bits(30, 1) == 1;
+            let set_flags = self.bits(29, 1) == 1;
+            if !sub && !set_flags && imm == 0 && (rd == 31 || rn == 31) {
+                return write!(f, "mov {}, {}", rsp(rd, sf), rsp(rn, sf));
+            }
+            if set_flags && rd == 31 {
+                let op = if sub { "cmp" } else { "cmn" };
+                return write!(f, "{} {}, #{:#x}", op, rsp(rn, sf), imm);
+            }
+            let op = match (sub, set_flags) {
+                (false, false) => "add",
+                (false, true) => "adds",
+                (true, false) => "sub",
+                (true, true) => "subs",
+            };
+            let dst = if set_flags { r(rd, sf) } else { rsp(rd, sf) };
+            return write!(f, "{} {}, {}, #{:#x}", op, dst, rsp(rn, sf), imm);
+        }
+
+        if w & 0x1f800000 == 0x12800000 {
+            let op = match self.bits(29, 2) {
+                0b00 => "movn",
+                0b10 => "movz",
+                0b11 => "movk",
+                _ => return write!(f, ".word {:#010x}", w),
+            };
+            let shift = self.bits(21, 2) * 16;
+            return match shift {
+                0 => write!(f, "{} {}, #{:#x}", op, r(rd, sf), self.bits(5, 16)),
+                _ => write!(f, "{} {}, #{:#x}, lsl #{}", op, r(rd, sf), self.bits(5, 16), shift),
+            };
+        }
+
+        if w & 0x7f200000 == 0x2a000000 {
+            let rm = self.bits(16, 5);
+            if rn == 31 && self.bits(10, 6) == 0 && self.bits(22, 2) == 0 {
+                return write!(f, "mov {}, {}", r(rd, sf), r(rm, sf));
+            }
+            return write!(f, "orr {}, {}, {}", r(rd, sf), r(rn, sf), r(rm, sf));
+        }
+
+        if w & 0x3b000000 == 0x39000000 {
+            let size = self.bits(30, 2);
+            let opc = self.bits(22, 2);
+            let offset = (self.bits(10, 12) << size) as u64;
+            let (op, wide) = match (size, opc) {
+                (0b00, 0b00) => ("strb", false),
+                (0b00, 0b01) => ("ldrb", false),
+                (0b01, 0b00) => ("strh", false),
+                (0b01, 0b01) => ("ldrh", false),
+                (0b10, 0b00) => ("str", false),
+                (0b10, 0b01) => ("ldr", false),
+                (0b11, 0b00) => ("str", true),
+                (0b11, 0b01) => ("ldr", true),
+                _ => return write!(f, ".word {:#010x}", w),
+            };
+            if self.bits(26, 1) == 1 {
+                return write!(f, ".word {:#010x}", w);
+            }
+            return write!(f, "{} {}, [{}, #{:#x}]", op, r(rd, wide), xsp(rn), offset);
+        }
+
+        if w & 0x3e000000 == 0x28000000 {
+            let wide = self.bits(31, 1) == 1;
+            let op = if self.bits(22, 1) == 1 { "ldp" } else { "stp" };
+            let scale = if wide { 3 } else { 2 };
+            let imm = ((self.bits(15, 7) as i64) << 57 >> 57) << scale;
+            let (rt, rt2) = (r(rd, wide), r(self.bits(10, 5), wide));
+            return match self.bits(23, 2) {
+                0b01 => write!(f, "{} {}, {}, [{}], #{}", op, rt, rt2, xsp(rn), imm),
+                0b11 => write!(f, "{} {}, {}, [{}, #{}]!", op, rt, rt2, xsp(rn), imm),
+                _ => write!(f, "{} {}, {}, [{}, #{}]", op, rt, rt2, xsp(rn), imm),
+            };
+        }
+
+        if w & 0xffd00000 == 0xd5100000 {
+            let sysreg = self.bits(5, 15);
+            let name = format!("s{}_{}_c{}_c{}_{}", 2 + (sysreg >> 14), (sysreg >> 11) & 0b111,
+                               (sysreg >> 7) & 0b1111, (sysreg >> 3) & 0b1111, sysreg & 0b111);
+            return match self.bits(21, 1) {
+                1 => write!(f, "mrs {}, {}", x(rd), name),
+                _ => write!(f, "msr {}, {}", name, x(rd)),
+            };
+        }
+
+        write!(f, ".word {:#010x}", w)
+    }
+}
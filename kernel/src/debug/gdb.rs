@@ -0,0 +1,360 @@
+//! A GDB remote serial protocol stub speaking over the console UART.
+//!
+//! Once attached, every debug exception stops into the stub instead of the
+//! monitor until GDB detaches. The UART is shared with the console, so
+//! output from other cores while stopped corrupts the session.
+
+use std::fmt::Write;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use pi::console::{self, CONSOLE};
+use pi::mutex::Mutex;
+use traps::TrapFrame;
+use lockup;
+use debug::{hw, is_brk, poke, register, sync_instructions, NUM_REGISTERS};
+
+/// The `brk` immediate that attaches the stub.
+pub const ATTACH_BRK: u16 = 0x6762;
+
+/// The largest packet the stub accepts, advertised to GDB.
+const PACKET_SIZE: usize = 4096;
+
+/// Signal numbers reported in stop replies.
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// `brk #0`, the instruction inserted for software breakpoints.
+const BREAKPOINT: u32 = 0xd4200000;
+
+/// The number of software breakpoints that can be inserted at once.
+const NUM_SOFTWARE_BREAKPOINTS: usize = 32;
+
+static ATTACHED: AtomicBool = AtomicBool::new(false);
+
+/// The address and original instruction of each inserted software
+/// breakpoint.
+static INSERTED: Mutex<[Option<(u64, u32)>; NUM_SOFTWARE_BREAKPOINTS]> =
+    Mutex::new([None; NUM_SOFTWARE_BREAKPOINTS]);
+
+/// Returns `true` while GDB is attached.
+pub fn is_attached() -> bool {
+    ATTACHED.load(Ordering::Acquire)
+}
+
+/// Stops into the stub for a debug exception, attaching if GDB isn't
+/// attached yet, and returns once GDB continues, steps or detaches.
+pub fn enter(tf: &mut TrapFrame) {
+    if !ATTACHED.swap(true, Ordering::AcqRel) {
+        // GDB sends its first packet without waiting for a stop reply.
+        session(tf, None);
+    } else {
+        session(tf, Some(SIGTRAP));
+    }
+}
+
+/// Stops into the stub because GDB sent an interrupt (Ctrl-C) while the
+/// target was running.
+pub fn interrupt(tf: &mut TrapFrame) {
+    lockup::pause();
+    session(tf, Some(SIGINT));
+    lockup::resume();
+}
+
+/// Runs the protocol until GDB resumes the target. If `signal` is set, the
+/// stop is reported to GDB first.
+fn session(tf: &mut TrapFrame, signal: Option<u8>) {
+    let mut buf = [0u8; PACKET_SIZE];
+    let mut last_signal = signal.unwrap_or(SIGTRAP);
+
+    if let Some(signal) = signal {
+        send(&format!("S{:02x}", signal));
+    }
+
+    loop {
+        let packet = match receive(&mut buf) {
+            Some(packet) => packet,
+            None => continue
+        };
+
+        // Packets are bytes off the wire, not necessarily ASCII.
+        let (command, args) = (packet[0], &packet[1..]);
+        match command {
+            b'?' => send(&format!("S{:02x}", last_signal)),
+            b'g' => send(&read_registers(tf)),
+            b'G' => send(write_registers(tf, args).map_or("E01", |_| "OK")),
+            b'p' => match parse_hex(args).and_then(|n| gdb_register(tf, n as usize)) {
+                Some(value) => send(&hex_u64(*value)),
+                None => send("E01"),
+            },
+            b'P' => send(write_register(tf, args).map_or("E01", |_| "OK")),
+            b'm' => match read_memory(args) {
+                Some(data) => send(&data),
+                None => send("E01"),
+            },
+            b'M' => send(write_memory(args).map_or("E01", |_| "OK")),
+            b'c' | b's' => {
+                if let Some(addr) = parse_hex(args) {
+                    tf.elr = addr;
+                }
+                resume(tf, command == b's');
+                return;
+            },
+            b'D' => {
+                send("OK");
+                ATTACHED.store(false, Ordering::Release);
+                resume(tf, false);
+                return;
+            },
+            b'k' => {
+                ATTACHED.store(false, Ordering::Release);
+                resume(tf, false);
+                return;
+            },
+            b'Z' | b'z' => send(breakpoint(command == b'Z', args).map_or("E01", |_| "OK")),
+            b'H' => send("OK"),
+            b'q' => query(args, tf),
+            _ => send(""),
+        }
+
+        last_signal = SIGTRAP;
+    }
+}
+
+/// Prepares `tf` to resume, stepping one instruction if `step` is set.
+fn resume(tf: &mut TrapFrame, step: bool) {
+    // GDB removes its own breakpoints while stopped, so any `brk` still at
+    // `elr` is part of the program; skip it.
+    if is_brk(tf.elr) {
+        tf.elr += 4;
+    }
+
+    hw::set_single_step(tf, step);
+}
+
+fn query(args: &[u8], tf: &TrapFrame) {
+    if args.starts_with(b"Supported") {
+        send(&format!("PacketSize={:x}", PACKET_SIZE));
+    } else if args == b"Attached" {
+        send("1");
+    } else if args == b"C" {
+        send(&format!("QC{:x}", tf.tpidr));
+    } else if args == b"fThreadInfo" {
+        send(&format!("m{:x}", tf.tpidr));
+    } else if args == b"sThreadInfo" {
+        send("l");
+    } else {
+        send("");
+    }
+}
+
+/// Reads a packet into `buf`, acknowledging it, and returns its contents.
+/// Returns `None` if the checksum didn't match or the packet is empty.
+fn receive(buf: &mut [u8]) -> Option<&[u8]> {
+    while console::read_byte() != b'$' {}
+
+    let mut len = 0;
+    let mut sum: u8 = 0;
+    loop {
+        match console::read_byte() {
+            b'#' => break,
+            byte => {
+                sum = sum.wrapping_add(byte);
+                if len < buf.len() {
+                    buf[len] = byte;
+                    len += 1;
+                }
+            }
+        }
+    }
+
+    let checksum = [console::read_byte(), console::read_byte()];
+    if parse_byte(&checksum) != Some(sum) || len == buf.len() {
+        write_bytes(b"-");
+        return None;
+    }
+
+    write_bytes(b"+");
+    match len {
+        0 => None,
+        _ => Some(&buf[..len]),
+    }
+}
+
+/// Sends `data` as a packet, retransmitting until GDB acknowledges it.
+fn send(data: &str) {
+    let sum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+    loop {
+        write_bytes(b"$");
+        write_bytes(data.as_bytes());
+        write_bytes(format!("#{:02x}", sum).as_bytes());
+
+        match console::read_byte() {
+            b'+' => return,
+            _ => continue
+        }
+    }
+}
+
+fn write_bytes(bytes: &[u8]) {
+    let mut console = CONSOLE.lock_irq();
+    for &byte in bytes {
+        console.write_byte(byte);
+    }
+}
+
+fn hex_digit(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|digit| digit as u8)
+}
+
+fn parse_hex(hex: &[u8]) -> Option<u64> {
+    if hex.is_empty() {
+        return None;
+    }
+
+    hex.iter().try_fold(0u64, |value, &byte| {
+        Some(value.checked_mul(16)? | hex_digit(byte)? as u64)
+    })
+}
+
+/// Parses a byte from exactly two hex digits.
+fn parse_byte(hex: &[u8]) -> Option<u8> {
+    match hex.len() {
+        2 => Some(hex_digit(hex[0])? << 4 | hex_digit(hex[1])?),
+        _ => None,
+    }
+}
+
+/// Formats `value` as 8 little-endian bytes in hex, as GDB expects.
+fn hex_u64(value: u64) -> String {
+    let mut hex = String::with_capacity(16);
+    for i in 0..8 {
+        let _ = write!(hex, "{:02x}", (value >> (8 * i)) as u8);
+    }
+    hex
+}
+
+/// Parses 8 little-endian bytes in hex.
+fn parse_u64(hex: &[u8]) -> Option<u64> {
+    if hex.len() != 16 {
+        return None;
+    }
+
+    hex.chunks(2).enumerate().try_fold(0u64, |value, (i, pair)| {
+        Some(value | (parse_byte(pair)? as u64) << (8 * i))
+    })
+}
+
+/// Returns GDB's register `n`: x0-x30, then sp, pc and cpsr.
+fn gdb_register(tf: &mut TrapFrame, n: usize) -> Option<&mut u64> {
+    match n {
+        31 => Some(&mut tf.sp),
+        32 => Some(&mut tf.elr),
+        33 => Some(&mut tf.spsr),
+        n => register(tf, n)
+    }
+}
+
+fn read_registers(tf: &mut TrapFrame) -> String {
+    let mut data = String::new();
+    for n in 0..NUM_REGISTERS + 2 {
+        data.push_str(&hex_u64(*gdb_register(tf, n).expect("register")));
+    }
+
+    // cpsr is 32 bits wide.
+    data.push_str(&hex_u64(tf.spsr)[..8]);
+    data
+}
+
+fn write_registers(tf: &mut TrapFrame, hex: &[u8]) -> Option<()> {
+    if hex.len() < (NUM_REGISTERS + 2) * 16 {
+        return None;
+    }
+
+    for n in 0..NUM_REGISTERS + 2 {
+        *gdb_register(tf, n)? = parse_u64(&hex[16 * n..16 * n + 16])?;
+    }
+
+    Some(())
+}
+
+fn write_register(tf: &mut TrapFrame, args: &[u8]) -> Option<()> {
+    let mut parts = args.splitn(2, |&byte| byte == b'=');
+    let n = parse_hex(parts.next()?)? as usize;
+    let mut value = parts.next()?.to_vec();
+    if n == 33 && value.len() == 8 {
+        value.extend_from_slice(b"00000000");
+    }
+
+    *gdb_register(tf, n)? = parse_u64(&value)?;
+    Some(())
+}
+
+/// Parses the `addr,len` arguments of a memory packet.
+fn parse_range(args: &[u8]) -> Option<(u64, usize)> {
+    let mut parts = args.splitn(2, |&byte| byte == b',');
+    let addr = parse_hex(parts.next()?)?;
+    let len = parse_hex(parts.next()?)? as usize;
+    Some((addr, len))
+}
+
+fn read_memory(args: &[u8]) -> Option<String> {
+    let (addr, len) = parse_range(args)?;
+    if 2 * len > PACKET_SIZE {
+        return None;
+    }
+
+    let mut data = String::with_capacity(2 * len);
+    for i in 0..len {
+        let byte = unsafe { ptr::read_volatile((addr + i as u64) as *const u8) };
+        let _ = write!(data, "{:02x}", byte);
+    }
+    Some(data)
+}
+
+fn write_memory(args: &[u8]) -> Option<()> {
+    let mut parts = args.splitn(2, |&byte| byte == b':');
+    let (addr, len) = parse_range(parts.next()?)?;
+    let hex = parts.next()?;
+    if hex.len() != 2 * len {
+        return None;
+    }
+
+    for (i, pair) in hex.chunks(2).enumerate() {
+        let byte = parse_byte(pair)?;
+        unsafe { poke(addr + i as u64, byte) };
+    }
+
+    sync_instructions(addr, len);
+    Some(())
+}
+
+/// Inserts (`Z`) or removes (`z`) a software breakpoint by swapping the
+/// instruction at its address with `BREAKPOINT`. Only type 0 with a kind of
+/// 4, one A64 instruction, is supported.
+fn breakpoint(insert: bool, args: &[u8]) -> Option<()> {
+    let mut parts = args.split(|&byte| byte == b',');
+    if parts.next()? != b"0" {
+        return None;
+    }
+
+    let addr = parse_hex(parts.next()?)?;
+    if addr & 0b11 != 0 || parse_hex(parts.next()?)? != 4 {
+        return None;
+    }
+
+    let mut saved = INSERTED.lock_irq();
+    if insert {
+        let slot = saved.iter().position(|s| s.is_none())?;
+        let original = unsafe { ptr::read_volatile(addr as *const u32) };
+        saved[slot] = Some((addr, original));
+        unsafe { poke(addr, BREAKPOINT) };
+    } else {
+        let slot = saved.iter().position(|s| s.map(|(a, _)| a) == Some(addr))?;
+        let (_, original) = saved[slot].take()?;
+        unsafe { poke(addr, original) };
+    }
+
+    sync_instructions(addr, 4);
+    Some(())
+}
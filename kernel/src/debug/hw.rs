@@ -0,0 +1,163 @@
+//! Access to the AArch64 self-hosted debug registers (ref: D2, D10).
+//!
+//! Breakpoints and watchpoints match in both EL0 and EL1, but debug
+//! exceptions are only taken from EL1 while `PSTATE.D` is clear, which the
+//! kernel never does. In practice they trigger in EL0 code only.
+
+use traps::TrapFrame;
+
+/// The number of hardware breakpoints on the Cortex-A53.
+pub const NUM_BREAKPOINTS: usize = 6;
+
+/// The number of hardware watchpoints on the Cortex-A53.
+pub const NUM_WATCHPOINTS: usize = 4;
+
+/// `MDSCR_EL1.SS`: software step enable.
+const MDSCR_SS: u64 = 1 << 0;
+
+/// `MDSCR_EL1.KDE`: local (kernel) debug enable.
+const MDSCR_KDE: u64 = 1 << 13;
+
+/// `MDSCR_EL1.MDE`: monitor debug events (breakpoints, watchpoints) enable.
+const MDSCR_MDE: u64 = 1 << 15;
+
+/// `SPSR.SS`: step the first instruction after the exception return.
+const SPSR_SS: u64 = 1 << 21;
+
+/// `SPSR.D`: debug exceptions masked.
+const SPSR_D: u64 = 1 << 9;
+
+/// `DBGBCR.E` / `DBGWCR.E`: enabled.
+const CR_ENABLE: u64 = 1 << 0;
+
+/// `DBGBCR.PMC` / `DBGWCR.PAC`: match in EL1 and EL0.
+const CR_EL1_EL0: u64 = 0b11 << 1;
+
+/// `DBGBCR.BAS`: match the whole A64 instruction.
+const BCR_BAS_ALL: u64 = 0b1111 << 5;
+
+/// The kind of access a watchpoint triggers on.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Access {
+    Load = 0b01,
+    Store = 0b10,
+    Any = 0b11,
+}
+
+macro_rules! read_sysreg {
+    ($reg:tt) => {{
+        let value: u64;
+        asm!(concat!("mrs $0, ", $reg) : "=r"(value) ::: "volatile");
+        value
+    }}
+}
+
+macro_rules! write_sysreg {
+    ($reg:tt, $value:expr) => {
+        asm!(concat!("msr ", $reg, ", $0") :: "r"($value) :: "volatile")
+    }
+}
+
+unsafe fn mdscr() -> u64 {
+    read_sysreg!("MDSCR_EL1")
+}
+
+unsafe fn set_mdscr(value: u64) {
+    write_sysreg!("MDSCR_EL1", value);
+    asm!("isb" :::: "volatile");
+}
+
+/// Unlocks the OS lock and enables breakpoints, watchpoints and software
+/// step. Must be called before any other function in this module takes
+/// effect.
+pub fn enable() {
+    unsafe {
+        write_sysreg!("OSLAR_EL1", 0u64);
+        set_mdscr(mdscr() | MDSCR_MDE | MDSCR_KDE);
+    }
+}
+
+/// Arranges for returning through `tf` to execute exactly one instruction
+/// before taking a `Step` exception, or cancels that if `enable` is `false`.
+pub fn set_single_step(tf: &mut TrapFrame, enable: bool) {
+    unsafe {
+        if enable {
+            set_mdscr(mdscr() | MDSCR_SS);
+            tf.spsr = (tf.spsr | SPSR_SS) & !SPSR_D;
+        } else {
+            set_mdscr(mdscr() & !MDSCR_SS);
+            tf.spsr &= !SPSR_SS;
+        }
+    }
+}
+
+unsafe fn write_breakpoint(n: usize, value: u64, control: u64) {
+    match n {
+        0 => { write_sysreg!("DBGBVR0_EL1", value); write_sysreg!("DBGBCR0_EL1", control); },
+        1 => { write_sysreg!("DBGBVR1_EL1", value); write_sysreg!("DBGBCR1_EL1", control); },
+        2 => { write_sysreg!("DBGBVR2_EL1", value); write_sysreg!("DBGBCR2_EL1", control); },
+        3 => { write_sysreg!("DBGBVR3_EL1", value); write_sysreg!("DBGBCR3_EL1", control); },
+        4 => { write_sysreg!("DBGBVR4_EL1", value); write_sysreg!("DBGBCR4_EL1", control); },
+        5 => { write_sysreg!("DBGBVR5_EL1", value); write_sysreg!("DBGBCR5_EL1", control); },
+        _ => panic!("no breakpoint {}", n)
+    }
+    asm!("isb" :::: "volatile");
+}
+
+unsafe fn write_watchpoint(n: usize, value: u64, control: u64) {
+    match n {
+        0 => { write_sysreg!("DBGWVR0_EL1", value); write_sysreg!("DBGWCR0_EL1", control); },
+        1 => { write_sysreg!("DBGWVR1_EL1", value); write_sysreg!("DBGWCR1_EL1", control); },
+        2 => { write_sysreg!("DBGWVR2_EL1", value); write_sysreg!("DBGWCR2_EL1", control); },
+        3 => { write_sysreg!("DBGWVR3_EL1", value); write_sysreg!("DBGWCR3_EL1", control); },
+        _ => panic!("no watchpoint {}", n)
+    }
+    asm!("isb" :::: "volatile");
+}
+
+/// Sets hardware breakpoint `n` to trigger on executing the instruction at
+/// `addr`.
+///
+/// # Panics
+///
+/// Panics if `n >= NUM_BREAKPOINTS`.
+pub fn set_breakpoint(n: usize, addr: u64) {
+    unsafe { write_breakpoint(n, addr & !0b11, BCR_BAS_ALL | CR_EL1_EL0 | CR_ENABLE) }
+}
+
+/// Disables hardware breakpoint `n`.
+///
+/// # Panics
+///
+/// Panics if `n >= NUM_BREAKPOINTS`.
+pub fn clear_breakpoint(n: usize) {
+    unsafe { write_breakpoint(n, 0, 0) }
+}
+
+/// Sets hardware watchpoint `n` to trigger on an `access` to any of the `len`
+/// bytes at `addr`. The bytes must lie within one naturally aligned
+/// doubleword. Returns `false` if they don't.
+///
+/// # Panics
+///
+/// Panics if `n >= NUM_WATCHPOINTS`.
+pub fn set_watchpoint(n: usize, addr: u64, len: u64, access: Access) -> bool {
+    let offset = addr & 0b111;
+    if len == 0 || offset + len > 8 {
+        return false;
+    }
+
+    let byte_select = ((1 << len) - 1) << offset;
+    let control = byte_select << 5 | (access as u64) << 3 | CR_EL1_EL0 | CR_ENABLE;
+    unsafe { write_watchpoint(n, addr & !0b111, control) }
+    true
+}
+
+/// Disables hardware watchpoint `n`.
+///
+/// # Panics
+///
+/// Panics if `n >= NUM_WATCHPOINTS`.
+pub fn clear_watchpoint(n: usize) {
+    unsafe { write_watchpoint(n, 0, 0) }
+}
@@ -0,0 +1,443 @@
+//! An interactive monitor entered on debug exceptions.
+
+mod disasm;
+mod hw;
+pub mod backtrace;
+pub mod gdb;
+
+use std::str;
+
+use aarch64;
+use lockup;
+use pi::console::{self, kprint, kprintln};
+use pi::mutex::Mutex;
+use stack_vec::StackVec;
+use traps::TrapFrame;
+use vm;
+
+pub use self::disasm::Instruction;
+pub use self::gdb::ATTACH_BRK;
+pub use self::hw::{Access, NUM_BREAKPOINTS, NUM_WATCHPOINTS};
+
+/// The number of general purpose registers, `x0` through `x30`.
+const NUM_REGISTERS: usize = 31;
+
+/// The debug exception that entered the monitor.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Reason {
+    /// A `brk #imm` instruction at `elr`.
+    Brk(u16),
+    /// A hardware breakpoint on the instruction at `elr`.
+    Breakpoint,
+    /// A hardware watchpoint hit by the instruction at `elr`.
+    Watchpoint,
+    /// A single step completed; `elr` is the next instruction to execute.
+    Step,
+}
+
+/// A watchpoint set from the monitor.
+#[derive(Debug, Copy, Clone)]
+struct Watchpoint {
+    addr: u64,
+    len: u64,
+    access: Access,
+}
+
+/// Breakpoints and watchpoints set from the monitor, and what the pending
+/// single step, if any, is for.
+#[derive(Debug)]
+struct Debugger {
+    breakpoints: [Option<u64>; NUM_BREAKPOINTS],
+    watchpoints: [Option<Watchpoint>; NUM_WATCHPOINTS],
+    /// The user asked to step, so the monitor is entered after the step.
+    stepping: bool,
+    /// Breakpoints and watchpoints are disarmed to step over the instruction
+    /// that hit one and must be rearmed after the step.
+    rearm: bool,
+}
+
+static DEBUGGER: Mutex<Debugger> = Mutex::new(Debugger {
+    breakpoints: [None; NUM_BREAKPOINTS],
+    watchpoints: [None; NUM_WATCHPOINTS],
+    stepping: false,
+    rearm: false,
+});
+
+impl Debugger {
+    fn arm(&self) {
+        for (n, bp) in self.breakpoints.iter().enumerate() {
+            match *bp {
+                Some(addr) => hw::set_breakpoint(n, addr),
+                None => hw::clear_breakpoint(n),
+            }
+        }
+
+        for (n, wp) in self.watchpoints.iter().enumerate() {
+            match *wp {
+                Some(wp) => { hw::set_watchpoint(n, wp.addr, wp.len, wp.access); },
+                None => hw::clear_watchpoint(n),
+            }
+        }
+    }
+
+    fn disarm(&self) {
+        (0..NUM_BREAKPOINTS).for_each(hw::clear_breakpoint);
+        (0..NUM_WATCHPOINTS).for_each(hw::clear_watchpoint);
+    }
+}
+
+/// What the monitor was told to do next.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+enum Next {
+    Continue,
+    Step,
+    /// Hand the target over to GDB.
+    Gdb,
+}
+
+/// Handles the debug exception `reason` taken with trap frame `tf`. Unless
+/// the exception only finished stepping over a breakpoint, this runs the
+/// monitor until it is told to continue or step, and prepares `tf` to do so.
+///
+/// While GDB is attached, or for `brk #ATTACH_BRK`, the GDB stub runs
+/// instead of the monitor.
+pub fn enter(reason: Reason, tf: &mut TrapFrame) {
+    let mut debugger = DEBUGGER.lock_irq();
+    hw::enable();
+
+    if reason == Reason::Step {
+        hw::set_single_step(tf, false);
+        if debugger.rearm {
+            debugger.rearm = false;
+            debugger.arm();
+        }
+
+        if !debugger.stepping && !gdb::is_attached() {
+            return;
+        }
+        debugger.stepping = false;
+    }
+
+    // The machine may stay stopped for longer than any lockup timeout.
+    lockup::pause();
+    stop(&mut debugger, reason, tf);
+    lockup::resume();
+}
+
+/// Runs the GDB stub or the monitor for the debug exception `reason`.
+fn stop(debugger: &mut Debugger, reason: Reason, tf: &mut TrapFrame) {
+    if gdb::is_attached() || reason == Reason::Brk(ATTACH_BRK) {
+        return gdb::enter(tf);
+    }
+
+    match reason {
+        Reason::Brk(imm) => kprintln!("brk #{:#x} at {:#x}", imm, tf.elr),
+        Reason::Breakpoint => kprintln!("breakpoint at {:#x}", tf.elr),
+        Reason::Watchpoint => kprintln!("watchpoint on {:#x} at {:#x}", aarch64::far(), tf.elr),
+        Reason::Step => {},
+    }
+
+    disassemble(tf.elr, 1, tf.elr);
+
+    match monitor(debugger, tf) {
+        Next::Continue => resume(debugger, reason, tf, false),
+        Next::Step => resume(debugger, reason, tf, true),
+        Next::Gdb => {
+            kprintln!("waiting for gdb");
+            gdb::enter(tf);
+        }
+    }
+}
+
+/// Prepares `tf` to continue from the exception `reason`, executing one
+/// instruction before re-entering the monitor if `step` is set.
+fn resume(debugger: &mut Debugger, reason: Reason, tf: &mut TrapFrame, step: bool) {
+    match reason {
+        // Don't execute the `brk` again, unless the user moved `elr` away.
+        Reason::Brk(_) if is_brk(tf.elr) => tf.elr += 4,
+        // The instruction at `elr` would hit the same breakpoint or watchpoint
+        // again; step over it with them disarmed.
+        Reason::Breakpoint | Reason::Watchpoint => {
+            debugger.disarm();
+            debugger.rearm = true;
+            hw::set_single_step(tf, true);
+        },
+        _ => {}
+    }
+
+    if step {
+        debugger.stepping = true;
+        hw::set_single_step(tf, true);
+    }
+}
+
+fn is_brk(addr: u64) -> bool {
+    unsafe { Instruction::read(addr).word & 0xffe0001f == 0xd4200000 }
+}
+
+/// Writes `value` at `addr`, which may be in the read-only kernel text.
+unsafe fn poke<T>(addr: u64, value: T) {
+    ::std::ptr::write_volatile(vm::mmu::writable(addr as usize) as *mut T, value);
+}
+
+/// Makes instructions written to the `len` bytes at `addr` visible to
+/// instruction fetches.
+fn sync_instructions(addr: u64, len: usize) {
+    aarch64::sync_icache(addr as usize, len);
+}
+
+/// Reads and runs monitor commands until one resumes execution.
+fn monitor(debugger: &mut Debugger, tf: &mut TrapFrame) -> Next {
+    let mut raw_line = [0u8; 128];
+    loop {
+        kprint!("(dbg) ");
+        let line = read_line(&mut raw_line);
+
+        let mut raw_args = [""; 8];
+        let mut args = StackVec::new(&mut raw_args);
+        for arg in line.split(' ').filter(|a| !a.is_empty()) {
+            if args.push(arg).is_err() {
+                break;
+            }
+        }
+
+        let (command, args) = match args.as_slice().split_first() {
+            Some((command, args)) => (*command, args),
+            None => continue
+        };
+
+        match command {
+            "c" | "continue" => return Next::Continue,
+            "s" | "step" => return Next::Step,
+            "gdb" => return Next::Gdb,
+            "r" | "regs" => dump_registers(tf),
+            "bt" | "backtrace" => backtrace::print_trap(tf),
+            "set" => set_register(tf, args),
+            "x" => hexdump(args),
+            "patch" => patch(args),
+            "dis" => {
+                let addr = args.get(0).and_then(|a| parse(a)).unwrap_or(tf.elr.saturating_sub(16));
+                let count = args.get(1).and_then(|a| parse(a)).unwrap_or(8);
+                disassemble(addr, count, tf.elr);
+            },
+            "b" | "break" => set_breakpoint(debugger, args),
+            "w" | "watch" => set_watchpoint(debugger, args),
+            "d" | "delete" => delete(debugger, args),
+            "i" | "info" => info(debugger),
+            "h" | "help" => help(),
+            _ => kprintln!("unknown command: {} (try `help`)", command),
+        }
+    }
+}
+
+/// Reads a line from the console into `buf`, echoing it, and returns it.
+fn read_line(buf: &mut [u8]) -> &str {
+    let mut len = 0;
+    loop {
+        match console::read_byte() {
+            b'\r' | b'\n' => break,
+            8 | 127 => if len > 0 {
+                len -= 1;
+                kprint!("\x08 \x08");
+            },
+            byte if len < buf.len() => {
+                buf[len] = byte;
+                len += 1;
+                kprint!("{}", byte as char);
+            },
+            _ => {}
+        }
+    }
+
+    kprintln!("");
+    str::from_utf8(&buf[..len]).unwrap_or("")
+}
+
+/// Parses a decimal or `0x`-prefixed hexadecimal number.
+fn parse(s: &str) -> Option<u64> {
+    if s.starts_with("0x") {
+        u64::from_str_radix(&s[2..], 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+fn help() {
+    kprintln!("c, continue               resume execution");
+    kprintln!("s, step                   execute one instruction");
+    kprintln!("gdb                       hand over to gdb on the console UART");
+    kprintln!("r, regs                   dump registers");
+    kprintln!("bt, backtrace             print the call stack");
+    kprintln!("set <reg> <value>         set x0-x30, sp, elr or spsr");
+    kprintln!("x <addr> [len]            hex-dump memory");
+    kprintln!("patch <addr> <byte>...    write bytes to memory");
+    kprintln!("dis [addr] [count]        disassemble, around elr by default");
+    kprintln!("b, break <addr>           set a hardware breakpoint");
+    kprintln!("w, watch <addr> [r|w|rw] [len]");
+    kprintln!("                          set a hardware watchpoint");
+    kprintln!("d, delete <b|w> <n>       delete a breakpoint or watchpoint");
+    kprintln!("i, info                   list breakpoints and watchpoints");
+}
+
+/// Returns general purpose register `xn`, if `n < NUM_REGISTERS`.
+fn register(tf: &mut TrapFrame, n: usize) -> Option<&mut u64> {
+    tf.register_mut(n)
+}
+
+fn dump_registers(tf: &mut TrapFrame) {
+    for n in 0..NUM_REGISTERS {
+        let value = *register(tf, n).expect("register");
+        kprint!("x{:<2} {:#018x}{}", n, value, if n % 3 == 2 { "\n" } else { "  " });
+    }
+
+    kprintln!("");
+    kprintln!("sp  {:#018x}  elr {:#018x}  spsr {:#010x}", tf.sp, tf.elr, tf.spsr);
+    kprintln!("tid {}", tf.tpidr);
+}
+
+fn set_register(tf: &mut TrapFrame, args: &[&str]) {
+    let (name, value) = match (args.get(0), args.get(1).and_then(|v| parse(v))) {
+        (Some(name), Some(value)) => (*name, value),
+        _ => return kprintln!("usage: set <reg> <value>"),
+    };
+
+    match name {
+        "sp" => tf.sp = value,
+        "elr" | "pc" => tf.elr = value,
+        "spsr" => tf.spsr = value,
+        _ => match name.trim_left_matches('x').parse::<usize>() {
+            Ok(n) if name.starts_with('x') => match register(tf, n) {
+                Some(register) => *register = value,
+                None => kprintln!("unknown register: {}", name),
+            },
+            _ => kprintln!("unknown register: {}", name),
+        }
+    }
+}
+
+fn hexdump(args: &[&str]) {
+    let addr = match args.get(0).and_then(|a| parse(a)) {
+        Some(addr) => addr,
+        None => return kprintln!("usage: x <addr> [len]"),
+    };
+    let len = args.get(1).and_then(|l| parse(l)).unwrap_or(64);
+
+    for line in (addr..addr + len).step_by(16) {
+        let end = ::std::cmp::min(line + 16, addr + len);
+        let bytes: &[u8] = unsafe {
+            ::std::slice::from_raw_parts(line as *const u8, (end - line) as usize)
+        };
+
+        kprint!("{:#010x}: ", line);
+        for i in 0..16 {
+            match bytes.get(i) {
+                Some(byte) => kprint!("{:02x} ", byte),
+                None => kprint!("   "),
+            }
+        }
+
+        kprint!(" |");
+        for &byte in bytes {
+            let c = if byte >= 0x20 && byte < 0x7f { byte as char } else { '.' };
+            kprint!("{}", c);
+        }
+        kprintln!("|");
+    }
+}
+
+fn patch(args: &[&str]) {
+    let addr = match args.get(0).and_then(|a| parse(a)) {
+        Some(addr) if args.len() > 1 => addr,
+        _ => return kprintln!("usage: patch <addr> <byte>..."),
+    };
+
+    for (i, arg) in args[1..].iter().enumerate() {
+        match parse(arg) {
+            Some(byte) if byte <= 0xff => unsafe { poke(addr + i as u64, byte as u8) },
+            _ => return kprintln!("not a byte: {}", arg),
+        }
+    }
+
+    // The patched bytes may be instructions.
+    sync_instructions(addr, args.len() - 1);
+}
+
+fn disassemble(addr: u64, count: u64, elr: u64) {
+    for i in 0..count {
+        let insn = unsafe { Instruction::read((addr & !0b11) + 4 * i) };
+        let marker = if insn.addr == elr { "=>" } else { "  " };
+        kprintln!("{} {:#010x}: {:08x}  {}", marker, insn.addr, insn.word, insn);
+    }
+}
+
+fn set_breakpoint(debugger: &mut Debugger, args: &[&str]) {
+    let addr = match args.get(0).and_then(|a| parse(a)) {
+        Some(addr) => addr,
+        None => return kprintln!("usage: break <addr>"),
+    };
+
+    match debugger.breakpoints.iter().position(|bp| bp.is_none()) {
+        Some(n) => {
+            debugger.breakpoints[n] = Some(addr);
+            hw::set_breakpoint(n, addr);
+            kprintln!("breakpoint {} at {:#x}", n, addr);
+        },
+        None => kprintln!("all {} breakpoints are in use", NUM_BREAKPOINTS),
+    }
+}
+
+fn set_watchpoint(debugger: &mut Debugger, args: &[&str]) {
+    let addr = match args.get(0).and_then(|a| parse(a)) {
+        Some(addr) => addr,
+        None => return kprintln!("usage: watch <addr> [r|w|rw] [len]"),
+    };
+
+    let access = match args.get(1).map(|a| *a) {
+        Some("r") => Access::Load,
+        Some("w") => Access::Store,
+        Some("rw") | None => Access::Any,
+        Some(other) => return kprintln!("unknown access: {}", other),
+    };
+    let len = args.get(2).and_then(|l| parse(l)).unwrap_or(8 - (addr & 0b111));
+
+    let n = match debugger.watchpoints.iter().position(|wp| wp.is_none()) {
+        Some(n) => n,
+        None => return kprintln!("all {} watchpoints are in use", NUM_WATCHPOINTS),
+    };
+
+    if !hw::set_watchpoint(n, addr, len, access) {
+        return kprintln!("a watchpoint can't cross an 8-byte boundary");
+    }
+
+    debugger.watchpoints[n] = Some(Watchpoint { addr, len, access });
+    kprintln!("watchpoint {} on {:#x}..{:#x}", n, addr, addr + len);
+}
+
+fn delete(debugger: &mut Debugger, args: &[&str]) {
+    let n = args.get(1).and_then(|n| parse(n)).map(|n| n as usize);
+    match (args.get(0).map(|a| *a), n) {
+        (Some("b"), Some(n)) if n < NUM_BREAKPOINTS => {
+            debugger.breakpoints[n] = None;
+            hw::clear_breakpoint(n);
+        },
+        (Some("w"), Some(n)) if n < NUM_WATCHPOINTS => {
+            debugger.watchpoints[n] = None;
+            hw::clear_watchpoint(n);
+        },
+        _ => kprintln!("usage: delete <b|w> <n>"),
+    }
+}
+
+fn info(debugger: &Debugger) {
+    for (n, bp) in debugger.breakpoints.iter().enumerate() {
+        if let Some(addr) = *bp {
+            kprintln!("breakpoint {}: {:#x}", n, addr);
+        }
+    }
+
+    for (n, wp) in debugger.watchpoints.iter().enumerate() {
+        if let Some(wp) = *wp {
+            kprintln!("watchpoint {}: {:#x}..{:#x} {:?}", n, wp.addr, wp.addr + wp.len, wp.access);
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use pi::console::CONSOLE;
+use pi::console;
 use pi::framebuffer::Pixel;
 use pi::screen::SCREEN;
 
@@ -7,13 +7,12 @@ pub fn draw_loop() {
     let mut pixel_cursor: Pixel = Default::default();
 
     loop {
-        let byte = {
-            let mut console = CONSOLE.lock();
-            console.read_byte()
-        };
+        let byte = console::read_byte();
 
         if byte == 0x1b {
-            SCREEN.lock().inner().clear();
+            let mut screen = SCREEN.lock_irq();
+            screen.inner().clear();
+            screen.present();
         }
 
         if byte == 0x60 {
@@ -42,6 +41,8 @@ pub fn draw_loop() {
             pixel_cursor.color.blue = pixel_cursor.color.blue.wrapping_add(10);
         }
 
-        SCREEN.lock().inner().draw_pixel(&pixel_cursor);
+        let mut screen = SCREEN.lock_irq();
+        screen.inner().draw_pixel(&pixel_cursor);
+        screen.present();
     }
 }
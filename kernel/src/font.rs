@@ -0,0 +1,39 @@
+//! Loading the screen's font from the SD card.
+//!
+//! PC Screen Font files, such as the Linux console's `/usr/share/consolefonts`
+//! (gunzipped first), can be copied to the card. The one at `FONT_FILE` is
+//! loaded at boot; the shell's `font` command switches fonts later.
+
+use std::io::{self, Read};
+
+use fs::traits;
+use pi::font::Font;
+use pi::screen::SCREEN;
+use FILE_SYSTEM;
+
+/// Where the font is loaded from at boot, if it exists.
+pub const FONT_FILE: &str = "/font.psf";
+
+/// Loads the PSF font at `path` and has the screen draw with it, `scale`
+/// times its size. Returns the screen's new size in columns and rows.
+pub fn load(path: &str, scale: usize) -> io::Result<(usize, usize)> {
+    let mut file = traits::FileSystem::open_file(&FILE_SYSTEM, path)?;
+    let mut contents = vec![];
+    file.read_to_end(&mut contents)?;
+    let font = Font::parse(&contents)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "not a PSF font"))?;
+
+    Ok(select(font, scale))
+}
+
+/// Has the screen draw with the built-in font, `scale` times its size.
+/// Returns the screen's new size in columns and rows.
+pub fn builtin(scale: usize) -> (usize, usize) {
+    select(Font::builtin(), scale)
+}
+
+fn select(font: Font, scale: usize) -> (usize, usize) {
+    let mut screen = SCREEN.lock_irq();
+    screen.set_font(font, scale);
+    screen.size()
+}
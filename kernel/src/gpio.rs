@@ -0,0 +1,148 @@
+//! GPIO event interrupts.
+//!
+//! Drivers hand an input pin, and the events to detect on it, to `watch`
+//! along with a callback. Every GPIO event raises the `Gpio3` interrupt, whose
+//! handler takes the detected events and runs the callbacks of the pins they
+//! were detected on. A callback can also ask to be run again at a given time
+//! without an event, which core 0's scheduler tick does through `tick`.
+//! Callbacks run in interrupt handlers with IRQs masked, so they must be quick
+//! and may only take locks with `lock_irq`.
+
+use std::time::Duration;
+
+use pi::gpio::{self, Debouncer, Event, Gpio, Input, Pull};
+use pi::interrupt::Interrupt;
+use pi::mutex::Mutex;
+use pi::timer;
+use traps::{Handled, TrapFrame};
+use IRQS;
+
+/// A callback run when an event is detected on a watched pin. It returns the
+/// time, in microseconds, at which it should be run again even if no event is
+/// detected by then, if any.
+pub type Callback = Box<FnMut(&mut Gpio<Input>) -> Option<u64> + Send>;
+
+struct Watch {
+    pin: Gpio<Input>,
+    callback: Callback,
+    /// When to run `callback` without an event, as it last asked.
+    recheck_at: Option<u64>,
+}
+
+static WATCHES: Mutex<Option<Vec<Watch>>> = Mutex::new(None);
+
+/// How a button is wired, which decides the pull and the level it reads when
+/// pressed.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Wiring {
+    /// Between the pin and ground: pulled up, and low when pressed.
+    ToGround,
+    /// Between the pin and 3.3V: pulled down, and high when pressed.
+    ToPower,
+}
+
+/// Starts detecting `events` on `pin`, calling `callback` with the pin from
+/// the GPIO interrupt handler whenever one is detected. Returns the pin back
+/// if it is already watched.
+pub fn watch(mut pin: Gpio<Input>, events: &[Event], callback: Callback)
+    -> Result<(), Gpio<Input>>
+{
+    let first = {
+        let mut watches = WATCHES.lock_irq();
+        let watches = watches.get_or_insert_with(Vec::new);
+        if watches.iter().any(|watch| watch.pin.pin() == pin.pin()) {
+            return Err(pin);
+        }
+
+        pin.disable_events();
+        pin.clear_event();
+        for &event in events {
+            pin.enable_event(event);
+        }
+        watches.push(Watch { pin, callback, recheck_at: None });
+        watches.len() == 1
+    };
+
+    // The registry is locked while the handler runs, and the handler locks
+    // the watches, so it must be registered with the watches unlocked.
+    if first {
+        IRQS.register(Interrupt::Gpio3, "gpio", Box::new(|_: &mut TrapFrame| handle()));
+    }
+    Ok(())
+}
+
+/// Stops watching pin number `pin` and returns it, with event detection
+/// disabled. Returns `None` if the pin isn't watched.
+pub fn unwatch(pin: u8) -> Option<Gpio<Input>> {
+    let mut watches = WATCHES.lock_irq();
+    let watches = watches.as_mut()?;
+    let index = watches.iter().position(|watch| watch.pin.pin() == pin)?;
+
+    let mut pin = watches.remove(index).pin;
+    pin.disable_events();
+    pin.clear_event();
+    Some(pin)
+}
+
+/// Returns `true` if pin number `pin` is watched.
+pub fn is_watched(pin: u8) -> bool {
+    match *WATCHES.lock_irq() {
+        Some(ref watches) => watches.iter().any(|watch| watch.pin.pin() == pin),
+        None => false,
+    }
+}
+
+/// Watches a push button on `pin` wired as `wiring`, calling `callback` with
+/// `true` when it is pressed and `false` when it is released. Contact bounce
+/// for `debounce` after each change is ignored. Returns the pin back if it is
+/// already watched.
+pub fn watch_button<F>(mut pin: Gpio<Input>, wiring: Wiring, debounce: Duration, mut callback: F)
+    -> Result<(), Gpio<Input>>
+    where F: FnMut(bool) + Send + 'static
+{
+    let (pull, pressed) = match wiring {
+        Wiring::ToGround => (Pull::Up, false),
+        Wiring::ToPower => (Pull::Down, true),
+    };
+    pin.set_pull(pull);
+
+    let debounce_us = debounce.as_secs() * 1000 * 1000 + (debounce.subsec_nanos() / 1000) as u64;
+    let mut debouncer = Debouncer::new(debounce_us, pin.level());
+    let events = [Event::RisingEdge, Event::FallingEdge];
+    watch(pin, &events, Box::new(move |pin: &mut Gpio<Input>| {
+        if let Some(level) = debouncer.update(pin.level(), timer::current_time()) {
+            callback(level == pressed);
+        }
+        // An edge while the level settles is ignored; look again once it has.
+        debouncer.settles_at()
+    }))
+}
+
+/// Handles the GPIO interrupt: runs the callback of every watched pin an
+/// event was detected on.
+fn handle() -> Handled {
+    let events = gpio::take_events();
+    if events == 0 {
+        return Handled::No;
+    }
+
+    if let Some(ref mut watches) = *WATCHES.lock_irq() {
+        for watch in watches.iter_mut().filter(|watch| events & (1 << watch.pin.pin()) != 0) {
+            watch.recheck_at = (watch.callback)(&mut watch.pin);
+        }
+    }
+    Handled::Yes
+}
+
+/// Runs the callbacks that asked to be run again by now. Called from core 0's
+/// scheduler tick.
+pub fn tick() {
+    let now = timer::current_time();
+    if let Some(ref mut watches) = *WATCHES.lock_irq() {
+        for watch in watches.iter_mut() {
+            if watch.recheck_at.map_or(false, |at| now >= at) {
+                watch.recheck_at = (watch.callback)(&mut watch.pin);
+            }
+        }
+    }
+}
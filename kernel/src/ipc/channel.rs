@@ -0,0 +1,142 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Arc;
+
+use pi::mutex::Mutex;
+use ipc::Error;
+use process::Descriptor;
+
+/// The maximum number of data bytes in a message.
+pub const MESSAGE_SIZE: usize = 64;
+
+/// The number of messages a channel buffers in each direction before senders
+/// block.
+pub const CHANNEL_CAPACITY: usize = 16;
+
+/// A message sent over a channel: up to `MESSAGE_SIZE` bytes of data and,
+/// optionally, a file descriptor handed to the receiver.
+pub struct Message {
+    data: [u8; MESSAGE_SIZE],
+    len: usize,
+    /// A descriptor transferred with the message. The receiver gets a new file
+    /// descriptor referring to it.
+    pub handle: Option<Descriptor>,
+}
+
+impl Message {
+    /// Returns a message holding a copy of `data` and `handle`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TooLarge` if `data` is longer than `MESSAGE_SIZE` bytes.
+    pub fn new(data: &[u8], handle: Option<Descriptor>) -> Result<Message, Error> {
+        if data.len() > MESSAGE_SIZE {
+            return Err(Error::TooLarge);
+        }
+
+        let mut message = Message { data: [0; MESSAGE_SIZE], len: data.len(), handle };
+        message.data[..data.len()].copy_from_slice(data);
+        Ok(message)
+    }
+
+    /// The message's data.
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+impl fmt::Debug for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Message")
+            .field("data", &self.data())
+            .field("handle", &self.handle)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+struct Queues {
+    /// `inbox[side]` holds the messages waiting to be received by `side`.
+    inbox: [VecDeque<Message>; 2],
+    /// The number of open `Endpoint`s of each side.
+    endpoints: [usize; 2],
+}
+
+/// One end of a bidirectional message channel. Messages sent on one end are
+/// received, in order, on the other. Cloning an `Endpoint` opens another
+/// handle to the same end; the end is closed when every handle has been
+/// dropped.
+///
+/// Sending an endpoint over its own channel keeps the channel open until the
+/// message is received.
+#[derive(Debug)]
+pub struct Endpoint {
+    queues: Arc<Mutex<Queues>>,
+    side: usize,
+}
+
+/// Creates a new channel and returns its two ends.
+pub fn channel() -> (Endpoint, Endpoint) {
+    let queues = Arc::new(Mutex::new(Queues {
+        inbox: [VecDeque::new(), VecDeque::new()],
+        endpoints: [1, 1],
+    }));
+
+    (Endpoint { queues: queues.clone(), side: 0 }, Endpoint { queues, side: 1 })
+}
+
+impl Endpoint {
+    fn peer(&self) -> usize {
+        1 - self.side
+    }
+
+    /// Queues `message` for the other end. On failure the message is handed
+    /// back along with the error so it can be retried.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Closed` if the other end has been closed and `WouldBlock` if
+    /// its queue is full.
+    pub fn try_send(&self, message: Message) -> Result<(), (Error, Message)> {
+        let mut queues = self.queues.lock();
+        let peer = self.peer();
+        if queues.endpoints[peer] == 0 {
+            return Err((Error::Closed, message));
+        }
+
+        if queues.inbox[peer].len() >= CHANNEL_CAPACITY {
+            return Err((Error::WouldBlock, message));
+        }
+
+        queues.inbox[peer].push_back(message);
+        Ok(())
+    }
+
+    /// Removes and returns the oldest message sent to this end.
+    ///
+    /// # Errors
+    ///
+    /// Returns `WouldBlock` if there is no message yet and `Closed` if there is
+    /// none and the other end has been closed.
+    pub fn try_recv(&self) -> Result<Message, Error> {
+        let mut queues = self.queues.lock();
+        match queues.inbox[self.side].pop_front() {
+            Some(message) => Ok(message),
+            None if queues.endpoints[self.peer()] == 0 => Err(Error::Closed),
+            None => Err(Error::WouldBlock)
+        }
+    }
+}
+
+impl Clone for Endpoint {
+    fn clone(&self) -> Endpoint {
+        self.queues.lock().endpoints[self.side] += 1;
+        Endpoint { queues: self.queues.clone(), side: self.side }
+    }
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        self.queues.lock().endpoints[self.side] -= 1;
+    }
+}
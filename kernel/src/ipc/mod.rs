@@ -0,0 +1,21 @@
+mod pipe;
+mod channel;
+
+pub use self::pipe::{pipe, PipeReader, PipeWriter, PIPE_CAPACITY};
+pub use self::channel::{channel, Endpoint, Message, MESSAGE_SIZE, CHANNEL_CAPACITY};
+
+/// Errors returned by pipe and channel operations. The discriminant is the
+/// error value a system call reports in `x7`.
+#[repr(u64)]
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Error {
+    /// The operation can't make progress yet. Blocking system calls wait and
+    /// retry instead of reporting this error.
+    WouldBlock = 1,
+    /// The file descriptor isn't open or doesn't support the operation.
+    BadDescriptor = 2,
+    /// The other end of the pipe or channel has been closed.
+    Closed = 3,
+    /// The message doesn't fit in `MESSAGE_SIZE` bytes.
+    TooLarge = 4,
+}
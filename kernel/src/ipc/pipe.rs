@@ -0,0 +1,115 @@
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use pi::mutex::Mutex;
+use ipc::Error;
+
+/// The number of bytes a pipe buffers before writers block.
+pub const PIPE_CAPACITY: usize = 4096;
+
+#[derive(Debug)]
+struct Buffer {
+    data: VecDeque<u8>,
+    /// The number of open `PipeReader`s.
+    readers: usize,
+    /// The number of open `PipeWriter`s.
+    writers: usize,
+}
+
+/// The read end of a pipe. Cloning a `PipeReader` opens another handle to
+/// the same end; the end is closed when every handle has been dropped.
+#[derive(Debug)]
+pub struct PipeReader(Arc<Mutex<Buffer>>);
+
+/// The write end of a pipe. Cloning a `PipeWriter` opens another handle to
+/// the same end; the end is closed when every handle has been dropped.
+#[derive(Debug)]
+pub struct PipeWriter(Arc<Mutex<Buffer>>);
+
+/// Creates a new anonymous pipe and returns its read and write ends.
+pub fn pipe() -> (PipeReader, PipeWriter) {
+    let buffer = Arc::new(Mutex::new(Buffer {
+        data: VecDeque::with_capacity(PIPE_CAPACITY),
+        readers: 1,
+        writers: 1,
+    }));
+
+    (PipeReader(buffer.clone()), PipeWriter(buffer))
+}
+
+impl PipeReader {
+    /// Reads as many buffered bytes as fit into `buf` and returns the number
+    /// of bytes read. Returns `Ok(0)` at end of file, once the pipe is empty
+    /// and every write end has been closed.
+    ///
+    /// # Errors
+    ///
+    /// Returns `WouldBlock` if the pipe is empty but may still be written to.
+    pub fn try_read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut buffer = self.0.lock();
+        if buffer.data.is_empty() {
+            return match buffer.writers {
+                0 => Ok(0),
+                _ => Err(Error::WouldBlock)
+            };
+        }
+
+        let n = min(buf.len(), buffer.data.len());
+        for (byte, data) in buf.iter_mut().zip(buffer.data.drain(..n)) {
+            *byte = data;
+        }
+
+        Ok(n)
+    }
+}
+
+impl PipeWriter {
+    /// Writes as many bytes of `buf` as there is room for and returns the
+    /// number of bytes written.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Closed` if every read end has been closed and `WouldBlock` if
+    /// the pipe is full.
+    pub fn try_write(&self, buf: &[u8]) -> Result<usize, Error> {
+        let mut buffer = self.0.lock();
+        if buffer.readers == 0 {
+            return Err(Error::Closed);
+        }
+
+        let n = min(buf.len(), PIPE_CAPACITY - buffer.data.len());
+        if n == 0 && !buf.is_empty() {
+            return Err(Error::WouldBlock);
+        }
+
+        buffer.data.extend(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl Clone for PipeReader {
+    fn clone(&self) -> PipeReader {
+        self.0.lock().readers += 1;
+        PipeReader(self.0.clone())
+    }
+}
+
+impl Clone for PipeWriter {
+    fn clone(&self) -> PipeWriter {
+        self.0.lock().writers += 1;
+        PipeWriter(self.0.clone())
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.0.lock().readers -= 1;
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.0.lock().writers -= 1;
+    }
+}
@@ -23,14 +23,24 @@ extern crate pi;
 extern crate stack_vec;
 extern crate volatile;
 
+pub mod clock;
+pub mod compositor;
+pub mod cpufreq;
+pub mod debug;
 pub mod draw;
+pub mod font;
 pub mod fs;
+pub mod gpio;
+pub mod ipc;
 pub mod lang_items;
+pub mod lockup;
+pub mod panel;
 pub mod shell;
 pub mod syscalls;
 pub mod traps;
 pub mod aarch64;
 pub mod process;
+pub mod smp;
 pub mod vm;
 
 use pi::console::kprintln;
@@ -42,8 +52,10 @@ use shell::shell;
 #[cfg(not(test))]
 use pi::allocator::Allocator;
 
+use compositor::Compositor;
 use fs::FileSystem;
 use process::GlobalScheduler;
+use traps::Registry;
 
 #[cfg(not(test))]
 #[global_allocator]
@@ -53,9 +65,20 @@ pub static FILE_SYSTEM: FileSystem = FileSystem::uninitialized();
 
 pub static SCHEDULER: GlobalScheduler = GlobalScheduler::uninitialized();
 
+pub static IRQS: Registry = Registry::uninitialized();
+
+pub static COMPOSITOR: Compositor = Compositor::uninitialized();
+
+/// The height in pixels of the desktop, which surfaces are drawn on, at the
+/// top of the screen. The console gets the rest.
+const DESKTOP_HEIGHT: usize = 128;
+
 #[no_mangle]
 #[cfg(not(test))]
 pub unsafe extern "C" fn kmain() {
+    // Locks don't work until the MMU and caches are on.
+    vm::mmu::initialize();
+
     timer::spin_sleep_ms(1000);
 
     kprintln!("{}", RACCOON_STRING);
@@ -70,6 +93,24 @@ pub unsafe extern "C" fn kmain() {
     ALLOCATOR.initialize();
     FILE_SYSTEM.initialize();
 
+    match debug::backtrace::load_symbols(debug::backtrace::SYMBOL_FILE) {
+        Ok(count) => kprintln!("loaded {} symbols", count),
+        Err(e) => kprintln!("no symbols for backtraces: {:?}", e),
+    }
+
+    match clock::initialize() {
+        Some(datetime) => kprintln!("clock set from {}: {} UTC", clock::CLOCK_FILE, datetime),
+        None => kprintln!("no valid time in {}; the clock starts at the epoch", clock::CLOCK_FILE),
+    }
+    if clock::probe_ds3231() {
+        kprintln!("clock set from the DS3231: {} UTC", clock::datetime());
+    }
+
+    match font::load(font::FONT_FILE, 1) {
+        Ok((columns, rows)) => kprintln!("loaded {}: {}x{} screen", font::FONT_FILE, columns, rows),
+        Err(e) => kprintln!("using the built-in font: {:?}", e),
+    }
+
     let mut v = vec![];
     for i in 0..1000 {
         v.push(i);
@@ -77,9 +118,12 @@ pub unsafe extern "C" fn kmain() {
     kprintln!("allocated vec:");
     kprintln!("{:x?}", v);
 
-    SCREEN.lock().clear();
-    SCREEN.lock().draw_string_scale(&"WELCOME TO MaxOS,5", 5);
-    SCREEN.lock().draw_char_scale(0x0d, 5);
+    match COMPOSITOR.initialize(DESKTOP_HEIGHT) {
+        Some((width, height)) => kprintln!("desktop is {}x{}", width, height),
+        None => kprintln!("no desktop: the console has the whole screen"),
+    }
+
+    SCREEN.lock_irq().draw_string_scale("WELCOME TO MaxOS", 5);
 
     SCHEDULER.start();
 }
@@ -93,6 +137,13 @@ pub extern fn start_shell() {
     loop { shell("1 >>> "); }
 }
 
+#[no_mangle]
+pub extern fn start_status_panel() {
+    let error = panel::status_panel();
+    kprintln!("status panel: {}", error);
+    syscalls::exit(1);
+}
+
 #[no_mangle]
 pub extern fn start_shell_2() {
     loop { shell("2 >>> "); }
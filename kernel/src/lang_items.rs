@@ -1,8 +1,10 @@
 extern crate core;
 extern crate std;
 
-use pi::console::kprintln;
+use pi::console::{kprintln, CONSOLE};
+use pi::propertytag;
 use pi::screen::SCREEN;
+use debug::backtrace;
 use std::alloc::Layout;
 
 use lang_items::core::panic::PanicInfo;
@@ -29,6 +31,12 @@ pub const OVERDONE_STRING: &str = "
 #[cfg(not(test))]
 #[no_mangle]
 pub extern "C" fn panic_fmt(panic_info: &PanicInfo) -> ! {
+    // We never return to whoever held these, so don't deadlock on them.
+    unsafe {
+        CONSOLE.force_unlock();
+        SCREEN.force_unlock();
+        propertytag::force_unlock();
+    }
 
     kprintln!("{}", OVERDONE_STRING);
     kprintln!("{:?}", &panic_info.payload());
@@ -43,8 +51,8 @@ pub extern "C" fn panic_fmt(panic_info: &PanicInfo) -> ! {
         kprintln!("panic occurred but can't get location information...");
     }
 
-    SCREEN.lock().draw_string(&OVERDONE_STRING);
-    SCREEN.lock().draw_char(0x0d);
+    backtrace::print();
+
     loop {}
 }
 
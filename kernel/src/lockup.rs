@@ -0,0 +1,159 @@
+//! Soft-lockup detection.
+//!
+//! Every core records a heartbeat, and the thread and process it is running,
+//! on each scheduler tick; idle cores tick too. Core 0's tick checks the other
+//! cores' heartbeats and pets the hardware watchdog only while all of them are
+//! fresh, and the first other core online checks core 0's. A core that stops
+//! ticking, because it spins with IRQs masked or deadlocked, is reported and
+//! the board is reset. If every core hangs, the watchdog resets the board.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::usize;
+use std::time::Duration;
+
+use aarch64;
+use pi::console::{kprintln, CONSOLE};
+use pi::mutex::Mutex;
+use pi::propertytag;
+use pi::screen::SCREEN;
+use pi::timer;
+use pi::watchdog::Watchdog;
+use process::Id;
+use smp::{self, NCORES};
+use traps::TrapFrame;
+
+/// How long a core may go without a tick before it is considered locked up.
+const SOFT_LOCKUP_US: usize = 5 * 1000 * 1000;
+
+/// How long the hardware watchdog waits for core 0's next tick.
+const WATCHDOG_TIMEOUT_SECS: u64 = 10;
+
+static WATCHDOG: Mutex<Option<Watchdog>> = Mutex::new(None);
+
+/// Set while a debugger has the machine stopped.
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// The time of each core's last tick, in microseconds.
+static HEARTBEATS: [AtomicUsize; NCORES] = [
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+];
+
+/// The thread each core was running at its last tick.
+static RUNNING: [AtomicUsize; NCORES] = [
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+];
+
+/// The process each core was running at its last tick, or `IDLE`.
+static PROCESSES: [AtomicUsize; NCORES] = [
+    AtomicUsize::new(IDLE), AtomicUsize::new(IDLE), AtomicUsize::new(IDLE),
+    AtomicUsize::new(IDLE),
+];
+
+/// Stands for no process in `PROCESSES`: the core was idle.
+const IDLE: usize = usize::MAX;
+
+/// Set once a core has started reporting a lockup, so that only one does.
+static REPORTING: AtomicBool = AtomicBool::new(false);
+
+fn now() -> usize {
+    timer::current_time() as usize
+}
+
+fn reset_heartbeats() {
+    let now = now();
+    for heartbeat in HEARTBEATS.iter() {
+        heartbeat.store(now, Ordering::Relaxed);
+    }
+}
+
+/// Starts the hardware watchdog and lockup detection.
+pub fn start() {
+    reset_heartbeats();
+    let mut watchdog = Watchdog::new();
+    watchdog.start(Duration::from_secs(WATCHDOG_TIMEOUT_SECS));
+    *WATCHDOG.lock_irq() = Some(watchdog);
+}
+
+/// Suspends lockup detection, and the watchdog, while the machine is
+/// deliberately stopped, for instance in the debug monitor.
+pub fn pause() {
+    PAUSED.store(true, Ordering::Release);
+    if let Some(ref mut watchdog) = *WATCHDOG.lock_irq() {
+        watchdog.stop();
+    }
+}
+
+/// Resumes lockup detection after `pause()`.
+pub fn resume() {
+    reset_heartbeats();
+    if let Some(ref mut watchdog) = *WATCHDOG.lock_irq() {
+        watchdog.pet();
+    }
+    PAUSED.store(false, Ordering::Release);
+}
+
+/// Records a heartbeat for the calling core, which took its scheduler tick
+/// while running the thread with trap frame `tf` in process `pid`, or idling
+/// if `pid` is `None`. On core 0, also checks the other cores and pets the
+/// watchdog; on the first other core, checks core 0.
+pub fn tick(tf: &TrapFrame, pid: Option<Id>) {
+    let core = aarch64::affinity();
+    let now = now();
+    HEARTBEATS[core].store(now, Ordering::Relaxed);
+    RUNNING[core].store(tf.tpidr as usize, Ordering::Relaxed);
+    PROCESSES[core].store(pid.map_or(IDLE, |pid| pid as usize), Ordering::Relaxed);
+
+    if PAUSED.load(Ordering::Acquire) {
+        return;
+    }
+
+    if core != 0 {
+        if (1..NCORES).find(|&other| smp::is_online(other)) == Some(core) {
+            check(0, now);
+        }
+        return;
+    }
+
+    for other in (1..NCORES).filter(|&other| smp::is_online(other)) {
+        check(other, now);
+    }
+
+    if let Some(ref mut watchdog) = *WATCHDOG.lock_irq() {
+        watchdog.pet();
+    }
+}
+
+/// Reports `core` and resets the board if its last heartbeat is more than
+/// `SOFT_LOCKUP_US` before `now`.
+fn check(core: usize, now: usize) {
+    // The other core may have ticked since `now` was read.
+    let stuck = now.saturating_sub(HEARTBEATS[core].load(Ordering::Relaxed));
+    if stuck > SOFT_LOCKUP_US && !REPORTING.swap(true, Ordering::AcqRel) {
+        report(core, stuck);
+    }
+}
+
+/// Reports that `core` hasn't ticked for `us` microseconds and resets the
+/// board.
+fn report(core: usize, us: usize) -> ! {
+    // The stuck core may be holding the console, the screen or the mailbox
+    // the screen flips pages through.
+    unsafe {
+        CONSOLE.force_unlock();
+        SCREEN.force_unlock();
+        propertytag::force_unlock();
+    }
+
+    kprintln!("soft lockup: core {} stuck for {}ms", core, us / 1000);
+    for n in (0..NCORES).filter(|&n| smp::is_online(n)) {
+        match PROCESSES[n].load(Ordering::Relaxed) {
+            IDLE => kprintln!("  core {}: idle", n),
+            pid => kprintln!("  core {}: process {}, thread {}", n, pid,
+                             RUNNING[n].load(Ordering::Relaxed)),
+        }
+    }
+    kprintln!("resetting");
+
+    timer::spin_sleep_ms(100);
+    Watchdog::new().reboot()
+}
@@ -0,0 +1,58 @@
+//! The status panel: a process showing the time and uptime in a surface on
+//! the desktop while the shell runs in the console below it.
+
+use pi::font::Font;
+use pi::graphics::{Canvas, Image, Rgba};
+use pi::rtc::DateTime;
+use syscalls::{self, CLOCK_MONOTONIC, CLOCK_REALTIME};
+
+const WIDTH: usize = 156;
+const HEIGHT: usize = 40;
+/// The space around the text, in pixels.
+const PADDING: i32 = 4;
+/// Where the panel is on the desktop.
+const POSITION: (i32, i32) = (8, 8);
+
+const BACKGROUND: Rgba = Rgba::rgb(0x20, 0x20, 0x20);
+const FOREGROUND: Rgba = Rgba::WHITE;
+const HINT: Rgba = Rgba::rgb(0x80, 0x80, 0x80);
+
+/// Draws the panel into `image` for the Unix time `now` and `uptime` seconds
+/// since boot.
+fn draw(image: &mut Image, font: &Font, now: u64, uptime: u64) {
+    let line = font.height() as i32 + 2;
+    let (hours, minutes, seconds) = (uptime / 3600, uptime / 60 % 60, uptime % 60);
+
+    let mut canvas = Canvas::new(image);
+    canvas.clear(BACKGROUND);
+    canvas.text(font, PADDING, PADDING, &format!("{} UTC", DateTime::from_unix(now)),
+                FOREGROUND);
+    canvas.text(font, PADDING, PADDING + line,
+                &format!("up {}:{:02}:{:02}", hours, minutes, seconds), FOREGROUND);
+    canvas.text(font, PADDING, PADDING + 2 * line, "Ctrl-T switches focus", HINT);
+}
+
+/// Shows the status panel, redrawing it every second. Returns the error if
+/// the panel can't be shown.
+pub fn status_panel() -> String {
+    let (x, y) = POSITION;
+    let id = match syscalls::surface_create(x, y, WIDTH, HEIGHT) {
+        Ok(id) => id,
+        Err(e) => return e,
+    };
+
+    let font = Font::builtin();
+    let mut image = Image::new(WIDTH, HEIGHT);
+    loop {
+        let now = syscalls::clock_gettime(CLOCK_REALTIME).map(|time| time.secs).unwrap_or(0);
+        let uptime = syscalls::clock_gettime(CLOCK_MONOTONIC).map(|time| time.secs).unwrap_or(0);
+        draw(&mut image, &font, now, uptime);
+
+        let pixels: Vec<u32> = image.pixels().iter().map(|pixel| pixel.to_argb()).collect();
+        if let Err(e) = syscalls::surface_update(id, 0, 0, WIDTH, HEIGHT, &pixels) {
+            return e;
+        }
+
+        let _ = syscalls::sleep(1000);
+    }
+}
@@ -0,0 +1,55 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use pi::mutex::Mutex;
+use ipc::{PipeReader, PipeWriter, Endpoint};
+
+/// Type alias for the type of a file descriptor.
+pub type Fd = u64;
+
+/// An open file description a file descriptor refers to.
+#[derive(Debug, Clone)]
+pub enum Descriptor {
+    PipeReader(PipeReader),
+    PipeWriter(PipeWriter),
+    Channel(Endpoint),
+}
+
+/// A process's table of open file descriptors.
+#[derive(Debug, Clone, Default)]
+pub struct Files {
+    table: BTreeMap<Fd, Descriptor>,
+}
+
+/// The file descriptor table shared by every thread of a process.
+pub type SharedFiles = Arc<Mutex<Files>>;
+
+impl Files {
+    /// Returns an empty file descriptor table.
+    pub fn new() -> Files {
+        Files::default()
+    }
+
+    /// Installs `descriptor` at the lowest free file descriptor and returns
+    /// that file descriptor.
+    pub fn insert(&mut self, descriptor: Descriptor) -> Fd {
+        let fd = self.table.keys()
+            .zip(0..)
+            .find(|&(&used, fd)| used != fd)
+            .map(|(_, fd)| fd)
+            .unwrap_or(self.table.len() as Fd);
+
+        self.table.insert(fd, descriptor);
+        fd
+    }
+
+    /// Returns the descriptor `fd` refers to, if it is open.
+    pub fn get(&self, fd: Fd) -> Option<&Descriptor> {
+        self.table.get(&fd)
+    }
+
+    /// Closes `fd`, returning the descriptor it referred to if it was open.
+    pub fn remove(&mut self, fd: Fd) -> Option<Descriptor> {
+        self.table.remove(&fd)
+    }
+}
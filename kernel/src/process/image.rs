@@ -0,0 +1,88 @@
+use std::fmt;
+use std::io::{self, Read};
+use std::ptr::Unique;
+use std::alloc::GlobalAlloc;
+
+use aarch64;
+use ALLOCATOR;
+use FILE_SYSTEM;
+use fs::traits;
+use std::alloc::Layout;
+use vm::PhysicalAddr;
+
+/// The memory holding a program loaded by `exec`. Programs are flat,
+/// position-independent binaries whose entry point is their first byte.
+pub struct Image {
+    ptr: Unique<u8>,
+    size: usize,
+}
+
+impl Image {
+    /// Images are page aligned so that `adrp`-relative addressing in the
+    /// program resolves correctly.
+    pub const ALIGN: usize = 4096;
+
+    fn layout(size: usize) -> Layout {
+        unsafe { Layout::from_size_align_unchecked(size, Self::ALIGN) }
+    }
+
+    /// Returns a newly allocated image holding a copy of `program`, or `None`
+    /// if `program` is empty.
+    pub fn new(program: &[u8]) -> Option<Image> {
+        if program.is_empty() {
+            return None;
+        }
+
+        let raw_ptr = unsafe {
+            let raw_ptr: *mut u8 = ALLOCATOR.alloc(Image::layout(program.len()));
+            raw_ptr.copy_from_nonoverlapping(program.as_ptr(), program.len());
+            raw_ptr
+        };
+        aarch64::sync_icache(raw_ptr as usize, program.len());
+
+        let ptr = Unique::new(raw_ptr).expect("non-null");
+        Some(Image { ptr, size: program.len() })
+    }
+
+    /// Loads the program at `path` from the file system.
+    ///
+    /// # Errors
+    ///
+    /// Returns the underlying I/O error if the file can't be opened or read,
+    /// or an error of kind `InvalidData` if the file is empty.
+    pub fn load(path: &str) -> io::Result<Image> {
+        let mut file = traits::FileSystem::open_file(&FILE_SYSTEM, path)?;
+        let mut program = vec![];
+        file.read_to_end(&mut program)?;
+
+        Image::new(&program)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "empty program"))
+    }
+
+    /// Returns the physical address of the program's entry point.
+    pub fn entry(&self) -> PhysicalAddr {
+        self.ptr.as_ptr().into()
+    }
+
+    /// Returns the size of the image in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        unsafe {
+            ALLOCATOR.dealloc(self.ptr.as_ptr(), Image::layout(self.size))
+        }
+    }
+}
+
+impl fmt::Debug for Image {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Image")
+            .field("entry", &self.entry())
+            .field("size", &self.size)
+            .finish()
+    }
+}
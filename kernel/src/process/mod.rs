@@ -2,8 +2,16 @@ mod process;
 mod state;
 mod scheduler;
 mod stack;
+mod thread;
+mod image;
+mod files;
+mod signal;
 
-pub use self::process::{Process, Id};
+pub use self::process::{Process, Children, Id};
 pub use self::state::State;
-pub use self::scheduler::{GlobalScheduler, TICK};
+pub use self::scheduler::{GlobalScheduler, TICK, start_tick, arm_tick, is_tick_pending};
 pub use self::stack::Stack;
+pub use self::thread::{Thread, ExitStatus};
+pub use self::image::Image;
+pub use self::files::{Files, SharedFiles, Descriptor, Fd};
+pub use self::signal::{Signal, Signals, SignalFrame, Action, Disposition, SIG_DFL, SIG_IGN};
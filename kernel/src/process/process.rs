@@ -1,69 +1,58 @@
-use std::mem;
-use traps::TrapFrame;
-use process::{State, Stack};
+use std::collections::BTreeMap;
+use std::sync::Arc;
 
-/// Type alias for the type of a process ID.
+use pi::mutex::Mutex;
+use process::{ExitStatus, Image, Files, SharedFiles, Signals};
+
+/// Type alias for the type of a process or thread ID. A process's first
+/// thread shares the process's ID.
 pub type Id = u64;
 
-/// A structure that represents the complete state of a process.
-#[repr(align(16))]
+/// The exit statuses of a process's children, keyed by child ID. A child's
+/// status is `None` while it is running. Entries are removed when the child
+/// is waited for.
+pub type Children = Arc<Mutex<BTreeMap<Id, ExitStatus>>>;
+
+/// The resources shared by all threads of a program.
+///
+/// There is no virtual memory yet, so every process shares the kernel's
+/// identity-mapped address space.
 #[derive(Debug)]
 pub struct Process {
-    /// The saved trap frame of a process.
-    pub trap_frame: Box<TrapFrame>,
-    /// The memory allocation used for the process's stack.
-    pub stack: Stack,
-    /// The scheduling state of the process.
-    pub state: State,
+    /// The ID of the process.
+    pub id: Id,
+    /// The ID of the process that created this one, if any.
+    pub parent: Option<Id>,
+    /// The exit statuses of this process's children.
+    pub children: Children,
+    /// Set when the process exits. Shared with the parent's `children`.
+    pub exit_status: ExitStatus,
+    /// The program loaded by `exec`, if any. Shared with forked children,
+    /// which keep executing its code.
+    pub image: Option<Arc<Image>>,
+    /// The process's open file descriptors. Forked children get a copy.
+    pub files: SharedFiles,
+    /// Pending and blocked signals and their dispositions.
+    pub signals: Signals,
+    /// The number of threads of this process that have not exited.
+    pub live_threads: usize,
+    /// Exit statuses of threads that have exited but not been joined.
+    pub exited_threads: BTreeMap<Id, u64>,
 }
 
 impl Process {
-    /// Creates a new process with a zeroed `TrapFrame` (the default), a zeroed
-    /// stack of the default size, and a state of `Ready`.
-    ///
-    /// If enough memory could not be allocated to start the process, returns
-    /// `None`. Otherwise returns `Some` of the new `Process`.
-    pub fn new() -> Option<Process> {
-        match Stack::new() {
-            Some(stack) => Some(Process {
-                stack,
-                state: State::Ready,
-                trap_frame: Default::default()
-            }),
-            None => None
-        }
-    }
-
-    /// Returns `true` if this process is ready to be scheduled.
-    ///
-    /// This functions returns `true` only if one of the following holds:
-    ///
-    ///   * The state is currently `Ready`.
-    ///
-    ///   * An event being waited for has arrived.
-    ///
-    ///     If the process is currently waiting, the corresponding event
-    ///     function is polled to determine if the event being waiting for has
-    ///     occured. If it has, the state is switched to `Ready` and this
-    ///     function returns `true`.
-    ///
-    /// Returns `false` in all other cases.
-    pub fn is_ready(&mut self) -> bool {
-        let state_copy = State::Ready;
-        let mut owned_state = mem::replace(&mut self.state, state_copy);
-
-        let ready = match owned_state {
-            State::Ready => true,
-            State::Waiting(ref mut poll_function) => {
-                poll_function(self)
-            },
-            _ => false
-        };
-
-        if !ready {
-            mem::replace(&mut self.state, owned_state);
+    /// Creates a new process with ID `id`, no parent, and no threads.
+    pub fn new(id: Id) -> Process {
+        Process {
+            id,
+            parent: None,
+            children: Arc::new(Mutex::new(BTreeMap::new())),
+            exit_status: Arc::new(Mutex::new(None)),
+            image: None,
+            files: Arc::new(Mutex::new(Files::new())),
+            signals: Signals::new(),
+            live_threads: 0,
+            exited_threads: BTreeMap::new(),
         }
-
-        ready
     }
 }
@@ -1,19 +1,66 @@
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
 use std::mem;
+use std::sync::Arc;
+use std::time::Duration;
 
 use pi::mutex::Mutex;
-use pi::console::kprintln;
-use pi::interrupt;
-use pi::timer;
+use pi::console::{kprintln, CONSOLE};
+use pi::interrupt::Interrupt;
+use pi::local_interrupt::LocalController;
+use pi::generic_timer::Timer;
 use aarch64;
-use process::{Process, State, Id};
-use traps::TrapFrame;
-use start_shell;
-
-/// The `tick` time. Currently set to 10ms
+use process::{Process, Children, SharedFiles, Thread, ExitStatus, Image, State, Id};
+use process::{Signal, Action, Disposition};
+use syscalls::thread_return;
+use smp::{self, Ipi, NCORES};
+use traps::{Handled, TrapFrame};
+use cpufreq;
+use debug;
+use lockup;
+use {start_shell, start_status_panel, COMPOSITOR, IRQS, SCHEDULER};
+
+/// The `tick` time in microseconds. Currently set to 10ms
 pub const TICK: u32 = 10 * 1000;
 
-/// Process scheduler for the entire machine.
+/// The generic timer driving each core's scheduler tick.
+const TICK_TIMER: Timer = Timer::Physical;
+
+/// Routes the calling core's tick timer to it and arms the first tick.
+pub fn start_tick() {
+    LocalController::new(aarch64::affinity()).enable_timer(TICK_TIMER.interrupt());
+    arm_tick();
+}
+
+/// Arms the calling core's next scheduler tick, `TICK` from now.
+pub fn arm_tick() {
+    TICK_TIMER.tick_in(Duration::from_micros(TICK as u64));
+}
+
+/// Returns `true` if the calling core's scheduler tick is pending.
+pub fn is_tick_pending() -> bool {
+    LocalController::new(aarch64::affinity()).is_pending(TICK_TIMER.interrupt())
+}
+
+/// What a core runs when it has no thread to: waits for its next tick or an
+/// IPI, with IRQs unmasked so they are taken as usual.
+extern "C" fn idle_loop(_: u64) -> u64 {
+    loop {
+        cpufreq::idle();
+    }
+}
+
+/// The outcome of delivering signals to a core's current thread.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+enum Delivery {
+    /// The thread may resume, possibly in a signal handler.
+    Resume,
+    /// The thread's process is stopped; the thread must be switched out.
+    Stopped,
+    /// The thread's process has exited and the thread has been removed.
+    Exited,
+}
+
+/// Thread scheduler for the entire machine.
 #[derive(Debug)]
 pub struct GlobalScheduler(Mutex<Option<Scheduler>>);
 
@@ -23,148 +70,758 @@ impl GlobalScheduler {
         GlobalScheduler(Mutex::new(None))
     }
 
-    /// Adds a process to the scheduler's queue and returns that process's ID.
-    /// For more details, see the documentation on `Scheduler::add()`.
-    pub fn add(&self, process: Process) -> Option<Id> {
-        self.0.lock().as_mut().expect("scheduler uninitialized").add(process)
+    /// Runs `f` with the scheduler locked.
+    fn critical<F, R>(&self, f: F) -> R
+        where F: FnOnce(&mut Scheduler) -> R
+    {
+        f(self.0.lock().as_mut().expect("scheduler uninitialized"))
+    }
+
+    /// Sends a `Reschedule` IPI to `core` if it isn't the calling core, so it
+    /// picks up newly queued work right away.
+    fn notify(core: usize) {
+        if core != aarch64::affinity() && smp::is_online(core) {
+            smp::send_ipi(core, Ipi::Reschedule);
+        }
+    }
+
+    /// Creates a new process whose only thread is `thread`, queues the thread,
+    /// and returns the new process's ID. For more details, see the
+    /// documentation on `Scheduler::add()`.
+    pub fn add(&self, thread: Thread) -> Option<Id> {
+        let (id, core) = self.critical(|scheduler| {
+            (scheduler.add(thread), scheduler.last_queued)
+        });
+
+        GlobalScheduler::notify(core);
+        id
+    }
+
+    /// Spawns a new EL0 thread calling `entry(arg)` in the process of the
+    /// thread currently running on this core. Returns the new thread's ID.
+    pub fn spawn_thread(&self, entry: u64, arg: u64) -> Option<Id> {
+        let thread = Thread::user(entry, arg)?;
+        let core = aarch64::affinity();
+
+        let (id, queued) = self.critical(|scheduler| {
+            let pid = scheduler.current(core).map(|t| t.pid)?;
+            Some((scheduler.add_thread(pid, thread)?, scheduler.last_queued))
+        })?;
+
+        GlobalScheduler::notify(queued);
+        Some(id)
+    }
+
+    /// Spawns a kernel worker thread calling `entry(arg)` in EL1. Kernel
+    /// threads all belong to the kernel process, which is created on first
+    /// use. Returns the new thread's ID.
+    pub fn spawn_kernel_thread(&self, entry: extern "C" fn(u64) -> u64, arg: u64) -> Option<Id> {
+        let thread = Thread::kernel(entry, arg)?;
+
+        let (id, queued) = self.critical(|scheduler| {
+            let id = match scheduler.kernel_pid {
+                Some(pid) => scheduler.add_thread(pid, thread),
+                None => {
+                    let pid = scheduler.add(thread);
+                    scheduler.kernel_pid = pid;
+                    pid
+                }
+            };
+            (id, scheduler.last_queued)
+        });
+
+        GlobalScheduler::notify(queued);
+        id
+    }
+
+    /// Returns the exit status of thread `tid` of the calling process, which
+    /// becomes `Some` once the thread exits. Returns `None` if the calling
+    /// process has no such thread or it has already been joined.
+    pub fn exit_status(&self, tid: Id) -> Option<ExitStatus> {
+        let core = aarch64::affinity();
+        self.critical(|scheduler| {
+            let pid = scheduler.current(core)?.pid;
+            scheduler.exit_status(pid, tid)
+        })
+    }
+
+    /// Creates a child of the calling process whose only thread is a copy of
+    /// the calling thread, with trap frame `tf`. The child resumes with `x0`
+    /// set to `0`. Returns the child's process ID.
+    pub fn fork(&self, tf: &TrapFrame) -> Option<Id> {
+        let core = aarch64::affinity();
+        let (id, queued) = self.critical(|scheduler| {
+            Some((scheduler.fork(core, tf)?, scheduler.last_queued))
+        })?;
+
+        GlobalScheduler::notify(queued);
+        Some(id)
+    }
+
+    /// Replaces the program of the calling process with `image` and resets
+    /// `tf` to start executing it. Returns `false`, leaving `tf` untouched, if
+    /// the process has more than one thread.
+    pub fn exec(&self, image: Image, tf: &mut TrapFrame) -> bool {
+        let core = aarch64::affinity();
+        self.critical(|scheduler| scheduler.exec(core, image, tf))
+    }
+
+    /// Returns the ID of the calling thread's process.
+    pub fn current_pid(&self) -> Option<Id> {
+        let core = aarch64::affinity();
+        self.critical(|scheduler| scheduler.current(core).map(|t| t.pid))
+    }
+
+    /// Returns the children table of the calling process.
+    pub fn children(&self) -> Option<Children> {
+        let core = aarch64::affinity();
+        self.critical(|scheduler| {
+            let pid = scheduler.current(core)?.pid;
+            Some(scheduler.processes.get(&pid)?.children.clone())
+        })
+    }
+
+    /// Returns the file descriptor table of the calling process.
+    pub fn files(&self) -> Option<SharedFiles> {
+        let core = aarch64::affinity();
+        self.critical(|scheduler| {
+            let pid = scheduler.current(core)?.pid;
+            Some(scheduler.processes.get(&pid)?.files.clone())
+        })
+    }
+
+    /// Sends `signal` to process `pid`, or only checks that the process exists
+    /// if `signal` is `None`. Returns `false` if there is no such process.
+    pub fn kill(&self, pid: Id, signal: Option<Signal>) -> bool {
+        let found = self.critical(|scheduler| scheduler.kill(pid, signal));
+        if found && signal.is_some() {
+            // Threads of the process may be running on other cores; switching
+            // them out lets the signal take effect promptly.
+            smp::broadcast_ipi(Ipi::Reschedule);
+        }
+
+        found
+    }
+
+    /// Raises `signal` in the calling process for a fault it can't continue
+    /// past. See `Signals::force()`.
+    pub fn fault(&self, signal: Signal) {
+        let core = aarch64::affinity();
+        self.critical(|scheduler| scheduler.fault(core, signal));
     }
 
-    /// Performs a context switch using `tf` by setting the state of the current
-    /// process to `new_state`, saving `tf` into the current process, and
-    /// restoring the next process's trap frame into `tf`. For more details, see
-    /// the documentation on `Scheduler::switch()`.
+    /// Sets the disposition of `signal` in the calling process and returns the
+    /// previous one. Returns `None` if `signal` can't be caught.
+    pub fn sigaction(&self, signal: Signal, disposition: Disposition) -> Option<Disposition> {
+        let core = aarch64::affinity();
+        self.critical(|scheduler| {
+            let pid = scheduler.current(core)?.pid;
+            scheduler.processes.get_mut(&pid)?.signals.set_disposition(signal, disposition)
+        })
+    }
+
+    /// Returns from a signal handler by restoring the trap frame saved on the
+    /// signal frame at `tf.sp`. Returns `false` if there is no signal frame.
+    pub fn sigreturn(&self, tf: &mut TrapFrame) -> bool {
+        let core = aarch64::affinity();
+        self.critical(|scheduler| {
+            let thread = match scheduler.queues[core].current {
+                Some(ref thread) => thread,
+                None => return false
+            };
+
+            let process = match scheduler.processes.get_mut(&thread.pid) {
+                Some(process) => process,
+                None => return false
+            };
+
+            match thread.pop_signal_frame(tf) {
+                Some(blocked) => {
+                    process.signals.set_blocked(blocked);
+                    true
+                },
+                None => false
+            }
+        })
+    }
+
+    /// Makes `pid` the foreground process, which receives `Int` when Ctrl-C
+    /// is pressed. Returns `false` if there is no such process.
+    pub fn set_foreground(&self, pid: Id) -> bool {
+        self.critical(|scheduler| {
+            if scheduler.processes.contains_key(&pid) {
+                scheduler.foreground = Some(pid);
+                true
+            } else {
+                false
+            }
+        })
+    }
+
+    /// Sends `Int` to the foreground process, if there is one.
+    pub fn interrupt_foreground(&self) {
+        if let Some(pid) = self.critical(|scheduler| scheduler.foreground) {
+            self.kill(pid, Some(Signal::Int));
+        }
+    }
+
+    /// Delivers the pending signals of the calling core's current thread
+    /// before it returns to EL0 through `tf`: either rewrites `tf` to run a
+    /// handler or takes the default action, switching to another thread if
+    /// the process stops or exits.
+    ///
+    /// Signals are only delivered on the way back to EL0, so a thread waiting
+    /// in a system call sees a handled signal once the call completes.
+    pub fn deliver_signals(&self, tf: &mut TrapFrame) {
+        let core = aarch64::affinity();
+        while tf.is_el0() {
+            match self.critical(|scheduler| scheduler.deliver_signals(core, tf)) {
+                Delivery::Resume => return,
+                Delivery::Stopped => {
+                    let _ = self.switch(State::Ready, tf);
+                },
+                Delivery::Exited => {
+                    self.schedule(core, true, tf);
+                }
+            }
+        }
+    }
+
+    /// Performs a context switch on the calling core using `tf` by setting the
+    /// state of the current thread to `new_state`, saving `tf` into the
+    /// current thread, and restoring the next thread's trap frame into `tf`.
+    /// For more details, see the documentation on `Scheduler::switch()`.
+    ///
+    /// If no thread is ready, this method returns `None`. A core that was
+    /// running a thread is switched to its idle loop; an idle core stays
+    /// idle.
     #[must_use]
     pub fn switch(&self, new_state: State, tf: &mut TrapFrame) -> Option<Id> {
-        self.0.lock().as_mut().expect("scheduler uninitialized").switch(new_state, tf)
+        let core = aarch64::affinity();
+        let was_running = self.critical(|scheduler| scheduler.park(core, new_state, tf));
+        self.schedule(core, was_running, tf)
+    }
+
+    /// Exits the thread currently running on the calling core with `status`
+    /// and switches to the next ready thread, or to the core's idle loop.
+    pub fn exit(&self, status: u64, tf: &mut TrapFrame) -> Option<Id> {
+        let core = aarch64::affinity();
+        self.critical(|scheduler| scheduler.exit_current(core, status));
+        self.schedule(core, true, tf)
+    }
+
+    /// Exits the process of the thread running on the calling core with
+    /// `status`, along with all of its threads, and switches to the next ready
+    /// thread, or to the core's idle loop.
+    pub fn exit_process(&self, status: u64, tf: &mut TrapFrame) -> Option<Id> {
+        let core = aarch64::affinity();
+        self.critical(|scheduler| {
+            if let Some(thread) = mem::replace(&mut scheduler.queues[core].current, None) {
+                scheduler.exit_process(thread.pid, status);
+            }
+        });
+        self.schedule(core, true, tf)
+    }
+
+    /// Switches `core` to the next ready thread. If there is none and `leave`
+    /// is set, because `tf` belongs to a thread that can't continue, switches
+    /// `core` to its idle loop instead; the next tick or IPI schedules again.
+    fn schedule(&self, core: usize, leave: bool, tf: &mut TrapFrame) -> Option<Id> {
+        self.critical(|scheduler| {
+            let id = scheduler.switch(core, tf);
+            if id.is_none() && leave {
+                scheduler.idle(core, tf);
+            }
+            id
+        })
     }
 
     /// Initializes the scheduler and starts executing processes in user space
     /// using timer interrupt based preemptive scheduling. This method should
     /// not return under normal conditions.
     pub fn start(&self) {
+        IRQS.register(Interrupt::Aux, "console", Box::new(|tf: &mut TrapFrame| {
+            // The debugger reads the console itself; otherwise input goes to
+            // the focused surface, if there is one.
+            let attached = debug::gdb::is_attached();
+            if CONSOLE.lock_irq().receive(|byte| !attached && COMPOSITOR.input(byte)) {
+                if attached {
+                    debug::gdb::interrupt(tf);
+                } else if let Some(pid) = COMPOSITOR.focused_owner() {
+                    SCHEDULER.kill(pid, Some(Signal::Int));
+                } else {
+                    SCHEDULER.interrupt_foreground();
+                }
+            }
+            Handled::Yes
+        }));
+        CONSOLE.lock_irq().enable_rx_interrupt();
+        LocalController::new(0).enable_mailbox(smp::IPI_MAILBOX);
 
-        let mut interrupt_controller = interrupt::Controller::new();
-        interrupt_controller.enable(interrupt::Interrupt::Timer1);
-
-        timer::tick_in(TICK);
+        start_tick();
+        lockup::start();
 
-        match Process::new() {
-            Some(mut start_process) => {
+        match Thread::user(start_shell as *const u64 as u64, 0) {
+            Some(start_thread) => {
                 let mut new_scheduler = Scheduler::new();
 
-                start_process.trap_frame.elr = start_shell as *const u64 as u64;
-                start_process.trap_frame.sp = start_process.stack.top().as_u64();
-
-                // All interrupts unmasked; el0; and aarch64
-                start_process.trap_frame.spsr = 0x00;
+                let trap_frame_address = (&(*start_thread.trap_frame)) as *const TrapFrame as *const u64 as u64;
 
-                let trap_frame_address = (&(*start_process.trap_frame)) as *const TrapFrame as *const u64 as u64;
+                kprintln!("start_thread = {:#x?}", &start_thread);
 
-                kprintln!("start_process = {:#x?}", &start_process);
+                new_scheduler.add(start_thread);
 
-                new_scheduler.add(start_process);
+                match Thread::user(start_status_panel as *const u64 as u64, 0) {
+                    Some(panel_thread) => {
+                        new_scheduler.add(panel_thread);
+                    },
+                    None => kprintln!("not enough memory for the status panel")
+                }
 
                 *self.0.lock() = Some(new_scheduler);
 
+                if self.spawn_kernel_thread(cpufreq::governor, 0).is_none() {
+                    kprintln!("not enough memory for the cpufreq governor");
+                }
+
                 unsafe {
-                    asm!("mov sp, $0"
-                         :: "r"(trap_frame_address)
-                         :: "volatile");
-
-                    asm!("bl context_restore
-                          ldr x1, =_start
-                          mov sp, x1
-                          mov x1, #0
-                          eret" :::: "volatile");
+                    smp::start_secondary_cores();
+
+                    asm!("mov x0, $1
+                          mov sp, $0
+                          bl context_restore
+                          mov sp, x0
+                          mov x0, #0
+                          eret"
+                         :: "r"(trap_frame_address), "r"(smp::stack_top(0))
+                         : "x0"
+                         : "volatile");
                 }
 
             },
             None => {
-                kprintln!("Could not create start process! 🔥🎆🎆🔥");
+                kprintln!("Could not create start thread! 🔥🎆🎆🔥");
             }
         }
     }
 }
 
+/// A single core's run queue.
+#[derive(Debug)]
+struct RunQueue {
+    /// Threads waiting for their turn on this core.
+    threads: VecDeque<Thread>,
+    /// The thread currently executing on this core, if any.
+    current: Option<Thread>,
+    /// The context the core idles in, on a stack of its own, created the
+    /// first time the core runs out of threads. It is never saved: every
+    /// switch to it starts the idle loop afresh.
+    idle: Option<Thread>,
+}
+
+impl RunQueue {
+    fn new() -> RunQueue {
+        RunQueue {
+            threads: VecDeque::new(),
+            current: None,
+            idle: None,
+        }
+    }
+
+    /// The number of threads assigned to this core, including the running
+    /// one.
+    fn load(&self) -> usize {
+        self.threads.len() + self.current.is_some() as usize
+    }
+}
+
 #[derive(Debug)]
 struct Scheduler {
-    processes: VecDeque<Process>,
-    current: Option<Process>,
+    queues: Vec<RunQueue>,
+    processes: BTreeMap<Id, Process>,
+    /// The process kernel worker threads belong to, once one is spawned.
+    kernel_pid: Option<Id>,
     last_id: Option<Id>,
+    /// The core whose queue received the most recently added thread.
+    last_queued: usize,
+    /// The process that receives `Int` on Ctrl-C.
+    foreground: Option<Id>,
 }
 
 impl Scheduler {
-    /// Returns a new `Scheduler` with an empty queue.
+    /// Returns a new `Scheduler` with an empty queue for every core.
     fn new() -> Scheduler {
         Scheduler {
-            processes: VecDeque::new(),
-            current: None,
-            last_id: None
+            queues: (0..NCORES).map(|_| RunQueue::new()).collect(),
+            processes: BTreeMap::new(),
+            kernel_pid: None,
+            last_id: None,
+            last_queued: 0,
+            foreground: None,
         }
     }
 
-    /// Adds a process to the scheduler's queue and returns that process's ID if
-    /// a new process can be scheduled. The process ID is newly allocated for
-    /// the process and saved in its `trap_frame`. If no further processes can
-    /// be scheduled, returns `None`.
-    ///
-    /// If this is the first process added, it is marked as the current process.
-    /// It is the caller's responsibility to ensure that the first time `switch`
-    /// is called, that process is executing on the CPU.
-    fn add(&mut self, mut process: Process) -> Option<Id> {
-        let is_first_process = self.last_id.is_none();
-
-        let new_id = match self.last_id {
+    /// Allocates a new process or thread ID.
+    fn next_id(&mut self) -> Id {
+        let id = match self.last_id {
             Some(id) => id.wrapping_add(1),
             None => 0
         };
 
-        process.trap_frame.tpidr = new_id;
-        self.last_id = Some(new_id);
+        self.last_id = Some(id);
+        id
+    }
+
+    /// Creates a new process whose only thread is `thread`, queues the thread,
+    /// and returns the process's ID if a new process can be scheduled. The
+    /// thread shares the process's ID, which is saved in its `trap_frame`. If
+    /// no further processes can be scheduled, returns `None`.
+    ///
+    /// If this is the first thread added, it is marked as the current thread
+    /// of core 0. It is the caller's responsibility to ensure that the first
+    /// time `switch` is called, that thread is executing on the CPU.
+    fn add(&mut self, thread: Thread) -> Option<Id> {
+        let id = self.next_id();
+        self.processes.insert(id, Process::new(id));
+        self.enqueue(id, id, thread);
+        Some(id)
+    }
+
+    /// Adds `thread` to the existing process `pid`, queues it, and returns the
+    /// newly allocated thread ID. Returns `None` if there is no such process.
+    fn add_thread(&mut self, pid: Id, thread: Thread) -> Option<Id> {
+        if !self.processes.contains_key(&pid) {
+            return None;
+        }
+
+        let tid = self.next_id();
+        self.enqueue(pid, tid, thread);
+        Some(tid)
+    }
+
+    /// Queues `thread` as thread `tid` of process `pid` on the least loaded
+    /// online core.
+    fn enqueue(&mut self, pid: Id, tid: Id, mut thread: Thread) {
+        thread.pid = pid;
+        thread.trap_frame.tpidr = tid;
+        self.processes.get_mut(&pid).expect("process exists").live_threads += 1;
+
+        if tid == 0 {
+            self.last_queued = 0;
+            self.queues[0].current = Some(thread);
+            return;
+        }
+
+        let core = (0..NCORES)
+            .filter(|&core| core == 0 || smp::is_online(core))
+            .min_by_key(|&core| self.queues[core].load())
+            .unwrap_or(0);
+
+        self.last_queued = core;
+        self.queues[core].threads.push_back(thread);
+    }
+
+    /// Returns the thread currently running on `core`, if any.
+    fn current(&mut self, core: usize) -> Option<&mut Thread> {
+        self.queues[core].current.as_mut()
+    }
+
+    /// Returns a mutable reference to the thread `tid` wherever it is queued.
+    fn find(&mut self, tid: Id) -> Option<&mut Thread> {
+        for queue in self.queues.iter_mut() {
+            if let Some(ref mut thread) = queue.current {
+                if thread.tid() == tid {
+                    return Some(thread);
+                }
+            }
+
+            if let Some(thread) = queue.threads.iter_mut().find(|t| t.tid() == tid) {
+                return Some(thread);
+            }
+        }
+
+        None
+    }
+
+    /// Returns the exit status of thread `tid` of process `pid`. See
+    /// `GlobalScheduler::exit_status()`.
+    fn exit_status(&mut self, pid: Id, tid: Id) -> Option<ExitStatus> {
+        if let Some(thread) = self.find(tid).filter(|thread| thread.pid == pid) {
+            return Some(thread.exit_status.clone());
+        }
+
+        let status = self.processes.get_mut(&pid)?.exited_threads.remove(&tid)?;
+        Some(Arc::new(Mutex::new(Some(status))))
+    }
+
+    /// Forks the process of `core`'s current thread. See
+    /// `GlobalScheduler::fork()`.
+    fn fork(&mut self, core: usize, tf: &TrapFrame) -> Option<Id> {
+        let (parent_pid, mut child_thread) = {
+            let parent_thread = self.queues[core].current.as_ref()?;
+            (parent_thread.pid, parent_thread.duplicate(tf)?)
+        };
+
+        child_thread.trap_frame.x0 = 0;
+        child_thread.trap_frame.x7 = 0;
+
+        let pid = self.next_id();
+        let mut child = Process::new(pid);
+        {
+            let parent = self.processes.get(&parent_pid)?;
+            child.parent = Some(parent_pid);
+            child.image = parent.image.clone();
+            child.files = Arc::new(Mutex::new(parent.files.lock().clone()));
+            child.signals = parent.signals.fork();
+            parent.children.lock().insert(pid, child.exit_status.clone());
+        }
+
+        self.processes.insert(pid, child);
+        self.enqueue(pid, pid, child_thread);
+        Some(pid)
+    }
+
+    /// Replaces the program of the process of `core`'s current thread. See
+    /// `GlobalScheduler::exec()`.
+    fn exec(&mut self, core: usize, image: Image, tf: &mut TrapFrame) -> bool {
+        let thread = match self.queues[core].current.as_ref() {
+            Some(thread) => thread,
+            None => return false
+        };
+
+        let process = match self.processes.get_mut(&thread.pid) {
+            Some(process) => process,
+            None => return false
+        };
 
-        if is_first_process {
-            self.current = Some(process);
-        } else {
-            self.processes.push_back(process);
+        if process.live_threads > 1 {
+            return false;
         }
 
-        Some(new_id)
+        let image = Arc::new(image);
+
+        *tf = Default::default();
+        tf.elr = image.entry().as_u64();
+        tf.sp = thread.stack.top().as_u64();
+        tf.tpidr = thread.tid();
+        tf.x30 = thread_return as u64;
+
+        process.image = Some(image);
+        process.signals.reset_handlers();
+        true
     }
 
-    /// Sets the current process's state to `new_state`, finds the next process
-    /// to switch to, and performs the context switch on `tf` by saving `tf`
-    /// into the current process and restoring the next process's trap frame
-    /// into `tf`. If there is no current process, returns `None`. Otherwise,
-    /// returns `Some` of the process ID that was context switched into `tf`.
-    ///
-    /// This method blocks until there is a process to switch to, conserving
-    /// energy as much as possible in the interim.
-    fn switch(&mut self, new_state: State, tf: &mut TrapFrame) -> Option<Id> {
-        let mut current_replacement = None;
+    /// Publishes `status` as the exit status of process `pid` and removes it.
+    /// Its remaining threads are dropped the next time they are scheduled.
+    fn exit_process(&mut self, pid: Id, status: u64) {
+        if let Some(process) = self.processes.remove(&pid) {
+            *process.exit_status.lock() = Some(status);
+            COMPOSITOR.remove_process(pid);
 
-        let mut owned_process = mem::replace(&mut self.current, current_replacement);
+            if let Some(parent) = process.parent.and_then(|id| self.processes.get_mut(&id)) {
+                parent.signals.raise(Signal::Chld);
+            }
+
+            if self.foreground == Some(pid) {
+                self.foreground = process.parent;
+            }
+        }
+
+        if self.kernel_pid == Some(pid) {
+            self.kernel_pid = None;
+        }
+    }
 
-        if let Some(mut current_process) = owned_process {
-            current_process.state = new_state;
-            *(current_process.trap_frame) = *tf;
-            self.processes.push_back(current_process);
+    /// Sends `signal` to process `pid`. See `GlobalScheduler::kill()`. The
+    /// kernel process can't be signalled.
+    fn kill(&mut self, pid: Id, signal: Option<Signal>) -> bool {
+        if self.kernel_pid == Some(pid) {
+            return false;
         }
 
-        let ready_index = self.processes.iter_mut().position(|p| p.is_ready());
+        let action = match self.processes.get_mut(&pid) {
+            Some(process) => signal.and_then(|signal| process.signals.raise(signal)),
+            None => return false
+        };
+
+        if let (Some(Action::Terminate), Some(signal)) = (action, signal) {
+            self.exit_process(pid, signal.exit_status());
+        }
 
-        match ready_index {
-            Some(i) => {
-                let mut p = self.processes.remove(i).expect("processes index out of range");
-                *tf = *(p.trap_frame);
-                p.state = State::Running;
+        true
+    }
 
-                self.current = Some(p);
-                return Some(tf.tpidr);
+    /// Forces `signal` on the process of `core`'s current thread. See
+    /// `GlobalScheduler::fault()`.
+    fn fault(&mut self, core: usize, signal: Signal) {
+        let pid = match self.queues[core].current {
+            Some(ref thread) => thread.pid,
+            None => return
+        };
+
+        let action = self.processes.get_mut(&pid).and_then(|p| p.signals.force(signal));
+        if action == Some(Action::Terminate) {
+            self.exit_process(pid, signal.exit_status());
+        }
+    }
+
+    /// Delivers pending signals to `core`'s current thread, which is about to
+    /// resume with `tf`. See `GlobalScheduler::deliver_signals()`.
+    fn deliver_signals(&mut self, core: usize, tf: &mut TrapFrame) -> Delivery {
+        let pid = match self.queues[core].current {
+            Some(ref thread) => thread.pid,
+            None => return Delivery::Resume
+        };
+
+        let terminated_by = {
+            let process = match self.processes.get_mut(&pid) {
+                Some(process) => process,
+                None => {
+                    self.queues[core].current = None;
+                    return Delivery::Exited;
+                }
+            };
+
+            if process.signals.is_stopped() {
+                return Delivery::Stopped;
+            }
+
+            let thread = self.queues[core].current.as_ref().expect("current thread");
+            loop {
+                let (signal, disposition) = match process.signals.take() {
+                    Some(pending) => pending,
+                    None => return Delivery::Resume
+                };
+
+                match (disposition, signal.default_action()) {
+                    (Disposition::Handler { handler, restorer }, _) => {
+                        let blocked = process.signals.blocked();
+                        if !thread.push_signal_frame(tf, signal, handler, restorer, blocked) {
+                            break Signal::Segv;
+                        }
+
+                        process.signals.set_blocked(blocked | 1 << signal as u64);
+                        return Delivery::Resume;
+                    },
+                    (Disposition::Default, Action::Stop) => {
+                        process.signals.stop();
+                        return Delivery::Stopped;
+                    },
+                    (Disposition::Default, Action::Terminate) => break signal,
+                    _ => continue
+                }
+            }
+        };
+
+        self.queues[core].current = None;
+        self.exit_process(pid, terminated_by.exit_status());
+        Delivery::Exited
+    }
+
+    /// Sets the state of `core`'s current thread to `new_state`, saves `tf`
+    /// into it, and moves it to the back of `core`'s queue. Returns `false` if
+    /// the core had no current thread.
+    ///
+    /// If the thread's process has exited, the thread is dropped instead.
+    fn park(&mut self, core: usize, new_state: State, tf: &TrapFrame) -> bool {
+        match mem::replace(&mut self.queues[core].current, None) {
+            Some(mut current_thread) => {
+                if self.processes.contains_key(&current_thread.pid) {
+                    current_thread.state = new_state;
+                    *(current_thread.trap_frame) = *tf;
+                    self.queues[core].threads.push_back(current_thread);
+                }
+                true
             },
-            None => {
-                aarch64::wfi();
-                return None;
+            None => false
+        }
+    }
+
+    /// Removes `core`'s current thread, publishes its exit `status`, and
+    /// frees it. The status is kept for a later join. When the last thread of
+    /// a process exits, the process exits with the same status.
+    fn exit_current(&mut self, core: usize, status: u64) {
+        let thread = match mem::replace(&mut self.queues[core].current, None) {
+            Some(thread) => thread,
+            None => return
+        };
+
+        *thread.exit_status.lock() = Some(status);
+
+        let last_thread = match self.processes.get_mut(&thread.pid) {
+            Some(process) => {
+                process.live_threads -= 1;
+                // Nobody holds a handle to this status; keep it for a join.
+                if Arc::strong_count(&thread.exit_status) == 1 {
+                    process.exited_threads.insert(thread.tid(), status);
+                }
+                process.live_threads == 0
+            },
+            None => false
+        };
+
+        if last_thread {
+            self.exit_process(thread.pid, status);
+        }
+    }
+
+    /// Removes and returns a ready thread from `core`'s queue. If there is
+    /// none, a ready thread is stolen from the most loaded other core. Threads
+    /// of exited processes are dropped along the way.
+    fn next_ready(&mut self, core: usize) -> Option<Thread> {
+        let processes = &self.processes;
+        for queue in self.queues.iter_mut() {
+            queue.threads.retain(|t| processes.contains_key(&t.pid));
+        }
+
+        // Threads of stopped processes stay queued until they're continued.
+        let runnable = |t: &mut Thread| {
+            !processes[&t.pid].signals.is_stopped() && t.is_ready()
+        };
+
+        if let Some(i) = self.queues[core].threads.iter_mut().position(|t| runnable(t)) {
+            return self.queues[core].threads.remove(i);
+        }
+
+        let mut victims: Vec<usize> = (0..NCORES).filter(|&c| c != core).collect();
+        victims.sort_by_key(|&c| usize::max_value() - self.queues[c].threads.len());
+
+        for victim in victims {
+            let queue = &mut self.queues[victim].threads;
+            if let Some(i) = queue.iter_mut().position(|t| runnable(t)) {
+                return queue.remove(i);
             }
         }
+
+        None
+    }
+
+    /// Finds the next thread to run on `core` and performs the context switch
+    /// on `tf` by restoring that thread's trap frame into `tf`. Threads are
+    /// taken from `core`'s own queue first. If none of them is ready, a ready
+    /// thread is taken from another core's queue.
+    ///
+    /// `core` must not have a current thread; see `park()`. Returns `Some` of
+    /// the thread ID that was context switched into `tf`, or `None` if no
+    /// thread is ready.
+    fn switch(&mut self, core: usize, tf: &mut TrapFrame) -> Option<Id> {
+        let mut thread = self.next_ready(core)?;
+        *tf = *(thread.trap_frame);
+        thread.state = State::Running;
+
+        self.queues[core].current = Some(thread);
+        Some(tf.tpidr)
+    }
+
+    /// Switches `core`, which has nothing to run, to its idle loop by
+    /// restoring the idle context into `tf`.
+    fn idle(&mut self, core: usize, tf: &mut TrapFrame) {
+        let queue = &mut self.queues[core];
+        if queue.idle.is_none() {
+            queue.idle = Thread::kernel(idle_loop, 0);
+        }
+
+        let idle = queue.idle.as_ref().expect("not enough memory for an idle stack");
+        *tf = *idle.trap_frame;
     }
 }
@@ -0,0 +1,281 @@
+use traps::TrapFrame;
+
+/// The number of signals a process can have pending or blocked at once.
+pub const NSIG: usize = 32;
+
+/// The raw handler value that restores a signal's default action.
+pub const SIG_DFL: u64 = 0;
+
+/// The raw handler value that ignores a signal.
+pub const SIG_IGN: u64 = 1;
+
+/// A signal, numbered as on Linux.
+#[repr(u64)]
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Signal {
+    Hup = 1,
+    Int = 2,
+    Quit = 3,
+    Ill = 4,
+    Trap = 5,
+    Abrt = 6,
+    Bus = 7,
+    Fpe = 8,
+    Kill = 9,
+    Usr1 = 10,
+    Segv = 11,
+    Usr2 = 12,
+    Pipe = 13,
+    Alrm = 14,
+    Term = 15,
+    Chld = 17,
+    Cont = 18,
+    Stop = 19,
+    Tstp = 20,
+}
+
+/// What happens to a process when a signal is delivered without a handler.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Action {
+    Terminate,
+    Ignore,
+    Stop,
+    Continue,
+}
+
+impl Signal {
+    /// Returns the signal numbered `number`, if there is one.
+    pub fn from_number(number: u64) -> Option<Signal> {
+        use self::Signal::*;
+
+        Some(match number {
+            1 => Hup,
+            2 => Int,
+            3 => Quit,
+            4 => Ill,
+            5 => Trap,
+            6 => Abrt,
+            7 => Bus,
+            8 => Fpe,
+            9 => Kill,
+            10 => Usr1,
+            11 => Segv,
+            12 => Usr2,
+            13 => Pipe,
+            14 => Alrm,
+            15 => Term,
+            17 => Chld,
+            18 => Cont,
+            19 => Stop,
+            20 => Tstp,
+            _ => return None
+        })
+    }
+
+    /// The action taken when the signal's disposition is `Default`.
+    pub fn default_action(self) -> Action {
+        match self {
+            Signal::Chld => Action::Ignore,
+            Signal::Cont => Action::Continue,
+            Signal::Stop | Signal::Tstp => Action::Stop,
+            _ => Action::Terminate
+        }
+    }
+
+    /// Returns `true` if the signal can be handled, ignored, or blocked. This
+    /// is the case for all signals but `Kill` and `Stop`.
+    pub fn can_be_caught(self) -> bool {
+        self != Signal::Kill && self != Signal::Stop
+    }
+
+    /// The exit status of a process terminated by this signal.
+    pub fn exit_status(self) -> u64 {
+        128 + self as u64
+    }
+
+    fn bit(self) -> u64 {
+        1 << self as u64
+    }
+}
+
+/// How a process responds to a signal.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Disposition {
+    /// Take the signal's default action.
+    Default,
+    /// Discard the signal.
+    Ignore,
+    /// Call `handler(signal, frame)` on the signal frame. The handler returns
+    /// to `restorer`, which must call `sigreturn` with the stack pointer still
+    /// pointing at the frame.
+    Handler { handler: u64, restorer: u64 },
+}
+
+impl Disposition {
+    /// Returns the disposition for the raw `handler` value passed to
+    /// `sigaction`.
+    pub fn from_raw(handler: u64, restorer: u64) -> Disposition {
+        match handler {
+            SIG_DFL => Disposition::Default,
+            SIG_IGN => Disposition::Ignore,
+            handler => Disposition::Handler { handler, restorer }
+        }
+    }
+
+    /// Returns the raw handler value `sigaction` reports for this disposition.
+    pub fn to_raw(&self) -> u64 {
+        match *self {
+            Disposition::Default => SIG_DFL,
+            Disposition::Ignore => SIG_IGN,
+            Disposition::Handler { handler, .. } => handler
+        }
+    }
+}
+
+/// The frame pushed onto the user stack when a handler is invoked. It holds
+/// everything `sigreturn` needs to resume the interrupted code.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct SignalFrame {
+    /// The interrupted trap frame.
+    pub trap_frame: TrapFrame,
+    /// The blocked set to restore.
+    pub blocked: u64,
+    /// The signal being handled.
+    pub signal: u64,
+}
+
+/// The signal state of a process, shared by all of its threads.
+#[derive(Debug)]
+pub struct Signals {
+    pending: u64,
+    blocked: u64,
+    dispositions: [Disposition; NSIG],
+    stopped: bool,
+}
+
+impl Signals {
+    /// Returns a signal state with nothing pending or blocked and every
+    /// disposition set to `Default`.
+    pub fn new() -> Signals {
+        Signals {
+            pending: 0,
+            blocked: 0,
+            dispositions: [Disposition::Default; NSIG],
+            stopped: false,
+        }
+    }
+
+    /// Returns the signal state a forked child inherits: the same dispositions
+    /// and blocked set, with nothing pending.
+    pub fn fork(&self) -> Signals {
+        Signals {
+            pending: 0,
+            blocked: self.blocked,
+            dispositions: self.dispositions,
+            stopped: false,
+        }
+    }
+
+    /// Resets every handled signal to `Default`, as `exec` does. Ignored
+    /// signals stay ignored.
+    pub fn reset_handlers(&mut self) {
+        for disposition in self.dispositions.iter_mut() {
+            if let Disposition::Handler { .. } = *disposition {
+                *disposition = Disposition::Default;
+            }
+        }
+    }
+
+    /// Returns `true` while the process is stopped.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Sets the disposition of `signal`, returning the previous one. Returns
+    /// `None` if `signal` can't be caught.
+    pub fn set_disposition(&mut self, signal: Signal, disposition: Disposition) -> Option<Disposition> {
+        if !signal.can_be_caught() {
+            return None;
+        }
+
+        let previous = self.dispositions[signal as usize];
+        self.dispositions[signal as usize] = disposition;
+        Some(previous)
+    }
+
+    /// Blocks or unblocks every signal in `blocked`. `Kill` and `Stop` can't
+    /// be blocked.
+    pub fn set_blocked(&mut self, blocked: u64) {
+        self.blocked = blocked & !(Signal::Kill.bit() | Signal::Stop.bit());
+    }
+
+    /// The currently blocked set.
+    pub fn blocked(&self) -> u64 {
+        self.blocked
+    }
+
+    /// Raises `signal` for a fault the process can't continue past. If the
+    /// signal is blocked or ignored, its disposition is reset to `Default` so
+    /// the process is terminated instead of faulting again.
+    pub fn force(&mut self, signal: Signal) -> Option<Action> {
+        let disposition = self.dispositions[signal as usize];
+        if self.blocked & signal.bit() != 0 || disposition == Disposition::Ignore {
+            self.blocked &= !signal.bit();
+            self.dispositions[signal as usize] = Disposition::Default;
+        }
+
+        self.raise(signal)
+    }
+
+    /// Raises `signal`. Returns the action that must be taken right away, if
+    /// any: `Terminate` or `Stop` for an unblocked signal with the default
+    /// action, and `Continue` for `Cont`. Otherwise the signal is discarded if
+    /// it is ignored, or left pending for `take()`.
+    pub fn raise(&mut self, signal: Signal) -> Option<Action> {
+        let disposition = self.dispositions[signal as usize];
+        let unblocked = self.blocked & signal.bit() == 0;
+
+        if signal == Signal::Cont {
+            self.stopped = false;
+            self.pending &= !(Signal::Stop.bit() | Signal::Tstp.bit());
+            if let Disposition::Handler { .. } = disposition {
+                self.pending |= signal.bit();
+            }
+            return Some(Action::Continue);
+        }
+
+        match (disposition, signal.default_action()) {
+            (Disposition::Ignore, _) | (Disposition::Default, Action::Ignore) => None,
+            (Disposition::Default, Action::Stop) if unblocked => {
+                self.pending &= !Signal::Cont.bit();
+                self.stopped = true;
+                Some(Action::Stop)
+            },
+            (Disposition::Default, Action::Terminate) if unblocked => Some(Action::Terminate),
+            _ => {
+                self.pending |= signal.bit();
+                None
+            }
+        }
+    }
+
+    /// Removes the lowest-numbered pending, unblocked signal and returns it
+    /// along with its disposition.
+    pub fn take(&mut self) -> Option<(Signal, Disposition)> {
+        let deliverable = self.pending & !self.blocked;
+        if deliverable == 0 {
+            return None;
+        }
+
+        let number = deliverable.trailing_zeros() as u64;
+        self.pending &= !(1 << number);
+        let signal = Signal::from_number(number)?;
+        Some((signal, self.dispositions[signal as usize]))
+    }
+
+    /// Marks the process as stopped.
+    pub fn stop(&mut self) {
+        self.stopped = true;
+    }
+}
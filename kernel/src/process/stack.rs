@@ -51,6 +51,18 @@ impl Stack {
     pub fn bottom(&self) -> PhysicalAddr {
         unsafe { self.as_mut_ptr().into() }
     }
+
+    /// Returns `true` if `addr` lies within this stack.
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.bottom().as_u64() && addr <= self.top().as_u64()
+    }
+
+    /// Overwrites the contents of this stack with the contents of `other`.
+    pub fn copy_from(&mut self, other: &Stack) {
+        unsafe {
+            self.as_mut_ptr().copy_from_nonoverlapping(other.as_mut_ptr(), Self::SIZE);
+        }
+    }
 }
 
 impl Drop for Stack {
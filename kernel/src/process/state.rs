@@ -1,21 +1,25 @@
 use std::fmt;
 
-use process::Process;
+use process::Thread;
 
-/// Type of a function used to determine if a process is ready to be scheduled
-/// again. The scheduler calls this function when it is the process's turn to
-/// execute. If the function returns `true`, the process is scheduled. If it
-/// returns `false`, the process is not scheduled, and this function will be
+/// Type of a function used to determine if a thread is ready to be scheduled
+/// again. The scheduler calls this function when it is the thread's turn to
+/// execute. If the function returns `true`, the thread is scheduled. If it
+/// returns `false`, the thread is not scheduled, and this function will be
 /// called on the next time slice.
-pub type EventPollFn = Box<FnMut(&mut Process) -> bool + Send>;
+///
+/// The function is called with the scheduler locked, so it may update the
+/// thread's trap frame (to set a system call's return values, for instance)
+/// but must not call into the scheduler.
+pub type EventPollFn = Box<FnMut(&mut Thread) -> bool + Send>;
 
-/// The scheduling state of a process.
+/// The scheduling state of a thread.
 pub enum State {
-    /// The process is ready to be scheduled.
+    /// The thread is ready to be scheduled.
     Ready,
-    /// The process is waiting on an event to occur before it can be scheduled.
+    /// The thread is waiting on an event to occur before it can be scheduled.
     Waiting(EventPollFn),
-    /// The process is currently running.
+    /// The thread is currently running.
     Running,
 }
 
@@ -0,0 +1,201 @@
+use std::mem;
+use std::ptr;
+use std::sync::Arc;
+
+use pi::mutex::Mutex;
+use traps::TrapFrame;
+use process::{State, Stack, Id, Signal, SignalFrame};
+use syscalls::thread_return;
+
+/// SPSR for a thread running in EL0 with all interrupts unmasked.
+const SPSR_EL0: u64 = 0b0000;
+
+/// SPSR for a thread running in EL1 on `SP_EL0` with all interrupts unmasked.
+/// Using `SP_EL0` keeps exceptions taken by the thread on the core's own EL1
+/// stack, so the thread's stack can be freed from the scheduler.
+const SPSR_EL1T: u64 = 0b0100;
+
+/// The `M[4:0]` field of the SPSR, selecting the exception level and stack
+/// pointer to return to.
+const SPSR_MODE: u64 = 0b1_1111;
+
+/// The exit status of a thread, shared with anyone waiting to join it. `None`
+/// until the thread exits.
+pub type ExitStatus = Arc<Mutex<Option<u64>>>;
+
+/// A schedulable thread of execution belonging to a process.
+#[repr(align(16))]
+#[derive(Debug)]
+pub struct Thread {
+    /// The saved trap frame of a thread. Its `tpidr` holds the thread ID.
+    pub trap_frame: Box<TrapFrame>,
+    /// The memory allocation used for the thread's stack.
+    pub stack: Stack,
+    /// The scheduling state of the thread.
+    pub state: State,
+    /// The ID of the process this thread belongs to.
+    pub pid: Id,
+    /// Set when the thread exits.
+    pub exit_status: ExitStatus,
+}
+
+impl Thread {
+    /// Creates a new thread with a zeroed `TrapFrame` (the default), a zeroed
+    /// stack of the default size, and a state of `Ready`.
+    ///
+    /// If enough memory could not be allocated to start the thread, returns
+    /// `None`. Otherwise returns `Some` of the new `Thread`.
+    pub fn new() -> Option<Thread> {
+        match Stack::new() {
+            Some(stack) => Some(Thread {
+                stack,
+                state: State::Ready,
+                trap_frame: Default::default(),
+                pid: 0,
+                exit_status: Arc::new(Mutex::new(None)),
+            }),
+            None => None
+        }
+    }
+
+    /// Creates a new EL0 thread that calls `entry(arg)` on its own stack and
+    /// exits with `entry`'s return value when it returns.
+    pub fn user(entry: u64, arg: u64) -> Option<Thread> {
+        Thread::with_entry(entry, arg, SPSR_EL0)
+    }
+
+    /// Creates a new EL1 thread that calls `entry(arg)` on its own stack and
+    /// exits with `entry`'s return value when it returns.
+    pub fn kernel(entry: extern "C" fn(u64) -> u64, arg: u64) -> Option<Thread> {
+        Thread::with_entry(entry as u64, arg, SPSR_EL1T)
+    }
+
+    fn with_entry(entry: u64, arg: u64, spsr: u64) -> Option<Thread> {
+        let mut thread = Thread::new()?;
+        thread.trap_frame.elr = entry;
+        thread.trap_frame.spsr = spsr;
+        thread.trap_frame.sp = thread.stack.top().as_u64();
+        thread.trap_frame.x0 = arg;
+        thread.trap_frame.x30 = thread_return as u64;
+        Some(thread)
+    }
+
+    /// Creates a copy of this thread, whose current trap frame is `tf`, for a
+    /// forked process.
+    ///
+    /// Until paging exists the stack is copied eagerly to a new address, so
+    /// every pointer into the stack is relocated into the copy: `sp`, every
+    /// general purpose register, and every word of the live part of the
+    /// stack, which holds saved frame pointers and spilled registers, whose
+    /// value lies within the original stack. A plain integer that happens to
+    /// look like such an address is relocated too.
+    pub fn duplicate(&self, tf: &TrapFrame) -> Option<Thread> {
+        let mut child = Thread::new()?;
+        child.stack.copy_from(&self.stack);
+        *child.trap_frame = *tf;
+
+        let delta = child.stack.bottom().as_u64().wrapping_sub(self.stack.bottom().as_u64());
+        let relocate = |addr: u64| {
+            if self.stack.contains(addr) { addr.wrapping_add(delta) } else { addr }
+        };
+
+        child.trap_frame.sp = relocate(tf.sp);
+        for n in 0..31 {
+            let register = child.trap_frame.register_mut(n).expect("register");
+            *register = relocate(*register);
+        }
+
+        let top = child.stack.top().as_u64();
+        let mut addr = child.trap_frame.sp & !0b111;
+        while child.stack.contains(addr) && addr + 8 <= top {
+            let word = unsafe { &mut *(addr as *mut u64) };
+            *word = relocate(*word);
+            addr += 8;
+        }
+
+        Some(child)
+    }
+
+    /// Pushes a `SignalFrame` saving `tf` and `blocked` onto this thread's
+    /// stack and rewrites `tf` to call `handler(signal, frame)` on it,
+    /// returning to `restorer`. Returns `false`, leaving `tf` untouched, if
+    /// the frame doesn't fit on the stack.
+    pub fn push_signal_frame(&self, tf: &mut TrapFrame, signal: Signal,
+                             handler: u64, restorer: u64, blocked: u64) -> bool {
+        let size = mem::size_of::<SignalFrame>() as u64;
+        let frame = tf.sp.wrapping_sub(size) & !0xf;
+        if !self.stack.contains(tf.sp) || !self.stack.contains(frame) || frame > tf.sp {
+            return false;
+        }
+
+        unsafe {
+            ptr::write(frame as *mut SignalFrame, SignalFrame {
+                trap_frame: *tf,
+                blocked,
+                signal: signal as u64,
+            });
+        }
+
+        tf.elr = handler;
+        tf.sp = frame;
+        tf.x0 = signal as u64;
+        tf.x1 = frame;
+        tf.x30 = restorer;
+        true
+    }
+
+    /// Restores `tf` from the `SignalFrame` at `tf.sp`, as pushed by
+    /// `push_signal_frame()`, and returns the saved blocked set. The restored
+    /// frame always returns to EL0 as this thread. Returns `None`, leaving
+    /// `tf` untouched, if there is no room for a frame at `tf.sp`.
+    pub fn pop_signal_frame(&self, tf: &mut TrapFrame) -> Option<u64> {
+        let size = mem::size_of::<SignalFrame>() as u64;
+        if !self.stack.contains(tf.sp) || !self.stack.contains(tf.sp + size) {
+            return None;
+        }
+
+        let frame = unsafe { ptr::read(tf.sp as *const SignalFrame) };
+        *tf = frame.trap_frame;
+        tf.tpidr = self.tid();
+        tf.spsr &= !SPSR_MODE;
+        Some(frame.blocked)
+    }
+
+    /// Returns the ID of this thread.
+    pub fn tid(&self) -> Id {
+        self.trap_frame.tpidr
+    }
+
+    /// Returns `true` if this thread is ready to be scheduled.
+    ///
+    /// This functions returns `true` only if one of the following holds:
+    ///
+    ///   * The state is currently `Ready`.
+    ///
+    ///   * An event being waited for has arrived.
+    ///
+    ///     If the thread is currently waiting, the corresponding event
+    ///     function is polled to determine if the event being waiting for has
+    ///     occured. If it has, the state is switched to `Ready` and this
+    ///     function returns `true`.
+    ///
+    /// Returns `false` in all other cases.
+    pub fn is_ready(&mut self) -> bool {
+        let state_copy = State::Ready;
+        let mut owned_state = mem::replace(&mut self.state, state_copy);
+
+        let ready = match owned_state {
+            State::Ready => true,
+            State::Waiting(ref mut poll_function) => {
+                poll_function(self)
+            },
+            _ => false
+        };
+
+        if !ready {
+            mem::replace(&mut self.state, owned_state);
+        }
+
+        ready
+    }
+}
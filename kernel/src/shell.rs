@@ -1,23 +1,33 @@
+use cpufreq::{self, Governor};
 use draw::draw_loop;
-use syscalls::sleep;
+use font;
+use syscalls::{self, sleep, Timespec, CLOCK_REALTIME};
 use fat32::traits::{self, Dir, Entry};
 use fs::FileSystem;
-use pi::common::{
-    ARM_POWER_MANAGEMENT_FULL_RESET, ARM_POWER_MANAGEMENT_PASSWD, ARM_POWER_MANAGEMENT_RSTC,
-    ARM_POWER_MANAGEMENT_WDOG,
-};
-use pi::console::{kprint, kprintln, CONSOLE};
+use gpio::{self, Wiring};
+use pi::console::{self, kprint, kprintln};
+use pi::gpio::{Gpio, Pull, NUM_PINS};
+use pi::graphics::{Canvas, Image};
+use pi::propertytag::{self, Clock, Voltage};
+use pi::pwm::audio::{self, Audio};
+use pi::pwm::wav::Wav;
 use pi::raccoon::RACCOON_STRING;
+use pi::rtc::DateTime;
 use pi::screen::SCREEN;
+use pi::watchdog;
 use stack_vec::StackVec;
 use std::io::Read;
 use std::str;
-use volatile::prelude::*;
-use volatile::WriteVolatile;
+use std::time::Duration;
+use IRQS;
 
 pub static FILE_SYSTEM: FileSystem = FileSystem::uninitialized();
 const BOOTLOADER_START_ADDR: usize = 0x4000000;
 
+/// The pitch and length of `beep`'s tone.
+const BEEP_HZ: u32 = 880;
+const BEEP_MS: u64 = 200;
+
 /// Error type for `Command` parse failures.
 #[derive(Debug)]
 enum Error {
@@ -70,8 +80,7 @@ impl<'a> Command<'a> {
                 draw_loop();
             }
             "raccoon" => {
-                SCREEN.lock().draw_string(&RACCOON_STRING);
-                SCREEN.lock().draw_char(0x0d);
+                kprintln!("{}", RACCOON_STRING);
             }
             "cat" => {
                 let mut iter = self.args.iter();
@@ -82,12 +91,8 @@ impl<'a> Command<'a> {
                             let mut buf = vec![0; 100];
                             match file.read(&mut buf[..]) {
                                 Ok(_bytes_read) => {
-                                    SCREEN.lock().draw_string(&file.metadata.name);
-                                    SCREEN.lock().draw_char(0x0d);
-                                    SCREEN
-                                        .lock()
-                                        .draw_string(&String::from_utf8_lossy(&buf[..]));
-                                    SCREEN.lock().draw_char(0x0d);
+                                    kprintln!("{}", &file.metadata.name);
+                                    kprintln!("{}", String::from_utf8_lossy(&buf[..]));
                                 }
                                 Err(error) => {
                                     kprintln!(
@@ -109,8 +114,7 @@ impl<'a> Command<'a> {
                     match traits::FileSystem::open_dir(&FILE_SYSTEM, arg) {
                         Ok(dir) => {
                             for entry in dir.entries().expect("iter") {
-                                SCREEN.lock().draw_string(&entry.name());
-                                SCREEN.lock().draw_char(0x0d);
+                                kprintln!("{}", entry.name());
                             }
                         }
                         Err(_) => {}
@@ -118,12 +122,20 @@ impl<'a> Command<'a> {
                 }
             }
             "clear" => {
-                SCREEN.lock().clear();
+                // Clears the serial terminal as well as the screen.
+                kprint!("\x1b[2J\x1b[H");
             }
             "print" => {
                 let mut iter = self.args.iter();
                 iter.next();
                 if let Some(scale) = iter.next() {
+                    let scale = match scale.parse::<usize>() {
+                        Ok(0) => {
+                            kprintln!("usage: print SCALE MESSAGE, with SCALE at least 1");
+                            return false;
+                        }
+                        scale => scale.unwrap_or(1),
+                    };
 
                     let message: String = iter
                         .fold(String::from(""), |mut acc, arg| {
@@ -133,12 +145,73 @@ impl<'a> Command<'a> {
                         });
 
                     SCREEN
-                        .lock()
-                        .draw_string_scale(&message, scale.parse::<usize>().unwrap_or(1));
+                        .lock_irq()
+                        .draw_string_scale(&message, scale);
+                }
+            }
+            "font" => {
+                let scale = match self.args.get(2).map(|scale| scale.parse::<usize>()) {
+                    Some(Ok(scale)) if scale > 0 => scale,
+                    Some(_) => {
+                        kprintln!("usage: font [builtin|path.psf] [scale]");
+                        return false;
+                    }
+                    None => 1,
+                };
 
-                    SCREEN
-                        .lock()
-                        .draw_char_scale(0x0d, scale.parse::<usize>().unwrap_or(1));
+                let size = match self.args.get(1) {
+                    None | Some(&"builtin") => Ok(font::builtin(scale)),
+                    Some(path) => font::load(path, scale),
+                };
+                match size {
+                    Ok((columns, rows)) => kprintln!("screen is {}x{}", columns, rows),
+                    Err(e) => kprintln!("font: {:?}", e),
+                }
+            }
+            "view" => {
+                let position = match (self.args.get(2), self.args.get(3)) {
+                    (None, None) => Some((0, 0)),
+                    (Some(x), Some(y)) => x.parse::<i32>().ok()
+                        .and_then(|x| y.parse::<i32>().ok().map(|y| (x, y))),
+                    _ => None,
+                };
+                let (path, (x, y)) = match (self.args.get(1), position) {
+                    (Some(path), Some(position)) if self.args.len() <= 4 => (path, position),
+                    _ => {
+                        kprintln!("usage: view <image.bmp|image.qoi> [x y]");
+                        return false;
+                    }
+                };
+
+                let mut contents = vec![];
+                let read = traits::FileSystem::open_file(&FILE_SYSTEM, path)
+                    .and_then(|mut file| file.read_to_end(&mut contents));
+                if let Err(e) = read {
+                    kprintln!("view: {:?}", e);
+                    return false;
+                }
+                let image = match Image::decode(&contents) {
+                    Ok(image) => image,
+                    Err(e) => {
+                        kprintln!("view: {:?}", e);
+                        return false;
+                    }
+                };
+                // Printing locks the screen, so let it go first.
+                let drawn = {
+                    let mut screen = SCREEN.lock_irq();
+                    let drawn = match screen.framebuffer() {
+                        Some(framebuffer) => {
+                            Canvas::new(framebuffer).blit(&image, x, y);
+                            true
+                        }
+                        None => false,
+                    };
+                    screen.present();
+                    drawn
+                };
+                if !drawn {
+                    kprintln!("view: no display");
                 }
             }
             "sleep" => {
@@ -148,17 +221,234 @@ impl<'a> Command<'a> {
 
                 sleep(ms);
             }
+            "beep" => {
+                let hz = self.args.get(1).map_or(Ok(BEEP_HZ), |hz| hz.parse::<u32>());
+                let ms = self.args.get(2).map_or(Ok(BEEP_MS), |ms| ms.parse::<u64>());
+                let (hz, ms) = match (hz, ms) {
+                    (Ok(hz), Ok(ms)) if self.args.len() <= 3 => (hz, ms),
+                    _ => {
+                        kprintln!("usage: beep [HZ [MS]]");
+                        return false;
+                    }
+                };
+
+                let result = Audio::new(audio::DEFAULT_SAMPLE_RATE)
+                    .and_then(|mut audio| audio.beep(hz, Duration::from_millis(ms)));
+                if let Err(e) = result {
+                    kprintln!("beep: {:?}", e);
+                }
+            }
+            "play" => {
+                let path = match self.args.get(1) {
+                    Some(path) if self.args.len() == 2 => path,
+                    _ => {
+                        kprintln!("usage: play <file.wav>");
+                        return false;
+                    }
+                };
+
+                let mut contents = vec![];
+                let read = traits::FileSystem::open_file(&FILE_SYSTEM, path)
+                    .and_then(|mut file| file.read_to_end(&mut contents));
+                if let Err(e) = read {
+                    kprintln!("play: {:?}", e);
+                    return false;
+                }
+                let wav = match Wav::parse(&contents) {
+                    Ok(wav) => wav,
+                    Err(e) => {
+                        kprintln!("play: {:?}", e);
+                        return false;
+                    }
+                };
+
+                let duration = wav.duration();
+                kprintln!("{}: {} Hz, {} bit, {} channel(s), {}.{}s", path, wav.sample_rate(),
+                          wav.bits_per_sample(), wav.channels(), duration.as_secs(),
+                          duration.subsec_nanos() / 100_000_000);
+                let result = Audio::new(wav.sample_rate())
+                    .and_then(|mut audio| audio.play(wav.frames()));
+                if let Err(e) = result {
+                    kprintln!("play: {:?}", e);
+                }
+            }
+            "irqstat" => {
+                kprintln!("{:<10} {:>10} {:>10}  {}", "IRQ", "COUNT", "SPURIOUS", "HANDLERS");
+                for stat in IRQS.stats() {
+                    kprintln!("{:<10} {:>10} {:>10}  {}", stat.irq.to_string(), stat.count,
+                              stat.spurious, stat.handlers.join(", "));
+                }
+                kprintln!("spurious IRQ exceptions: {}", IRQS.spurious_count());
+            }
+            "cpufreq" => {
+                let usage = "usage: cpufreq [ondemand | performance | powersave | set MHZ \
+                             | throttle CELSIUS]";
+                let result = match (self.args.len(), self.args.get(1).cloned()) {
+                    (1, _) => Ok(()),
+                    (2, Some(name)) => match Governor::from_name(name) {
+                        Some(governor) => cpufreq::set_governor(governor).map(|_| ()),
+                        None => {
+                            kprintln!("{}", usage);
+                            return false;
+                        }
+                    },
+                    (3, Some("set")) => match parse_mhz(self.args[2]) {
+                        Some(rate) => cpufreq::set_clock_rate(Clock::Arm, rate).map(|_| ()),
+                        None => {
+                            kprintln!("{}", usage);
+                            return false;
+                        }
+                    },
+                    (3, Some("throttle")) => match self.args[2].parse::<u32>() {
+                        Ok(celsius) if celsius < 100 => {
+                            cpufreq::set_throttle_temperature(celsius * 1000)
+                        }
+                        _ => {
+                            kprintln!("{}", usage);
+                            return false;
+                        }
+                    },
+                    _ => {
+                        kprintln!("{}", usage);
+                        return false;
+                    }
+                };
+                if let Err(e) = result {
+                    kprintln!("cpufreq: {:?}", e);
+                    return false;
+                }
+
+                match cpufreq::status() {
+                    Some(status) => {
+                        kprintln!("governor:    {}", status.governor);
+                        kprintln!("arm:         {}MHz ({}-{}MHz), load {}%", mhz(status.rate),
+                                  mhz(status.min), mhz(status.max), status.load);
+                        kprintln!("core:        {}MHz", mhz(status.core_clock));
+                        kprintln!("temperature: {} (throttling at {})",
+                                  celsius(status.temperature),
+                                  celsius(status.throttle_temperature));
+                        if status.is_throttled() {
+                            kprintln!("throttled:   at most {}MHz", mhz(status.ceiling));
+                        }
+                    }
+                    None => kprintln!("cpufreq: the governor isn't running"),
+                }
+            }
+            "clock" => {
+                if self.args.len() == 3 {
+                    let (clock, rate) = match (Clock::from_name(self.args[1]),
+                                               parse_mhz(self.args[2])) {
+                        (Some(clock), Some(rate)) => (clock, rate),
+                        _ => {
+                            kprintln!("usage: clock [NAME MHZ]");
+                            return false;
+                        }
+                    };
+                    if let Err(e) = cpufreq::set_clock_rate(clock, rate) {
+                        kprintln!("clock: {:?}", e);
+                        return false;
+                    }
+                } else if self.args.len() != 1 {
+                    kprintln!("usage: clock [NAME MHZ]");
+                    return false;
+                }
+
+                for clock in Clock::ALL.iter().cloned() {
+                    let limits = propertytag::min_clock_rate(clock)
+                        .and_then(|min| Ok((min, propertytag::max_clock_rate(clock)?)));
+                    match (propertytag::clock_rate(clock), limits) {
+                        (Ok(rate), Ok((min, max))) => {
+                            kprintln!("{:<6} {:>5}MHz ({}-{}MHz)", clock.name(), mhz(rate),
+                                      mhz(min), mhz(max));
+                        }
+                        (Ok(rate), Err(_)) => kprintln!("{:<6} {:>5}MHz", clock.name(), mhz(rate)),
+                        (Err(_), _) => {}
+                    }
+                }
+                if let Ok(microvolts) = propertytag::voltage(Voltage::Core) {
+                    kprintln!("core voltage: {}.{:04}V", microvolts / 1000000,
+                              microvolts % 1000000 / 100);
+                }
+                match propertytag::temperature() {
+                    Ok(temperature) => kprintln!("temperature: {}", celsius(temperature)),
+                    Err(e) => kprintln!("temperature: {:?}", e),
+                }
+            }
+            "gpio" => {
+                let usage = "usage: gpio PIN [in | high | low | up | down | off | button \
+                             | unwatch]";
+                let pin = match self.args.get(1).map(|pin| pin.parse::<u8>()) {
+                    Some(Ok(pin)) if pin < NUM_PINS && self.args.len() <= 3 => pin,
+                    _ => {
+                        kprintln!("{}", usage);
+                        return false;
+                    }
+                };
+                if gpio::is_watched(pin) && self.args.get(2) != Some(&"unwatch") {
+                    kprintln!("gpio: pin {} is watched", pin);
+                    return false;
+                }
+
+                match self.args.get(2).cloned().unwrap_or("in") {
+                    "in" => {
+                        let level = Gpio::new(pin).into_input().level();
+                        kprintln!("pin {}: {}", pin, if level { "high" } else { "low" });
+                    }
+                    "high" => Gpio::new(pin).into_output().set(),
+                    "low" => Gpio::new(pin).into_output().clear(),
+                    "up" => Gpio::new(pin).into_input().set_pull(Pull::Up),
+                    "down" => Gpio::new(pin).into_input().set_pull(Pull::Down),
+                    "off" => Gpio::new(pin).into_input().set_pull(Pull::Off),
+                    "button" => {
+                        let input = Gpio::new(pin).into_input();
+                        let debounce = Duration::from_millis(20);
+                        let report = move |pressed: bool| {
+                            let state = if pressed { "pressed" } else { "released" };
+                            kprintln!("pin {}: {}", pin, state);
+                        };
+                        if gpio::watch_button(input, Wiring::ToGround, debounce, report).is_err() {
+                            kprintln!("gpio: pin {} is watched", pin);
+                        }
+                    }
+                    "unwatch" => {
+                        if gpio::unwatch(pin).is_none() {
+                            kprintln!("gpio: pin {} isn't watched", pin);
+                        }
+                    }
+                    _ => kprintln!("{}", usage),
+                }
+            }
+            "date" => {
+                if self.args.len() > 1 {
+                    let text = self.args[1..].join(" ");
+                    let datetime = match text.parse::<DateTime>() {
+                        Ok(datetime) => datetime,
+                        Err(_) => {
+                            kprintln!("usage: date [YYYY-MM-DD HH:MM:SS]");
+                            return false;
+                        }
+                    };
+                    let time = Timespec { secs: datetime.to_unix(), nanos: 0 };
+                    if let Err(e) = syscalls::clock_settime(CLOCK_REALTIME, time) {
+                        kprintln!("date: {}", e);
+                        return false;
+                    }
+                }
+
+                match syscalls::clock_gettime(CLOCK_REALTIME) {
+                    Ok(time) => kprintln!("{} UTC", DateTime::from_unix(time.secs)),
+                    Err(e) => kprintln!("date: {}", e),
+                }
+            }
             "break" => unsafe {
                 asm!("brk 2" :::: "volatile");
             }
-            "reboot" => unsafe {
-                let watchdog_register: &mut WriteVolatile<u32> =
-                    &mut *(ARM_POWER_MANAGEMENT_WDOG as *mut WriteVolatile<u32>);
-                let reset_register: &mut WriteVolatile<u32> =
-                    &mut *(ARM_POWER_MANAGEMENT_RSTC as *mut WriteVolatile<u32>);
-                watchdog_register.write(ARM_POWER_MANAGEMENT_PASSWD | 1);
-                reset_register.write(ARM_POWER_MANAGEMENT_PASSWD | ARM_POWER_MANAGEMENT_FULL_RESET);
-            },
+            "gdb" => unsafe {
+                // `debug::ATTACH_BRK`
+                asm!("brk 0x6762" :::: "volatile");
+            }
+            "reboot" => watchdog::reboot(),
+            "poweroff" => watchdog::power_off(),
             "exit" => {
                 return true;
             },
@@ -173,6 +463,21 @@ impl<'a> Command<'a> {
     }
 }
 
+/// Parses a rate in MHz, returning it in Hz.
+fn parse_mhz(text: &str) -> Option<u32> {
+    text.parse::<u32>().ok().and_then(|mhz| mhz.checked_mul(1000 * 1000))
+}
+
+/// Formats a rate in Hz as whole MHz.
+fn mhz(hz: u32) -> u32 {
+    hz / 1000 / 1000
+}
+
+/// Formats a temperature in thousandths of a degree Celsius.
+fn celsius(millidegrees: u32) -> String {
+    format!("{}.{}°C", millidegrees / 1000, millidegrees % 1000 / 100)
+}
+
 /// Starts a shell using `prefix` as the prefix for each line. This function
 /// returns if the `exit` command is called.
 pub fn shell(prefix: &str) {
@@ -186,7 +491,7 @@ pub fn shell(prefix: &str) {
 
         // read until a full command (+ newline) has been written
         loop {
-            let byte = CONSOLE.lock().read_byte();
+            let byte = console::read_byte();
             if byte == b'\n' || byte == b'\r' {
                 break;
             }
@@ -219,7 +524,7 @@ pub fn shell(prefix: &str) {
                 Err(Error::Empty) => {}
             };
         } else {
-            kprint!("{}", 7); // sound bell for unrecognized character
+            kprint!("\x07"); // sound bell for unrecognized character
         }
 
         buffer.truncate(0);
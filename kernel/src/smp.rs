@@ -0,0 +1,116 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use pi::console::kprintln;
+use pi::local_interrupt::LocalController;
+use aarch64;
+use cpufreq;
+use process;
+use vm;
+
+/// The number of cores on the BCM2837.
+pub const NCORES: usize = 4;
+
+/// The size of each core's EL1 stack. Must match `CORE_STACK_SIZE` in
+/// `init.S`.
+pub const CORE_STACK_SIZE: usize = 0x10000;
+
+/// The base of the spin table, which holds one 8-byte release address per
+/// core: core `n`'s slot is at `SPIN_TABLE_BASE + 8 * n`. Cores 1-3 wait for
+/// a non-zero entry address in their slot, both in the firmware's stub and in
+/// `init.S`.
+const SPIN_TABLE_BASE: usize = 0xd8;
+
+/// The local mailbox used to deliver `Ipi` messages.
+pub const IPI_MAILBOX: usize = 0;
+
+/// Messages that can be sent between cores. Each is one bit in the receiving
+/// core's `IPI_MAILBOX`, so several may be pending at once.
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Ipi {
+    /// Run the scheduler on the receiving core.
+    Reschedule = 1 << 0,
+}
+
+/// Bitmask of the cores that have finished initialization.
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+extern "C" {
+    static _start: u8;
+    fn _start_secondary();
+}
+
+/// Returns the address of the top of `core`'s EL1 stack.
+pub fn stack_top(core: usize) -> usize {
+    unsafe { (&_start as *const u8 as usize) - core * CORE_STACK_SIZE }
+}
+
+/// Returns `true` if `core` has finished initialization.
+pub fn is_online(core: usize) -> bool {
+    ONLINE.load(Ordering::Acquire) & (1 << core) != 0
+}
+
+/// Releases cores 1-3 from their spin loops into `_start_secondary`.
+///
+/// # Safety
+///
+/// Must only be called once, from core 0, after the scheduler has been
+/// initialized.
+pub unsafe fn start_secondary_cores() {
+    for core in 1..NCORES {
+        let release = (SPIN_TABLE_BASE + 8 * core) as *mut u64;
+        release.write_volatile(_start_secondary as usize as u64);
+    }
+
+    // The waiting cores read the table with their caches off.
+    aarch64::clean_dcache(SPIN_TABLE_BASE + 8, 8 * (NCORES - 1));
+    asm!("dsb sy
+          sev" :::: "volatile");
+}
+
+/// Sends `ipi` to `core`.
+pub fn send_ipi(core: usize, ipi: Ipi) {
+    let this = aarch64::affinity();
+    LocalController::new(this).send(core, IPI_MAILBOX, ipi as u32);
+}
+
+/// Sends `ipi` to every online core except the calling one.
+pub fn broadcast_ipi(ipi: Ipi) {
+    let this = aarch64::affinity();
+    for core in (0..NCORES).filter(|&core| core != this && is_online(core)) {
+        send_ipi(core, ipi);
+    }
+}
+
+/// Reads and clears the pending IPIs of the calling core.
+pub fn take_ipis() -> u32 {
+    let this = aarch64::affinity();
+    LocalController::new(this).take(IPI_MAILBOX)
+}
+
+/// Entry point of cores 1-3, called from `init.S` once the core is in EL1 with
+/// its own stack and exception vectors. The core turns on its MMU and caches
+/// with core 0's tables, then idles until its own scheduler tick or an IPI
+/// gives it work.
+#[no_mangle]
+pub unsafe extern "C" fn kinit_secondary() -> ! {
+    let core = aarch64::affinity();
+
+    // This core has used its stack uncached so far; drop anything core 0 may
+    // have speculatively cached from it before turning on the MMU, which has
+    // to be on before taking any lock.
+    let stack = stack_top(core) - CORE_STACK_SIZE;
+    aarch64::clean_invalidate_dcache(stack, CORE_STACK_SIZE);
+    vm::mmu::enable();
+
+    LocalController::new(core).enable_mailbox(IPI_MAILBOX);
+    process::start_tick();
+    ONLINE.fetch_or(1 << core, Ordering::Release);
+
+    kprintln!("core {} online", core);
+
+    aarch64::enable_irqs();
+    loop {
+        cpufreq::idle();
+    }
+}
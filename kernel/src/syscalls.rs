@@ -1,3 +1,15 @@
+/// The `pid` argument of `waitpid` that waits for any child.
+pub const WAIT_ANY: u64 = u64::max_value();
+
+/// Passed to `send`, and returned by `recv`, when a message carries no file
+/// descriptor.
+pub const NO_HANDLE: u64 = u64::max_value();
+
+pub use ipc::MESSAGE_SIZE;
+pub use process::{Signal, SIG_DFL, SIG_IGN};
+pub use clock::{Timespec, CLOCK_REALTIME, CLOCK_MONOTONIC};
+pub use compositor::SurfaceId;
+
 pub fn sleep(ms: u32) -> Result<(u32), String> {
     let error: u64;
     let actual_sleep_time: u32;
@@ -17,3 +29,545 @@ pub fn sleep(ms: u32) -> Result<(u32), String> {
         Ok(actual_sleep_time)
     }
 }
+
+pub fn thread_spawn(entry: extern "C" fn(u64) -> u64, arg: u64) -> Result<u64, String> {
+    let error: u64;
+    let tid: u64;
+    unsafe {
+        asm!("mov x0, $2
+              mov x1, $3
+              svc 2
+              mov $0, x0
+              mov $1, x7"
+              : "=r"(tid), "=r"(error)
+              : "r"(entry as u64), "r"(arg)
+              : "x0", "x1", "x7")
+    }
+
+    if error != 0 {
+        Err(format!("Error in thread_spawn syscall: {}", error))
+    } else {
+        Ok(tid)
+    }
+}
+
+pub fn thread_join(tid: u64) -> Result<u64, String> {
+    let error: u64;
+    let status: u64;
+    unsafe {
+        asm!("mov x0, $2
+              svc 3
+              mov $0, x0
+              mov $1, x7"
+              : "=r"(status), "=r"(error)
+              : "r"(tid)
+              : "x0", "x7")
+    }
+
+    if error != 0 {
+        Err(format!("Error in thread_join syscall: {}", error))
+    } else {
+        Ok(status)
+    }
+}
+
+pub fn thread_exit(status: u64) -> ! {
+    unsafe {
+        asm!("mov x0, $0
+              svc 4"
+              :: "r"(status)
+              : "x0")
+    }
+
+    unreachable!("thread_exit returned")
+}
+
+pub fn exit(status: u64) -> ! {
+    unsafe {
+        asm!("mov x0, $0
+              svc 5"
+              :: "r"(status)
+              : "x0")
+    }
+
+    unreachable!("exit returned")
+}
+
+/// Returns the child's process ID in the parent and `0` in the child.
+pub fn fork() -> Result<u64, String> {
+    let error: u64;
+    let pid: u64;
+    unsafe {
+        asm!("svc 6
+              mov $0, x0
+              mov $1, x7"
+              : "=r"(pid), "=r"(error)
+              :
+              : "x0", "x7")
+    }
+
+    if error != 0 {
+        Err(format!("Error in fork syscall: {}", error))
+    } else {
+        Ok(pid)
+    }
+}
+
+/// Only returns if the program at `path` could not be executed.
+pub fn exec(path: &str) -> String {
+    let error: u64;
+    unsafe {
+        asm!("mov x0, $1
+              mov x1, $2
+              svc 7
+              mov $0, x7"
+              : "=r"(error)
+              : "r"(path.as_ptr() as u64), "r"(path.len() as u64)
+              : "x0", "x1", "x7")
+    }
+
+    format!("Error in exec syscall: {}", error)
+}
+
+/// Waits for the child `pid`, or any child if `pid` is `WAIT_ANY`, to exit.
+/// Returns the ID and exit status of the child that exited.
+pub fn waitpid(pid: u64) -> Result<(u64, u64), String> {
+    let error: u64;
+    let child: u64;
+    let status: u64;
+    unsafe {
+        asm!("mov x0, $3
+              svc 8
+              mov $0, x0
+              mov $1, x1
+              mov $2, x7"
+              : "=r"(child), "=r"(status), "=r"(error)
+              : "r"(pid)
+              : "x0", "x1", "x7")
+    }
+
+    if error != 0 {
+        Err(format!("Error in waitpid syscall: {}", error))
+    } else {
+        Ok((child, status))
+    }
+}
+
+/// Returns the file descriptors of the read and write ends of a new pipe.
+pub fn pipe() -> Result<(u64, u64), String> {
+    let error: u64;
+    let reader: u64;
+    let writer: u64;
+    unsafe {
+        asm!("svc 9
+              mov $0, x0
+              mov $1, x1
+              mov $2, x7"
+              : "=r"(reader), "=r"(writer), "=r"(error)
+              :
+              : "x0", "x1", "x7")
+    }
+
+    if error != 0 {
+        Err(format!("Error in pipe syscall: {}", error))
+    } else {
+        Ok((reader, writer))
+    }
+}
+
+pub fn read(fd: u64, buf: &mut [u8]) -> Result<usize, String> {
+    let error: u64;
+    let n: u64;
+    unsafe {
+        asm!("mov x0, $2
+              mov x1, $3
+              mov x2, $4
+              svc 10
+              mov $0, x0
+              mov $1, x7"
+              : "=r"(n), "=r"(error)
+              : "r"(fd), "r"(buf.as_mut_ptr() as u64), "r"(buf.len() as u64)
+              : "x0", "x1", "x2", "x7")
+    }
+
+    if error != 0 {
+        Err(format!("Error in read syscall: {}", error))
+    } else {
+        Ok(n as usize)
+    }
+}
+
+pub fn write(fd: u64, buf: &[u8]) -> Result<usize, String> {
+    let error: u64;
+    let n: u64;
+    unsafe {
+        asm!("mov x0, $2
+              mov x1, $3
+              mov x2, $4
+              svc 11
+              mov $0, x0
+              mov $1, x7"
+              : "=r"(n), "=r"(error)
+              : "r"(fd), "r"(buf.as_ptr() as u64), "r"(buf.len() as u64)
+              : "x0", "x1", "x2", "x7")
+    }
+
+    if error != 0 {
+        Err(format!("Error in write syscall: {}", error))
+    } else {
+        Ok(n as usize)
+    }
+}
+
+pub fn close(fd: u64) -> Result<(), String> {
+    let error: u64;
+    unsafe {
+        asm!("mov x0, $1
+              svc 12
+              mov $0, x7"
+              : "=r"(error)
+              : "r"(fd)
+              : "x0", "x7")
+    }
+
+    if error != 0 {
+        Err(format!("Error in close syscall: {}", error))
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns the file descriptors of the two ends of a new message channel.
+pub fn channel() -> Result<(u64, u64), String> {
+    let error: u64;
+    let a: u64;
+    let b: u64;
+    unsafe {
+        asm!("svc 13
+              mov $0, x0
+              mov $1, x1
+              mov $2, x7"
+              : "=r"(a), "=r"(b), "=r"(error)
+              :
+              : "x0", "x1", "x7")
+    }
+
+    if error != 0 {
+        Err(format!("Error in channel syscall: {}", error))
+    } else {
+        Ok((a, b))
+    }
+}
+
+/// Sends `data`, at most `MESSAGE_SIZE` bytes, and optionally a copy of the
+/// file descriptor `handle` over the channel `fd`.
+pub fn send(fd: u64, data: &[u8], handle: Option<u64>) -> Result<(), String> {
+    let error: u64;
+    unsafe {
+        asm!("mov x0, $1
+              mov x1, $2
+              mov x2, $3
+              mov x3, $4
+              svc 14
+              mov $0, x7"
+              : "=r"(error)
+              : "r"(fd), "r"(data.as_ptr() as u64), "r"(data.len() as u64),
+                "r"(handle.unwrap_or(NO_HANDLE))
+              : "x0", "x1", "x2", "x3", "x7")
+    }
+
+    if error != 0 {
+        Err(format!("Error in send syscall: {}", error))
+    } else {
+        Ok(())
+    }
+}
+
+/// Receives a message from the channel `fd` into `buf`. Returns the length of
+/// the message's data and the file descriptor passed with it, if any.
+pub fn recv(fd: u64, buf: &mut [u8; MESSAGE_SIZE]) -> Result<(usize, Option<u64>), String> {
+    let error: u64;
+    let len: u64;
+    let handle: u64;
+    unsafe {
+        asm!("mov x0, $3
+              mov x1, $4
+              svc 15
+              mov $0, x0
+              mov $1, x1
+              mov $2, x7"
+              : "=r"(len), "=r"(handle), "=r"(error)
+              : "r"(fd), "r"(buf.as_mut_ptr() as u64)
+              : "x0", "x1", "x7")
+    }
+
+    if error != 0 {
+        Err(format!("Error in recv syscall: {}", error))
+    } else if handle == NO_HANDLE {
+        Ok((len as usize, None))
+    } else {
+        Ok((len as usize, Some(handle)))
+    }
+}
+
+/// Sets the handler of `signal` to `handler`: `SIG_DFL`, `SIG_IGN`, or the
+/// address of an `extern "C" fn(signal: u64, frame: u64)`. Returns the
+/// previous handler.
+pub fn sigaction(signal: Signal, handler: u64) -> Result<u64, String> {
+    let error: u64;
+    let previous: u64;
+    unsafe {
+        asm!("mov x0, $2
+              mov x1, $3
+              mov x2, $4
+              svc 16
+              mov $0, x0
+              mov $1, x7"
+              : "=r"(previous), "=r"(error)
+              : "r"(signal as u64), "r"(handler), "r"(signal_return as u64)
+              : "x0", "x1", "x2", "x7")
+    }
+
+    if error != 0 {
+        Err(format!("Error in sigaction syscall: {}", error))
+    } else {
+        Ok(previous)
+    }
+}
+
+/// Sends `signal` to process `pid`. With `None`, only checks that the process
+/// exists.
+pub fn kill(pid: u64, signal: Option<Signal>) -> Result<(), String> {
+    let error: u64;
+    unsafe {
+        asm!("mov x0, $1
+              mov x1, $2
+              svc 17
+              mov $0, x7"
+              : "=r"(error)
+              : "r"(pid), "r"(signal.map(|s| s as u64).unwrap_or(0))
+              : "x0", "x1", "x7")
+    }
+
+    if error != 0 {
+        Err(format!("Error in kill syscall: {}", error))
+    } else {
+        Ok(())
+    }
+}
+
+/// Makes `pid` the process that receives `Signal::Int` on Ctrl-C.
+pub fn set_foreground(pid: u64) -> Result<(), String> {
+    let error: u64;
+    unsafe {
+        asm!("mov x0, $1
+              svc 19
+              mov $0, x7"
+              : "=r"(error)
+              : "r"(pid)
+              : "x0", "x7")
+    }
+
+    if error != 0 {
+        Err(format!("Error in set_foreground syscall: {}", error))
+    } else {
+        Ok(())
+    }
+}
+
+pub fn clock_gettime(clock_id: u64) -> Result<Timespec, String> {
+    let error: u64;
+    let secs: u64;
+    let nanos: u64;
+    unsafe {
+        asm!("mov x0, $3
+              svc 20
+              mov $0, x0
+              mov $1, x1
+              mov $2, x7"
+              : "=r"(secs), "=r"(nanos), "=r"(error)
+              : "r"(clock_id)
+              : "x0", "x1", "x7")
+    }
+
+    if error != 0 {
+        Err(format!("Error in clock_gettime syscall: {}", error))
+    } else {
+        Ok(Timespec { secs, nanos: nanos as u32 })
+    }
+}
+
+pub fn clock_settime(clock_id: u64, time: Timespec) -> Result<(), String> {
+    let error: u64;
+    unsafe {
+        asm!("mov x0, $1
+              mov x1, $2
+              mov x2, $3
+              svc 21
+              mov $0, x7"
+              : "=r"(error)
+              : "r"(clock_id), "r"(time.secs), "r"(time.nanos as u64)
+              : "x0", "x1", "x2", "x7")
+    }
+
+    if error != 0 {
+        Err(format!("Error in clock_settime syscall: {}", error))
+    } else {
+        Ok(())
+    }
+}
+
+/// Creates a transparent `width` by `height` surface at (`x`, `y`) on the
+/// desktop, on top of every other surface.
+pub fn surface_create(x: i32, y: i32, width: usize, height: usize) -> Result<SurfaceId, String> {
+    let error: u64;
+    let id: u64;
+    unsafe {
+        asm!("mov x0, $2
+              mov x1, $3
+              mov x2, $4
+              mov x3, $5
+              svc 22
+              mov $0, x0
+              mov $1, x7"
+              : "=r"(id), "=r"(error)
+              : "r"(x as i64 as u64), "r"(y as i64 as u64), "r"(width as u64),
+                "r"(height as u64)
+              : "x0", "x1", "x2", "x3", "x7")
+    }
+
+    if error != 0 {
+        Err(format!("Error in surface_create syscall: {}", error))
+    } else {
+        Ok(id)
+    }
+}
+
+pub fn surface_destroy(id: SurfaceId) -> Result<(), String> {
+    let error: u64;
+    unsafe {
+        asm!("mov x0, $1
+              svc 23
+              mov $0, x7"
+              : "=r"(error)
+              : "r"(id)
+              : "x0", "x7")
+    }
+
+    if error != 0 {
+        Err(format!("Error in surface_destroy syscall: {}", error))
+    } else {
+        Ok(())
+    }
+}
+
+/// Replaces the `width` by `height` pixels at (`x`, `y`) in surface `id` with
+/// `pixels`, given row by row as `0xAARRGGBB`.
+pub fn surface_update(id: SurfaceId, x: usize, y: usize, width: usize, height: usize,
+                      pixels: &[u32]) -> Result<(), String> {
+    if pixels.len() < width * height {
+        return Err(format!("Error in surface_update syscall: too few pixels"));
+    }
+
+    let error: u64;
+    unsafe {
+        asm!("mov x0, $1
+              mov x1, $2
+              mov x2, $3
+              mov x3, $4
+              mov x4, $5
+              mov x5, $6
+              svc 24
+              mov $0, x7"
+              : "=r"(error)
+              : "r"(id), "r"(pixels.as_ptr() as u64), "r"(x as u64), "r"(y as u64),
+                "r"(width as u64), "r"(height as u64)
+              : "x0", "x1", "x2", "x3", "x4", "x5", "x7")
+    }
+
+    if error != 0 {
+        Err(format!("Error in surface_update syscall: {}", error))
+    } else {
+        Ok(())
+    }
+}
+
+pub fn surface_move(id: SurfaceId, x: i32, y: i32) -> Result<(), String> {
+    let error: u64;
+    unsafe {
+        asm!("mov x0, $1
+              mov x1, $2
+              mov x2, $3
+              svc 25
+              mov $0, x7"
+              : "=r"(error)
+              : "r"(id), "r"(x as i64 as u64), "r"(y as i64 as u64)
+              : "x0", "x1", "x2", "x7")
+    }
+
+    if error != 0 {
+        Err(format!("Error in surface_move syscall: {}", error))
+    } else {
+        Ok(())
+    }
+}
+
+/// Raises surface `id` above every other surface and gives it the keyboard
+/// focus.
+pub fn surface_raise(id: SurfaceId) -> Result<(), String> {
+    let error: u64;
+    unsafe {
+        asm!("mov x0, $1
+              svc 26
+              mov $0, x7"
+              : "=r"(error)
+              : "r"(id)
+              : "x0", "x7")
+    }
+
+    if error != 0 {
+        Err(format!("Error in surface_raise syscall: {}", error))
+    } else {
+        Ok(())
+    }
+}
+
+/// Reads console input sent to surface `id` while it had the keyboard focus,
+/// blocking until there is some.
+pub fn surface_read(id: SurfaceId, buf: &mut [u8]) -> Result<usize, String> {
+    let error: u64;
+    let read: u64;
+    unsafe {
+        asm!("mov x0, $2
+              mov x1, $3
+              mov x2, $4
+              svc 27
+              mov $0, x0
+              mov $1, x7"
+              : "=r"(read), "=r"(error)
+              : "r"(id), "r"(buf.as_mut_ptr() as u64), "r"(buf.len() as u64)
+              : "x0", "x1", "x2", "x7")
+    }
+
+    if error != 0 {
+        Err(format!("Error in surface_read syscall: {}", error))
+    } else {
+        Ok(read as usize)
+    }
+}
+
+/// Every signal handler installed by `sigaction` returns here (via `x30`).
+/// The stack pointer still points at the signal frame, so this must not touch
+/// the stack before calling `sigreturn`.
+#[naked]
+#[no_mangle]
+pub unsafe extern "C" fn signal_return() {
+    asm!("svc 18" :::: "volatile");
+}
+
+/// Every thread's entry function returns here (via `x30`) with its return
+/// value as `status`.
+#[no_mangle]
+pub extern "C" fn thread_return(status: u64) -> ! {
+    thread_exit(status)
+}
@@ -1,22 +1,229 @@
-use pi::interrupt::Interrupt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use pi::interrupt::{Controller, Irq, NUM_IRQS};
 use pi::console::kprintln;
-use pi::timer;
-use process::{TICK, State};
+use pi::mutex::Mutex;
+use aarch64;
+use gpio;
+use lockup;
+use smp::{self, Ipi};
+use process::{self, State};
 use SCHEDULER;
 
 use traps::TrapFrame;
 
-pub fn handle_irq(interrupt: Interrupt, tf: &mut TrapFrame) {
-    match interrupt {
-        Interrupt::Timer1 => {
-            timer::tick_in(TICK);
-            SCHEDULER.switch(State::Ready, tf);
-        },
-        Interrupt::Timer3 => {
-            kprintln!("IRQ from Timer3 unhandled!");
+/// How many interrupts in a row a line may raise without any handler
+/// claiming them before it is disabled.
+const UNHANDLED_LIMIT: u32 = 1000;
+
+/// What a handler did with an interrupt.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Handled {
+    /// The interrupt didn't come from this handler's device.
+    No,
+    /// The handler serviced its device. Later handlers on the line still
+    /// run, since a shared line can be raised by several devices at once.
+    Yes,
+    /// The handler serviced the interrupt and later handlers must not run.
+    Stop,
+}
+
+/// An interrupt handler. Any `FnMut(&mut TrapFrame) -> Handled` closure is
+/// one.
+pub trait Handler: Send {
+    /// Handles an interrupt taken with trap frame `tf`.
+    fn handle(&mut self, tf: &mut TrapFrame) -> Handled;
+}
+
+impl<F: FnMut(&mut TrapFrame) -> Handled + Send> Handler for F {
+    fn handle(&mut self, tf: &mut TrapFrame) -> Handled {
+        self(tf)
+    }
+}
+
+/// A handler registered on a line, with the name it was registered under.
+struct Action {
+    name: &'static str,
+    handler: Box<Handler>,
+}
+
+/// The handlers and counters of one interrupt line.
+#[derive(Default)]
+struct Line {
+    actions: Vec<Action>,
+    count: u64,
+    spurious: u64,
+    /// Interrupts raised in a row that no handler claimed.
+    unhandled: u32,
+}
+
+/// Counters for one interrupt line, as reported by `Registry::stats()`.
+#[derive(Debug, Clone)]
+pub struct Stat {
+    pub irq: Irq,
+    /// The number of times the line was raised.
+    pub count: u64,
+    /// The number of times the line was raised without a handler claiming
+    /// the interrupt.
+    pub spurious: u64,
+    /// The names of the line's handlers, in the order they run.
+    pub handlers: Vec<&'static str>,
+}
+
+/// The interrupt handlers registered by drivers, and dispatch to them.
+///
+/// Several handlers can be registered on one line. They run in order until
+/// one returns `Handled::Stop`; `register_first` chains a handler in front of
+/// those already registered. Handlers run with the registry locked, so they
+/// must not register or unregister handlers themselves.
+pub struct Registry {
+    lines: Mutex<Option<Vec<Line>>>,
+    /// IRQ exceptions taken with no interrupt pending.
+    spurious: AtomicUsize,
+}
+
+impl Registry {
+    /// Returns a registry with no handlers.
+    pub const fn uninitialized() -> Registry {
+        Registry {
+            lines: Mutex::new(None),
+            spurious: AtomicUsize::new(0),
         }
-        _ => {
-            kprintln!("IRQ from non-timer");
+    }
+
+    fn with_lines<R, F: FnOnce(&mut Vec<Line>) -> R>(&self, f: F) -> R {
+        let mut lines = self.lines.lock_irq();
+        if lines.is_none() {
+            *lines = Some((0..NUM_IRQS).map(|_| Line::default()).collect());
         }
+        f(lines.as_mut().unwrap())
+    }
+
+    /// Registers `handler` under `name` to run after any handlers already on
+    /// `irq`, and enables the interrupt.
+    pub fn register<I: Into<Irq>>(&self, irq: I, name: &'static str, handler: Box<Handler>) {
+        let irq = irq.into();
+        self.with_lines(|lines| {
+            lines[irq.number()].actions.push(Action { name, handler });
+        });
+        Controller::new().enable(irq);
+    }
+
+    /// Registers `handler` under `name` to run before any handlers already
+    /// on `irq`, and enables the interrupt.
+    pub fn register_first<I: Into<Irq>>(&self, irq: I, name: &'static str, handler: Box<Handler>) {
+        let irq = irq.into();
+        self.with_lines(|lines| {
+            lines[irq.number()].actions.insert(0, Action { name, handler });
+        });
+        Controller::new().enable(irq);
+    }
+
+    /// Removes the handler registered under `name` from `irq`, disabling the
+    /// interrupt if it was the last one. Returns `false` if there was no such
+    /// handler.
+    pub fn unregister<I: Into<Irq>>(&self, irq: I, name: &'static str) -> bool {
+        let irq = irq.into();
+        let (found, empty) = self.with_lines(|lines| {
+            let line = &mut lines[irq.number()];
+            let before = line.actions.len();
+            line.actions.retain(|action| action.name != name);
+            (line.actions.len() != before, line.actions.is_empty())
+        });
+
+        if found && empty {
+            Controller::new().disable(irq);
+        }
+        found
+    }
+
+    /// Runs the handlers of every pending interrupt.
+    pub fn dispatch(&self, tf: &mut TrapFrame) {
+        let pending = Controller::new().pending();
+        if pending.is_empty() {
+            return self.spurious();
+        }
+
+        for irq in pending {
+            self.handle(irq, tf);
+        }
+    }
+
+    /// Counts an IRQ exception that had no pending source.
+    pub fn spurious(&self) {
+        self.spurious.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn handle(&self, irq: Irq, tf: &mut TrapFrame) {
+        let disable = self.with_lines(|lines| {
+            let line = &mut lines[irq.number()];
+            line.count += 1;
+
+            let mut claimed = false;
+            for action in line.actions.iter_mut() {
+                match action.handler.handle(tf) {
+                    Handled::No => {},
+                    Handled::Yes => claimed = true,
+                    Handled::Stop => {
+                        claimed = true;
+                        break;
+                    },
+                }
+            }
+
+            if claimed {
+                line.unhandled = 0;
+                return false;
+            }
+
+            line.spurious += 1;
+            line.unhandled += 1;
+            line.actions.is_empty() || line.unhandled >= UNHANDLED_LIMIT
+        });
+
+        // Nothing will acknowledge the interrupt, so it would fire forever.
+        if disable {
+            kprintln!("irq {}: nobody cared, disabling", irq);
+            Controller::new().disable(irq);
+        }
+    }
+
+    /// Returns the counters of every line that has a handler or has been
+    /// raised.
+    pub fn stats(&self) -> Vec<Stat> {
+        self.with_lines(|lines| {
+            lines.iter().enumerate()
+                .filter(|&(_, line)| line.count != 0 || !line.actions.is_empty())
+                .map(|(n, line)| Stat {
+                    irq: Irq::from_number(n).expect("irq"),
+                    count: line.count,
+                    spurious: line.spurious,
+                    handlers: line.actions.iter().map(|action| action.name).collect(),
+                })
+                .collect()
+        })
+    }
+
+    /// The number of IRQ exceptions taken with no interrupt pending.
+    pub fn spurious_count(&self) -> usize {
+        self.spurious.load(Ordering::Relaxed)
+    }
+}
+
+/// Handles the calling core's scheduler tick.
+pub fn handle_tick(tf: &mut TrapFrame) {
+    process::arm_tick();
+    lockup::tick(tf, SCHEDULER.current_pid());
+    if aarch64::affinity() == 0 {
+        gpio::tick();
+    }
+    let _ = SCHEDULER.switch(State::Ready, tf);
+}
+
+/// Handles the inter-processor interrupts pending on the calling core.
+pub fn handle_ipi(tf: &mut TrapFrame) {
+    let pending = smp::take_ipis();
+    if pending & Ipi::Reschedule as u32 != 0 {
+        let _ = SCHEDULER.switch(State::Ready, tf);
     }
 }
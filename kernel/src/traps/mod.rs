@@ -3,16 +3,18 @@ mod trap_frame;
 mod syndrome;
 mod syscall;
 
-use pi::interrupt::{Controller};
-
-use shell::shell;
+use pi::local_interrupt::{LocalController, LocalInterrupt};
 
 pub use self::trap_frame::TrapFrame;
+pub use self::irq::{Handled, Handler, Registry, Stat};
 
 use aarch64;
+use debug;
 use pi::console::kprintln;
-use self::syndrome::Syndrome;
-use self::irq::handle_irq;
+use process::{self, Signal};
+use {IRQS, SCHEDULER};
+use self::syndrome::{Exception, Syndrome};
+use self::irq::{handle_ipi, handle_tick};
 use self::syscall::handle_syscall;
 
 #[repr(u16)]
@@ -40,55 +42,90 @@ pub struct Info {
     kind: Kind,
 }
 
+/// Prints everything known about an exception the kernel can't handle. System
+/// calls and other exceptions that are handled print nothing, and nothing is
+/// printed into the protocol stream while GDB is attached.
+fn dump(info: Info, esr: u32, exception: &Exception, tf: &TrapFrame) {
+    if debug::gdb::is_attached() {
+        return;
+    }
+
+    kprintln!("info: {:#x?}", info);
+    kprintln!("esr: {:#x?}", esr);
+    kprintln!("exception: {}", exception);
+    kprintln!("tf: {:#x?}", tf);
+    debug::backtrace::print_trap(tf);
+}
+
 /// This function is called when an exception occurs. The `info` parameter
 /// specifies the source and kind of exception that has occurred. The `esr` is
 /// the value of the exception syndrome register. Finally, `tf` is a pointer to
 /// the trap frame for the exception.
 #[no_mangle]
 pub extern fn handle_exception(info: Info, esr: u32, tf: &mut TrapFrame) {
-    let syndrome = Syndrome::from(esr);
+    let exception = Exception::new(esr, aarch64::far());
+    let syndrome = exception.syndrome;
     match info.kind {
         Kind::Synchronous => {
-            kprintln!("info: {:#x?}", info);
-            kprintln!("esr: {:#x?}", esr);
-            kprintln!("syndrome = {:#x?}", syndrome);
-            kprintln!("tf: {:#x?}", tf);
-
             match syndrome {
-                Syndrome::Brk(break_num) => {
-                    shell(&format!("{} $!> ", break_num));
-                    tf.elr = tf.elr + 0x04;
+                Syndrome::Brk(imm) => {
+                    debug::enter(debug::Reason::Brk(imm), tf);
                     return;
                 },
                 Syndrome::Breakpoint => {
-                    shell("$!> ");
-                    tf.elr = tf.elr + 0x04; // TODO: same?
+                    debug::enter(debug::Reason::Breakpoint, tf);
+                    return;
+                },
+                Syndrome::Watchpoint { .. } => {
+                    debug::enter(debug::Reason::Watchpoint, tf);
+                    return;
+                },
+                Syndrome::Step => {
+                    debug::enter(debug::Reason::Step, tf);
                     return;
                 },
                 Syndrome::Svc(exception_num) => {
-                    kprintln!("svc exception num: {}", exception_num);
                     handle_syscall(exception_num, tf);
+                    SCHEDULER.deliver_signals(tf);
+                    return;
+                },
+                Syndrome::DataAbort { .. } | Syndrome::InstructionAbort { .. }
+                | Syndrome::PCAlignmentFault | Syndrome::SpAlignmentFault
+                    if info.source == Source::LowerAArch64 => {
+                    SCHEDULER.fault(Signal::Segv);
+                    SCHEDULER.deliver_signals(tf);
+                    return;
+                },
+                Syndrome::Unknown | Syndrome::IllegalExecutionState
+                    if info.source == Source::LowerAArch64 => {
+                    SCHEDULER.fault(Signal::Ill);
+                    SCHEDULER.deliver_signals(tf);
                     return;
                 },
                 _ => {
+                    dump(info, esr, &exception, tf);
                     unimplemented!("syndrome")
                 }
             }
         },
         Kind::Irq => {
-            if let Some(int) = Controller::new().first_pending() {
-                handle_irq(int, tf);
+            let local = LocalController::new(aarch64::affinity());
+            if local.is_pending(LocalInterrupt::Mailbox0) {
+                handle_ipi(tf);
+            } else if process::is_tick_pending() {
+                handle_tick(tf);
+            } else if local.is_pending(LocalInterrupt::Gpu) {
+                IRQS.dispatch(tf);
+            } else {
+                IRQS.spurious();
             }
+            SCHEDULER.deliver_signals(tf);
             return;
         },
         _ => {}
     }
 
-    kprintln!("info: {:#x?}", info);
-    kprintln!("esr: {:#x?}", esr);
-    kprintln!("syndrome = {:#x?}", syndrome);
-    kprintln!("tf: {:#x?}", tf);
-
+    dump(info, esr, &exception, tf);
     kprintln!("infinite looping 🛸");
     loop {
         aarch64::nop();
@@ -1,9 +1,17 @@
+use std::fmt;
+
+/// The cause of an abort, decoded from the `IFSC`/`DFSC` field of the ISS
+/// (ref: D1.10.4, ISS encoding for an exception from a Data Abort).
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum Fault {
     AddressSize,
     Translation,
     AccessFlag,
     Permission,
+    SynchronousExternal,
+    SynchronousExternalOnWalk,
+    Parity,
+    ParityOnWalk,
     Alignment,
     TlbConflict,
     Other(u8)
@@ -11,14 +19,119 @@ pub enum Fault {
 
 impl From<u32> for Fault {
     fn from(val: u32) -> Fault {
-        match (val as u8 & 0b111111) >> 2 {
+        let status = val as u8 & 0b111111;
+        match status {
+            0b100001 => return Fault::Alignment,
+            0b110000 => return Fault::TlbConflict,
+            0b010000 => return Fault::SynchronousExternal,
+            0b011000 => return Fault::Parity,
+            _ => {}
+        }
+
+        match status >> 2 {
             0b0000 => Fault::AddressSize,
             0b0001 => Fault::Translation,
             0b0010 => Fault::AccessFlag,
             0b0011 => Fault::Permission,
-            0b1000 => Fault::Alignment,
-            0b1100 => Fault::TlbConflict,
-            _ => Fault::Other(val as u8 & 0b111111)
+            0b0101 => Fault::SynchronousExternalOnWalk,
+            0b0111 => Fault::ParityOnWalk,
+            _ => Fault::Other(status)
+        }
+    }
+}
+
+impl Fault {
+    /// Returns the translation table level a fault with status code `val`
+    /// happened at, for the kinds of fault that report one.
+    fn level(val: u32) -> Option<u8> {
+        match Fault::from(val) {
+            Fault::AddressSize | Fault::Translation | Fault::AccessFlag
+            | Fault::Permission | Fault::SynchronousExternalOnWalk
+            | Fault::ParityOnWalk => Some(val as u8 & 0b11),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Fault::AddressSize => write!(f, "address size fault"),
+            Fault::Translation => write!(f, "translation fault"),
+            Fault::AccessFlag => write!(f, "access flag fault"),
+            Fault::Permission => write!(f, "permission fault"),
+            Fault::SynchronousExternal => write!(f, "synchronous external abort"),
+            Fault::SynchronousExternalOnWalk => write!(f, "synchronous external abort on table walk"),
+            Fault::Parity => write!(f, "parity error"),
+            Fault::ParityOnWalk => write!(f, "parity error on table walk"),
+            Fault::Alignment => write!(f, "alignment fault"),
+            Fault::TlbConflict => write!(f, "TLB conflict abort"),
+            Fault::Other(status) => write!(f, "fault {:#04x}", status),
+        }
+    }
+}
+
+/// The access that caused a data abort, reported when the ISS is valid
+/// (`ISV` set). Only single-register loads and stores report one.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Access {
+    /// The size of the access in bytes (`SAS`).
+    pub size: u8,
+    /// The register transferred (`SRT`).
+    pub register: u8,
+    /// The loaded value is sign-extended (`SSE`).
+    pub sign_extend: bool,
+    /// The register is 64 bits wide (`SF`).
+    pub wide: bool,
+    /// The access has acquire/release semantics (`AR`).
+    pub acquire_release: bool,
+}
+
+/// The severity of an asynchronous SError, from the ISS's `AET` field.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Severity {
+    Uncontainable,
+    Unrecoverable,
+    Restartable,
+    Recoverable,
+    Corrected,
+    Other(u8)
+}
+
+impl From<u32> for Severity {
+    fn from(val: u32) -> Severity {
+        match val & 0b111 {
+            0b000 => Severity::Uncontainable,
+            0b001 => Severity::Unrecoverable,
+            0b010 => Severity::Restartable,
+            0b011 => Severity::Recoverable,
+            0b110 => Severity::Corrected,
+            other => Severity::Other(other as u8)
+        }
+    }
+}
+
+/// What the ISS of an SError says about it.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum SError {
+    /// The ISS has an implementation defined format (`IDS` set); this is its
+    /// raw value.
+    ImplementationDefined(u32),
+    /// The core gave no further information.
+    Uncategorized,
+    /// An asynchronous SError interrupt of the given severity.
+    Asynchronous(Severity),
+}
+
+impl From<u32> for SError {
+    fn from(iss: u32) -> SError {
+        if iss & (1 << 24) != 0 {
+            return SError::ImplementationDefined(iss & 0xffffff);
+        }
+
+        match iss & 0b111111 {
+            0b010001 => SError::Asynchronous(Severity::from(iss >> 10)),
+            _ => SError::Uncategorized
         }
     }
 }
@@ -26,7 +139,10 @@ impl From<u32> for Fault {
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum Syndrome {
     Unknown,
-    WfiWfe,
+    WfiWfe {
+        /// A `wfe` was trapped rather than a `wfi`.
+        wfe: bool
+    },
     McrMrc,
     McrrMrrc,
     LdcStc,
@@ -37,22 +153,47 @@ pub enum Syndrome {
     Svc(u16),
     Hvc(u16),
     Smc(u16),
-    MsrMrsSystem,
+    /// A trapped `msr`, `mrs` or system instruction. The register is encoded
+    /// as `s<op0>_<op1>_c<crn>_c<crm>_<op2>`.
+    MsrMrsSystem {
+        op0: u8,
+        op1: u8,
+        crn: u8,
+        crm: u8,
+        op2: u8,
+        /// The general purpose register transferred.
+        rt: u8,
+        /// The register was read (`mrs`) rather than written.
+        read: bool,
+    },
     InstructionAbort {
         kind: Fault,
-        level: u8,
+        level: Option<u8>,
+        /// `FAR_EL1` holds the faulting address (`FnV` clear).
+        far_valid: bool,
     },
     PCAlignmentFault,
     DataAbort {
         kind: Fault,
-        level: u8
+        level: Option<u8>,
+        /// The access, if the ISS describes it.
+        access: Option<Access>,
+        /// The abort was caused by a write (`WnR`).
+        write: bool,
+        /// The abort was caused by a cache maintenance instruction (`CM`).
+        cache_maintenance: bool,
+        /// `FAR_EL1` holds the faulting address (`FnV` clear).
+        far_valid: bool,
     },
     SpAlignmentFault,
     TrappedFpu,
-    SError,
+    SError(SError),
     Breakpoint,
     Step,
-    Watchpoint,
+    Watchpoint {
+        /// The watchpoint was hit by a write (`WnR`).
+        write: bool
+    },
     Brk(u16),
     Other(u32)
 }
@@ -62,9 +203,13 @@ impl From<u32> for Syndrome {
     fn from(esr: u32) -> Syndrome {
         use self::Syndrome::*;
 
-        match esr >> 26 { // TODO: check if I need to mask high bits?
+        let iss = esr & 0x1ffffff;
+        let bit = |n: u32| iss & (1 << n) != 0;
+        let field = |lo: u32, len: u32| ((iss >> lo) & ((1 << len) - 1)) as u8;
+
+        match (esr >> 26) & 0b111111 {
             0b000000 => Unknown,
-            0b000001 => WfiWfe,
+            0b000001 => WfiWfe { wfe: bit(0) },
             0b000011 => McrMrc,
             0b000100 => McrrMrrc,
             0b000101 => McrMrc,
@@ -73,44 +218,238 @@ impl From<u32> for Syndrome {
             0b001000 => Vmrs,
             0b001100 => Mrrc,
             0b001110 => IllegalExecutionState,
-            0b010001 => Svc(esr as u16), // TODO: check that this is correct encoding
-            0b010010 => Hvc(esr as u16),
-            0b010011 => Smc(esr as u16),
-            0b010101 => Svc(esr as u16),
-            0b010110 => Hvc(esr as u16),
-            0b010111 => Smc(esr as u16),
-            0b011000 => MsrMrsSystem,
-            // TODO: What is `IMPLEMENTATION DEFINED` for 0b011111??
-            0b100000 => InstructionAbort {
-                kind: Fault::from(esr),
-                level: esr as u8 & 0b00000011
+            // AArch32 `svc`, `hvc` and `smc`, then their AArch64 forms. All
+            // carry the instruction's immediate in the low 16 bits.
+            0b010001 | 0b010101 => Svc(iss as u16),
+            0b010010 | 0b010110 => Hvc(iss as u16),
+            0b010011 | 0b010111 => Smc(iss as u16),
+            0b011000 => MsrMrsSystem {
+                op0: field(20, 2),
+                op2: field(17, 3),
+                op1: field(14, 3),
+                crn: field(10, 4),
+                rt: field(5, 5),
+                crm: field(1, 4),
+                read: bit(0),
             },
-            0b100001 => InstructionAbort {
-                kind: Fault::from(esr),
-                level: esr as u8 & 0b00000011
+            // Instruction aborts from a lower and the current EL.
+            0b100000 | 0b100001 => InstructionAbort {
+                kind: Fault::from(iss),
+                level: Fault::level(iss),
+                far_valid: !bit(10),
             },
             0b100010 => PCAlignmentFault,
-            0b100100 => DataAbort {
-                kind: Fault::from(esr),
-                level: esr as u8 & 0b00000011
-            },
-            0b100101 => DataAbort {
-                kind: Fault::from(esr),
-                level: esr as u8 & 0b00000011
+            // Data aborts from a lower and the current EL.
+            0b100100 | 0b100101 => DataAbort {
+                kind: Fault::from(iss),
+                level: Fault::level(iss),
+                access: if bit(24) {
+                    Some(Access {
+                        size: 1 << field(22, 2),
+                        register: field(16, 5),
+                        sign_extend: bit(21),
+                        wide: bit(15),
+                        acquire_release: bit(14),
+                    })
+                } else {
+                    None
+                },
+                write: bit(6),
+                cache_maintenance: bit(8),
+                far_valid: !bit(10),
             },
             0b100110 => SpAlignmentFault,
-            0b101000 => TrappedFpu,
-            0b101100 => TrappedFpu,
-            0b101111 => SError,
-            0b110000 => Breakpoint,
-            0b110001 => Breakpoint,
-            0b110010 => Step,
-            0b110011 => Step,
-            0b110100 => Watchpoint,
-            0b110101 => Watchpoint,
-            0b111000 => Brk(esr as u16),
-            0b111100 => Brk(esr as u16),
+            0b101000 | 0b101100 => TrappedFpu,
+            0b101111 => SError(self::SError::from(iss)),
+            0b110000 | 0b110001 => Breakpoint,
+            0b110010 | 0b110011 => Step,
+            0b110100 | 0b110101 => Watchpoint { write: bit(6) },
+            0b111000 | 0b111100 => Brk(iss as u16),
             _ => Other(esr),
         }
     }
 }
+
+impl Syndrome {
+    /// Returns `true` if `FAR_EL1` holds the faulting address for this
+    /// exception.
+    pub fn has_fault_address(&self) -> bool {
+        match *self {
+            Syndrome::InstructionAbort { far_valid, .. } => far_valid,
+            Syndrome::DataAbort { far_valid, .. } => far_valid,
+            Syndrome::PCAlignmentFault | Syndrome::Watchpoint { .. } => true,
+            _ => false
+        }
+    }
+}
+
+fn write_level(f: &mut fmt::Formatter, level: Option<u8>) -> fmt::Result {
+    match level {
+        Some(level) => write!(f, " (level {})", level),
+        None => Ok(())
+    }
+}
+
+impl fmt::Display for Syndrome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Syndrome::*;
+
+        match *self {
+            Unknown => write!(f, "unknown reason"),
+            WfiWfe { wfe } => write!(f, "trapped {}", if wfe { "wfe" } else { "wfi" }),
+            McrMrc => write!(f, "trapped AArch32 mcr/mrc"),
+            McrrMrrc => write!(f, "trapped AArch32 mcrr/mrrc"),
+            LdcStc => write!(f, "trapped AArch32 ldc/stc"),
+            SimdFp => write!(f, "trapped SIMD/floating-point access"),
+            Vmrs => write!(f, "trapped AArch32 vmrs"),
+            Mrrc => write!(f, "trapped AArch32 mrrc"),
+            IllegalExecutionState => write!(f, "illegal execution state"),
+            Svc(imm) => write!(f, "svc #{:#x}", imm),
+            Hvc(imm) => write!(f, "hvc #{:#x}", imm),
+            Smc(imm) => write!(f, "smc #{:#x}", imm),
+            MsrMrsSystem { op0, op1, crn, crm, op2, rt, read } => {
+                let name = format!("s{}_{}_c{}_c{}_{}", op0, op1, crn, crm, op2);
+                match read {
+                    true => write!(f, "trapped mrs x{}, {}", rt, name),
+                    false => write!(f, "trapped msr {}, x{}", name, rt),
+                }
+            },
+            InstructionAbort { kind, level, .. } => {
+                write!(f, "instruction abort: {}", kind)?;
+                write_level(f, level)
+            },
+            PCAlignmentFault => write!(f, "pc alignment fault"),
+            DataAbort { kind, level, access, write, cache_maintenance, .. } => {
+                let direction = match (cache_maintenance, write) {
+                    (true, _) => "cache maintenance",
+                    (false, true) => "write",
+                    (false, false) => "read",
+                };
+                write!(f, "data abort on {}: {}", direction, kind)?;
+                write_level(f, level)?;
+                if let Some(access) = access {
+                    let register = if access.wide { 'x' } else { 'w' };
+                    write!(f, ", {}-byte access with {}{}", access.size, register, access.register)?;
+                }
+                Ok(())
+            },
+            SpAlignmentFault => write!(f, "sp alignment fault"),
+            TrappedFpu => write!(f, "floating-point exception"),
+            SError(self::SError::ImplementationDefined(iss)) => write!(f, "SError: syndrome {:#x}", iss),
+            SError(self::SError::Uncategorized) => write!(f, "SError"),
+            SError(self::SError::Asynchronous(severity)) => write!(f, "SError: {:?}", severity),
+            Breakpoint => write!(f, "breakpoint"),
+            Step => write!(f, "software step"),
+            Watchpoint { write } => write!(f, "watchpoint on {}", if write { "write" } else { "read" }),
+            Brk(imm) => write!(f, "brk #{:#x}", imm),
+            Other(esr) => write!(f, "exception class {:#x} (esr {:#x})", esr >> 26, esr),
+        }
+    }
+}
+
+/// A decoded exception with its faulting address, if it has one.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Exception {
+    pub syndrome: Syndrome,
+    pub far: Option<u64>,
+}
+
+impl Exception {
+    /// Decodes the exception with syndrome `esr`, keeping `far`, the value of
+    /// `FAR_EL1`, only if the syndrome says it is valid.
+    pub fn new(esr: u32, far: u64) -> Exception {
+        let syndrome = Syndrome::from(esr);
+        let far = if syndrome.has_fault_address() { Some(far) } else { None };
+        Exception { syndrome, far }
+    }
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.syndrome)?;
+        match self.far {
+            Some(far) => write!(f, " at {:#x}", far),
+            None => Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn translation_fault(write: bool, access: Option<Access>) -> Syndrome {
+        Syndrome::DataAbort {
+            kind: Fault::Translation,
+            level: Some(3),
+            access,
+            write,
+            cache_maintenance: false,
+            far_valid: true,
+        }
+    }
+
+    #[test]
+    fn test_decode() {
+        let x1 = Access { size: 8, register: 1, sign_extend: false, wide: true, acquire_release: false };
+        let table = [
+            (0x02000000, Syndrome::Unknown, "unknown reason"),
+            (0x06000001, Syndrome::WfiWfe { wfe: true }, "trapped wfe"),
+            (0x56000000, Syndrome::Svc(0), "svc #0x0"),
+            (0x56000005, Syndrome::Svc(5), "svc #0x5"),
+            (0x5a000007, Syndrome::Hvc(7), "hvc #0x7"),
+            (0xf2006762, Syndrome::Brk(0x6762), "brk #0x6762"),
+            (0x62300421, Syndrome::MsrMrsSystem {
+                op0: 3, op1: 0, crn: 1, crm: 0, op2: 0, rt: 1, read: true
+            }, "trapped mrs x1, s3_0_c1_c0_0"),
+            (0x92000047, translation_fault(true, None), "data abort on write: translation fault (level 3)"),
+            (0x93c18007, translation_fault(false, Some(x1)),
+             "data abort on read: translation fault (level 3), 8-byte access with x1"),
+            (0x96000021, Syndrome::DataAbort {
+                kind: Fault::Alignment, level: None, access: None,
+                write: false, cache_maintenance: false, far_valid: true
+            }, "data abort on read: alignment fault"),
+            (0x96000410, Syndrome::DataAbort {
+                kind: Fault::SynchronousExternal, level: None, access: None,
+                write: false, cache_maintenance: false, far_valid: false
+            }, "data abort on read: synchronous external abort"),
+            (0x8200000f, Syndrome::InstructionAbort {
+                kind: Fault::Permission, level: Some(3), far_valid: true
+            }, "instruction abort: permission fault (level 3)"),
+            (0x8a000000, Syndrome::PCAlignmentFault, "pc alignment fault"),
+            (0x9a000000, Syndrome::SpAlignmentFault, "sp alignment fault"),
+            (0xbf001234, Syndrome::SError(SError::ImplementationDefined(0x1234)), "SError: syndrome 0x1234"),
+            (0xbe000000, Syndrome::SError(SError::Uncategorized), "SError"),
+            (0xbe000c11, Syndrome::SError(SError::Asynchronous(Severity::Recoverable)), "SError: Recoverable"),
+            (0xc2000000, Syndrome::Breakpoint, "breakpoint"),
+            (0xca000022, Syndrome::Step, "software step"),
+            (0xd2000062, Syndrome::Watchpoint { write: true }, "watchpoint on write"),
+            (0xfe000000, Syndrome::Other(0xfe000000), "exception class 0x3f (esr 0xfe000000)"),
+        ];
+
+        for &(esr, syndrome, display) in table.iter() {
+            assert_eq!(Syndrome::from(esr), syndrome, "esr {:#x}", esr);
+            assert_eq!(syndrome.to_string(), display, "esr {:#x}", esr);
+        }
+    }
+
+    #[test]
+    fn test_fault_levels() {
+        assert_eq!(Fault::from(0b000100), Fault::Translation);
+        assert_eq!(Fault::level(0b000100), Some(0));
+        assert_eq!(Fault::level(0b001011), Some(3));
+        assert_eq!(Fault::level(0b010101), Some(1));
+        assert_eq!(Fault::level(0b100001), None);
+        assert_eq!(Fault::level(0b110000), None);
+        assert_eq!(Fault::from(0b111111), Fault::Other(0b111111));
+    }
+
+    #[test]
+    fn test_fault_address() {
+        assert_eq!(Exception::new(0x92000047, 0x1000).far, Some(0x1000));
+        assert_eq!(Exception::new(0x96000410, 0x1000).far, None);
+        assert_eq!(Exception::new(0x56000000, 0x1000).far, None);
+        assert_eq!(Exception::new(0x92000047, 0xdead0).to_string(),
+                   "data abort on write: translation fault (level 3) at 0xdead0");
+    }
+}
@@ -1,8 +1,13 @@
-use SCHEDULER;
+use std::{slice, str};
+
+use {COMPOSITOR, SCHEDULER};
+use clock::{self, Timespec, CLOCK_REALTIME};
+use compositor::{self, SurfaceId};
 use pi::timer;
 use traps::TrapFrame;
-use process::{Process, State};
-use pi::console::kprintln;
+use ipc::{self, Message, MESSAGE_SIZE};
+use process::{Thread, Image, State, Id, Descriptor, Fd, SharedFiles, Signal, Disposition};
+use syscalls::{WAIT_ANY, NO_HANDLE};
 
 /// Sleep for `ms` milliseconds.
 ///
@@ -13,33 +18,636 @@ use pi::console::kprintln;
 /// when `sleep` returned.
 pub fn sleep(ms: u32, tf: &mut TrapFrame) {
     let start_time = timer::current_time();
-    let start_time_outside = start_time.clone();
 
-    let poll_fn = Box::new(move |_p: &mut Process| {
+    let poll_fn = Box::new(move |t: &mut Thread| {
         let diff = timer::current_time().wrapping_sub(start_time);
         if diff as u32 > (ms * 1000) {
+            t.trap_frame.x7 = 0;
+            t.trap_frame.x0 = diff / 1000;
             return true;
         } else {
             return false;
         }
     });
 
-    SCHEDULER.switch(State::Waiting(poll_fn), tf);
-    tf.x7 = 0;
-    let diff = (timer::current_time().wrapping_sub(start_time_outside)) / 1000;
-    tf.x0 = diff.into();
+    let _ = SCHEDULER.switch(State::Waiting(poll_fn), tf);
 }
 
-pub fn handle_syscall(num: u16, tf: &mut TrapFrame) {
-    match num {
-        1 => {
-            kprintln!("sleep syscall");
-            sleep(tf.x0 as u32, tf);
-            tf.elr = tf.elr + 0x04;
+/// Spawn a new thread in the calling thread's process.
+///
+/// This system call takes two parameters: the address of the function the
+/// thread starts executing and a `u64` argument passed to that function. When
+/// the function returns, the thread exits with the returned value.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the ID of the new thread.
+pub fn thread_spawn(entry: u64, arg: u64, tf: &mut TrapFrame) {
+    match SCHEDULER.spawn_thread(entry, arg) {
+        Some(tid) => {
+            tf.x0 = tid;
+            tf.x7 = 0;
         },
+        None => tf.x7 = 1
+    }
+}
+
+/// Wait for a thread to exit.
+///
+/// This system call takes one parameter: the ID of the thread to wait for,
+/// which must belong to the calling process. A thread may be joined at most
+/// once.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the joined thread's exit status.
+pub fn thread_join(tid: Id, tf: &mut TrapFrame) {
+    let status = match SCHEDULER.exit_status(tid) {
+        Some(status) => status,
+        None => {
+            tf.x7 = 1;
+            return;
+        }
+    };
+
+    let poll_fn = Box::new(move |t: &mut Thread| {
+        match *status.lock() {
+            Some(code) => {
+                t.trap_frame.x7 = 0;
+                t.trap_frame.x0 = code;
+                true
+            },
+            None => false
+        }
+    });
+
+    let _ = SCHEDULER.switch(State::Waiting(poll_fn), tf);
+}
+
+/// Exit the calling thread.
+///
+/// This system call takes one parameter: the thread's exit status. It does not
+/// return.
+pub fn thread_exit(status: u64, tf: &mut TrapFrame) {
+    SCHEDULER.exit(status, tf);
+}
+
+/// Exit the calling process.
+///
+/// This system call takes one parameter: the process's exit status, reported
+/// to its parent by `waitpid`. Every thread of the process is terminated. It
+/// does not return.
+pub fn exit(status: u64, tf: &mut TrapFrame) {
+    SCHEDULER.exit_process(status, tf);
+}
+
+/// Create a copy of the calling process.
+///
+/// This system call takes no parameters. The child process starts with a copy
+/// of the calling thread only.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the child's process ID in the parent, and `0` in the child.
+pub fn fork(tf: &mut TrapFrame) {
+    match SCHEDULER.fork(tf) {
+        Some(pid) => {
+            tf.x0 = pid;
+            tf.x7 = 0;
+        },
+        None => tf.x7 = 1
+    }
+}
+
+/// Replace the calling process's program with a program from the file system.
+///
+/// This system call takes two parameters: the address and length of a UTF-8
+/// path to a flat, position-independent binary. The calling process must have
+/// a single thread. On success, this system call does not return; the program
+/// starts at its first instruction with a fresh stack.
+pub fn exec(path_ptr: u64, path_len: u64, tf: &mut TrapFrame) {
+    let path = unsafe { slice::from_raw_parts(path_ptr as *const u8, path_len as usize) };
+    let image = match str::from_utf8(path).map(Image::load) {
+        Ok(Ok(image)) => image,
         _ => {
             tf.x7 = 1;
-            tf.elr = tf.elr + 0x04;
+            return;
+        }
+    };
+
+    if !SCHEDULER.exec(image, tf) {
+        tf.x7 = 1;
+    }
+}
+
+/// Wait for a child process to exit.
+///
+/// This system call takes one parameter: the ID of the child to wait for, or
+/// `WAIT_ANY` to wait for any child.
+///
+/// In addition to the usual status value, this system call returns two
+/// parameters: the ID of the child that exited and its exit status.
+pub fn waitpid(pid: u64, tf: &mut TrapFrame) {
+    let children = match SCHEDULER.children() {
+        Some(children) => children,
+        None => {
+            tf.x7 = 1;
+            return;
+        }
+    };
+
+    let has_child = {
+        let children = children.lock();
+        match pid {
+            WAIT_ANY => !children.is_empty(),
+            pid => children.contains_key(&pid)
+        }
+    };
+
+    if !has_child {
+        tf.x7 = 1;
+        return;
+    }
+
+    let poll_fn = Box::new(move |t: &mut Thread| {
+        let mut children = children.lock();
+        let exited = children.iter()
+            .filter(|&(&id, _)| pid == WAIT_ANY || id == pid)
+            .filter_map(|(&id, status)| status.lock().map(|code| (id, code)))
+            .next();
+
+        match exited {
+            Some((id, code)) => {
+                children.remove(&id);
+                t.trap_frame.x7 = 0;
+                t.trap_frame.x0 = id;
+                t.trap_frame.x1 = code;
+                true
+            },
+            None => false
+        }
+    });
+
+    let _ = SCHEDULER.switch(State::Waiting(poll_fn), tf);
+}
+
+/// Runs `attempt` on `tf`. If it returns `false` because the operation would
+/// block, the calling thread waits, retrying `attempt` on its own trap frame
+/// each time it is polled until it returns `true`.
+fn block_on<F>(mut attempt: F, tf: &mut TrapFrame)
+    where F: FnMut(&mut TrapFrame) -> bool + Send + 'static
+{
+    if attempt(tf) {
+        return;
+    }
+
+    let poll_fn = Box::new(move |t: &mut Thread| attempt(&mut t.trap_frame));
+    let _ = SCHEDULER.switch(State::Waiting(poll_fn), tf);
+}
+
+/// Sets the return values in `tf` for the outcome of an IPC operation.
+/// Returns `false`, leaving `tf` untouched, if the operation would block.
+fn complete(result: Result<u64, ipc::Error>, tf: &mut TrapFrame) -> bool {
+    match result {
+        Ok(value) => {
+            tf.x0 = value;
+            tf.x7 = 0;
+            true
+        },
+        Err(ipc::Error::WouldBlock) => false,
+        Err(error) => {
+            tf.x7 = error as u64;
+            true
+        }
+    }
+}
+
+/// Returns the calling process's file descriptor table, reporting an error in
+/// `tf` if there is none.
+fn current_files(tf: &mut TrapFrame) -> Option<SharedFiles> {
+    let files = SCHEDULER.files();
+    if files.is_none() {
+        tf.x7 = ipc::Error::BadDescriptor as u64;
+    }
+
+    files
+}
+
+/// Create an anonymous pipe.
+///
+/// This system call takes no parameters.
+///
+/// In addition to the usual status value, this system call returns two
+/// parameters: the file descriptors of the read end and the write end.
+pub fn pipe(tf: &mut TrapFrame) {
+    let files = match current_files(tf) {
+        Some(files) => files,
+        None => return
+    };
+
+    let (reader, writer) = ipc::pipe();
+    let mut files = files.lock();
+    tf.x0 = files.insert(Descriptor::PipeReader(reader));
+    tf.x1 = files.insert(Descriptor::PipeWriter(writer));
+    tf.x7 = 0;
+}
+
+/// Read from a file descriptor.
+///
+/// This system call takes three parameters: the file descriptor and the
+/// address and length of the buffer to read into. It blocks until at least
+/// one byte is available or the end of file is reached.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the number of bytes read, `0` at end of file.
+pub fn read(fd: Fd, buf: u64, len: u64, tf: &mut TrapFrame) {
+    let files = match current_files(tf) {
+        Some(files) => files,
+        None => return
+    };
+
+    block_on(move |tf: &mut TrapFrame| {
+        let buf = unsafe { slice::from_raw_parts_mut(buf as *mut u8, len as usize) };
+        let result = match files.lock().get(fd) {
+            Some(&Descriptor::PipeReader(ref reader)) => reader.try_read(buf),
+            _ => Err(ipc::Error::BadDescriptor)
+        };
+
+        complete(result.map(|n| n as u64), tf)
+    }, tf);
+}
+
+/// Write to a file descriptor.
+///
+/// This system call takes three parameters: the file descriptor and the
+/// address and length of the buffer to write from. It blocks until at least
+/// one byte can be written.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the number of bytes written.
+pub fn write(fd: Fd, buf: u64, len: u64, tf: &mut TrapFrame) {
+    let files = match current_files(tf) {
+        Some(files) => files,
+        None => return
+    };
+
+    block_on(move |tf: &mut TrapFrame| {
+        let buf = unsafe { slice::from_raw_parts(buf as *const u8, len as usize) };
+        let result = match files.lock().get(fd) {
+            Some(&Descriptor::PipeWriter(ref writer)) => writer.try_write(buf),
+            _ => Err(ipc::Error::BadDescriptor)
+        };
+
+        complete(result.map(|n| n as u64), tf)
+    }, tf);
+}
+
+/// Close a file descriptor.
+///
+/// This system call takes one parameter: the file descriptor to close.
+pub fn close(fd: Fd, tf: &mut TrapFrame) {
+    let files = match current_files(tf) {
+        Some(files) => files,
+        None => return
+    };
+
+    let descriptor = files.lock().remove(fd);
+    tf.x7 = match descriptor {
+        Some(_) => 0,
+        None => ipc::Error::BadDescriptor as u64
+    };
+}
+
+/// Create a bidirectional message channel.
+///
+/// This system call takes no parameters.
+///
+/// In addition to the usual status value, this system call returns two
+/// parameters: the file descriptors of the channel's two ends.
+pub fn channel(tf: &mut TrapFrame) {
+    let files = match current_files(tf) {
+        Some(files) => files,
+        None => return
+    };
+
+    let (a, b) = ipc::channel();
+    let mut files = files.lock();
+    tf.x0 = files.insert(Descriptor::Channel(a));
+    tf.x1 = files.insert(Descriptor::Channel(b));
+    tf.x7 = 0;
+}
+
+/// Send a message over a channel.
+///
+/// This system call takes four parameters: the channel's file descriptor, the
+/// address and length of at most `MESSAGE_SIZE` bytes of data, and a file
+/// descriptor to pass to the receiver or `NO_HANDLE`. The passed descriptor
+/// stays open in the sender. This system call blocks while the channel is
+/// full.
+pub fn send(fd: Fd, buf: u64, len: u64, handle: Fd, tf: &mut TrapFrame) {
+    let files = match current_files(tf) {
+        Some(files) => files,
+        None => return
+    };
+
+    block_on(move |tf: &mut TrapFrame| {
+        let data = unsafe { slice::from_raw_parts(buf as *const u8, len as usize) };
+        let files = files.lock();
+        let handle = match handle {
+            NO_HANDLE => None,
+            handle => match files.get(handle) {
+                Some(descriptor) => Some(descriptor.clone()),
+                None => return complete(Err(ipc::Error::BadDescriptor), tf)
+            }
+        };
+
+        let result = match (files.get(fd), Message::new(data, handle)) {
+            (Some(&Descriptor::Channel(ref endpoint)), Ok(message)) => {
+                endpoint.try_send(message).map(|_| 0).map_err(|(error, _)| error)
+            },
+            (Some(&Descriptor::Channel(_)), Err(error)) => Err(error),
+            _ => Err(ipc::Error::BadDescriptor)
+        };
+
+        complete(result, tf)
+    }, tf);
+}
+
+/// Receive a message from a channel.
+///
+/// This system call takes two parameters: the channel's file descriptor and
+/// the address of a `MESSAGE_SIZE` byte buffer for the message's data. It
+/// blocks until a message arrives.
+///
+/// In addition to the usual status value, this system call returns two
+/// parameters: the length of the message's data and the new file descriptor
+/// of the descriptor passed with the message, or `NO_HANDLE`.
+pub fn recv(fd: Fd, buf: u64, tf: &mut TrapFrame) {
+    let files = match current_files(tf) {
+        Some(files) => files,
+        None => return
+    };
+
+    block_on(move |tf: &mut TrapFrame| {
+        let mut files = files.lock();
+        let message = match files.get(fd) {
+            Some(&Descriptor::Channel(ref endpoint)) => endpoint.try_recv(),
+            _ => Err(ipc::Error::BadDescriptor)
+        };
+
+        let message = match message {
+            Ok(message) => message,
+            Err(error) => return complete(Err(error), tf)
+        };
+
+        let len = message.data().len();
+        let buf = unsafe { slice::from_raw_parts_mut(buf as *mut u8, MESSAGE_SIZE) };
+        buf[..len].copy_from_slice(message.data());
+        tf.x1 = match message.handle {
+            Some(descriptor) => files.insert(descriptor),
+            None => NO_HANDLE
+        };
+
+        complete(Ok(len as u64), tf)
+    }, tf);
+}
+
+/// Set how the calling process handles a signal.
+///
+/// This system call takes three parameters: the signal number, the handler
+/// (`SIG_DFL`, `SIG_IGN`, or the address of an `extern "C" fn(signal, frame)`)
+/// and the address the handler returns to, which must call `sigreturn`.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the previous handler.
+pub fn sigaction(signal: u64, handler: u64, restorer: u64, tf: &mut TrapFrame) {
+    let disposition = Disposition::from_raw(handler, restorer);
+    match Signal::from_number(signal).and_then(|s| SCHEDULER.sigaction(s, disposition)) {
+        Some(previous) => {
+            tf.x0 = previous.to_raw();
+            tf.x7 = 0;
+        },
+        None => tf.x7 = 1
+    }
+}
+
+/// Send a signal to a process.
+///
+/// This system call takes two parameters: the process ID and the signal
+/// number. A signal number of `0` only checks that the process exists.
+pub fn kill(pid: Id, signal: u64, tf: &mut TrapFrame) {
+    let signal = match signal {
+        0 => None,
+        number => match Signal::from_number(number) {
+            Some(signal) => Some(signal),
+            None => {
+                tf.x7 = 1;
+                return;
+            }
+        }
+    };
+
+    tf.x7 = match SCHEDULER.kill(pid, signal) {
+        true => 0,
+        false => 1
+    };
+}
+
+/// Return from a signal handler.
+///
+/// This system call takes no parameters; the stack pointer must point at the
+/// signal frame the handler was called with. On success, the interrupted code
+/// resumes and this system call does not return.
+pub fn sigreturn(tf: &mut TrapFrame) {
+    if !SCHEDULER.sigreturn(tf) {
+        tf.x7 = 1;
+    }
+}
+
+/// Make a process the foreground process, which receives `SIGINT` when Ctrl-C
+/// is pressed on the console.
+///
+/// This system call takes one parameter: the process ID.
+pub fn set_foreground(pid: Id, tf: &mut TrapFrame) {
+    tf.x7 = match SCHEDULER.set_foreground(pid) {
+        true => 0,
+        false => 1
+    };
+}
+
+/// Read a clock.
+///
+/// This system call takes one parameter: the clock to read, either
+/// `CLOCK_REALTIME` for UTC time since the Unix epoch or `CLOCK_MONOTONIC` for
+/// the time since boot.
+///
+/// In addition to the usual status value, this system call returns two
+/// parameters: the clock's seconds and nanoseconds.
+pub fn clock_gettime(clock_id: u64, tf: &mut TrapFrame) {
+    match clock::get(clock_id) {
+        Some(time) => {
+            tf.x0 = time.secs;
+            tf.x1 = time.nanos as u64;
+            tf.x7 = 0;
+        },
+        None => tf.x7 = 1
+    }
+}
+
+/// Set a clock.
+///
+/// This system call takes three parameters: the clock to set, which must be
+/// `CLOCK_REALTIME`, and the seconds and nanoseconds since the Unix epoch. The
+/// real-time clock chip, if there is one, is set as well.
+pub fn clock_settime(clock_id: u64, secs: u64, nanos: u64, tf: &mut TrapFrame) {
+    if clock_id != CLOCK_REALTIME || nanos >= 1_000_000_000 {
+        tf.x7 = 1;
+        return;
+    }
+
+    tf.x7 = match clock::set(Timespec { secs, nanos: nanos as u32 }) {
+        true => 0,
+        false => 1
+    };
+}
+
+/// Sets the return values in `tf` for the outcome of a surface operation.
+/// Returns `false`, leaving `tf` untouched, if the operation would block.
+fn complete_surface(result: Result<u64, compositor::Error>, tf: &mut TrapFrame) -> bool {
+    match result {
+        Ok(value) => {
+            tf.x0 = value;
+            tf.x7 = 0;
+            true
+        },
+        Err(compositor::Error::WouldBlock) => false,
+        Err(error) => {
+            tf.x7 = error as u64;
+            true
         }
     }
 }
+
+/// Returns the calling thread's process ID, reporting an error in `tf` if
+/// there is none.
+fn current_pid(tf: &mut TrapFrame) -> Option<Id> {
+    let pid = SCHEDULER.current_pid();
+    if pid.is_none() {
+        tf.x7 = compositor::Error::BadSurface as u64;
+    }
+
+    pid
+}
+
+/// Create a surface to draw on, on top of the calling process's other
+/// surfaces and everyone else's.
+///
+/// This system call takes four parameters: the surface's position on the
+/// desktop, which may be negative and is clamped to just off its edges, and
+/// its width and height. The surface starts out transparent.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the ID of the new surface.
+pub fn surface_create(x: u64, y: u64, width: u64, height: u64, tf: &mut TrapFrame) {
+    if let Some(pid) = current_pid(tf) {
+        let result = COMPOSITOR.create(pid, x as i64, y as i64, width as usize, height as usize);
+        complete_surface(result, tf);
+    }
+}
+
+/// Destroy a surface. A process's surfaces are destroyed when it exits.
+///
+/// This system call takes one parameter: the surface's ID.
+pub fn surface_destroy(id: SurfaceId, tf: &mut TrapFrame) {
+    if let Some(pid) = current_pid(tf) {
+        complete_surface(COMPOSITOR.destroy(pid, id).map(|_| 0), tf);
+    }
+}
+
+/// Draw on a surface.
+///
+/// This system call takes six parameters: the surface's ID, the address of
+/// the pixels to draw, as `u32`s of the form `0xAARRGGBB` row by row, and the
+/// position, width and height of the rectangle of the surface they replace.
+pub fn surface_update(id: SurfaceId, pixels: u64, x: u64, y: u64, width: u64, height: u64,
+                      tf: &mut TrapFrame) {
+    if let Some(pid) = current_pid(tf) {
+        let len = (width as usize).saturating_mul(height as usize);
+        let pixels = unsafe { slice::from_raw_parts(pixels as *const u32, len) };
+        let result = COMPOSITOR.update(pid, id, x as usize, y as usize,
+                                       width as usize, height as usize, pixels);
+        complete_surface(result.map(|_| 0), tf);
+    }
+}
+
+/// Move a surface.
+///
+/// This system call takes three parameters: the surface's ID and its new
+/// position on the desktop.
+pub fn surface_move(id: SurfaceId, x: u64, y: u64, tf: &mut TrapFrame) {
+    if let Some(pid) = current_pid(tf) {
+        complete_surface(COMPOSITOR.move_to(pid, id, x as i64, y as i64).map(|_| 0), tf);
+    }
+}
+
+/// Raise a surface above all others and give it the keyboard focus.
+///
+/// This system call takes one parameter: the surface's ID.
+pub fn surface_raise(id: SurfaceId, tf: &mut TrapFrame) {
+    if let Some(pid) = current_pid(tf) {
+        complete_surface(COMPOSITOR.raise(pid, id).map(|_| 0), tf);
+    }
+}
+
+/// Read console input sent to a surface while it had the keyboard focus.
+///
+/// This system call takes three parameters: the surface's ID and the address
+/// and length of the buffer to read into. It blocks until at least one byte
+/// is available.
+///
+/// In addition to the usual status value, this system call returns one
+/// parameter: the number of bytes read.
+pub fn surface_read(id: SurfaceId, buf: u64, len: u64, tf: &mut TrapFrame) {
+    let pid = match current_pid(tf) {
+        Some(pid) => pid,
+        None => return
+    };
+
+    block_on(move |tf: &mut TrapFrame| {
+        let buf = unsafe { slice::from_raw_parts_mut(buf as *mut u8, len as usize) };
+        complete_surface(COMPOSITOR.read(pid, id, buf).map(|n| n as u64), tf)
+    }, tf);
+}
+
+pub fn handle_syscall(num: u16, tf: &mut TrapFrame) {
+    // `elr` already points past the `svc` instruction. Handlers that block
+    // replace `tf` with the next thread's trap frame, so they set their return
+    // values from the event poll function instead of after `switch` returns.
+    match num {
+        1 => sleep(tf.x0 as u32, tf),
+        2 => thread_spawn(tf.x0, tf.x1, tf),
+        3 => thread_join(tf.x0, tf),
+        4 => thread_exit(tf.x0, tf),
+        5 => exit(tf.x0, tf),
+        6 => fork(tf),
+        7 => exec(tf.x0, tf.x1, tf),
+        8 => waitpid(tf.x0, tf),
+        9 => pipe(tf),
+        10 => read(tf.x0, tf.x1, tf.x2, tf),
+        11 => write(tf.x0, tf.x1, tf.x2, tf),
+        12 => close(tf.x0, tf),
+        13 => channel(tf),
+        14 => send(tf.x0, tf.x1, tf.x2, tf.x3, tf),
+        15 => recv(tf.x0, tf.x1, tf),
+        16 => sigaction(tf.x0, tf.x1, tf.x2, tf),
+        17 => kill(tf.x0, tf.x1, tf),
+        18 => sigreturn(tf),
+        19 => set_foreground(tf.x0, tf),
+        20 => clock_gettime(tf.x0, tf),
+        21 => clock_settime(tf.x0, tf.x1, tf.x2, tf),
+        22 => surface_create(tf.x0, tf.x1, tf.x2, tf.x3, tf),
+        23 => surface_destroy(tf.x0, tf),
+        24 => surface_update(tf.x0, tf.x1, tf.x2, tf.x3, tf.x4, tf.x5, tf),
+        25 => surface_move(tf.x0, tf.x1, tf.x2, tf),
+        26 => surface_raise(tf.x0, tf),
+        27 => surface_read(tf.x0, tf.x1, tf.x2, tf),
+        _ => tf.x7 = 1
+    }
+}
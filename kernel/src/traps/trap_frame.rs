@@ -71,3 +71,25 @@ pub struct TrapFrame {
     pub x30: u64,
     pub x0: u64,
 }
+
+impl TrapFrame {
+    /// Returns `true` if restoring this frame returns to EL0.
+    pub fn is_el0(&self) -> bool {
+        self.spsr & 0b1111 == 0
+    }
+
+    /// Returns general purpose register `n`, `x0` to `x30`.
+    pub fn register_mut(&mut self, n: usize) -> Option<&mut u64> {
+        Some(match n {
+            0 => &mut self.x0, 1 => &mut self.x1, 2 => &mut self.x2, 3 => &mut self.x3,
+            4 => &mut self.x4, 5 => &mut self.x5, 6 => &mut self.x6, 7 => &mut self.x7,
+            8 => &mut self.x8, 9 => &mut self.x9, 10 => &mut self.x10, 11 => &mut self.x11,
+            12 => &mut self.x12, 13 => &mut self.x13, 14 => &mut self.x14, 15 => &mut self.x15,
+            16 => &mut self.x16, 17 => &mut self.x17, 18 => &mut self.x18, 19 => &mut self.x19,
+            20 => &mut self.x20, 21 => &mut self.x21, 22 => &mut self.x22, 23 => &mut self.x23,
+            24 => &mut self.x24, 25 => &mut self.x25, 26 => &mut self.x26, 27 => &mut self.x27,
+            28 => &mut self.x28, 29 => &mut self.x29, 30 => &mut self.x30,
+            _ => return None
+        })
+    }
+}
@@ -0,0 +1,241 @@
+//! The kernel's address space: an identity mapping of physical memory with
+//! the MMU and caches on.
+//!
+//! The MMU has to be on before any core takes a lock. With it off, every
+//! data access is to Device memory, and the exclusive loads and stores behind
+//! `Mutex` and the atomics only work on cacheable Normal memory: on the
+//! BCM2837, nothing monitors them outside the caches, so a store-exclusive
+//! may fail forever. Core 0 calls `initialize` first thing in `kmain`, and
+//! cores 1-3 call `enable` first thing in `kinit_secondary`.
+//!
+//! RAM below `IO_BASE` is Normal, write-back cacheable memory shared by all
+//! cores; the peripherals above it are Device memory. Both are reachable from
+//! EL0, which runs kernel code and drives hardware directly. The kernel's code
+//! and read-only data are read-only at every level, since memory EL0 may write
+//! can't be executed at EL1. The kernel writes code through `writable`, which
+//! returns the address of the same memory in an alias only EL1 can reach.
+//!
+//! The GPU and the DMA engine don't see the ARM's caches: memory they read or
+//! write must be cleaned or invalidated with the `pi::aarch64` cache functions.
+
+use pi::common::IO_BASE;
+
+extern "C" {
+    static _start: u8;
+    static __text_end: u8;
+}
+
+/// The number of entries in a translation table.
+const ENTRIES: usize = 512;
+
+/// The size of a page, mapped by an L3 entry.
+const PAGE_SIZE: usize = 1 << 12;
+
+/// The size of a block mapped by an L2 entry.
+const BLOCK_SIZE: usize = 1 << 21;
+
+/// The size of a block mapped by an L1 entry.
+const GIGABYTE: usize = 1 << 30;
+
+/// Where the ARM local peripherals, like the per-core timers and mailboxes,
+/// start.
+const LOCAL_BASE: usize = 0x4000_0000;
+
+/// Where `writable`'s alias of RAM starts.
+const ALIAS_BASE: usize = 0x8000_0000;
+
+/// Descriptor: the entry is valid.
+const VALID: u64 = 1 << 0;
+/// Descriptor: points to the next level's table. At L3, marks a page.
+const TABLE: u64 = 1 << 1;
+/// Descriptor: the memory attributes are `MAIR_EL1`'s attribute 0.
+const ATTR_NORMAL: u64 = 0 << 2;
+/// Descriptor: the memory attributes are `MAIR_EL1`'s attribute 1.
+const ATTR_DEVICE: u64 = 1 << 2;
+/// Descriptor: EL0 has the same access as EL1 (`AP[1]`).
+const AP_EL0: u64 = 1 << 6;
+/// Descriptor: read-only (`AP[2]`).
+const AP_RO: u64 = 1 << 7;
+/// Descriptor: coherent between all cores.
+const INNER_SHAREABLE: u64 = 0b11 << 8;
+/// Descriptor: the access flag, set so first accesses don't fault.
+const AF: u64 = 1 << 10;
+/// Descriptor: EL1 may not execute from here.
+const PXN: u64 = 1 << 53;
+/// Descriptor: EL0 may not execute from here.
+const UXN: u64 = 1 << 54;
+
+/// RAM: readable, writable and, for loaded programs, executable by EL0.
+const DATA: u64 = ATTR_NORMAL | INNER_SHAREABLE | AF | AP_EL0 | PXN;
+/// The kernel's code and read-only data.
+const TEXT: u64 = ATTR_NORMAL | INNER_SHAREABLE | AF | AP_EL0 | AP_RO;
+/// The peripherals.
+const DEVICE: u64 = ATTR_DEVICE | AF | AP_EL0 | PXN | UXN;
+/// `writable`'s alias of RAM.
+const ALIAS: u64 = ATTR_NORMAL | INNER_SHAREABLE | AF | PXN | UXN;
+
+/// `MAIR_EL1`: attribute 0 is Normal memory, write-back cacheable with read
+/// and write allocation; attribute 1 is Device-nGnRnE memory, the same as
+/// every data access with the MMU off.
+const MAIR: u64 = 0xff;
+
+/// `TCR_EL1`: a 4GiB address space (`T0SZ`) translated from `TTBR0_EL1` with
+/// 4KiB pages, walking write-back cached, inner shareable tables. Lookups
+/// start at L1. `TTBR1_EL1` isn't used (`EPD1`).
+const TCR: u64 = 32 | 0b01 << 8 | 0b01 << 10 | 0b11 << 12 | 1 << 23;
+
+/// `SCTLR_EL1`: the MMU (`M`), the data cache (`C`), the instruction cache
+/// (`I`) and cache maintenance from EL0 (`UCI`).
+const SCTLR_ENABLE: u64 = 1 << 0 | 1 << 2 | 1 << 12 | 1 << 26;
+
+/// A translation table.
+#[repr(C, align(4096))]
+struct Table([u64; ENTRIES]);
+
+/// The kernel's translation tables. At most two 2MiB blocks hold both
+/// kernel text and other memory, one at each end of the text, and only
+/// those are mapped page by page.
+struct Tables {
+    l1: Table,
+    l2: Table,
+    alias: Table,
+    l3: [Table; 2],
+}
+
+/// Written by core 0 in `initialize`, before any other core runs, and only
+/// read by the MMU afterwards.
+static mut TABLES: Tables = Tables {
+    l1: Table([0; ENTRIES]),
+    l2: Table([0; ENTRIES]),
+    alias: Table([0; ENTRIES]),
+    l3: [Table([0; ENTRIES]), Table([0; ENTRIES])],
+};
+
+/// Returns the start and end of the kernel's code and read-only data.
+fn text() -> (usize, usize) {
+    unsafe { (&_start as *const u8 as usize, &__text_end as *const u8 as usize) }
+}
+
+/// Returns the attributes of the RAM from `start` up to `end` given the
+/// kernel text is at `text`, or `None` if only part of it is text.
+fn attributes(start: usize, end: usize, text: (usize, usize)) -> Option<u64> {
+    match (start, end) {
+        (start, end) if end <= text.0 || start >= text.1 => Some(DATA),
+        (start, end) if start >= text.0 && end <= text.1 => Some(TEXT),
+        _ => None,
+    }
+}
+
+/// Builds the kernel's translation tables, then turns on the MMU and caches
+/// of the calling core.
+///
+/// # Safety
+///
+/// Must be called once, from core 0, before any lock is taken and before the
+/// other cores are started.
+pub unsafe fn initialize() {
+    let text = text();
+    let tables = &mut TABLES;
+
+    let mut l3 = tables.l3.iter_mut();
+    for (i, entry) in tables.l2.0.iter_mut().enumerate() {
+        let base = i * BLOCK_SIZE;
+        if base >= IO_BASE {
+            *entry = base as u64 | DEVICE | VALID;
+            continue;
+        }
+
+        *entry = match attributes(base, base + BLOCK_SIZE, text) {
+            Some(flags) => base as u64 | flags | VALID,
+            None => {
+                let table = l3.next().expect("kernel text spans too many blocks");
+                for (j, page) in table.0.iter_mut().enumerate() {
+                    let address = base + j * PAGE_SIZE;
+                    let flags = attributes(address, address + PAGE_SIZE, text).unwrap_or(TEXT);
+                    *page = address as u64 | flags | TABLE | VALID;
+                }
+                &table.0 as *const _ as u64 | TABLE | VALID
+            }
+        };
+    }
+
+    for (i, entry) in tables.alias.0.iter_mut().enumerate() {
+        let base = i * BLOCK_SIZE;
+        if base < IO_BASE {
+            *entry = base as u64 | ALIAS | VALID;
+        }
+    }
+
+    tables.l1.0[0] = &tables.l2 as *const _ as u64 | TABLE | VALID;
+    tables.l1.0[LOCAL_BASE / GIGABYTE] = LOCAL_BASE as u64 | DEVICE | VALID;
+    tables.l1.0[ALIAS_BASE / GIGABYTE] = &tables.alias as *const _ as u64 | TABLE | VALID;
+
+    enable();
+}
+
+/// Turns on the MMU and caches of the calling core, using the tables built by
+/// `initialize`.
+///
+/// # Safety
+///
+/// `initialize` must have returned on core 0. Must be called before the
+/// calling core takes a lock.
+pub unsafe fn enable() {
+    let l1 = &TABLES.l1 as *const Table as u64;
+    asm!("msr MAIR_EL1, $0
+          msr TCR_EL1, $1
+          msr TTBR0_EL1, $2
+          isb
+          tlbi vmalle1
+          dsb ish
+          isb"
+         :: "r"(MAIR), "r"(TCR), "r"(l1) : "memory" : "volatile");
+
+    let sctlr: u64;
+    asm!("mrs $0, SCTLR_EL1" : "=r"(sctlr) ::: "volatile");
+    asm!("msr SCTLR_EL1, $0
+          isb"
+         :: "r"(sctlr | SCTLR_ENABLE) : "memory" : "volatile");
+}
+
+/// Returns an address through which EL1 can write the byte at `addr`, even
+/// if it's read-only kernel text. Instructions written this way must still
+/// be made visible with `pi::aarch64::sync_icache` on `addr`.
+pub fn writable(addr: usize) -> usize {
+    match addr < IO_BASE {
+        true => ALIAS_BASE + addr,
+        false => addr,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+    use super::*;
+
+    #[test]
+    fn test_attributes() {
+        let text = (0x80000, 0x283000);
+        assert_eq!(attributes(0, BLOCK_SIZE, text), None);
+        assert_eq!(attributes(BLOCK_SIZE, 2 * BLOCK_SIZE, text), None);
+        assert_eq!(attributes(2 * BLOCK_SIZE, 3 * BLOCK_SIZE, text), Some(DATA));
+        assert_eq!(attributes(0x7f000, 0x80000, text), Some(DATA));
+        assert_eq!(attributes(0x80000, 0x81000, text), Some(TEXT));
+        assert_eq!(attributes(0x282000, 0x283000, text), Some(TEXT));
+        assert_eq!(attributes(0x283000, 0x284000, text), Some(DATA));
+
+        let large = (0x80000, 3 * BLOCK_SIZE + 0x1000);
+        assert_eq!(attributes(BLOCK_SIZE, 2 * BLOCK_SIZE, large), Some(TEXT));
+    }
+
+    #[test]
+    fn test_descriptor_bits() {
+        assert_eq!(mem::align_of::<Table>(), PAGE_SIZE);
+        assert_eq!(mem::size_of::<Table>(), PAGE_SIZE);
+        assert_eq!(DATA & (AP_RO | UXN), 0);
+        assert_eq!(TEXT & (PXN | UXN), 0);
+        assert_eq!(ALIAS & AP_EL0, 0);
+        assert_eq!(writable(0x80000), 0x8008_0000);
+        assert_eq!(writable(IO_BASE + 0x20_0000), IO_BASE + 0x20_0000);
+    }
+}
@@ -1,3 +1,4 @@
 mod address;
+pub mod mmu;
 
 pub use self::address::{PhysicalAddr, VirtualAddr};
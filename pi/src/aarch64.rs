@@ -0,0 +1,138 @@
+/// Returns the core currently executing.
+///
+/// The core number is read from `TPIDRRO_EL0`, which `init.S` seeds from
+/// `MPIDR_EL1` on every core before entering Rust. Unlike `MPIDR_EL1`, this
+/// register is readable from EL0, so this function may be called from any
+/// exception level.
+#[inline(always)]
+pub fn affinity() -> usize {
+    #[cfg(not(test))]
+    {
+        let x: usize;
+        unsafe {
+            asm!("mrs $0, TPIDRRO_EL0" : "=r"(x));
+        }
+        x & 0b11
+    }
+
+    #[cfg(test)]
+    { 0 }
+}
+
+/// Masks IRQs on the current core and returns the previous value of `DAIF`
+/// so it can later be restored with `restore_irqs`.
+///
+/// # Safety
+///
+/// At EL0 this requires `SCTLR_EL1.UMA` to be set, which `init.S` does.
+#[inline(always)]
+pub unsafe fn disable_irqs() -> u64 {
+    #[cfg(not(test))]
+    {
+        let daif: u64;
+        asm!("mrs $0, DAIF
+              msr DAIFSet, #0b0010"
+              : "=r"(daif) ::: "volatile");
+        daif
+    }
+
+    #[cfg(test)]
+    { 0 }
+}
+
+/// Restores `DAIF` to `daif`, a value previously returned by `disable_irqs`.
+///
+/// # Safety
+///
+/// At EL0 this requires `SCTLR_EL1.UMA` to be set, which `init.S` does.
+#[inline(always)]
+pub unsafe fn restore_irqs(daif: u64) {
+    #[cfg(not(test))]
+    asm!("msr DAIF, $0" :: "r"(daif) :: "volatile");
+
+    #[cfg(test)]
+    { let _ = daif; }
+}
+
+/// Returns `true` if IRQs are masked on the current core.
+#[inline(always)]
+pub fn irqs_masked() -> bool {
+    #[cfg(not(test))]
+    {
+        let daif: u64;
+        unsafe {
+            asm!("mrs $0, DAIF" : "=r"(daif));
+        }
+        daif & (1 << 7) != 0
+    }
+
+    #[cfg(test)]
+    { false }
+}
+
+/// Hints to the core that it is spinning on a lock.
+#[inline(always)]
+pub fn spin_hint() {
+    #[cfg(not(test))]
+    unsafe {
+        asm!("yield" :::: "volatile");
+    }
+}
+
+/// The size of a data and instruction cache line on the Cortex-A53.
+pub const CACHE_LINE_SIZE: usize = 64;
+
+/// Runs the cache maintenance instruction `op` on every line covering `len`
+/// bytes at `addr`, then waits for it to complete.
+macro_rules! for_each_line {
+    ($op:expr, $addr:expr, $len:expr) => {{
+        #[cfg(not(test))]
+        {
+            let end = $addr + $len;
+            let mut line = $addr & !(CACHE_LINE_SIZE - 1);
+            while line < end {
+                unsafe { asm!(concat!($op, ", $0") :: "r"(line) :: "volatile") };
+                line += CACHE_LINE_SIZE;
+            }
+            unsafe { asm!("dsb sy" ::: "memory" : "volatile") };
+        }
+
+        #[cfg(test)]
+        { let _ = ($addr, $len); }
+    }}
+}
+
+/// Writes the cached data covering `len` bytes at `addr` back to memory, so
+/// the GPU and the DMA engine, which don't see the ARM's caches, read what
+/// was last written there.
+///
+/// At EL0 this requires `SCTLR_EL1.UCI` to be set, which `vm::enable` does.
+#[inline(always)]
+pub fn clean_dcache(addr: usize, len: usize) {
+    for_each_line!("dc cvac", addr, len);
+}
+
+/// Writes the cached data covering `len` bytes at `addr` back to memory and
+/// drops it from the caches, so the next reads fetch whatever the GPU or the
+/// DMA engine has written there since.
+///
+/// At EL0 this requires `SCTLR_EL1.UCI` to be set, which `vm::enable` does.
+#[inline(always)]
+pub fn clean_invalidate_dcache(addr: usize, len: usize) {
+    for_each_line!("dc civac", addr, len);
+}
+
+/// Makes instructions written to the `len` bytes at `addr` visible to
+/// instruction fetches on every core.
+///
+/// At EL0 this requires `SCTLR_EL1.UCI` to be set, which `vm::enable` does.
+#[inline(always)]
+pub fn sync_icache(addr: usize, len: usize) {
+    for_each_line!("dc cvau", addr, len);
+    for_each_line!("ic ivau", addr, len);
+
+    #[cfg(not(test))]
+    unsafe {
+        asm!("isb" :::: "volatile");
+    }
+}
@@ -58,7 +58,7 @@ unsafe impl GlobalAlloc for Allocator {
     /// (`AllocError::Exhausted`) or `layout` does not meet this allocator's
     /// size or alignment constraints (`AllocError::Unsupported`).
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        self.0.lock().as_mut().expect("allocator uninitialized").alloc(layout).expect("allocate failed")
+        self.0.lock_irq().as_mut().expect("allocator uninitialized").alloc(layout).expect("allocate failed")
     }
 
     /// Deallocates the memory referenced by `ptr`.
@@ -75,7 +75,7 @@ unsafe impl GlobalAlloc for Allocator {
     /// Parameters not meeting these conditions may result in undefined
     /// behavior.
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        self.0.lock().as_mut().expect("allocator uninitialized").dealloc(ptr, layout);
+        self.0.lock_irq().as_mut().expect("allocator uninitialized").dealloc(ptr, layout);
     }
 }
 
@@ -101,7 +101,7 @@ unsafe impl<'a> Alloc for &'a Allocator {
     /// (`AllocError::Exhausted`) or `layout` does not meet this allocator's
     /// size or alignment constraints (`AllocError::Unsupported`).
     unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
-        match self.0.lock().as_mut().expect("allocator uninitialized").alloc(layout) {
+        match self.0.lock_irq().as_mut().expect("allocator uninitialized").alloc(layout) {
             Err(err) => Err(err),
             Ok(ptr) => {
                 Ok(NonNull::new(ptr).expect("allocator non null"))
@@ -123,7 +123,7 @@ unsafe impl<'a> Alloc for &'a Allocator {
     /// Parameters not meeting these conditions may result in undefined
     /// behavior.
     unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
-        self.0.lock().as_mut().expect("allocator uninitialized").dealloc(ptr.as_ptr(), layout);
+        self.0.lock_irq().as_mut().expect("allocator uninitialized").dealloc(ptr.as_ptr(), layout);
     }
 }
 
@@ -0,0 +1,205 @@
+//! A parser for the ANSI/VT100 escape sequences a terminal understands.
+//!
+//! Bytes are fed to `Parser::advance` one at a time. Printable bytes and
+//! control characters come straight back out; escape sequences are buffered
+//! until they are complete and then returned as a whole.
+
+/// The most parameters a control sequence may carry. Further ones are
+/// ignored.
+pub const MAX_PARAMS: usize = 8;
+
+const ESC: u8 = 0x1b;
+/// Cancels an escape sequence in progress.
+const CAN: u8 = 0x18;
+/// Also cancels an escape sequence in progress.
+const SUB: u8 = 0x1a;
+
+/// A control sequence: `ESC [`, optionally `?`, numeric parameters separated
+/// by `;`, then a final byte naming the command.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Csi {
+    params: [u16; MAX_PARAMS],
+    len: usize,
+    /// `true` if the sequence started with `?`, as DEC private modes do.
+    pub private: bool,
+    /// The final byte, such as `b'H'` for cursor position.
+    pub command: u8,
+}
+
+impl Csi {
+    /// Returns the parameters that were given. An omitted parameter is zero.
+    pub fn params(&self) -> &[u16] {
+        &self.params[..self.len]
+    }
+
+    /// Returns parameter `index`, or `default` if it was omitted or zero.
+    pub fn param(&self, index: usize, default: u16) -> u16 {
+        match self.params().get(index) {
+            Some(&value) if value != 0 => value,
+            _ => default
+        }
+    }
+}
+
+/// What a byte fed to the parser amounts to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// A byte to display.
+    Print(u8),
+    /// A C0 control character such as `\n` or `\r`.
+    Control(u8),
+    /// A two-byte escape sequence, `ESC` followed by this byte.
+    Escape(u8),
+    /// A complete control sequence.
+    Csi(Csi),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    Csi,
+}
+
+/// An incremental escape sequence parser.
+#[derive(Debug)]
+pub struct Parser {
+    state: State,
+    csi: Csi,
+}
+
+impl Parser {
+    pub const fn new() -> Parser {
+        Parser {
+            state: State::Ground,
+            csi: Csi { params: [0; MAX_PARAMS], len: 0, private: false, command: 0 },
+        }
+    }
+
+    /// Feeds `byte` to the parser, returning what it completed, if anything.
+    pub fn advance(&mut self, byte: u8) -> Option<Action> {
+        match (self.state, byte) {
+            (_, ESC) => {
+                self.state = State::Escape;
+                None
+            }
+            (_, CAN) | (_, SUB) => {
+                self.state = State::Ground;
+                None
+            }
+            // Control characters take effect even in the middle of a
+            // sequence.
+            (_, 0x00...0x1f) => Some(Action::Control(byte)),
+            (State::Ground, 0x7f) => None,
+            (State::Ground, _) => Some(Action::Print(byte)),
+            (State::Escape, b'[') => {
+                self.state = State::Csi;
+                self.csi = Csi { params: [0; MAX_PARAMS], len: 0, private: false, command: 0 };
+                None
+            }
+            (State::Escape, _) => {
+                self.state = State::Ground;
+                Some(Action::Escape(byte))
+            }
+            (State::Csi, b'?') if self.csi.len == 0 => {
+                self.csi.private = true;
+                None
+            }
+            (State::Csi, b'0'...b'9') => {
+                if self.csi.len == 0 {
+                    self.csi.len = 1;
+                }
+                if self.csi.len <= MAX_PARAMS {
+                    let param = &mut self.csi.params[self.csi.len - 1];
+                    *param = param.saturating_mul(10).saturating_add((byte - b'0') as u16);
+                }
+                None
+            }
+            (State::Csi, b';') => {
+                // An empty first parameter still counts as one.
+                self.csi.len = ::std::cmp::min(::std::cmp::max(self.csi.len, 1) + 1,
+                                               MAX_PARAMS + 1);
+                None
+            }
+            (State::Csi, 0x40...0x7e) => {
+                self.state = State::Ground;
+                self.csi.len = ::std::cmp::min(self.csi.len, MAX_PARAMS);
+                self.csi.command = byte;
+                Some(Action::Csi(self.csi))
+            }
+            // Intermediate bytes aren't used by any sequence we handle.
+            (State::Csi, _) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(bytes: &[u8]) -> Vec<Action> {
+        let mut parser = Parser::new();
+        bytes.iter().filter_map(|&byte| parser.advance(byte)).collect()
+    }
+
+    fn csi(bytes: &[u8]) -> Csi {
+        let actions = parse(bytes);
+        match actions.first() {
+            Some(&Action::Csi(csi)) if actions.len() == 1 => csi,
+            _ => panic!("expected a single control sequence, got {:?}", actions)
+        }
+    }
+
+    #[test]
+    fn test_text_and_controls() {
+        assert_eq!(parse(b"a\r\n\x7f"), vec![
+            Action::Print(b'a'), Action::Control(b'\r'), Action::Control(b'\n')
+        ]);
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(parse(b"\x1b7x\x1b8"), vec![
+            Action::Escape(b'7'), Action::Print(b'x'), Action::Escape(b'8')
+        ]);
+    }
+
+    #[test]
+    fn test_csi_params() {
+        let position = csi(b"\x1b[12;34H");
+        assert_eq!(position.command, b'H');
+        assert_eq!(position.params(), &[12, 34]);
+        assert!(!position.private);
+
+        let home = csi(b"\x1b[H");
+        assert_eq!(home.params(), &[]);
+        assert_eq!(home.param(0, 1), 1);
+
+        let column = csi(b"\x1b[;5H");
+        assert_eq!(column.params(), &[0, 5]);
+        assert_eq!(column.param(0, 1), 1);
+        assert_eq!(column.param(1, 1), 5);
+
+        let hide = csi(b"\x1b[?25l");
+        assert!(hide.private);
+        assert_eq!(hide.params(), &[25]);
+        assert_eq!(hide.command, b'l');
+    }
+
+    #[test]
+    fn test_csi_limits() {
+        let many = csi(b"\x1b[1;2;3;4;5;6;7;8;9;10m");
+        assert_eq!(many.params(), &[1, 2, 3, 4, 5, 6, 7, 8]);
+
+        let huge = csi(b"\x1b[999999A");
+        assert_eq!(huge.params(), &[u16::max_value()]);
+    }
+
+    #[test]
+    fn test_interrupted_sequences() {
+        assert_eq!(parse(b"\x1b[1\x18x"), vec![Action::Print(b'x')]);
+        assert_eq!(parse(b"\x1b[1\x1b[2J"), vec![Action::Csi(csi(b"\x1b[2J"))]);
+        assert_eq!(parse(b"\x1b[3\nA"),
+                   vec![Action::Control(b'\n'), Action::Csi(csi(b"\x1b[3A"))]);
+    }
+}
@@ -2,7 +2,7 @@
 // Character definitions from SAA5050 datasheet
 // Each character is a 5x9 bit matrix
 // 9 rows of 5-bit numbers
-pub const TELETEXT: [ [u8; 9]; 96 ] = [
+pub static TELETEXT: [ [u8; 9]; 96 ] = [
 	[ 0,0,0,0,0,0,0,0,0 ],		// space
 	[ 4,4,4,4,4,0,4,0,0 ],		// !
 	[ 10,10,10,0,0,0,0,0,0 ],	// "
@@ -101,11 +101,3 @@ pub const TELETEXT: [ [u8; 9]; 96 ] = [
 	[ 31,31,31,31,31,31,31,0,0 ]	// character-sized block
 					//		not defined in ASCII
 ];
-
-pub fn ascii_to_glyph(ascii: u8) -> Option<[u8; 9]> {
-    if ascii - 32 < 96 {
-        Some(TELETEXT[ascii as usize - 32])
-    } else {
-        None
-    }
-}
@@ -3,16 +3,29 @@ use std::fmt;
 
 use uart::MiniUart;
 use mutex::Mutex;
+#[cfg(feature = "custom-std")]
+use screen::SCREEN;
+use aarch64;
+
+/// The number of received bytes the console buffers between reads.
+const INPUT_SIZE: usize = 64;
+
+/// The byte sent by Ctrl-C.
+pub const INTERRUPT_BYTE: u8 = 0x03;
 
 /// A global singleton allowing read/write access to the console.
 pub struct Console {
-    inner: Option<MiniUart>
+    inner: Option<MiniUart>,
+    /// Bytes drained from the UART by `receive()` but not read yet.
+    input: [u8; INPUT_SIZE],
+    input_start: usize,
+    input_len: usize,
 }
 
 impl Console {
     /// Creates a new instance of `Console`.
     const fn new() -> Console {
-        Console { inner: None }
+        Console { inner: None, input: [0; INPUT_SIZE], input_start: 0, input_len: 0 }
     }
 
     /// Initializes the console if it's not already initialized.
@@ -30,9 +43,61 @@ impl Console {
         self.inner.as_mut().unwrap()
     }
 
+    /// Enables the UART's receive interrupt. The interrupt handler must call
+    /// `receive()` to drain the UART.
+    pub fn enable_rx_interrupt(&mut self) {
+        self.inner().enable_rx_interrupt();
+    }
+
+    /// Keeps the UART's baud rate after the core clock changes to
+    /// `core_clock` Hz.
+    pub fn set_core_clock(&mut self, core_clock: u32) {
+        self.inner().set_core_clock(core_clock);
+    }
+
+    /// Moves every byte waiting in the UART into the console's input buffer,
+    /// dropping bytes once the buffer is full. Bytes `divert` returns `true`
+    /// for have been taken elsewhere and aren't buffered. Neither are
+    /// `INTERRUPT_BYTE`s; returns `true` if at least one was received.
+    pub fn receive<F: FnMut(u8) -> bool>(&mut self, mut divert: F) -> bool {
+        let mut interrupted = false;
+        while let Some(byte) = self.inner().try_read_byte() {
+            if byte == INTERRUPT_BYTE {
+                interrupted = true;
+            } else if divert(byte) {
+                continue;
+            } else if self.input_len < INPUT_SIZE {
+                self.input[(self.input_start + self.input_len) % INPUT_SIZE] = byte;
+                self.input_len += 1;
+            }
+        }
+
+        interrupted
+    }
+
+    /// Reads a byte, from the input buffer first, if one is available. This
+    /// method does not block.
+    pub fn try_read_byte(&mut self) -> Option<u8> {
+        if self.input_len == 0 {
+            return self.inner().try_read_byte();
+        }
+
+        let byte = self.input[self.input_start];
+        self.input_start = (self.input_start + 1) % INPUT_SIZE;
+        self.input_len -= 1;
+        Some(byte)
+    }
+
     /// Reads a byte from the UART device, blocking until a byte is available.
+    ///
+    /// The console stays locked while this method blocks. Use the free
+    /// function `read_byte()` to let others use the console in the meantime.
     pub fn read_byte(&mut self) -> u8 {
-        self.inner().read_byte()
+        loop {
+            if let Some(byte) = self.try_read_byte() {
+                return byte;
+            }
+        }
     }
 
     /// Writes the byte `byte` to the UART device.
@@ -53,7 +118,7 @@ impl io::Write for Console {
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        Ok(())
+        self.inner().flush()
     }
 }
 
@@ -66,14 +131,29 @@ impl fmt::Write for Console {
 /// Global `Console` singleton.
 pub static CONSOLE: Mutex<Console> = Mutex::new(Console::new());
 
-/// Internal function called by the `kprint[ln]!` macros.
+/// Reads a byte from the console, blocking until a byte is available. The
+/// console is only locked while checking for a byte.
+pub fn read_byte() -> u8 {
+    loop {
+        if let Some(byte) = CONSOLE.lock_irq().try_read_byte() {
+            return byte;
+        }
+
+        aarch64::spin_hint();
+    }
+}
+
+/// Internal function called by the `kprint[ln]!` macros. Output goes to both
+/// the UART and the screen.
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     #[cfg(not(test))]
     {
         use std::fmt::Write;
-        let mut console = CONSOLE.lock();
-        console.write_fmt(args).unwrap();
+        // The console is also locked from the UART interrupt handler.
+        CONSOLE.lock_irq().write_fmt(args).unwrap();
+        #[cfg(feature = "custom-std")]
+        { SCREEN.lock_irq().write_fmt(args).unwrap(); }
     }
 
     #[cfg(test)]
@@ -0,0 +1,324 @@
+//! The BCM2837's DMA controller.
+//!
+//! A channel runs a chain of `ControlBlock`s, each moving a block of memory
+//! to or from memory or a peripheral. Drivers claim a channel for as long as
+//! they need it with `Channel::claim`; only channels the firmware leaves to
+//! the ARM can be claimed.
+//!
+//! The DMA engine sees bus addresses, not ARM physical ones, and reaches
+//! memory through the uncached alias, behind the ARM's caches. Control blocks
+//! and the buffers a channel reads must be passed to `clean` once written, and
+//! buffers it writes to `invalidate` both before the transfer and after it.
+
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use aarch64;
+use common::IO_BASE;
+use timer;
+use volatile::prelude::*;
+use volatile::Volatile;
+
+/// The registers of channel 0; every other channel's follow at `0x100`
+/// intervals.
+const DMA_BASE: usize = IO_BASE + 0x7000;
+
+/// The global register enabling each channel.
+const ENABLE: usize = IO_BASE + 0x7FF0;
+
+/// The number of channels below 15, which alone lives elsewhere.
+pub const NUM_CHANNELS: usize = 15;
+
+/// The channels the firmware leaves to the ARM. It reports the same mask as
+/// `GetDmachannels`.
+const USABLE_CHANNELS: usize = 0x7F35;
+
+/// The channels claimed so far, one bit each.
+static CLAIMED: AtomicUsize = AtomicUsize::new(0);
+
+/// Where the ARM's physical memory appears on the bus, uncached.
+const MEMORY_BUS_BASE: u32 = 0xC000_0000;
+
+/// Where the peripherals appear on the bus.
+const PERIPHERAL_BUS_BASE: u32 = 0x7E00_0000;
+
+/// `CS`: the channel is running.
+const CS_ACTIVE: u32 = 1 << 0;
+/// `CS`: a control block with `TI_INTEN` finished. Write 1 to clear.
+const CS_END: u32 = 1 << 1;
+/// `CS`: the channel raised its interrupt. Write 1 to clear.
+const CS_INT: u32 = 1 << 2;
+/// `CS`: the channel hit an error; `DEBUG` says which.
+const CS_ERROR: u32 = 1 << 8;
+/// `CS`: the channel waits for its writes to land before finishing.
+const CS_WAIT_FOR_OUTSTANDING_WRITES: u32 = 1 << 28;
+/// `CS`: abandons the current control block.
+const CS_ABORT: u32 = 1 << 30;
+/// `CS`: resets the channel.
+const CS_RESET: u32 = 1 << 31;
+
+/// `DEBUG`: the error flags. Write 1 to clear.
+const DEBUG_ERRORS: u32 = 0b111;
+
+/// `TI`: raise the interrupt when the block is done.
+pub const TI_INTEN: u32 = 1 << 0;
+/// `TI`: wait for each write to be acknowledged.
+pub const TI_WAIT_RESP: u32 = 1 << 3;
+/// `TI`: step the destination address after each write.
+pub const TI_DEST_INC: u32 = 1 << 4;
+/// `TI`: pace writes by the peripheral's DREQ.
+pub const TI_DEST_DREQ: u32 = 1 << 6;
+/// `TI`: step the source address after each read.
+pub const TI_SRC_INC: u32 = 1 << 8;
+/// `TI`: pace reads by the peripheral's DREQ.
+pub const TI_SRC_DREQ: u32 = 1 << 10;
+/// `TI`: don't issue wide bursts, which some peripherals can't take.
+pub const TI_NO_WIDE_BURSTS: u32 = 1 << 26;
+
+/// The peripherals whose data requests can pace a transfer.
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Dreq {
+    /// No pacing: the transfer runs flat out.
+    None = 0,
+    PcmTx = 2,
+    PcmRx = 3,
+    Pwm = 5,
+    SpiTx = 6,
+    SpiRx = 7,
+}
+
+impl Dreq {
+    /// The `TI.PERMAP` bits for this peripheral.
+    pub fn permap(self) -> u32 {
+        (self as u32) << 16
+    }
+}
+
+/// Returns the bus address the DMA engine reaches `ptr` at.
+pub fn bus_address<T>(ptr: *const T) -> u32 {
+    ptr as usize as u32 | MEMORY_BUS_BASE
+}
+
+/// Returns the bus address of the peripheral register at ARM address
+/// `address`.
+pub fn peripheral_bus_address(address: usize) -> u32 {
+    (address - IO_BASE) as u32 | PERIPHERAL_BUS_BASE
+}
+
+/// Writes what the ARM has cached of `data` back to memory, where a channel
+/// reads it.
+pub fn clean<T: ?Sized>(data: &T) {
+    aarch64::clean_dcache(data as *const T as *const u8 as usize, mem::size_of_val(data));
+}
+
+/// Drops what the ARM has cached of `data`, so reads see what a channel wrote
+/// there. Before the transfer, this keeps dirty lines from later landing over
+/// the channel's writes; after it, stale lines from being read.
+pub fn invalidate<T: ?Sized>(data: &mut T) {
+    let address = data as *mut T as *mut u8 as usize;
+    aarch64::clean_invalidate_dcache(address, mem::size_of_val(data));
+}
+
+/// A DMA transfer: what the channel reads, where it writes, and the block to
+/// run next. Channels read control blocks from memory, so they must stay put
+/// for as long as the channel may reach them.
+#[repr(C, align(32))]
+#[derive(Debug, Clone)]
+pub struct ControlBlock {
+    /// `TI`: the transfer information flags.
+    pub transfer_info: u32,
+    /// The bus address read from.
+    pub source: u32,
+    /// The bus address written to.
+    pub destination: u32,
+    /// The number of bytes to move.
+    pub length: u32,
+    /// Unused: 2D mode strides.
+    pub stride: u32,
+    /// The bus address of the block to run next, or 0 to stop.
+    pub next: u32,
+    _reserved: [u32; 2],
+}
+
+impl ControlBlock {
+    /// Returns a block moving `length` bytes from bus address `source` to
+    /// bus address `destination`, with the `TI` flags `transfer_info`, that
+    /// stops the channel when it's done.
+    pub fn new(transfer_info: u32, source: u32, destination: u32, length: u32) -> ControlBlock {
+        ControlBlock {
+            transfer_info,
+            source,
+            destination,
+            length,
+            stride: 0,
+            next: 0,
+            _reserved: [0; 2],
+        }
+    }
+
+    /// Makes the channel run `next` after this block, or stop if it's
+    /// `None`.
+    pub fn set_next(&mut self, next: Option<&ControlBlock>) {
+        self.next = next.map_or(0, |block| bus_address(block));
+    }
+}
+
+/// An error running a DMA transfer.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The transfer didn't finish in time.
+    Timeout,
+    /// The channel hit a bus or FIFO error.
+    Bus,
+}
+
+#[repr(C)]
+#[allow(non_snake_case)]
+struct Registers {
+    CS: Volatile<u32>,
+    CONBLK_AD: Volatile<u32>,
+    TI: Volatile<u32>,
+    SOURCE_AD: Volatile<u32>,
+    DEST_AD: Volatile<u32>,
+    TXFR_LEN: Volatile<u32>,
+    STRIDE: Volatile<u32>,
+    NEXTCONBK: Volatile<u32>,
+    DEBUG: Volatile<u32>,
+}
+
+/// A claimed DMA channel. Dropping it stops the channel and releases it.
+pub struct Channel {
+    number: usize,
+    registers: &'static mut Registers,
+}
+
+impl Channel {
+    /// Claims the lowest numbered free channel the ARM may use, if any.
+    pub fn claim() -> Option<Channel> {
+        let mut claimed = CLAIMED.load(Ordering::Relaxed);
+        loop {
+            let free = USABLE_CHANNELS & !claimed;
+            if free == 0 {
+                return None;
+            }
+
+            let number = free.trailing_zeros() as usize;
+            let new = claimed | 1 << number;
+            let exchanged = CLAIMED.compare_exchange_weak(claimed, new, Ordering::Acquire,
+                                                          Ordering::Relaxed);
+            match exchanged {
+                Ok(_) => return Some(Channel::new(number)),
+                Err(current) => claimed = current,
+            }
+        }
+    }
+
+    fn new(number: usize) -> Channel {
+        let address = DMA_BASE + number * 0x100;
+        let mut channel = Channel {
+            number,
+            registers: unsafe { &mut *(address as *mut Registers) },
+        };
+
+        let enable = unsafe { &mut *(ENABLE as *mut Volatile<u32>) };
+        let enabled = enable.read();
+        enable.write(enabled | 1 << number);
+        channel.reset();
+        channel
+    }
+
+    /// The channel's number.
+    pub fn number(&self) -> usize {
+        self.number
+    }
+
+    /// Starts running the chain of control blocks beginning at `block`.
+    ///
+    /// # Safety
+    ///
+    /// Every block in the chain, and the memory they read and write, must
+    /// stay in place until the channel is done with them or stopped. The
+    /// blocks and the memory they read must have been passed to `clean`.
+    pub unsafe fn start(&mut self, block: &ControlBlock) {
+        self.registers.CS.write(CS_END | CS_INT);
+        self.registers.DEBUG.write(DEBUG_ERRORS);
+        self.registers.CONBLK_AD.write(bus_address(block));
+        self.registers.CS.write(CS_WAIT_FOR_OUTSTANDING_WRITES | CS_ACTIVE);
+    }
+
+    /// Returns `true` while the channel is running a chain.
+    pub fn is_active(&self) -> bool {
+        self.registers.CS.read() & CS_ACTIVE != 0
+    }
+
+    /// Returns the bus address of the control block the channel is running,
+    /// or 0 if it's stopped.
+    pub fn current_block(&self) -> u32 {
+        self.registers.CONBLK_AD.read()
+    }
+
+    /// Waits up to `timeout_us` microseconds for the chain to finish. The
+    /// channel is stopped if it doesn't.
+    pub fn wait(&mut self, timeout_us: u64) -> Result<(), Error> {
+        let start = timer::current_time();
+        loop {
+            let cs = self.registers.CS.read();
+            if cs & CS_ERROR != 0 {
+                self.reset();
+                return Err(Error::Bus);
+            }
+            if cs & CS_ACTIVE == 0 {
+                return Ok(());
+            }
+            if timer::current_time().wrapping_sub(start) > timeout_us {
+                self.reset();
+                return Err(Error::Timeout);
+            }
+        }
+    }
+
+    /// Stops the channel, abandoning the rest of the chain.
+    pub fn reset(&mut self) {
+        if self.is_active() {
+            self.registers.CS.write(CS_ABORT);
+        }
+        self.registers.CS.write(CS_RESET);
+        self.registers.DEBUG.write(DEBUG_ERRORS);
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        self.reset();
+        CLAIMED.fetch_and(!(1 << self.number), Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+    use super::*;
+
+    #[test]
+    fn test_control_block_layout() {
+        assert_eq!(mem::size_of::<ControlBlock>(), 32);
+        assert_eq!(mem::align_of::<ControlBlock>(), 32);
+
+        let mut first = ControlBlock::new(TI_SRC_INC | Dreq::Pwm.permap(), 0x1000, 0x2000, 64);
+        assert_eq!(first.transfer_info, 0x0005_0100);
+        assert_eq!(first.next, 0);
+
+        let second = ControlBlock::new(0, 0, 0, 0);
+        first.set_next(Some(&second));
+        assert_eq!(first.next, bus_address(&second));
+        first.set_next(None);
+        assert_eq!(first.next, 0);
+    }
+
+    #[test]
+    fn test_bus_addresses() {
+        assert_eq!(bus_address(0x0008_0000 as *const u8), 0xC008_0000);
+        assert_eq!(peripheral_bus_address(IO_BASE + 0x204004), 0x7E20_4004);
+    }
+}
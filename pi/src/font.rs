@@ -0,0 +1,466 @@
+//! Bitmap fonts for the screen.
+//!
+//! Besides the built-in 5x9 teletext glyphs, fonts can be loaded from PC
+//! Screen Font files, versions 1 and 2, as used by the Linux console. A font
+//! with a Unicode table maps characters to glyphs through it; one without
+//! maps character `n` to glyph `n`.
+
+use std::borrow::Cow;
+use std::{char, cmp, str};
+
+use character_set::TELETEXT;
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_HEADER_SIZE: usize = 4;
+/// `PSF1` mode bit: the font has 512 glyphs instead of 256.
+const PSF1_MODE_512: u8 = 0x01;
+/// `PSF1` mode bits: the font has a Unicode table.
+const PSF1_MODE_HAS_TABLE: u8 = 0x02 | 0x04;
+const PSF1_SEPARATOR: u16 = 0xFFFF;
+const PSF1_START_SEQUENCE: u16 = 0xFFFE;
+
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+const PSF2_HEADER_SIZE: usize = 32;
+/// `PSF2` flag: the font has a Unicode table.
+const PSF2_HAS_UNICODE_TABLE: u32 = 0x01;
+const PSF2_SEPARATOR: u8 = 0xFF;
+const PSF2_START_SEQUENCE: u8 = 0xFE;
+
+/// The built-in font's glyphs start at the space character.
+const BUILTIN_FIRST: u32 = 0x20;
+
+/// An error parsing a font file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The file isn't a PSF1 or PSF2 font.
+    BadMagic,
+    /// The file is shorter than its header says.
+    Truncated,
+    /// The header describes a font that can't be drawn, such as one with no
+    /// glyphs or zero-sized ones.
+    Invalid,
+}
+
+/// A font: a set of equally sized glyphs.
+#[derive(Debug, Clone)]
+pub struct Font {
+    width: usize,
+    height: usize,
+    bytes_per_row: usize,
+    /// How many bits of each row precede the glyph's leftmost pixel.
+    bit_offset: usize,
+    glyph_size: usize,
+    count: usize,
+    /// The character drawn by glyph 0 when there is no Unicode table.
+    first: u32,
+    glyphs: Cow<'static, [u8]>,
+    /// Characters and their glyphs, sorted by character.
+    unicode: Vec<(char, u16)>,
+}
+
+/// The bitmap of one glyph. Each row is a run of bytes, most significant bit
+/// leftmost.
+#[derive(Debug, Copy, Clone)]
+pub struct Glyph<'a> {
+    data: &'a [u8],
+    width: usize,
+    height: usize,
+    bytes_per_row: usize,
+    bit_offset: usize,
+}
+
+impl<'a> Glyph<'a> {
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Returns `true` if the pixel at (`x`, `y`) is set. Pixels outside the
+    /// glyph's bitmap aren't.
+    pub fn is_set(&self, x: usize, y: usize) -> bool {
+        if x >= self.width {
+            return false;
+        }
+
+        let bit = self.bit_offset + x;
+        if bit / 8 >= self.bytes_per_row {
+            return false;
+        }
+
+        match self.data.get(y * self.bytes_per_row + bit / 8) {
+            Some(byte) => byte & (0x80 >> (bit % 8)) != 0,
+            None => false
+        }
+    }
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    data[offset..offset + 4].iter().rev().fold(0, |value, &byte| value << 8 | byte as u32)
+}
+
+impl Font {
+    /// Returns the built-in font: the 5x9 teletext glyphs for printable
+    /// ASCII, in 6x10 cells.
+    pub fn builtin() -> Font {
+        let glyphs: &'static [[u8; 9]] = &TELETEXT;
+        let data = unsafe {
+            ::std::slice::from_raw_parts(glyphs.as_ptr() as *const u8, glyphs.len() * 9)
+        };
+
+        Font {
+            width: 6,
+            height: 10,
+            bytes_per_row: 1,
+            // The 5 pixels are the low bits of each row.
+            bit_offset: 3,
+            glyph_size: 9,
+            count: glyphs.len(),
+            first: BUILTIN_FIRST,
+            glyphs: Cow::Borrowed(data),
+            unicode: Vec::new(),
+        }
+    }
+
+    /// Parses a PSF1 or PSF2 font file.
+    pub fn parse(data: &[u8]) -> Result<Font, Error> {
+        if data.starts_with(&PSF2_MAGIC) {
+            Font::parse_psf2(data)
+        } else if data.starts_with(&PSF1_MAGIC) {
+            Font::parse_psf1(data)
+        } else {
+            Err(Error::BadMagic)
+        }
+    }
+
+    fn parse_psf1(data: &[u8]) -> Result<Font, Error> {
+        if data.len() < PSF1_HEADER_SIZE {
+            return Err(Error::Truncated);
+        }
+
+        let (mode, height) = (data[2], data[3] as usize);
+        let count = if mode & PSF1_MODE_512 != 0 { 512 } else { 256 };
+        let glyphs_end = PSF1_HEADER_SIZE + count * height;
+        if height == 0 {
+            return Err(Error::Invalid);
+        }
+        if data.len() < glyphs_end {
+            return Err(Error::Truncated);
+        }
+
+        let mut unicode = vec![];
+        if mode & PSF1_MODE_HAS_TABLE != 0 {
+            let mut entries = data[glyphs_end..].chunks(2)
+                .filter(|entry| entry.len() == 2)
+                .map(|entry| entry[0] as u16 | (entry[1] as u16) << 8);
+
+            for glyph in 0..count {
+                // Single characters come first, then sequences of combining
+                // characters, which we can't draw.
+                let mut in_sequence = false;
+                loop {
+                    match entries.next() {
+                        None | Some(PSF1_SEPARATOR) => break,
+                        Some(PSF1_START_SEQUENCE) => in_sequence = true,
+                        Some(_) if in_sequence => {}
+                        Some(code) => if let Some(c) = char::from_u32(code as u32) {
+                            unicode.push((c, glyph as u16));
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(Font::new(8, height, count, data[PSF1_HEADER_SIZE..glyphs_end].to_vec(), unicode))
+    }
+
+    fn parse_psf2(data: &[u8]) -> Result<Font, Error> {
+        if data.len() < PSF2_HEADER_SIZE {
+            return Err(Error::Truncated);
+        }
+
+        let header_size = u32_at(data, 8) as usize;
+        let flags = u32_at(data, 12);
+        let count = u32_at(data, 16) as usize;
+        let glyph_size = u32_at(data, 20) as usize;
+        let height = u32_at(data, 24) as usize;
+        let width = u32_at(data, 28) as usize;
+
+        if count == 0 || width == 0 || height == 0 || header_size < PSF2_HEADER_SIZE
+            || glyph_size < (width + 7) / 8 * height {
+            return Err(Error::Invalid);
+        }
+
+        let glyphs_end = count.checked_mul(glyph_size)
+            .and_then(|size| size.checked_add(header_size))
+            .ok_or(Error::Invalid)?;
+        if data.len() < glyphs_end {
+            return Err(Error::Truncated);
+        }
+
+        let mut unicode = vec![];
+        if flags & PSF2_HAS_UNICODE_TABLE != 0 {
+            let mut table = data[glyphs_end..].split(|&byte| byte == PSF2_SEPARATOR);
+            for glyph in 0..count {
+                let entry = match table.next() {
+                    Some(entry) => entry,
+                    None => break,
+                };
+
+                // Sequences of combining characters follow the single ones.
+                let singles = entry.split(|&byte| byte == PSF2_START_SEQUENCE).next().unwrap();
+                let singles = match str::from_utf8(singles) {
+                    Ok(singles) => singles,
+                    Err(e) => str::from_utf8(&singles[..e.valid_up_to()]).unwrap(),
+                };
+                unicode.extend(singles.chars().map(|c| (c, glyph as u16)));
+            }
+        }
+
+        let mut font = Font::new(width, height, count, data[header_size..glyphs_end].to_vec(),
+                                 unicode);
+        font.glyph_size = glyph_size;
+        Ok(font)
+    }
+
+    fn new(width: usize, height: usize, count: usize, glyphs: Vec<u8>,
+           mut unicode: Vec<(char, u16)>) -> Font {
+        // When a character is listed twice, the first glyph wins.
+        unicode.sort_by_key(|&(c, _)| c);
+        unicode.dedup_by_key(|&mut (c, _)| c);
+
+        let bytes_per_row = (width + 7) / 8;
+        Font {
+            width,
+            height,
+            bytes_per_row,
+            bit_offset: 0,
+            glyph_size: bytes_per_row * height,
+            count,
+            first: 0,
+            glyphs: Cow::Owned(glyphs),
+            unicode,
+        }
+    }
+
+    /// The width of every glyph, in pixels.
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// The height of every glyph, in pixels.
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Returns the index of the glyph for `c`, if the font has one.
+    pub fn glyph_index(&self, c: char) -> Option<usize> {
+        if !self.unicode.is_empty() {
+            return self.unicode.binary_search_by_key(&c, |&(c, _)| c).ok()
+                .map(|index| self.unicode[index].1 as usize);
+        }
+
+        match (c as u32).checked_sub(self.first) {
+            Some(index) if (index as usize) < self.count => Some(index as usize),
+            _ => None
+        }
+    }
+
+    /// Returns the glyph for `c`, if the font has one.
+    pub fn glyph(&self, c: char) -> Option<Glyph> {
+        let index = self.glyph_index(c)?;
+        let start = index * self.glyph_size;
+        let end = cmp::min(start + self.glyph_size, self.glyphs.len());
+        Some(Glyph {
+            data: &self.glyphs[start..end],
+            width: self.width,
+            height: self.height,
+            bytes_per_row: self.bytes_per_row,
+            bit_offset: self.bit_offset,
+        })
+    }
+}
+
+/// Decodes UTF-8 a byte at a time. Malformed input decodes to U+FFFD.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Utf8Decoder {
+    code_point: u32,
+    /// The number of continuation bytes still expected.
+    remaining: u8,
+    /// The smallest code point the sequence may encode without being
+    /// overlong.
+    min: u32,
+}
+
+impl Utf8Decoder {
+    pub const fn new() -> Utf8Decoder {
+        Utf8Decoder { code_point: 0, remaining: 0, min: 0 }
+    }
+
+    /// Feeds `byte` to the decoder, passing each character it completes to
+    /// `emit`. A byte that interrupts a sequence yields U+FFFD and is then
+    /// decoded afresh, so the character it starts isn't lost.
+    pub fn push<F: FnMut(char)>(&mut self, byte: u8, mut emit: F) {
+        if self.remaining > 0 {
+            if byte & 0xC0 == 0x80 {
+                self.code_point = self.code_point << 6 | (byte & 0x3F) as u32;
+                self.remaining -= 1;
+                if self.remaining == 0 {
+                    let c = match self.code_point >= self.min {
+                        true => char::from_u32(self.code_point),
+                        false => None,
+                    };
+                    emit(c.unwrap_or(char::REPLACEMENT_CHARACTER));
+                }
+                return;
+            }
+
+            self.remaining = 0;
+            emit(char::REPLACEMENT_CHARACTER);
+        }
+
+        if let Some(c) = self.start(byte) {
+            emit(c);
+        }
+    }
+
+    fn start(&mut self, byte: u8) -> Option<char> {
+        let (remaining, bits, min) = match byte {
+            0x00...0x7F => return Some(byte as char),
+            0xC0...0xDF => (1, byte & 0x1F, 0x80),
+            0xE0...0xEF => (2, byte & 0x0F, 0x800),
+            0xF0...0xF7 => (3, byte & 0x07, 0x10000),
+            _ => return Some(char::REPLACEMENT_CHARACTER),
+        };
+
+        self.code_point = bits as u32;
+        self.remaining = remaining;
+        self.min = min;
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> String {
+        let mut decoder = Utf8Decoder::new();
+        let mut decoded = String::new();
+        for &byte in bytes {
+            decoder.push(byte, |c| decoded.push(c));
+        }
+        decoded
+    }
+
+    #[test]
+    fn test_utf8() {
+        assert_eq!(decode("aé─😀".as_bytes()), "aé─😀");
+        assert_eq!(decode(b"\xC3x"), "\u{FFFD}x");
+        assert_eq!(decode(b"\xFFa"), "\u{FFFD}a");
+        // Overlong and surrogate encodings.
+        assert_eq!(decode(b"\xC0\xAF"), "\u{FFFD}");
+        assert_eq!(decode(b"\xED\xA0\x80"), "\u{FFFD}");
+    }
+
+    fn psf1(mode: u8, glyphs: usize, table: &[u16]) -> Vec<u8> {
+        let mut data = vec![0x36, 0x04, mode, 2];
+        for glyph in 0..glyphs {
+            data.extend_from_slice(&[glyph as u8, 0x80]);
+        }
+        for entry in table {
+            data.extend_from_slice(&[*entry as u8, (*entry >> 8) as u8]);
+        }
+        data
+    }
+
+    fn psf2(flags: u32, glyphs: usize, table: &[u8]) -> Vec<u8> {
+        let mut data = vec![];
+        for field in &[0x864ab572, 0, 32, flags, glyphs as u32, 4, 2, 10] {
+            data.extend((0..4).map(|i| (field >> (8 * i)) as u8));
+        }
+        for glyph in 0..glyphs {
+            data.extend_from_slice(&[glyph as u8, 0, 0xC0, 0x40]);
+        }
+        data.extend_from_slice(table);
+        data
+    }
+
+    #[test]
+    fn test_builtin() {
+        let font = Font::builtin();
+        assert_eq!((font.width(), font.height()), (6, 10));
+        assert_eq!(font.glyph_index(' '), Some(0));
+        assert_eq!(font.glyph_index('A'), Some(33));
+        assert_eq!(font.glyph_index('\n'), None);
+        assert_eq!(font.glyph_index('é'), None);
+
+        // `!` is a vertical bar in the middle column.
+        let glyph = font.glyph('!').unwrap();
+        assert!(glyph.is_set(2, 0));
+        assert!(!glyph.is_set(1, 0) && !glyph.is_set(3, 0));
+        assert!(!glyph.is_set(2, 5) && glyph.is_set(2, 6));
+        assert!(!glyph.is_set(2, 9) && !glyph.is_set(5, 0));
+    }
+
+    #[test]
+    fn test_psf1() {
+        let font = Font::parse(&psf1(0, 256, &[])).unwrap();
+        assert_eq!((font.width(), font.height()), (8, 2));
+        assert_eq!(font.glyph_index('A'), Some(65));
+        assert_eq!(font.glyph_index('\u{100}'), None);
+        let glyph = font.glyph('\u{3}').unwrap();
+        assert!(glyph.is_set(6, 0) && glyph.is_set(7, 0) && !glyph.is_set(5, 0));
+        assert!(glyph.is_set(0, 1) && !glyph.is_set(1, 1));
+
+        let mut table = vec![];
+        for glyph in 0..512 {
+            match glyph {
+                1 => table.extend_from_slice(&[0x2500, 0x2501, 0xFFFE, 0x41, 0x301, 0xFFFF]),
+                2 => table.extend_from_slice(&[0xE9, 0x2500, 0xFFFF]),
+                _ => table.push(0xFFFF),
+            }
+        }
+        let font = Font::parse(&psf1(0x03, 512, &table)).unwrap();
+        assert_eq!(font.glyph_index('─'), Some(1));
+        assert_eq!(font.glyph_index('━'), Some(1));
+        assert_eq!(font.glyph_index('é'), Some(2));
+        assert_eq!(font.glyph_index('A'), None);
+        assert_eq!(font.glyph_index('\u{301}'), None);
+    }
+
+    #[test]
+    fn test_psf2() {
+        let font = Font::parse(&psf2(0, 3, &[])).unwrap();
+        assert_eq!((font.width(), font.height()), (10, 2));
+        assert_eq!(font.glyph_index('\u{2}'), Some(2));
+        assert_eq!(font.glyph_index('\u{3}'), None);
+        let glyph = font.glyph('\u{1}').unwrap();
+        assert!(glyph.is_set(7, 0) && !glyph.is_set(6, 0) && !glyph.is_set(8, 0));
+        assert!(glyph.is_set(0, 1) && glyph.is_set(1, 1) && glyph.is_set(9, 1));
+
+        let mut table = b"a\xFF".to_vec();
+        table.extend_from_slice("é─".as_bytes());
+        table.push(0xFE);
+        table.extend_from_slice("e\u{301}".as_bytes());
+        table.push(0xFF);
+        let font = Font::parse(&psf2(PSF2_HAS_UNICODE_TABLE, 3, &table)).unwrap();
+        assert_eq!(font.glyph_index('a'), Some(0));
+        assert_eq!(font.glyph_index('é'), Some(1));
+        assert_eq!(font.glyph_index('─'), Some(1));
+        assert_eq!(font.glyph_index('e'), None);
+        assert_eq!(font.glyph_index('b'), None);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(Font::parse(b"hello").unwrap_err(), Error::BadMagic);
+        assert_eq!(Font::parse(&psf1(0, 100, &[])).unwrap_err(), Error::Truncated);
+        let mut data = psf2(0, 3, &[]);
+        data.truncate(40);
+        assert_eq!(Font::parse(&data).unwrap_err(), Error::Truncated);
+        assert_eq!(Font::parse(&psf2(0, 0, &[])).unwrap_err(), Error::Invalid);
+    }
+}
@@ -1,17 +1,17 @@
-use std::fmt;
-use volatile::prelude::*;
-use volatile::{Volatile};
-use console::kprintln;
-use propertytag::{send_tags, PropertyTag, PropertyId};
+use std::{cmp, fmt, ptr};
+use aarch64;
+use propertytag::{Message, PropertyId};
+use graphics::{Rgba, Target};
 
-use stack_vec::StackVec;
-
-// Many thanks to 
+// Many thanks to
 // https://elinux.org/RPi_Framebuffer
 // and
 // https://github.com/raspberrypi/firmware/wiki/Mailbox
 // for info on address locations, etc
 
+/// The alignment requested for the framebuffer, in bytes.
+const BUFFER_ALIGNMENT: u32 = 16;
+
 #[derive(Clone, Copy, Debug)]
 pub struct Color {
     pub red: u8,
@@ -50,84 +50,461 @@ pub struct Pixel {
     pub position: Position,
 }
 
+/// The number of bits per pixel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Depth {
+    /// 5 bits of red, 6 of green and 5 of blue.
+    Bpp16,
+    /// A byte each of blue, green and red.
+    Bpp24,
+    /// A byte each of blue, green and red, then one unused.
+    Bpp32,
+}
+
+impl Depth {
+    pub fn from_bits(bits: u32) -> Option<Depth> {
+        match bits {
+            16 => Some(Depth::Bpp16),
+            24 => Some(Depth::Bpp24),
+            32 => Some(Depth::Bpp32),
+            _ => None
+        }
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.bytes() as u32 * 8
+    }
+
+    pub fn bytes(&self) -> usize {
+        match *self {
+            Depth::Bpp16 => 2,
+            Depth::Bpp24 => 3,
+            Depth::Bpp32 => 4,
+        }
+    }
+
+    /// Returns `color` as it is stored in memory. Only the first `bytes()`
+    /// bytes are used.
+    pub fn encode(&self, color: Color) -> [u8; 4] {
+        match *self {
+            Depth::Bpp16 => {
+                let rgb565 = ((color.red as u16 >> 3) << 11)
+                    | ((color.green as u16 >> 2) << 5)
+                    | (color.blue as u16 >> 3);
+                [rgb565 as u8, (rgb565 >> 8) as u8, 0, 0]
+            }
+            Depth::Bpp24 | Depth::Bpp32 => [color.blue, color.green, color.red, 0xff],
+        }
+    }
+
+    /// Returns the color stored in memory as `bytes`, the inverse of
+    /// `encode`.
+    pub fn decode(&self, bytes: &[u8]) -> Color {
+        match *self {
+            Depth::Bpp16 => {
+                let rgb565 = bytes[0] as u16 | (bytes[1] as u16) << 8;
+                let (red, green, blue) = (rgb565 >> 11, (rgb565 >> 5) & 0x3f, rgb565 & 0x1f);
+                Color {
+                    red: (red << 3 | red >> 2) as u8,
+                    green: (green << 2 | green >> 4) as u8,
+                    blue: (blue << 3 | blue >> 2) as u8,
+                }
+            }
+            Depth::Bpp24 | Depth::Bpp32 => Color { red: bytes[2], green: bytes[1], blue: bytes[0] },
+        }
+    }
+}
+
+/// A display mode to request from the firmware.
+#[derive(Clone, Copy, Debug)]
+pub struct Mode {
+    pub width: usize,
+    pub height: usize,
+    pub depth: Depth,
+    /// The number of pages: 1, or 2 for double buffering.
+    pub buffers: usize,
+}
+
+impl Default for Mode {
+    fn default() -> Mode {
+        Mode {
+            width: 1024,
+            height: 768,
+            depth: Depth::Bpp32,
+            buffers: 1,
+        }
+    }
+}
+
+/// A framebuffer allocated by the firmware.
+///
+/// Rows are `pitch` bytes apart, which may be more than `width` pixels. When
+/// double buffered, the virtual screen is two pages tall: all drawing goes to
+/// the page that isn't displayed, and `present` displays it. Either way,
+/// drawing stays in the ARM's caches until `present` writes it back to
+/// memory, where the GPU reads it.
 pub struct Framebuffer {
     pub size: usize,
     pub width: usize,
     pub height: usize,
-    pub buffer: &'static mut[Volatile<u8>]
+    pub depth: Depth,
+    pub pitch: usize,
+    buffers: usize,
+    front: usize,
+    /// The rows drawn on since the last `present`, from the first up to the
+    /// last; empty if none.
+    dirty: (usize, usize),
+    buffer: &'static mut [u8],
 }
 
 impl Framebuffer {
-    pub fn new() -> Result<Framebuffer, ()> {
-
-        let physical_width_height_tag = PropertyTag::new(PropertyId::SetPhysicalWidthHeight);
-        let virtual_width_height_tag = PropertyTag::new(PropertyId::SetVirtualWidthHeight);
-        let set_depth_tag = PropertyTag::new(PropertyId::SetDepth);
-        let get_width_height_tag = PropertyTag::new(PropertyId::GetPhysicalWidthHeight);
-        let allocate_buffer_tag = PropertyTag::new(PropertyId::AllocateBuffer);
-
-        kprintln!("Allocate Buffer Property Tag:");
+    /// Asks the firmware for a framebuffer in `mode`. The firmware may pick a
+    /// different resolution; the framebuffer's fields hold the one it picked.
+    /// If it can't provide two pages, the framebuffer is single buffered.
+    pub fn new(mode: Mode) -> Result<Framebuffer, ()> {
+        let buffers = cmp::min(cmp::max(mode.buffers, 1), 2);
 
-        let mut tag_backing: [PropertyTag; 8] = [Default::default(); 8];
-        let mut tags = StackVec::new(&mut tag_backing);
-        tags.push(physical_width_height_tag)?;
-        tags.push(virtual_width_height_tag)?;
-        tags.push(set_depth_tag)?;
-        tags.push(get_width_height_tag)?;
-        tags.push(allocate_buffer_tag)?;
-        let tags = send_tags(&mut tags);
+        let mut message = Message::new();
+        let physical_width_height = message.push(PropertyId::SetPhysicalWidthHeight,
+                                                 &[mode.width as u32, mode.height as u32], 2)
+            .map_err(|_| ())?;
+        let virtual_height = (mode.height * buffers) as u32;
+        let virtual_width_height = message.push(PropertyId::SetVirtualWidthHeight,
+                                                &[mode.width as u32, virtual_height], 2)
+            .map_err(|_| ())?;
+        let set_depth = message.push(PropertyId::SetDepth, &[mode.depth.bits()], 1)
+            .map_err(|_| ())?;
+        message.push(PropertyId::SetVirtualOffset, &[0, 0], 2).map_err(|_| ())?;
+        let allocate_buffer = message.push(PropertyId::AllocateBuffer, &[BUFFER_ALIGNMENT], 2)
+            .map_err(|_| ())?;
+        let get_pitch = message.push(PropertyId::GetPitch, &[], 1).map_err(|_| ())?;
+        message.send().map_err(|_| ())?;
 
-        let allocate_buffer_tag = tags.pop().unwrap();
-        let get_width_height_tag = tags.pop().unwrap();
-        let set_depth_tag = tags.pop().unwrap();
-        let virtual_width_height_tag = tags.pop().unwrap();
-        let physical_width_height_tag = tags.pop().unwrap();
+        let (mut physical, mut virtual_size) = ([0; 2], [0; 2]);
+        let (mut bits, mut allocation, mut pitch) = ([0; 1], [0; 2], [0; 1]);
+        message.response(physical_width_height, &mut physical).map_err(|_| ())?;
+        message.response(virtual_width_height, &mut virtual_size).map_err(|_| ())?;
+        message.response(set_depth, &mut bits).map_err(|_| ())?;
+        message.response(allocate_buffer, &mut allocation).map_err(|_| ())?;
+        message.response(get_pitch, &mut pitch).map_err(|_| ())?;
 
-        kprintln!("physical_width_height_tag data: {:x?}", &physical_width_height_tag.data[..]);
-        kprintln!("virtual_width_height_tag data: {:x?}", &virtual_width_height_tag.data[..]);
-        kprintln!("set_depth_tag data: {:x?}", &set_depth_tag.data[..]);
-        kprintln!("get_width_height_tag data: {:x?}", &get_width_height_tag.data[..]);
-        kprintln!("allocate_buffer_tag data: {:x?}", &allocate_buffer_tag.data[..]);
-
-        // TODO: Check physical_width_height_tag data
-        let width = virtual_width_height_tag.data[0] as usize;
-        let height = virtual_width_height_tag.data[1] as usize;
+        let width = physical[0] as usize;
+        let height = physical[1] as usize;
+        let depth = Depth::from_bits(bits[0]).ok_or(())?;
+        let virtual_height = virtual_size[1] as usize;
+        let buffers = cmp::min(buffers, virtual_height / cmp::max(height, 1));
+        if width == 0 || height == 0 || buffers == 0 || allocation[0] == 0 {
+            return Err(());
+        }
 
-        let fb_base_addr = (allocate_buffer_tag.data[0] - 0xc0000000) as *mut Volatile<u8>;
-        let size = allocate_buffer_tag.data[1] as usize;
+        let pitch = match pitch[0] as usize {
+            0 => width * depth.bytes(),
+            pitch => pitch,
+        };
 
-        kprintln!("fb_base_addr = {:x}", fb_base_addr as u32);
-        kprintln!("size = {:x}", size);
+        // The firmware returns a bus address; strip the cache alias bits.
+        let fb_base_addr = (allocation[0] & 0x3FFFFFFF) as usize as *mut u8;
+        let size = allocation[1] as usize;
+        if size < pitch * height * buffers {
+            return Err(());
+        }
 
-        let buffer = unsafe { std::slice::from_raw_parts_mut(fb_base_addr, size) };
+        let buffer = unsafe { ::std::slice::from_raw_parts_mut(fb_base_addr, size) };
 
         Ok(Framebuffer {
             size,
             width,
             height,
+            depth,
+            pitch,
+            buffers,
+            front: 0,
+            dirty: (0, 0),
             buffer,
         })
     }
 
-    pub fn clear(&mut self) {
-        for i in 0..self.size {
-            self.buffer[i].write(0);
+    /// Returns `true` if drawing goes to a page that isn't displayed until
+    /// `flip` is called.
+    pub fn is_double_buffered(&self) -> bool {
+        self.buffers == 2
+    }
+
+    /// The page drawn to.
+    fn back(&self) -> usize {
+        (self.front + 1) % self.buffers
+    }
+
+    /// Returns the bytes of the page drawn to. All of it is presented next
+    /// time.
+    pub fn page(&mut self) -> &mut [u8] {
+        let height = self.height;
+        self.touch(0, height);
+        self.back_page()
+    }
+
+    fn back_page(&mut self) -> &mut [u8] {
+        let page_size = self.pitch * self.height;
+        let start = self.back() * page_size;
+        &mut self.buffer[start..start + page_size]
+    }
+
+    /// Notes that `height` rows from row `y` have been drawn on.
+    fn touch(&mut self, y: usize, height: usize) {
+        let (from, to) = (cmp::min(y, self.height), cmp::min(y + height, self.height));
+        if from == to {
+            return;
+        }
+
+        self.dirty = match self.dirty {
+            (start, end) if start == end => (from, to),
+            (start, end) => (cmp::min(start, from), cmp::max(end, to)),
+        };
+    }
+
+    /// Displays what has been drawn since the last call, then copies the rows
+    /// drawn on to the page drawn to, so drawing carries on from what is on
+    /// screen.
+    pub fn present(&mut self) -> Result<(), ()> {
+        let (start, end) = self.dirty;
+        let back = self.back();
+        self.clean_rows(back, start, end);
+        if !self.is_double_buffered() {
+            self.dirty = (0, 0);
+            return Ok(());
+        }
+
+        if start == end {
+            return Ok(());
+        }
+
+        self.flip()?;
+        self.copy_rows(start, end);
+        self.dirty = (0, 0);
+        Ok(())
+    }
+
+    /// Displays the page drawn to, after which drawing goes to the page that
+    /// was displayed. Its contents are left as they were. Does nothing if the
+    /// framebuffer is single buffered.
+    pub fn flip(&mut self) -> Result<(), ()> {
+        if !self.is_double_buffered() {
+            return Ok(());
+        }
+
+        let back = self.back();
+        let offset = (back * self.height) as u32;
+        let mut message = Message::new();
+        let slot = message.push(PropertyId::SetVirtualOffset, &[0, offset], 2).map_err(|_| ())?;
+        message.send().map_err(|_| ())?;
+
+        let mut response = [0; 2];
+        message.response(slot, &mut response).map_err(|_| ())?;
+        if response[1] != offset {
+            return Err(());
         }
+
+        self.front = back;
+        Ok(())
+    }
+
+    /// Copies the displayed page to the page drawn to, so drawing can carry on
+    /// from what is on screen after a `flip`.
+    pub fn copy_front_to_back(&mut self) {
+        let height = self.height;
+        self.copy_rows(0, height);
+    }
+
+    /// Copies the rows `start` up to `end` of the displayed page to the page
+    /// drawn to.
+    fn copy_rows(&mut self, start: usize, end: usize) {
+        if !self.is_double_buffered() {
+            return;
+        }
+
+        let page_size = self.pitch * self.height;
+        let offset = start * self.pitch;
+        let front = self.front * page_size + offset;
+        let back = self.back() * page_size + offset;
+        unsafe {
+            let base = self.buffer.as_mut_ptr();
+            ptr::copy_nonoverlapping(base.offset(front as isize), base.offset(back as isize),
+                                     (end - start) * self.pitch);
+        }
+
+        let back = self.back();
+        self.clean_rows(back, start, end);
+    }
+
+    /// Writes the rows `start` up to `end` of `page` back from the ARM's
+    /// caches to memory, where the GPU reads them.
+    fn clean_rows(&self, page: usize, start: usize, end: usize) {
+        let offset = (page * self.height + start) * self.pitch;
+        let address = self.buffer.as_ptr() as usize + offset;
+        aarch64::clean_dcache(address, (end - start) * self.pitch);
+    }
+
+    /// Fills the page drawn to with black.
+    pub fn clear(&mut self) {
+        let page = self.page();
+        unsafe { ptr::write_bytes(page.as_mut_ptr(), 0, page.len()); }
     }
 
     pub fn draw_pixel(&mut self, pixel: &Pixel) {
-        let mut fb_index = (pixel.position.y * self.width * 3) + (pixel.position.x * 3);
-        if fb_index + 3 >= self.buffer.len() {
-            fb_index = fb_index % self.buffer.len();
+        let (x, y) = (pixel.position.x, pixel.position.y);
+        if x >= self.width || y >= self.height {
+            return;
+        }
+
+        let bytes = self.depth.bytes();
+        let encoded = self.depth.encode(pixel.color);
+        let index = y * self.pitch + x * bytes;
+        self.touch(y, 1);
+        self.back_page()[index..index + bytes].copy_from_slice(&encoded[..bytes]);
+    }
+
+    /// Clips the rectangle at (`x`, `y`) to the screen, returning its width
+    /// and height, either of which may be zero.
+    fn clip(&self, x: usize, y: usize, width: usize, height: usize) -> (usize, usize) {
+        (cmp::min(width, self.width.saturating_sub(x)),
+         cmp::min(height, self.height.saturating_sub(y)))
+    }
+
+    /// Fills a rectangle with `color`.
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Color) {
+        let (width, height) = self.clip(x, y, width, height);
+        if width == 0 || height == 0 {
+            return;
+        }
+
+        // Encode the first row pixel by pixel, then copy it to the others.
+        let (bytes, pitch) = (self.depth.bytes(), self.pitch);
+        let encoded = self.depth.encode(color);
+        let start = y * pitch + x * bytes;
+        let row_len = width * bytes;
+        self.touch(y, height);
+        let page = self.back_page();
+        for pixel in page[start..start + row_len].chunks_mut(bytes) {
+            pixel.copy_from_slice(&encoded[..bytes]);
         }
-        self.buffer[fb_index].write(pixel.color.blue);
-        self.buffer[fb_index + 1].write(pixel.color.green);
-        self.buffer[fb_index + 2].write(pixel.color.red);
+
+        let base = page.as_mut_ptr();
+        for row in 1..height {
+            let to = start + row * pitch;
+            unsafe {
+                ptr::copy_nonoverlapping(base.offset(start as isize), base.offset(to as isize),
+                                         row_len);
+            }
+        }
+    }
+
+    /// Inverts the color of every pixel in a rectangle. Inverting it again
+    /// restores it.
+    pub fn invert_rect(&mut self, x: usize, y: usize, width: usize, height: usize) {
+        let (width, height) = self.clip(x, y, width, height);
+        let (bytes, pitch) = (self.depth.bytes(), self.pitch);
+        // Leave the unused byte of 32-bit pixels alone.
+        let color_bytes = cmp::min(bytes, 3);
+        self.touch(y, height);
+        let page = self.back_page();
+        for row in y..y + height {
+            let start = row * pitch + x * bytes;
+            for pixel in page[start..start + width * bytes].chunks_mut(bytes) {
+                for byte in pixel[..color_bytes].iter_mut() {
+                    *byte = !*byte;
+                }
+            }
+        }
+    }
+
+    /// Copies `width` by `height` pixels from `src`, whose rows are
+    /// `src_pitch` bytes apart and already in the framebuffer's pixel format,
+    /// to (`x`, `y`).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `src` is too small to hold the rectangle.
+    pub fn blit(&mut self, x: usize, y: usize, width: usize, height: usize,
+                src: &[u8], src_pitch: usize) {
+        let (clipped_width, clipped_height) = self.clip(x, y, width, height);
+        let (bytes, pitch) = (self.depth.bytes(), self.pitch);
+        let row_len = clipped_width * bytes;
+        self.touch(y, clipped_height);
+        let page = self.back_page();
+        for row in 0..clipped_height {
+            let from = row * src_pitch;
+            let to = (y + row) * pitch + x * bytes;
+            page[to..to + row_len].copy_from_slice(&src[from..from + row_len]);
+        }
+    }
+
+    /// Copies the `width` by `height` pixels at (`src_x`, `src_y`) to (`x`,
+    /// `y`). The rectangles may overlap.
+    pub fn copy_rect(&mut self, src_x: usize, src_y: usize, width: usize, height: usize,
+                     x: usize, y: usize) {
+        let (width, height) = self.clip(src_x, src_y, width, height);
+        let (width, height) = self.clip(x, y, width, height);
+        let (bytes, pitch) = (self.depth.bytes(), self.pitch);
+        let row_len = width * bytes;
+        self.touch(y, height);
+        let base = self.back_page().as_mut_ptr();
+
+        let copy_row = |row: usize| unsafe {
+            let from = (src_y + row) * pitch + src_x * bytes;
+            let to = (y + row) * pitch + x * bytes;
+            ptr::copy(base.offset(from as isize), base.offset(to as isize), row_len);
+        };
+
+        // Copy downwards from the bottom so overlapping rows aren't
+        // overwritten before they are read.
+        if y > src_y {
+            (0..height).rev().for_each(&copy_row);
+        } else {
+            (0..height).for_each(&copy_row);
+        }
+    }
+
+    /// Scrolls the page drawn to up by `rows` pixels, filling the rows
+    /// uncovered at the bottom with `color`.
+    pub fn scroll_up(&mut self, rows: usize, color: Color) {
+        let rows = cmp::min(rows, self.height);
+        let (width, height, pitch) = (self.width, self.height, self.pitch);
+        {
+            let page = self.page();
+            let (from, len) = (rows * pitch, (height - rows) * pitch);
+            unsafe { ptr::copy(page.as_ptr().offset(from as isize), page.as_mut_ptr(), len); }
+        }
+        self.fill_rect(0, height - rows, width, rows, color);
+    }
+}
+
+impl Target for Framebuffer {
+    fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    fn get_pixel(&self, x: usize, y: usize) -> Rgba {
+        let bytes = self.depth.bytes();
+        let index = (self.back() * self.height + y) * self.pitch + x * bytes;
+        let color = self.depth.decode(&self.buffer[index..index + bytes]);
+        Rgba::rgb(color.red, color.green, color.blue)
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize, color: Rgba) {
+        let position = Position { x, y };
+        let color = Color { red: color.red, green: color.green, blue: color.blue };
+        self.draw_pixel(&Pixel { position, color });
+    }
+
+    fn fill_span(&mut self, x: usize, y: usize, width: usize, color: Rgba) {
+        let color = Color { red: color.red, green: color.green, blue: color.blue };
+        self.fill_rect(x, y, width, 1, color);
     }
 }
 
 impl fmt::Display for Framebuffer {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Size: {}, pixel 1: ({}, {}, {})", self.size, self.buffer[0].read(), self.buffer[1].read(), self.buffer[2].read())
+        write!(f, "{}x{}, {}bpp, pitch {}, {} page(s), {} bytes",
+               self.width, self.height, self.depth.bits(), self.pitch, self.buffers, self.size)
     }
 }
@@ -1,11 +1,16 @@
 use std::marker::PhantomData;
 
 use common::{IO_BASE, states};
+use timer;
 use volatile::prelude::*;
 use volatile::{Volatile, WriteVolatile, ReadVolatile, Reserved};
 
+/// The number of GPIO pins.
+pub const NUM_PINS: u8 = 54;
+
 /// An alternative GPIO function.
 #[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Function {
     Input = 0b000,
     Output = 0b001,
@@ -17,6 +22,35 @@ pub enum Function {
     Alt5 = 0b010
 }
 
+/// A pin's internal pull resistor.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Pull {
+    Off = 0b00,
+    Down = 0b01,
+    Up = 0b10,
+}
+
+/// A change in a pin's level that sets its bit in the event detect status
+/// register and, if the GPIO interrupts are enabled, raises one.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// A low to high transition, sampled with the system clock so glitches
+    /// shorter than two samples are ignored.
+    RisingEdge,
+    /// A high to low transition, sampled like `RisingEdge`.
+    FallingEdge,
+    /// The pin is high. Raised again as soon as it is cleared, until the pin
+    /// goes low.
+    High,
+    /// The pin is low, like `High`.
+    Low,
+    /// A low to high transition, detected without sampling.
+    AsyncRisingEdge,
+    /// A high to low transition, detected without sampling.
+    AsyncFallingEdge,
+}
+
 #[repr(C)]
 #[allow(non_snake_case)]
 struct Registers {
@@ -79,6 +113,35 @@ impl<T> Gpio<T> {
             _state: PhantomData
         }
     }
+
+    /// The pin's number.
+    pub fn pin(&self) -> u8 {
+        self.pin
+    }
+
+    /// The index of the register holding this pin's bit in the banked
+    /// registers.
+    fn bank(&self) -> usize {
+        (self.pin / 32) as usize
+    }
+
+    /// This pin's bit in the banked registers.
+    fn bit(&self) -> u32 {
+        1 << (self.pin % 32)
+    }
+
+    /// Sets the pin's pull resistor. The setting is kept while the pin
+    /// changes function and, on the BCM2837, can't be read back.
+    pub fn set_pull(&mut self, pull: Pull) {
+        // The control signal must be set up, and then held, for 150 cycles
+        // either side of the clock; a microsecond is plenty.
+        self.registers.PUD.write(pull as u32);
+        timer::spin_sleep_us(1);
+        self.registers.PUDCLK[self.bank()].write(self.bit());
+        timer::spin_sleep_us(1);
+        self.registers.PUD.write(Pull::Off as u32);
+        self.registers.PUDCLK[self.bank()].write(0);
+    }
 }
 
 impl Gpio<Uninitialized> {
@@ -100,14 +163,10 @@ impl Gpio<Uninitialized> {
     }
 
     fn select_function(&mut self, function: Function) {
-        let select_register = self.pin / 10;
-        let register_state = self.registers.FSEL[select_register as usize].read();
-
-        let bits_to_shift = (self.pin % 10) * 3;
-        let mask = (function as u32) << bits_to_shift;
-        let new_val = register_state | mask;
-
-        self.registers.FSEL[select_register as usize].write(new_val);
+        let select_register = (self.pin / 10) as usize;
+        let register_state = self.registers.FSEL[select_register].read();
+        let new_val = with_function(register_state, self.pin, function);
+        self.registers.FSEL[select_register].write(new_val);
     }
 
     /// Enables the alternative function `function` for `self`. Consumes self
@@ -150,6 +209,183 @@ impl Gpio<Input> {
     /// Reads the pin's value. Returns `true` if the level is high and `false`
     /// if the level is low.
     pub fn level(&mut self) -> bool {
-        self.registers.LEV[(self.pin / 32) as usize].read() & (1 << self.pin % 32) == 1
+        self.registers.LEV[self.bank()].read() & self.bit() != 0
+    }
+
+    /// The detect enable registers for `event`.
+    fn enable_register(&mut self, event: Event) -> &mut Volatile<u32> {
+        let bank = self.bank();
+        match event {
+            Event::RisingEdge => &mut self.registers.REN[bank],
+            Event::FallingEdge => &mut self.registers.FEN[bank],
+            Event::High => &mut self.registers.HEN[bank],
+            Event::Low => &mut self.registers.LEN[bank],
+            Event::AsyncRisingEdge => &mut self.registers.AREN[bank],
+            Event::AsyncFallingEdge => &mut self.registers.AFEN[bank],
+        }
+    }
+
+    /// Starts detecting `event` on this pin.
+    pub fn enable_event(&mut self, event: Event) {
+        let bit = self.bit();
+        self.enable_register(event).or_mask(bit);
+    }
+
+    /// Stops detecting `event` on this pin. An event already detected stays
+    /// detected until `clear_event`.
+    pub fn disable_event(&mut self, event: Event) {
+        let bit = self.bit();
+        self.enable_register(event).and_mask(!bit);
+    }
+
+    /// Stops detecting any event on this pin.
+    pub fn disable_events(&mut self) {
+        for &event in [Event::RisingEdge, Event::FallingEdge, Event::High, Event::Low,
+                       Event::AsyncRisingEdge, Event::AsyncFallingEdge].iter() {
+            self.disable_event(event);
+        }
+    }
+
+    /// Returns `true` if an enabled event has been detected on this pin since
+    /// it was last cleared.
+    pub fn is_event_detected(&self) -> bool {
+        self.registers.EDS[self.bank()].read() & self.bit() != 0
+    }
+
+    /// Clears this pin's detected event, acknowledging its interrupt.
+    pub fn clear_event(&mut self) {
+        let bit = self.bit();
+        self.registers.EDS[self.bank()].write(bit);
+    }
+}
+
+/// Reads and clears the detected events of every pin. Bit `n` of the result
+/// is set if an event was detected on pin `n`.
+pub fn take_events() -> u64 {
+    let registers = unsafe { &mut *(GPIO_BASE as *mut Registers) };
+    let low = registers.EDS[0].read();
+    let high = registers.EDS[1].read();
+    // Writing a 1 clears a bit; only the events read are cleared.
+    registers.EDS[0].write(low);
+    registers.EDS[1].write(high);
+    low as u64 | (high as u64) << 32
+}
+
+/// Returns the value of the function select register `register` after
+/// selecting `function` for `pin`, one of the ten pins it controls.
+fn with_function(register: u32, pin: u8, function: Function) -> u32 {
+    let shift = (pin % 10) * 3;
+    register & !(0b111 << shift) | (function as u32) << shift
+}
+
+/// Debounces a noisy input, such as a mechanical button. A change in level is
+/// reported at once, and further changes are ignored until the level has had
+/// `debounce_us` microseconds to settle. A change back during that window is
+/// only seen if the input is sampled again once it closes, at `settles_at`.
+#[derive(Copy, Clone, Debug)]
+pub struct Debouncer {
+    debounce_us: u64,
+    level: bool,
+    changed_at: Option<u64>,
+}
+
+impl Debouncer {
+    /// Returns a debouncer for an input currently at `level`.
+    pub fn new(debounce_us: u64, level: bool) -> Debouncer {
+        Debouncer { debounce_us, level, changed_at: None }
+    }
+
+    /// The debounced level.
+    pub fn level(&self) -> bool {
+        self.level
+    }
+
+    /// Updates the debouncer with the input's `level` at time `now`, in
+    /// microseconds. Returns the new debounced level if it changed.
+    pub fn update(&mut self, level: bool, now: u64) -> Option<bool> {
+        if let Some(changed_at) = self.changed_at {
+            if now.wrapping_sub(changed_at) < self.debounce_us {
+                return None;
+            }
+            self.changed_at = None;
+        }
+
+        if level == self.level {
+            return None;
+        }
+
+        self.level = level;
+        self.changed_at = Some(now);
+        Some(level)
+    }
+
+    /// Returns the time, in microseconds, at which the input should be
+    /// sampled again because the level last reported has settled, or `None`
+    /// if it has been sampled since.
+    pub fn settles_at(&self) -> Option<u64> {
+        self.changed_at.map(|changed_at| changed_at.wrapping_add(self.debounce_us))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_function_replaces_the_old_function() {
+        let alt5 = with_function(0, 14, Function::Alt5);
+        assert_eq!(alt5, 0b010 << 12);
+        assert_eq!(with_function(alt5, 14, Function::Output), 0b001 << 12);
+        assert_eq!(with_function(alt5, 14, Function::Input), 0);
+    }
+
+    #[test]
+    fn test_with_function_leaves_other_pins_alone() {
+        let register = with_function(!0, 19, Function::Alt4);
+        assert_eq!(register, !(0b111 << 27) | 0b011 << 27);
+        assert_eq!(with_function(register, 10, Function::Input), register & !0b111);
+    }
+
+    #[test]
+    fn test_debouncer_reports_changes() {
+        let mut button = Debouncer::new(1000, true);
+        assert_eq!(button.update(true, 0), None);
+        assert_eq!(button.update(false, 10), Some(false));
+        assert_eq!(button.update(true, 5000), Some(true));
+        assert!(button.level());
+    }
+
+    #[test]
+    fn test_debouncer_ignores_bounces() {
+        let mut button = Debouncer::new(1000, true);
+        assert_eq!(button.update(false, 100), Some(false));
+        assert_eq!(button.update(true, 300), None);
+        assert_eq!(button.update(false, 600), None);
+        assert_eq!(button.update(true, 1000), None);
+        assert!(!button.level());
+        assert_eq!(button.update(true, 1100), Some(true));
+    }
+
+    #[test]
+    fn test_debouncer_catches_a_release_while_settling() {
+        let mut button = Debouncer::new(1000, true);
+        assert_eq!(button.update(false, 100), Some(false));
+        assert_eq!(button.settles_at(), Some(1100));
+
+        // A quick tap: the release edge comes before the level has settled.
+        assert_eq!(button.update(true, 300), None);
+        assert!(!button.level());
+        assert_eq!(button.update(true, 1100), Some(true));
+        assert_eq!(button.settles_at(), Some(2100));
+        assert_eq!(button.update(true, 2100), None);
+        assert_eq!(button.settles_at(), None);
+    }
+
+    #[test]
+    fn test_debouncer_handles_a_wrapping_clock() {
+        let mut button = Debouncer::new(1000, false);
+        assert_eq!(button.update(true, u64::max_value() - 10), Some(true));
+        assert_eq!(button.update(false, 100), None);
+        assert_eq!(button.update(false, 990), Some(false));
     }
 }
@@ -0,0 +1,308 @@
+use byteorder::{ByteOrder, BigEndian, LittleEndian};
+
+use super::{Rgba, Target};
+
+/// An error decoding an image file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The file isn't a BMP or QOI image.
+    Unrecognized,
+    /// The file ends before the image does.
+    Truncated,
+    /// The image uses a feature that isn't supported, such as BMP's run
+    /// length compression.
+    Unsupported,
+    /// The header describes an image that can't exist, such as one with no
+    /// pixels.
+    Invalid,
+}
+
+const BMP_MAGIC: &[u8] = b"BM";
+const BMP_FILE_HEADER_SIZE: usize = 14;
+/// The size of the OS/2 `BITMAPCOREHEADER`, which has 16-bit dimensions.
+const BMP_CORE_HEADER_SIZE: usize = 12;
+/// The size of `BITMAPINFOHEADER`; later versions extend it.
+const BMP_INFO_HEADER_SIZE: usize = 40;
+const BMP_RGB: u32 = 0;
+const BMP_BITFIELDS: u32 = 3;
+const BMP_ALPHABITFIELDS: u32 = 6;
+
+const QOI_MAGIC: &[u8] = b"qoif";
+const QOI_HEADER_SIZE: usize = 14;
+const QOI_OP_RGB: u8 = 0xfe;
+const QOI_OP_RGBA: u8 = 0xff;
+const QOI_OP_INDEX: u8 = 0b00;
+const QOI_OP_DIFF: u8 = 0b01;
+const QOI_OP_LUMA: u8 = 0b10;
+const QOI_OP_RUN: u8 = 0b11;
+
+/// The largest image, in pixels, that will be decoded.
+const MAX_PIXELS: usize = 1 << 24;
+
+/// An image in memory, which can be drawn on and blitted to other targets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    width: usize,
+    height: usize,
+    pixels: Vec<Rgba>,
+}
+
+impl Image {
+    /// Returns a fully transparent image.
+    pub fn new(width: usize, height: usize) -> Image {
+        Image { width, height, pixels: vec![Rgba::TRANSPARENT; width * height] }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Returns the pixels row by row, from the top left.
+    pub fn pixels(&self) -> &[Rgba] {
+        &self.pixels
+    }
+
+    /// Decodes a BMP or QOI file, telling them apart by their magic numbers.
+    pub fn decode(data: &[u8]) -> Result<Image, Error> {
+        if data.starts_with(BMP_MAGIC) {
+            Image::from_bmp(data)
+        } else if data.starts_with(QOI_MAGIC) {
+            Image::from_qoi(data)
+        } else {
+            Err(Error::Unrecognized)
+        }
+    }
+
+    fn with_size(width: usize, height: usize) -> Result<Image, Error> {
+        match width.checked_mul(height) {
+            Some(pixels) if pixels > 0 && pixels <= MAX_PIXELS => Ok(Image::new(width, height)),
+            _ => Err(Error::Invalid),
+        }
+    }
+
+    /// Decodes a Windows bitmap: uncompressed with 1, 4, 8, 16, 24 or 32 bits
+    /// per pixel, with or without bit field masks, stored bottom-up or
+    /// top-down.
+    pub fn from_bmp(data: &[u8]) -> Result<Image, Error> {
+        if !data.starts_with(BMP_MAGIC) {
+            return Err(Error::Unrecognized);
+        }
+        if data.len() < BMP_FILE_HEADER_SIZE + 4 {
+            return Err(Error::Truncated);
+        }
+
+        let pixel_offset = LittleEndian::read_u32(&data[10..]) as usize;
+        let header_size = LittleEndian::read_u32(&data[14..]) as usize;
+        if data.len() < BMP_FILE_HEADER_SIZE + header_size {
+            return Err(Error::Truncated);
+        }
+
+        let header = &data[BMP_FILE_HEADER_SIZE..];
+        let (width, height, bits, compression, colors_used, palette_entry_size);
+        if header_size == BMP_CORE_HEADER_SIZE {
+            width = LittleEndian::read_u16(&header[4..]) as i64;
+            height = LittleEndian::read_u16(&header[6..]) as i64;
+            bits = LittleEndian::read_u16(&header[10..]);
+            compression = BMP_RGB;
+            colors_used = 0;
+            palette_entry_size = 3;
+        } else if header_size >= BMP_INFO_HEADER_SIZE {
+            width = LittleEndian::read_i32(&header[4..]) as i64;
+            height = LittleEndian::read_i32(&header[8..]) as i64;
+            bits = LittleEndian::read_u16(&header[14..]);
+            compression = LittleEndian::read_u32(&header[16..]);
+            colors_used = LittleEndian::read_u32(&header[32..]) as usize;
+            palette_entry_size = 4;
+        } else {
+            return Err(Error::Invalid);
+        }
+
+        // A negative height means the rows are stored from the top down.
+        let top_down = height < 0;
+        let (width, height) = (width, height.abs());
+        if width <= 0 || height == 0 {
+            return Err(Error::Invalid);
+        }
+        let mut image = Image::with_size(width as usize, height as usize)?;
+
+        // The masks for bit fields follow `BITMAPINFOHEADER` and are part of
+        // the later versions, so they are in the same place either way.
+        let mut masks_size = 0;
+        let masks = match (compression, bits) {
+            (BMP_RGB, 16) => [0x7c00, 0x03e0, 0x001f, 0],
+            (BMP_RGB, 32) => [0xff0000, 0x00ff00, 0x0000ff, 0],
+            (BMP_RGB, 1) | (BMP_RGB, 4) | (BMP_RGB, 8) | (BMP_RGB, 24) => [0; 4],
+            (BMP_BITFIELDS, 16) | (BMP_BITFIELDS, 32)
+            | (BMP_ALPHABITFIELDS, 16) | (BMP_ALPHABITFIELDS, 32) => {
+                let count = match compression == BMP_ALPHABITFIELDS || header_size >= 56 {
+                    true => 4,
+                    false => 3,
+                };
+                let start = BMP_FILE_HEADER_SIZE + BMP_INFO_HEADER_SIZE;
+                if data.len() < start + 4 * count {
+                    return Err(Error::Truncated);
+                }
+                if header_size == BMP_INFO_HEADER_SIZE {
+                    masks_size = 4 * count;
+                }
+
+                let mut masks = [0; 4];
+                for (i, mask) in masks.iter_mut().take(count).enumerate() {
+                    *mask = LittleEndian::read_u32(&data[start + 4 * i..]);
+                }
+                masks
+            }
+            _ => return Err(Error::Unsupported),
+        };
+
+        let mut palette = vec![];
+        if bits <= 8 {
+            let count = match colors_used {
+                0 => 1 << bits,
+                count => ::std::cmp::min(count, 1 << bits),
+            };
+            let start = BMP_FILE_HEADER_SIZE + header_size + masks_size;
+            let end = start + count * palette_entry_size;
+            if data.len() < end {
+                return Err(Error::Truncated);
+            }
+            palette = data[start..end].chunks(palette_entry_size)
+                .map(|entry| Rgba::rgb(entry[2], entry[1], entry[0]))
+                .collect();
+        }
+
+        // Rows are padded to a multiple of four bytes.
+        let (width, height) = (width as usize, height as usize);
+        let row_size = (width * bits as usize + 31) / 32 * 4;
+        if data.len() < pixel_offset || data.len() - pixel_offset < row_size * height {
+            return Err(Error::Truncated);
+        }
+
+        for row in 0..height {
+            let start = pixel_offset + row * row_size;
+            let source = &data[start..start + row_size];
+            let y = if top_down { row } else { height - 1 - row };
+            for x in 0..width {
+                let color = match bits {
+                    1 | 4 | 8 => {
+                        let bit = x * bits as usize;
+                        let shift = 8 - bits as usize - bit % 8;
+                        let index = (source[bit / 8] >> shift) as usize & ((1 << bits) - 1);
+                        *palette.get(index).ok_or(Error::Invalid)?
+                    }
+                    16 => unpack(LittleEndian::read_u16(&source[2 * x..]) as u32, &masks),
+                    24 => Rgba::rgb(source[3 * x + 2], source[3 * x + 1], source[3 * x]),
+                    _ => unpack(LittleEndian::read_u32(&source[4 * x..]), &masks),
+                };
+                image.set_pixel(x, y, color);
+            }
+        }
+
+        Ok(image)
+    }
+
+    /// Decodes a "Quite OK Image" file.
+    pub fn from_qoi(data: &[u8]) -> Result<Image, Error> {
+        if !data.starts_with(QOI_MAGIC) {
+            return Err(Error::Unrecognized);
+        }
+        if data.len() < QOI_HEADER_SIZE {
+            return Err(Error::Truncated);
+        }
+
+        let width = BigEndian::read_u32(&data[4..]) as usize;
+        let height = BigEndian::read_u32(&data[8..]) as usize;
+        let mut image = Image::with_size(width, height)?;
+
+        let mut seen = [Rgba::TRANSPARENT; 64];
+        let mut pixel = Rgba::BLACK;
+        let mut bytes = data[QOI_HEADER_SIZE..].iter().cloned();
+        let mut next = || bytes.next().ok_or(Error::Truncated);
+
+        let mut i = 0;
+        while i < image.pixels.len() {
+            let byte = next()?;
+            let mut run = 1;
+            match (byte, byte >> 6) {
+                (QOI_OP_RGB, _) => {
+                    pixel = Rgba { red: next()?, green: next()?, blue: next()?, ..pixel };
+                }
+                (QOI_OP_RGBA, _) => {
+                    pixel = Rgba::new(next()?, next()?, next()?, next()?);
+                }
+                (_, QOI_OP_INDEX) => pixel = seen[byte as usize],
+                (_, QOI_OP_DIFF) => {
+                    pixel.red = pixel.red.wrapping_add((byte >> 4 & 0b11).wrapping_sub(2));
+                    pixel.green = pixel.green.wrapping_add((byte >> 2 & 0b11).wrapping_sub(2));
+                    pixel.blue = pixel.blue.wrapping_add((byte & 0b11).wrapping_sub(2));
+                }
+                (_, QOI_OP_LUMA) => {
+                    let green = (byte & 0x3f).wrapping_sub(32);
+                    let rest = next()?;
+                    pixel.red = pixel.red
+                        .wrapping_add(green.wrapping_add((rest >> 4).wrapping_sub(8)));
+                    pixel.green = pixel.green.wrapping_add(green);
+                    pixel.blue = pixel.blue
+                        .wrapping_add(green.wrapping_add((rest & 0x0f).wrapping_sub(8)));
+                }
+                (_, QOI_OP_RUN) => run = (byte & 0x3f) as usize + 1,
+                _ => unreachable!(),
+            }
+
+            let hash = pixel.red as usize * 3 + pixel.green as usize * 5
+                + pixel.blue as usize * 7 + pixel.alpha as usize * 11;
+            seen[hash % 64] = pixel;
+
+            let end = ::std::cmp::min(i + run, image.pixels.len());
+            for slot in &mut image.pixels[i..end] {
+                *slot = pixel;
+            }
+            i = end;
+        }
+
+        Ok(image)
+    }
+}
+
+/// Extracts a color from `value` with red, green, blue and alpha `masks`. A
+/// zero alpha mask means the color is opaque.
+fn unpack(value: u32, masks: &[u32; 4]) -> Rgba {
+    let channel = |mask: u32, default: u8| {
+        if mask == 0 {
+            return default;
+        }
+        let max = mask >> mask.trailing_zeros();
+        (((value & mask) >> mask.trailing_zeros()) as u64 * 255 / max as u64) as u8
+    };
+    Rgba {
+        red: channel(masks[0], 0),
+        green: channel(masks[1], 0),
+        blue: channel(masks[2], 0),
+        alpha: channel(masks[3], 255),
+    }
+}
+
+impl Target for Image {
+    fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    fn get_pixel(&self, x: usize, y: usize) -> Rgba {
+        self.pixels[y * self.width + x]
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize, color: Rgba) {
+        self.pixels[y * self.width + x] = color;
+    }
+
+    fn fill_span(&mut self, x: usize, y: usize, width: usize, color: Rgba) {
+        let start = y * self.width + x;
+        for pixel in &mut self.pixels[start..start + width] {
+            *pixel = color;
+        }
+    }
+}
@@ -0,0 +1,443 @@
+//! 2D drawing: lines, shapes, filled polygons and alpha-blended images.
+//!
+//! Drawing goes through a `Canvas`, which clips everything to its clipping
+//! rectangle and draws onto any `Target`: the framebuffer, or an `Image` in
+//! memory. Coordinates are signed so shapes may lie partly off the target.
+
+mod image;
+
+#[cfg(test)]
+mod tests;
+
+pub use self::image::{Image, Error};
+
+use std::cmp::{max, min};
+
+use font::Font;
+
+/// A color with an alpha channel: 0 is fully transparent, 255 opaque.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct Rgba {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+    pub alpha: u8,
+}
+
+impl Rgba {
+    pub const TRANSPARENT: Rgba = Rgba { red: 0, green: 0, blue: 0, alpha: 0 };
+    pub const BLACK: Rgba = Rgba { red: 0, green: 0, blue: 0, alpha: 255 };
+    pub const WHITE: Rgba = Rgba { red: 255, green: 255, blue: 255, alpha: 255 };
+
+    pub const fn new(red: u8, green: u8, blue: u8, alpha: u8) -> Rgba {
+        Rgba { red, green, blue, alpha }
+    }
+
+    /// Returns an opaque color.
+    pub const fn rgb(red: u8, green: u8, blue: u8) -> Rgba {
+        Rgba { red, green, blue, alpha: 255 }
+    }
+
+    /// Returns the color packed as `0xAARRGGBB`.
+    pub fn from_argb(argb: u32) -> Rgba {
+        Rgba::new((argb >> 16) as u8, (argb >> 8) as u8, argb as u8, (argb >> 24) as u8)
+    }
+
+    /// Packs the color as `0xAARRGGBB`.
+    pub fn to_argb(self) -> u32 {
+        (self.alpha as u32) << 24 | (self.red as u32) << 16 | (self.green as u32) << 8
+            | self.blue as u32
+    }
+
+    /// Returns this color composited over `below` (Porter-Duff "source
+    /// over").
+    pub fn over(self, below: Rgba) -> Rgba {
+        match self.alpha {
+            255 => return self,
+            0 => return below,
+            _ => {}
+        }
+
+        let alpha = self.alpha as u32;
+        let below_alpha = below.alpha as u32 * (255 - alpha) / 255;
+        let total = alpha + below_alpha;
+        if total == 0 {
+            return Rgba::TRANSPARENT;
+        }
+
+        let channel = |top: u8, bottom: u8| {
+            ((top as u32 * alpha + bottom as u32 * below_alpha + total / 2) / total) as u8
+        };
+        Rgba {
+            red: channel(self.red, below.red),
+            green: channel(self.green, below.green),
+            blue: channel(self.blue, below.blue),
+            alpha: total as u8,
+        }
+    }
+}
+
+/// Something that can be drawn on: a grid of pixels.
+pub trait Target {
+    /// Returns the width and height in pixels.
+    fn size(&self) -> (usize, usize);
+
+    /// Returns the pixel at (`x`, `y`), which is within the target.
+    fn get_pixel(&self, x: usize, y: usize) -> Rgba;
+
+    /// Replaces the pixel at (`x`, `y`), which is within the target.
+    fn set_pixel(&mut self, x: usize, y: usize, color: Rgba);
+
+    /// Replaces `width` pixels of row `y` from `x` onward, all of which are
+    /// within the target. Targets override this when they can do it faster
+    /// than a pixel at a time.
+    fn fill_span(&mut self, x: usize, y: usize, width: usize, color: Rgba) {
+        for x in x..x + width {
+            self.set_pixel(x, y, color);
+        }
+    }
+}
+
+/// A rectangle. It covers the pixels from (`x`, `y`) up to but not including
+/// (`x + width`, `y + height`).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub fn new(x: i32, y: i32, width: u32, height: u32) -> Rect {
+        Rect { x, y, width, height }
+    }
+
+    fn right(&self) -> i64 {
+        self.x as i64 + self.width as i64
+    }
+
+    fn bottom(&self) -> i64 {
+        self.y as i64 + self.height as i64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        x >= self.x && (x as i64) < self.right() && y >= self.y && (y as i64) < self.bottom()
+    }
+
+    /// Returns the area covered by both rectangles, which may be empty.
+    pub fn intersect(&self, other: &Rect) -> Rect {
+        let (x, y) = (max(self.x, other.x), max(self.y, other.y));
+        let right = min(self.right(), other.right());
+        let bottom = min(self.bottom(), other.bottom());
+        Rect {
+            x,
+            y,
+            width: max(right - x as i64, 0) as u32,
+            height: max(bottom - y as i64, 0) as u32,
+        }
+    }
+}
+
+/// Integer square root, rounded down.
+fn isqrt(n: u64) -> u64 {
+    if n < 2 {
+        return n;
+    }
+
+    let mut x = n;
+    let mut y = (x + 1) / 2;
+    while y < x {
+        x = y;
+        y = (x + n / x) / 2;
+    }
+    x
+}
+
+/// Divides `a` by `b`, rounding toward positive infinity.
+fn div_ceil(a: i64, b: i64) -> i64 {
+    let quotient = a / b;
+    match a % b != 0 && (a < 0) == (b < 0) {
+        true => quotient + 1,
+        false => quotient,
+    }
+}
+
+/// Draws on a `Target`, clipped to a rectangle.
+pub struct Canvas<'a, T: Target + 'a> {
+    target: &'a mut T,
+    clip: Rect,
+}
+
+impl<'a, T: Target> Canvas<'a, T> {
+    /// Returns a canvas drawing on all of `target`.
+    pub fn new(target: &'a mut T) -> Canvas<'a, T> {
+        let (width, height) = target.size();
+        Canvas { target, clip: Rect::new(0, 0, width as u32, height as u32) }
+    }
+
+    fn bounds(&self) -> Rect {
+        let (width, height) = self.target.size();
+        Rect::new(0, 0, width as u32, height as u32)
+    }
+
+    /// Restricts drawing to `clip`, within the target.
+    pub fn set_clip(&mut self, clip: Rect) {
+        self.clip = clip.intersect(&self.bounds());
+    }
+
+    /// Removes the clipping rectangle so the whole target can be drawn on.
+    pub fn reset_clip(&mut self) {
+        self.clip = self.bounds();
+    }
+
+    pub fn clip(&self) -> Rect {
+        self.clip
+    }
+
+    /// Returns the target drawn on.
+    pub fn target(&mut self) -> &mut T {
+        &mut *self.target
+    }
+
+    /// Draws a pixel, blending it with what is below unless it is opaque.
+    pub fn pixel(&mut self, x: i32, y: i32, color: Rgba) {
+        if !self.clip.contains(x, y) || color.alpha == 0 {
+            return;
+        }
+
+        let (x, y) = (x as usize, y as usize);
+        let color = match color.alpha {
+            255 => color,
+            _ => color.over(self.target.get_pixel(x, y)),
+        };
+        self.target.set_pixel(x, y, color);
+    }
+
+    /// Draws `width` pixels of row `y` from `x` onward.
+    fn span(&mut self, x: i32, y: i32, width: u32, color: Rgba) {
+        let span = Rect::new(x, y, width, 1).intersect(&self.clip);
+        if span.is_empty() || color.alpha == 0 {
+            return;
+        }
+
+        if color.alpha == 255 {
+            self.target.fill_span(span.x as usize, span.y as usize, span.width as usize, color);
+        } else {
+            for x in span.x..span.x + span.width as i32 {
+                self.pixel(x, y, color);
+            }
+        }
+    }
+
+    /// Fills the whole clipping rectangle.
+    pub fn clear(&mut self, color: Rgba) {
+        let clip = self.clip;
+        self.fill_rect(clip, color);
+    }
+
+    /// Draws a line from (`x0`, `y0`) to (`x1`, `y1`), both ends included,
+    /// with Bresenham's algorithm.
+    pub fn line(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, color: Rgba) {
+        self.segment(x0, y0, x1, y1, color, true);
+    }
+
+    /// Draws a line, leaving out its last pixel unless `include_end` is set.
+    fn segment(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, color: Rgba, include_end: bool) {
+        // Skip lines entirely to one side of the clipping rectangle.
+        let clip = self.clip;
+        if clip.is_empty()
+            || (x0 < clip.x && x1 < clip.x) || (y0 < clip.y && y1 < clip.y)
+            || (x0 as i64 >= clip.right() && x1 as i64 >= clip.right())
+            || (y0 as i64 >= clip.bottom() && y1 as i64 >= clip.bottom()) {
+            return;
+        }
+
+        let (x0, y0, x1, y1) = (x0 as i64, y0 as i64, x1 as i64, y1 as i64);
+        let (dx, dy) = ((x1 - x0).abs(), -(y1 - y0).abs());
+        let (step_x, step_y) = (if x0 < x1 { 1 } else { -1 }, if y0 < y1 { 1 } else { -1 });
+        let (mut x, mut y, mut error) = (x0, y0, dx + dy);
+        loop {
+            if x == x1 && y == y1 {
+                if include_end {
+                    self.pixel(x as i32, y as i32, color);
+                }
+                break;
+            }
+            self.pixel(x as i32, y as i32, color);
+
+            let error2 = 2 * error;
+            if error2 >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if error2 <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    /// Draws the outline of `rect`, one pixel wide, inside it.
+    pub fn rect(&mut self, rect: Rect, color: Rgba) {
+        if rect.is_empty() {
+            return;
+        }
+
+        let (right, bottom) = ((rect.right() - 1) as i32, (rect.bottom() - 1) as i32);
+        self.span(rect.x, rect.y, rect.width, color);
+        if rect.height > 1 {
+            self.span(rect.x, bottom, rect.width, color);
+        }
+        for y in rect.y + 1..bottom {
+            self.pixel(rect.x, y, color);
+            if rect.width > 1 {
+                self.pixel(right, y, color);
+            }
+        }
+    }
+
+    /// Fills `rect`.
+    pub fn fill_rect(&mut self, rect: Rect, color: Rgba) {
+        let rect = rect.intersect(&self.clip);
+        for y in rect.y..rect.y + rect.height as i32 {
+            self.span(rect.x, y, rect.width, color);
+        }
+    }
+
+    /// Draws the outline of the circle of radius `radius` around (`cx`,
+    /// `cy`) with the midpoint algorithm.
+    pub fn circle(&mut self, cx: i32, cy: i32, radius: u32, color: Rgba) {
+        let (mut x, mut y) = (radius as i32, 0i32);
+        let mut error = 1 - x;
+        while x >= y {
+            let points = [(x, y), (y, x), (-y, x), (-x, y), (-x, -y), (-y, -x), (y, -x), (x, -y)];
+            for (i, &(px, py)) in points.iter().enumerate() {
+                // Where the symmetric points coincide, plot them once so
+                // blended colors aren't applied twice.
+                if !points[..i].contains(&(px, py)) {
+                    self.pixel(cx + px, cy + py, color);
+                }
+            }
+
+            y += 1;
+            if error < 0 {
+                error += 2 * y + 1;
+            } else {
+                x -= 1;
+                error += 2 * (y - x) + 1;
+            }
+        }
+    }
+
+    /// Fills the circle of radius `radius` around (`cx`, `cy`): the pixels
+    /// whose distance from the center is at most `radius`.
+    pub fn fill_circle(&mut self, cx: i32, cy: i32, radius: u32, color: Rgba) {
+        let radius = radius as i64;
+        for dy in -radius..radius + 1 {
+            let half = isqrt((radius * radius - dy * dy) as u64) as i64;
+            self.span((cx as i64 - half) as i32, (cy as i64 + dy) as i32,
+                      (2 * half + 1) as u32, color);
+        }
+    }
+
+    /// Draws the outline of the polygon with vertices `points`, closing it.
+    pub fn polygon(&mut self, points: &[(i32, i32)], color: Rgba) {
+        match points.len() {
+            0 => return,
+            1 => return self.pixel(points[0].0, points[0].1, color),
+            _ => {}
+        }
+
+        // Each edge leaves out its last pixel, which starts the next edge, so
+        // blended colors are applied once per pixel.
+        for (i, &(x0, y0)) in points.iter().enumerate() {
+            let (x1, y1) = points[(i + 1) % points.len()];
+            self.segment(x0, y0, x1, y1, color, false);
+        }
+    }
+
+    /// Fills the polygon with vertices `points` with the even-odd rule. Like
+    /// `fill_rect`, it covers the pixels whose top left corners are inside,
+    /// so `(0, 0), (4, 0), (4, 4), (0, 4)` fills 4 by 4 pixels.
+    pub fn fill_polygon(&mut self, points: &[(i32, i32)], color: Rgba) {
+        if points.len() < 3 {
+            return;
+        }
+
+        let top = points.iter().map(|p| p.1).min().unwrap();
+        let bottom = points.iter().map(|p| p.1).max().unwrap();
+        let top = max(top, self.clip.y);
+        let bottom = min(bottom as i64, self.clip.bottom()) as i32;
+
+        let mut crossings: Vec<i64> = Vec::with_capacity(points.len());
+        for y in top..bottom {
+            crossings.clear();
+            for (i, &(x0, y0)) in points.iter().enumerate() {
+                let (x1, y1) = points[(i + 1) % points.len()];
+                // Each edge covers the rows from its upper end up to but not
+                // including its lower end, so shared vertices count once.
+                if (y0 <= y && y < y1) || (y1 <= y && y < y0) {
+                    let (x0, y0, x1, y1) = (x0 as i64, y0 as i64, x1 as i64, y1 as i64);
+                    // The first pixel whose top left corner is right of the
+                    // edge.
+                    crossings.push(x0 + div_ceil((y as i64 - y0) * (x1 - x0), y1 - y0));
+                }
+            }
+
+            crossings.sort();
+            for pair in crossings.chunks(2).filter(|pair| pair.len() == 2 && pair[1] > pair[0]) {
+                self.span(pair[0] as i32, y, (pair[1] - pair[0]) as u32, color);
+            }
+        }
+    }
+
+    /// Draws `text` in `font` with its top left corner at (`x`, `y`), leaving
+    /// the pixels between strokes alone. Characters the font has no glyph for
+    /// are drawn as `?`. Returns the x coordinate just past the text.
+    pub fn text(&mut self, font: &Font, x: i32, y: i32, text: &str, color: Rgba) -> i32 {
+        let (width, height) = (font.width() as i32, font.height() as i32);
+        let mut x = x;
+        for c in text.chars() {
+            if let Some(glyph) = font.glyph(c).or_else(|| font.glyph('?')) {
+                for glyph_y in 0..height {
+                    for glyph_x in 0..width {
+                        if glyph.is_set(glyph_x as usize, glyph_y as usize) {
+                            self.pixel(x + glyph_x, y + glyph_y, color);
+                        }
+                    }
+                }
+            }
+            x += width;
+        }
+        x
+    }
+
+    /// Draws all of `image` with its top left corner at (`x`, `y`),
+    /// blending it by its alpha channel.
+    pub fn blit(&mut self, image: &Image, x: i32, y: i32) {
+        let (width, height) = (image.width() as u32, image.height() as u32);
+        self.blit_region(image, Rect::new(0, 0, width, height), x, y);
+    }
+
+    /// Draws the part `source` of `image` with its top left corner at (`x`,
+    /// `y`), blending it by its alpha channel.
+    pub fn blit_region(&mut self, image: &Image, source: Rect, x: i32, y: i32) {
+        let (width, height) = (image.width() as u32, image.height() as u32);
+        let source = source.intersect(&Rect::new(0, 0, width, height));
+        let dest = Rect::new(x, y, source.width, source.height).intersect(&self.clip);
+
+        for dest_y in dest.y..dest.y + dest.height as i32 {
+            let source_y = (source.y + dest_y - y) as usize;
+            for dest_x in dest.x..dest.x + dest.width as i32 {
+                let source_x = (source.x + dest_x - x) as usize;
+                let color = image.get_pixel(source_x, source_y);
+                self.pixel(dest_x, dest_y, color);
+            }
+        }
+    }
+}
@@ -0,0 +1,340 @@
+use font::Font;
+use graphics::{Canvas, Error, Image, Rect, Rgba, Target};
+
+const RED: Rgba = Rgba::rgb(255, 0, 0);
+
+/// Returns the coordinates of the pixels of `image` that aren't transparent.
+fn drawn(image: &Image) -> Vec<(usize, usize)> {
+    let mut pixels = vec![];
+    for y in 0..image.height() {
+        for x in 0..image.width() {
+            if image.get_pixel(x, y) != Rgba::TRANSPARENT {
+                pixels.push((x, y));
+            }
+        }
+    }
+    pixels
+}
+
+fn count(image: &Image, color: Rgba) -> usize {
+    image.pixels().iter().filter(|&&pixel| pixel == color).count()
+}
+
+#[test]
+fn test_blend() {
+    assert_eq!(RED.over(Rgba::BLACK), RED);
+    assert_eq!(Rgba::TRANSPARENT.over(RED), RED);
+    assert_eq!(Rgba::new(255, 255, 255, 128).over(Rgba::BLACK), Rgba::rgb(128, 128, 128));
+    assert_eq!(Rgba::new(0, 0, 255, 128).over(Rgba::TRANSPARENT), Rgba::new(0, 0, 255, 128));
+}
+
+#[test]
+fn test_argb() {
+    assert_eq!(Rgba::from_argb(0x80112233), Rgba::new(0x11, 0x22, 0x33, 0x80));
+    assert_eq!(Rgba::new(0x11, 0x22, 0x33, 0x80).to_argb(), 0x80112233);
+}
+
+#[test]
+fn test_rect_intersect() {
+    let a = Rect::new(0, 0, 10, 10);
+    assert_eq!(a.intersect(&Rect::new(5, -5, 10, 10)), Rect::new(5, 0, 5, 5));
+    assert!(a.intersect(&Rect::new(20, 20, 5, 5)).is_empty());
+    assert!(a.contains(9, 9));
+    assert!(!a.contains(10, 0));
+}
+
+#[test]
+fn test_line() {
+    let mut image = Image::new(8, 8);
+    Canvas::new(&mut image).line(0, 0, 4, 2, RED);
+    assert_eq!(drawn(&image), vec![(0, 0), (1, 1), (2, 1), (3, 2), (4, 2)]);
+
+    let mut image = Image::new(8, 8);
+    Canvas::new(&mut image).line(2, 5, 2, 1, RED);
+    assert_eq!(drawn(&image), vec![(2, 1), (2, 2), (2, 3), (2, 4), (2, 5)]);
+}
+
+#[test]
+fn test_clipping() {
+    let mut image = Image::new(8, 8);
+    {
+        let mut canvas = Canvas::new(&mut image);
+        canvas.set_clip(Rect::new(2, 2, 3, 3));
+        canvas.line(-10, 3, 100, 3, RED);
+        canvas.fill_rect(Rect::new(-5, -5, 100, 1), RED);
+    }
+    assert_eq!(drawn(&image), vec![(2, 3), (3, 3), (4, 3)]);
+
+    let mut image = Image::new(4, 4);
+    {
+        let mut canvas = Canvas::new(&mut image);
+        canvas.set_clip(Rect::new(-10, -10, 100, 100));
+        assert_eq!(canvas.clip(), Rect::new(0, 0, 4, 4));
+        canvas.fill_circle(1000, 1000, 5, RED);
+        canvas.line(-1000, -1000, -500, 2000, RED);
+    }
+    assert!(drawn(&image).is_empty());
+}
+
+#[test]
+fn test_rect() {
+    let mut image = Image::new(6, 6);
+    Canvas::new(&mut image).rect(Rect::new(1, 1, 4, 3), RED);
+    assert_eq!(drawn(&image), vec![
+        (1, 1), (2, 1), (3, 1), (4, 1),
+        (1, 2), (4, 2),
+        (1, 3), (2, 3), (3, 3), (4, 3),
+    ]);
+
+    let mut image = Image::new(6, 6);
+    Canvas::new(&mut image).fill_rect(Rect::new(4, 4, 10, 10), RED);
+    assert_eq!(drawn(&image), vec![(4, 4), (5, 4), (4, 5), (5, 5)]);
+}
+
+#[test]
+fn test_circle() {
+    let mut image = Image::new(11, 11);
+    Canvas::new(&mut image).circle(5, 5, 4, RED);
+    for &(x, y) in &[(9, 5), (1, 5), (5, 9), (5, 1)] {
+        assert_eq!(image.get_pixel(x, y), RED);
+    }
+    assert_eq!(image.get_pixel(5, 5), Rgba::TRANSPARENT);
+    // Symmetric in both axes.
+    for (x, y) in drawn(&image) {
+        assert_eq!(image.get_pixel(10 - x, y), RED);
+        assert_eq!(image.get_pixel(x, 10 - y), RED);
+    }
+
+    // Translucent outlines blend each pixel once.
+    let mut image = Image::new(11, 11);
+    let translucent = Rgba::new(255, 0, 0, 128);
+    Canvas::new(&mut image).circle(5, 5, 4, translucent);
+    assert_eq!(count(&image, translucent), drawn(&image).len());
+}
+
+#[test]
+fn test_fill_circle() {
+    let mut image = Image::new(5, 5);
+    Canvas::new(&mut image).fill_circle(2, 2, 1, RED);
+    assert_eq!(drawn(&image), vec![(2, 1), (1, 2), (2, 2), (3, 2), (2, 3)]);
+
+    let mut image = Image::new(21, 21);
+    Canvas::new(&mut image).fill_circle(10, 10, 10, RED);
+    let area = count(&image, RED) as f64;
+    assert!((area - 314.16).abs() < 30.0, "area {}", area);
+}
+
+#[test]
+fn test_fill_polygon() {
+    let mut image = Image::new(8, 8);
+    Canvas::new(&mut image).fill_polygon(&[(1, 1), (5, 1), (5, 5), (1, 5)], RED);
+    assert_eq!(count(&image, RED), 16);
+    assert_eq!(image.get_pixel(1, 1), RED);
+    assert_eq!(image.get_pixel(4, 4), RED);
+    assert_eq!(image.get_pixel(5, 5), Rgba::TRANSPARENT);
+
+    // A triangle: one more pixel on each row.
+    let mut image = Image::new(8, 8);
+    Canvas::new(&mut image).fill_polygon(&[(0, 0), (4, 4), (0, 4)], RED);
+    assert_eq!(drawn(&image), vec![
+        (0, 1),
+        (0, 2), (1, 2),
+        (0, 3), (1, 3), (2, 3),
+    ]);
+
+    // Adjacent polygons sharing an edge don't overlap.
+    let mut image = Image::new(8, 8);
+    {
+        let mut canvas = Canvas::new(&mut image);
+        let translucent = Rgba::new(255, 0, 0, 128);
+        canvas.fill_polygon(&[(0, 0), (6, 0), (0, 6)], translucent);
+        canvas.fill_polygon(&[(6, 0), (6, 6), (0, 6)], translucent);
+        assert_eq!(count(canvas.target(), translucent), 36);
+    }
+
+    // A self-intersecting star leaves its middle empty under even-odd.
+    let mut image = Image::new(20, 20);
+    let star = [(10, 0), (16, 19), (0, 7), (19, 7), (4, 19)];
+    Canvas::new(&mut image).fill_polygon(&star, RED);
+    assert_eq!(image.get_pixel(10, 11), Rgba::TRANSPARENT);
+    assert_eq!(image.get_pixel(10, 3), RED);
+}
+
+#[test]
+fn test_polygon_outline() {
+    let mut image = Image::new(6, 6);
+    let translucent = Rgba::new(0, 255, 0, 100);
+    Canvas::new(&mut image).polygon(&[(1, 1), (4, 1), (4, 4), (1, 4)], translucent);
+    assert_eq!(count(&image, translucent), 12);
+}
+
+#[test]
+fn test_blit() {
+    let mut sprite = Image::new(2, 2);
+    sprite.set_pixel(0, 0, RED);
+    sprite.set_pixel(1, 1, Rgba::new(255, 255, 255, 128));
+
+    let mut image = Image::new(4, 4);
+    {
+        let mut canvas = Canvas::new(&mut image);
+        canvas.clear(Rgba::BLACK);
+        canvas.blit(&sprite, 1, 1);
+        canvas.blit(&sprite, 3, 3);
+        canvas.blit(&sprite, -1, -1);
+    }
+    assert_eq!(image.get_pixel(1, 1), RED);
+    assert_eq!(image.get_pixel(2, 2), Rgba::rgb(128, 128, 128));
+    assert_eq!(image.get_pixel(3, 3), RED);
+    assert_eq!(image.get_pixel(0, 0), Rgba::rgb(128, 128, 128));
+    assert_eq!(image.get_pixel(2, 1), Rgba::BLACK);
+    assert_eq!(count(&image, Rgba::BLACK), 12);
+
+    let mut image = Image::new(4, 4);
+    Canvas::new(&mut image).blit_region(&sprite, Rect::new(1, 1, 5, 5), 0, 0);
+    assert_eq!(drawn(&image), vec![(0, 0)]);
+}
+
+#[test]
+fn test_text() {
+    let font = Font::builtin();
+    let (width, height) = (font.width(), font.height());
+    let mut image = Image::new(width * 3, height);
+    let end = Canvas::new(&mut image).text(&font, 0, 0, "|  ", RED);
+    assert_eq!(end, 3 * width as i32);
+
+    // Only the first cell is drawn in, and only the strokes.
+    let pixels = drawn(&image);
+    assert!(!pixels.is_empty());
+    assert!(pixels.iter().all(|&(x, _)| x < width));
+    assert!(pixels.len() < width * height);
+}
+
+/// Returns a BMP file with a `BITMAPINFOHEADER`, `extra` (masks or a
+/// palette) and then `pixels`.
+fn bmp(width: i32, height: i32, bits: u16, compression: u32, extra: &[u8], pixels: &[u8])
+    -> Vec<u8> {
+    let offset = 14 + 40 + extra.len() as u32;
+    let mut data = vec![];
+    data.extend_from_slice(b"BM");
+    data.extend_from_slice(&le32(offset + pixels.len() as u32));
+    data.extend_from_slice(&[0; 4]);
+    data.extend_from_slice(&le32(offset));
+    data.extend_from_slice(&le32(40));
+    data.extend_from_slice(&le32(width as u32));
+    data.extend_from_slice(&le32(height as u32));
+    data.extend_from_slice(&[1, 0, bits as u8, (bits >> 8) as u8]);
+    data.extend_from_slice(&le32(compression));
+    data.extend_from_slice(&[0; 20]);
+    data.extend_from_slice(extra);
+    data.extend_from_slice(pixels);
+    data
+}
+
+fn le32(value: u32) -> [u8; 4] {
+    [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]
+}
+
+#[test]
+fn test_bmp_24() {
+    // Bottom-up: the first row stored is the bottom one. Rows are padded to
+    // four bytes.
+    let pixels = [
+        0, 0, 255, 0, 255, 0, 0, 0,
+        255, 0, 0, 255, 255, 255, 0, 0,
+    ];
+    let image = Image::decode(&bmp(2, 2, 24, 0, &[], &pixels)).unwrap();
+    assert_eq!((image.width(), image.height()), (2, 2));
+    assert_eq!(image.pixels(), &[
+        Rgba::rgb(0, 0, 255), Rgba::WHITE,
+        Rgba::rgb(255, 0, 0), Rgba::rgb(0, 255, 0),
+    ]);
+
+    let image = Image::decode(&bmp(2, -2, 24, 0, &[], &pixels)).unwrap();
+    assert_eq!(image.get_pixel(0, 0), Rgba::rgb(255, 0, 0));
+}
+
+#[test]
+fn test_bmp_palette() {
+    let palette = [0, 0, 0, 0, 0, 0, 255, 0];
+    let image = Image::decode(&bmp(3, 1, 1, 0, &palette, &[0b1010_0000, 0, 0, 0])).unwrap();
+    assert_eq!(image.pixels(), &[Rgba::rgb(255, 0, 0), Rgba::BLACK, Rgba::rgb(255, 0, 0)]);
+
+    // Without a color count, the palette has an entry for every index.
+    let mut palette = palette.to_vec();
+    palette.resize(16 * 4, 0);
+    let image = Image::decode(&bmp(2, 1, 4, 0, &palette, &[0x10, 0, 0, 0])).unwrap();
+    assert_eq!(image.pixels(), &[Rgba::rgb(255, 0, 0), Rgba::BLACK]);
+}
+
+#[test]
+fn test_bmp_bitfields() {
+    // RGB565.
+    let masks: Vec<u8> = [0xf800, 0x07e0, 0x001f].iter().flat_map(|&m| le32(m).to_vec()).collect();
+    let image = Image::decode(&bmp(2, 1, 16, 3, &masks, &[0x00, 0xf8, 0x1f, 0x00])).unwrap();
+    assert_eq!(image.pixels(), &[Rgba::rgb(255, 0, 0), Rgba::rgb(0, 0, 255)]);
+
+    // ARGB with an alpha mask.
+    let masks: Vec<u8> = [0x00ff0000, 0x0000ff00, 0x000000ff, 0xff000000].iter()
+        .flat_map(|&m| le32(m).to_vec()).collect();
+    let image = Image::decode(&bmp(1, 1, 32, 6, &masks, &[1, 2, 3, 4])).unwrap();
+    assert_eq!(image.pixels(), &[Rgba::new(3, 2, 1, 4)]);
+
+    // Plain 32-bit pixels are opaque.
+    let image = Image::decode(&bmp(1, 1, 32, 0, &[], &[1, 2, 3, 4])).unwrap();
+    assert_eq!(image.pixels(), &[Rgba::rgb(3, 2, 1)]);
+}
+
+#[test]
+fn test_bmp_errors() {
+    let data = bmp(2, 2, 24, 0, &[], &[0; 16]);
+    assert_eq!(Image::decode(&data[..data.len() - 1]), Err(Error::Truncated));
+    assert_eq!(Image::decode(&data[..20]), Err(Error::Truncated));
+    assert_eq!(Image::decode(&bmp(2, 2, 8, 1, &[], &[0; 8])), Err(Error::Unsupported));
+    assert_eq!(Image::decode(&bmp(0, 2, 24, 0, &[], &[])), Err(Error::Invalid));
+    assert_eq!(Image::decode(b"GIF89a"), Err(Error::Unrecognized));
+}
+
+fn qoi(width: u32, height: u32, chunks: &[u8]) -> Vec<u8> {
+    let mut data = b"qoif".to_vec();
+    data.extend_from_slice(&[(width >> 24) as u8, (width >> 16) as u8, (width >> 8) as u8,
+                             width as u8]);
+    data.extend_from_slice(&[(height >> 24) as u8, (height >> 16) as u8, (height >> 8) as u8,
+                             height as u8]);
+    data.extend_from_slice(&[4, 0]);
+    data.extend_from_slice(chunks);
+    data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+    data
+}
+
+#[test]
+fn test_qoi() {
+    let index_of = |pixel: Rgba| {
+        (pixel.red as usize * 3 + pixel.green as usize * 5 + pixel.blue as usize * 7
+            + pixel.alpha as usize * 11) % 64
+    };
+    let chunks = [
+        // RGB: red.
+        0xfe, 200, 0, 0,
+        // Run of 2 more.
+        0xc0 | 1,
+        // Diff: red -1, green +1, blue 0.
+        0x40 | 1 << 4 | 3 << 2 | 2,
+        // Luma: green +10, red +10 - 2, blue +10 + 3.
+        0x80 | (10 + 32), (6 << 4) | (8 + 3),
+        // RGBA.
+        0xff, 1, 2, 3, 4,
+        // Index: back to the first color.
+        index_of(Rgba::rgb(200, 0, 0)) as u8,
+    ];
+    let image = Image::decode(&qoi(7, 1, &chunks)).unwrap();
+    let red = Rgba::rgb(200, 0, 0);
+    assert_eq!(image.pixels(), &[
+        red, red, red, Rgba::rgb(199, 1, 0), Rgba::rgb(207, 11, 13), Rgba::new(1, 2, 3, 4), red,
+    ]);
+}
+
+#[test]
+fn test_qoi_errors() {
+    assert_eq!(Image::decode(&qoi(2, 1, &[0xfe, 1, 2, 3])[..18]), Err(Error::Truncated));
+    assert_eq!(Image::decode(&qoi(0, 1, &[])), Err(Error::Invalid));
+}
@@ -0,0 +1,442 @@
+//! I2C master driver for the BCM2837's Broadcom Serial Controllers (BSC).
+//!
+//! Transfers are polled. The controller has no repeated start of its own:
+//! `write_read` queues the read while the write is still in progress, which
+//! makes the controller issue a repeated start instead of a stop. This only
+//! works if the whole write fits in the FIFO.
+//!
+//! The driver reaches the controller through the `Registers` trait, so the
+//! transaction state machine can be tested on the host against a mock.
+
+#[cfg(test)]
+mod tests;
+
+use std::cmp;
+
+use common::IO_BASE;
+use gpio::{Function, Gpio};
+use rtc;
+use timer;
+use uart::DEFAULT_CORE_CLOCK;
+use volatile::prelude::*;
+use volatile::Volatile;
+
+/// The clock rate of standard mode I2C, in Hz.
+pub const STANDARD_MODE: u32 = 100 * 1000;
+
+/// The clock rate of fast mode I2C, in Hz.
+pub const FAST_MODE: u32 = 400 * 1000;
+
+/// The depth of the controller's FIFO, in bytes.
+pub const FIFO_SIZE: usize = 16;
+
+/// The longest transfer `DLEN` can describe, in bytes.
+pub const MAX_TRANSFER: usize = 0xFFFF;
+
+/// How long a transfer may take before it is abandoned, in microseconds, if
+/// the controller never reports it done.
+const DEFAULT_TIMEOUT_US: u64 = 100 * 1000;
+
+/// The clock stretch timeout the controller resets to, in SCL cycles.
+const DEFAULT_CLOCK_STRETCH_TIMEOUT: u16 = 0x40;
+
+/// The high bits of the first address byte of a 10-bit address.
+const TEN_BIT_PREFIX: u8 = 0b1111_0000 >> 1;
+
+/// `C`: enables the controller.
+const C_I2CEN: u32 = 1 << 15;
+/// `C`: starts a transfer.
+const C_ST: u32 = 1 << 7;
+/// `C`: clears the FIFO.
+const C_CLEAR: u32 = 0b11 << 4;
+/// `C`: the transfer is a read.
+const C_READ: u32 = 1 << 0;
+
+/// `S`: the slave held SCL low for longer than the clock stretch timeout.
+const S_CLKT: u32 = 1 << 9;
+/// `S`: the slave didn't acknowledge its address or a byte.
+const S_ERR: u32 = 1 << 8;
+/// `S`: the FIFO holds data to read.
+const S_RXD: u32 = 1 << 5;
+/// `S`: the FIFO has room to write.
+const S_TXD: u32 = 1 << 4;
+/// `S`: the transfer is complete.
+const S_DONE: u32 = 1 << 1;
+/// `S`: a transfer is active.
+const S_TA: u32 = 1 << 0;
+
+/// A BSC controller's registers.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Register {
+    /// `C`: control.
+    Control,
+    /// `S`: status. Writing 1 to `CLKT`, `ERR` or `DONE` clears it.
+    Status,
+    /// `DLEN`: the length of the transfer.
+    DataLength,
+    /// `A`: the slave's address.
+    SlaveAddress,
+    /// `FIFO`: the data FIFO.
+    Fifo,
+    /// `DIV`: the core clock divider giving SCL.
+    ClockDivider,
+    /// `DEL`: the delays between SCL edges and SDA being sampled or changed.
+    DataDelay,
+    /// `CLKT`: the clock stretch timeout, in SCL cycles.
+    ClockStretchTimeout,
+}
+
+/// Access to a BSC controller's registers, and the time.
+pub trait Registers {
+    fn read(&mut self, register: Register) -> u32;
+
+    fn write(&mut self, register: Register, value: u32);
+
+    /// The current time in microseconds, for transfer timeouts.
+    fn now(&self) -> u64 {
+        timer::current_time()
+    }
+}
+
+#[repr(C)]
+#[allow(non_snake_case)]
+struct BscRegisters {
+    C: Volatile<u32>,
+    S: Volatile<u32>,
+    DLEN: Volatile<u32>,
+    A: Volatile<u32>,
+    FIFO: Volatile<u32>,
+    DIV: Volatile<u32>,
+    DEL: Volatile<u32>,
+    CLKT: Volatile<u32>,
+}
+
+/// The registers of one of the BSC controllers.
+pub struct Mmio {
+    registers: &'static mut BscRegisters,
+}
+
+impl Registers for Mmio {
+    fn read(&mut self, register: Register) -> u32 {
+        let registers = &self.registers;
+        match register {
+            Register::Control => registers.C.read(),
+            Register::Status => registers.S.read(),
+            Register::DataLength => registers.DLEN.read(),
+            Register::SlaveAddress => registers.A.read(),
+            Register::Fifo => registers.FIFO.read(),
+            Register::ClockDivider => registers.DIV.read(),
+            Register::DataDelay => registers.DEL.read(),
+            Register::ClockStretchTimeout => registers.CLKT.read(),
+        }
+    }
+
+    fn write(&mut self, register: Register, value: u32) {
+        let registers = &mut self.registers;
+        match register {
+            Register::Control => registers.C.write(value),
+            Register::Status => registers.S.write(value),
+            Register::DataLength => registers.DLEN.write(value),
+            Register::SlaveAddress => registers.A.write(value),
+            Register::Fifo => registers.FIFO.write(value),
+            Register::ClockDivider => registers.DIV.write(value),
+            Register::DataDelay => registers.DEL.write(value),
+            Register::ClockStretchTimeout => registers.CLKT.write(value),
+        }
+    }
+}
+
+/// The BSC controllers usable as I2C masters.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Bsc {
+    /// BSC0 on GPIO 0 and 1, reserved for HAT ID EEPROMs.
+    Bsc0,
+    /// BSC1 on GPIO 2 and 3: the I2C bus on the 40-pin header.
+    Bsc1,
+}
+
+impl Bsc {
+    fn base(&self) -> usize {
+        match *self {
+            Bsc::Bsc0 => IO_BASE + 0x205000,
+            Bsc::Bsc1 => IO_BASE + 0x804000,
+        }
+    }
+
+    /// The SDA and SCL pins, both alternative function 0.
+    fn pins(&self) -> (u8, u8) {
+        match *self {
+            Bsc::Bsc0 => (0, 1),
+            Bsc::Bsc1 => (2, 3),
+        }
+    }
+}
+
+/// A slave's address.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Address {
+    /// A 7-bit address, below `0x80`.
+    Seven(u8),
+    /// A 10-bit address, below `0x400`.
+    Ten(u16),
+}
+
+impl Address {
+    fn is_valid(&self) -> bool {
+        match *self {
+            Address::Seven(address) => address < 0x80,
+            Address::Ten(address) => address < 0x400,
+        }
+    }
+}
+
+/// An error in an I2C transfer.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The slave didn't acknowledge its address or a byte.
+    Nack,
+    /// The slave stretched the clock for longer than the clock stretch
+    /// timeout.
+    ClockStretchTimeout,
+    /// The controller didn't finish the transfer in time.
+    Timeout,
+    /// The address is out of range for its kind.
+    InvalidAddress,
+    /// The transfer is longer than the controller can describe, or the write
+    /// before a repeated start doesn't fit in the FIFO.
+    TooLong,
+}
+
+/// An I2C master on one of the BSC controllers.
+pub struct I2c<R = Mmio> {
+    registers: R,
+    timeout_us: u64,
+}
+
+impl I2c<Mmio> {
+    /// Sets up `bsc`'s pins and returns a master on it, running at
+    /// `STANDARD_MODE` for the default core clock.
+    pub fn new(bsc: Bsc) -> I2c<Mmio> {
+        let (sda, scl) = bsc.pins();
+        let _sda = Gpio::new(sda).into_alt(Function::Alt0);
+        let _scl = Gpio::new(scl).into_alt(Function::Alt0);
+
+        let registers = unsafe { &mut *(bsc.base() as *mut BscRegisters) };
+        let mut i2c = I2c::with_registers(Mmio { registers });
+        i2c.set_clock(STANDARD_MODE, DEFAULT_CORE_CLOCK);
+        i2c
+    }
+}
+
+impl<R: Registers> I2c<R> {
+    /// Returns a master on the controller behind `registers`, enabled with
+    /// its FIFO and status cleared.
+    pub fn with_registers(registers: R) -> I2c<R> {
+        let mut i2c = I2c { registers, timeout_us: DEFAULT_TIMEOUT_US };
+        i2c.reset();
+        i2c.set_clock_stretch_timeout(DEFAULT_CLOCK_STRETCH_TIMEOUT);
+        i2c
+    }
+
+    /// Returns the registers this master drives.
+    pub fn registers(&mut self) -> &mut R {
+        &mut self.registers
+    }
+
+    /// Sets SCL to the fastest rate no faster than `hz`, given a core clock
+    /// of `core_clock` Hz. Returns the rate set.
+    pub fn set_clock(&mut self, hz: u32, core_clock: u32) -> u32 {
+        // The divider is rounded up to an even number; 0 means 32768.
+        let hz = cmp::max(hz, 1) as u64;
+        let divider = ((core_clock as u64 + hz - 1) / hz) as u32;
+        let divider = cmp::min(cmp::max(divider + divider % 2, 2), 0x8000);
+        self.registers.write(Register::ClockDivider, divider & 0xFFFF);
+
+        // Data is changed and sampled a quarter of a clock after the edges.
+        let delay = cmp::max(divider / 4, 1);
+        self.registers.write(Register::DataDelay, delay << 16 | delay);
+        core_clock / divider
+    }
+
+    /// Sets how many SCL cycles a slave may stretch the clock for before the
+    /// transfer fails with `ClockStretchTimeout`. Zero disables the timeout.
+    pub fn set_clock_stretch_timeout(&mut self, scl_cycles: u16) {
+        self.registers.write(Register::ClockStretchTimeout, scl_cycles as u32);
+    }
+
+    /// Sets how long a transfer may take, in microseconds, before it is
+    /// abandoned with `Timeout`.
+    pub fn set_timeout(&mut self, us: u64) {
+        self.timeout_us = us;
+    }
+
+    /// Writes `data` to the slave at `address`.
+    pub fn write(&mut self, address: Address, data: &[u8]) -> Result<(), Error> {
+        self.transfer(address, data, None)
+    }
+
+    /// Reads `buf.len()` bytes from the slave at `address`.
+    pub fn read(&mut self, address: Address, buf: &mut [u8]) -> Result<(), Error> {
+        self.transfer(address, &[], Some(buf))
+    }
+
+    /// Writes `data` to the slave at `address`, then reads `buf.len()` bytes
+    /// from it after a repeated start, without releasing the bus in between.
+    /// `data` must fit in the FIFO, along with the low address byte for a
+    /// 10-bit address.
+    pub fn write_read(&mut self, address: Address, data: &[u8], buf: &mut [u8])
+        -> Result<(), Error>
+    {
+        self.transfer(address, data, Some(buf))
+    }
+
+    /// Writes `data` and then, if `read` is `Some`, reads into it after a
+    /// repeated start. A 10-bit address is sent as its high bits in the
+    /// address byte and its low byte as the first byte written; reading from
+    /// one always takes a repeated start after that byte.
+    fn transfer(&mut self, address: Address, data: &[u8], read: Option<&mut [u8]>)
+        -> Result<(), Error>
+    {
+        if !address.is_valid() {
+            return Err(Error::InvalidAddress);
+        }
+
+        let (slave, low) = match address {
+            Address::Seven(address) => (address, None),
+            Address::Ten(address) => (TEN_BIT_PREFIX | (address >> 8) as u8, Some(address as u8)),
+        };
+        let write_len = data.len() + low.is_some() as usize;
+        let read_len = read.as_ref().map_or(0, |buf| buf.len());
+        let repeated_start = read.is_some() && write_len > 0;
+        if write_len > MAX_TRANSFER || read_len > MAX_TRANSFER
+            || (repeated_start && write_len > FIFO_SIZE) {
+            return Err(Error::TooLong);
+        }
+
+        self.reset();
+        self.registers.write(Register::SlaveAddress, slave as u32);
+        let result = match read {
+            None => self.write_phase(low, data, false),
+            Some(buf) => match repeated_start {
+                true => self.write_phase(low, data, true).and_then(|_| self.read_phase(buf)),
+                false => self.read_phase(buf),
+            },
+        };
+
+        self.finish(result)
+    }
+
+    /// Writes `low`, if any, and `data` to the addressed slave. If `then_read`
+    /// is set, returns once the transfer is active with the whole write
+    /// queued in the FIFO, so that the read can follow with a repeated start.
+    /// Otherwise returns once the write is done.
+    fn write_phase(&mut self, low: Option<u8>, data: &[u8], then_read: bool)
+        -> Result<(), Error>
+    {
+        self.registers.write(Register::DataLength, (data.len() + low.is_some() as usize) as u32);
+
+        let mut bytes = low.into_iter().chain(data.iter().cloned()).peekable();
+        // Fill the FIFO before starting, so the transfer doesn't stall.
+        while bytes.peek().is_some() && self.status() & S_TXD != 0 {
+            self.registers.write(Register::Fifo, bytes.next().unwrap() as u32);
+        }
+        self.registers.write(Register::Control, C_I2CEN | C_ST);
+
+        let start = self.registers.now();
+        loop {
+            let status = self.status();
+            if status & (S_ERR | S_CLKT) != 0 {
+                return Err(error(status));
+            }
+            if then_read && status & S_TA != 0 && bytes.peek().is_none() {
+                return Ok(());
+            }
+            while bytes.peek().is_some() && self.status() & S_TXD != 0 {
+                self.registers.write(Register::Fifo, bytes.next().unwrap() as u32);
+            }
+            if status & S_DONE != 0 {
+                return match bytes.peek() {
+                    None => Ok(()),
+                    // Done early: the slave must have stopped acknowledging.
+                    Some(_) => Err(Error::Nack),
+                };
+            }
+            self.check_timeout(start)?;
+        }
+    }
+
+    /// Reads into `buf`, after a repeated start if a write is still active.
+    fn read_phase(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        self.registers.write(Register::DataLength, buf.len() as u32);
+        self.registers.write(Register::Control, C_I2CEN | C_ST | C_READ);
+
+        let start = self.registers.now();
+        let mut received = 0;
+        loop {
+            let status = self.status();
+            while received < buf.len() && self.status() & S_RXD != 0 {
+                buf[received] = self.registers.read(Register::Fifo) as u8;
+                received += 1;
+            }
+            if status & (S_ERR | S_CLKT) != 0 {
+                return Err(error(status));
+            }
+            // The FIFO was drained after `status` was read, so if the transfer
+            // was done then, everything has been received.
+            if status & S_DONE != 0 {
+                return match received == buf.len() {
+                    true => Ok(()),
+                    false => Err(Error::Nack),
+                };
+            }
+            self.check_timeout(start)?;
+        }
+    }
+
+    fn status(&mut self) -> u32 {
+        self.registers.read(Register::Status)
+    }
+
+    fn check_timeout(&self, start: u64) -> Result<(), Error> {
+        match self.registers.now().wrapping_sub(start) > self.timeout_us {
+            true => Err(Error::Timeout),
+            false => Ok(()),
+        }
+    }
+
+    /// Leaves the controller idle after a transfer that ended with `result`.
+    fn finish(&mut self, result: Result<(), Error>) -> Result<(), Error> {
+        if result.is_err() {
+            self.reset();
+        }
+        self.registers.write(Register::Status, S_CLKT | S_ERR | S_DONE);
+        result
+    }
+
+    /// Abandons any transfer, clears the FIFO and the status flags, and
+    /// leaves the controller enabled.
+    fn reset(&mut self) {
+        self.registers.write(Register::Control, C_I2CEN | C_CLEAR);
+        self.registers.write(Register::Status, S_CLKT | S_ERR | S_DONE);
+    }
+}
+
+/// The error the flags set in `status` report.
+fn error(status: u32) -> Error {
+    match status & S_CLKT != 0 {
+        true => Error::ClockStretchTimeout,
+        false => Error::Nack,
+    }
+}
+
+impl<R: Registers> rtc::Bus for I2c<R> {
+    type Error = Error;
+
+    fn write(&mut self, addr: u8, data: &[u8]) -> Result<(), Error> {
+        I2c::write(self, Address::Seven(addr), data)
+    }
+
+    fn write_read(&mut self, addr: u8, data: &[u8], buf: &mut [u8]) -> Result<(), Error> {
+        I2c::write_read(self, Address::Seven(addr), data, buf)
+    }
+}
@@ -0,0 +1,442 @@
+use std::cell::Cell;
+use std::collections::VecDeque;
+
+use rtc::{DateTime, Ds3231};
+use super::*;
+
+/// What the mock controller put on the wire.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Wire {
+    /// A start with the address byte's upper seven bits, and whether it reads.
+    Start(u8, bool),
+    RepeatedStart(u8, bool),
+    Byte(u8),
+    Stop,
+}
+
+/// A slave with a register pointer, like most sensors and RTCs: the first
+/// byte written sets the pointer, and later bytes are written or read at it.
+struct Slave {
+    address: Address,
+    memory: Vec<u8>,
+    pointer: usize,
+}
+
+/// A transfer in progress on the mock controller.
+struct Transfer {
+    address: u8,
+    read: bool,
+    remaining: u32,
+    /// The address byte hasn't been sent yet.
+    starting: bool,
+    repeated: bool,
+    /// The first byte written hasn't been received yet.
+    first: bool,
+}
+
+/// A BSC controller simulated at the register level. Every read of the
+/// status register moves the transfer in progress on by one byte.
+struct MockBsc {
+    slaves: Vec<Slave>,
+    wire: Vec<Wire>,
+    control: u32,
+    data_length: u32,
+    slave_address: u32,
+    clock_divider: u32,
+    data_delay: u32,
+    clock_stretch_timeout: u32,
+    tx: VecDeque<u8>,
+    rx: VecDeque<u8>,
+    done: bool,
+    error: bool,
+    clock_stretched: bool,
+    transfer: Option<Transfer>,
+    /// A read started while a write was in progress, with its length.
+    queued_read: Option<u32>,
+    /// The slave a 10-bit address selected, until the next stop.
+    selected: Option<usize>,
+    /// The addressed slave holds SCL low forever.
+    stretch: bool,
+    /// The controller never makes progress.
+    hang: bool,
+    time: Cell<u64>,
+}
+
+impl MockBsc {
+    fn new(slaves: Vec<Slave>) -> MockBsc {
+        MockBsc {
+            slaves,
+            wire: vec![],
+            control: 0,
+            data_length: 0,
+            slave_address: 0,
+            clock_divider: 0,
+            data_delay: 0,
+            clock_stretch_timeout: 0,
+            tx: VecDeque::new(),
+            rx: VecDeque::new(),
+            done: false,
+            error: false,
+            clock_stretched: false,
+            transfer: None,
+            queued_read: None,
+            selected: None,
+            stretch: false,
+            hang: false,
+            time: Cell::new(0),
+        }
+    }
+
+    fn start(&mut self, read: bool, remaining: u32, repeated: bool) {
+        self.transfer = Some(Transfer {
+            address: self.slave_address as u8,
+            read,
+            remaining,
+            starting: true,
+            repeated,
+            first: true,
+        });
+    }
+
+    fn stop(&mut self) {
+        self.wire.push(Wire::Stop);
+        self.transfer = None;
+        self.queued_read = None;
+        self.selected = None;
+        self.done = true;
+    }
+
+    fn nack(&mut self) {
+        self.error = true;
+        self.stop();
+    }
+
+    /// Finds the slave a transfer to the 7 bits `address` is for: the one
+    /// with that address, or the one a 10-bit address selected.
+    fn addressed(&self, address: u8) -> Option<usize> {
+        if address & 0x7C == TEN_BIT_PREFIX {
+            return self.selected;
+        }
+        self.slaves.iter().position(|slave| slave.address == Address::Seven(address))
+    }
+
+    fn tick(&mut self) {
+        if self.hang {
+            return;
+        }
+
+        let (address, read, remaining, starting, repeated, first) = match self.transfer {
+            Some(ref t) => (t.address, t.read, t.remaining, t.starting, t.repeated, t.first),
+            None => return,
+        };
+
+        let ten_bit = address & 0x7C == TEN_BIT_PREFIX;
+        if starting {
+            self.wire.push(match repeated {
+                true => Wire::RepeatedStart(address, read),
+                false => Wire::Start(address, read),
+            });
+            self.transfer.as_mut().unwrap().starting = false;
+
+            let acknowledged = match (ten_bit, read) {
+                // Any slave with these high bits answers the first byte.
+                (true, false) => self.slaves.iter().any(|slave| match slave.address {
+                    Address::Ten(ten) => TEN_BIT_PREFIX | (ten >> 8) as u8 == address,
+                    _ => false,
+                }),
+                // Reading takes a repeated start after selecting a slave.
+                (true, true) => repeated && self.selected.is_some(),
+                (false, _) => self.addressed(address).is_some(),
+            };
+            if !acknowledged {
+                return self.nack();
+            }
+            if self.stretch {
+                self.clock_stretched = true;
+                return self.stop();
+            }
+            return;
+        }
+
+        if remaining == 0 {
+            return match (read, self.queued_read) {
+                (false, Some(length)) => {
+                    self.queued_read = None;
+                    self.start(true, length, true);
+                }
+                _ => self.stop(),
+            };
+        }
+
+        if read {
+            if self.rx.len() < FIFO_SIZE {
+                let index = self.addressed(address).unwrap();
+                let slave = &mut self.slaves[index];
+                let byte = slave.memory[slave.pointer];
+                slave.pointer += 1;
+                self.wire.push(Wire::Byte(byte));
+                self.rx.push_back(byte);
+                self.transfer.as_mut().unwrap().remaining -= 1;
+            }
+            return;
+        }
+
+        // An empty FIFO stalls a write until it is refilled.
+        let byte = match self.tx.pop_front() {
+            Some(byte) => byte,
+            None => return,
+        };
+        self.wire.push(Wire::Byte(byte));
+        self.transfer.as_mut().unwrap().remaining -= 1;
+
+        // After a 10-bit address's first byte, the next is its low byte.
+        if ten_bit && self.selected.is_none() {
+            let high = ((address & 0b11) as u16) << 8;
+            let wanted = Address::Ten(high | byte as u16);
+            self.selected = self.slaves.iter().position(|slave| slave.address == wanted);
+            if self.selected.is_none() {
+                self.nack();
+            }
+            return;
+        }
+
+        self.transfer.as_mut().unwrap().first = false;
+        let index = self.addressed(address).unwrap();
+        let slave = &mut self.slaves[index];
+        if first {
+            slave.pointer = byte as usize;
+        } else {
+            slave.memory[slave.pointer] = byte;
+            slave.pointer += 1;
+        }
+    }
+
+    fn status(&self) -> u32 {
+        let mut status = 0;
+        if self.transfer.is_some() { status |= S_TA; }
+        if self.done { status |= S_DONE; }
+        if self.error { status |= S_ERR; }
+        if self.clock_stretched { status |= S_CLKT; }
+        if self.tx.len() < FIFO_SIZE { status |= S_TXD; }
+        if !self.rx.is_empty() { status |= S_RXD; }
+        status
+    }
+}
+
+impl Registers for MockBsc {
+    fn read(&mut self, register: Register) -> u32 {
+        match register {
+            Register::Control => self.control,
+            Register::Status => {
+                self.tick();
+                self.status()
+            }
+            Register::DataLength => self.data_length,
+            Register::SlaveAddress => self.slave_address,
+            Register::Fifo => self.rx.pop_front().unwrap_or(0) as u32,
+            Register::ClockDivider => self.clock_divider,
+            Register::DataDelay => self.data_delay,
+            Register::ClockStretchTimeout => self.clock_stretch_timeout,
+        }
+    }
+
+    fn write(&mut self, register: Register, value: u32) {
+        match register {
+            Register::Control => {
+                self.control = value & !(C_ST | C_CLEAR);
+                if value & C_CLEAR != 0 {
+                    self.tx.clear();
+                    self.rx.clear();
+                    // Clearing the FIFO with no start abandons the transfer.
+                    if value & C_ST == 0 && self.transfer.is_some() {
+                        self.stop();
+                    }
+                }
+                if value & C_ST != 0 {
+                    let read = value & C_READ != 0;
+                    let writing = self.transfer.as_ref().map_or(false, |t| !t.read);
+                    match (read, writing) {
+                        (true, true) => self.queued_read = Some(self.data_length),
+                        _ => self.start(read, self.data_length, false),
+                    }
+                }
+            }
+            Register::Status => {
+                if value & S_DONE != 0 { self.done = false; }
+                if value & S_ERR != 0 { self.error = false; }
+                if value & S_CLKT != 0 { self.clock_stretched = false; }
+            }
+            Register::DataLength => self.data_length = value & 0xFFFF,
+            Register::SlaveAddress => self.slave_address = value & 0x7F,
+            Register::Fifo => {
+                if self.tx.len() < FIFO_SIZE {
+                    self.tx.push_back(value as u8);
+                }
+            }
+            Register::ClockDivider => self.clock_divider = value,
+            Register::DataDelay => self.data_delay = value,
+            Register::ClockStretchTimeout => self.clock_stretch_timeout = value,
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.time.set(self.time.get() + 1);
+        self.time.get()
+    }
+}
+
+fn slave(address: Address) -> Slave {
+    Slave { address, memory: (0..64).collect(), pointer: 0 }
+}
+
+fn i2c(slaves: Vec<Slave>) -> I2c<MockBsc> {
+    I2c::with_registers(MockBsc::new(slaves))
+}
+
+#[test]
+fn test_write_sends_the_address_and_data() {
+    let mut i2c = i2c(vec![slave(Address::Seven(0x50))]);
+    assert_eq!(i2c.write(Address::Seven(0x50), &[4, 0xAA, 0xBB]), Ok(()));
+
+    let bsc = i2c.registers();
+    assert_eq!(bsc.wire, [Wire::Start(0x50, false), Wire::Byte(4), Wire::Byte(0xAA),
+                          Wire::Byte(0xBB), Wire::Stop]);
+    assert_eq!(&bsc.slaves[0].memory[3..7], &[3, 0xAA, 0xBB, 6]);
+    assert_eq!(bsc.status() & (S_DONE | S_ERR | S_CLKT), 0);
+}
+
+#[test]
+fn test_write_refills_the_fifo() {
+    let mut i2c = i2c(vec![slave(Address::Seven(0x50))]);
+    let mut data = [0x5A; 41];
+    data[0] = 8;
+    assert_eq!(i2c.write(Address::Seven(0x50), &data), Ok(()));
+
+    let bsc = i2c.registers();
+    assert_eq!(bsc.wire.len(), 1 + 41 + 1);
+    assert!(bsc.slaves[0].memory[8..48].iter().all(|&byte| byte == 0x5A));
+}
+
+#[test]
+fn test_read_receives_from_the_pointer() {
+    let mut i2c = i2c(vec![slave(Address::Seven(0x20))]);
+    i2c.registers().slaves[0].pointer = 10;
+
+    let mut buf = [0; 20];
+    assert_eq!(i2c.read(Address::Seven(0x20), &mut buf), Ok(()));
+    assert_eq!(buf[0], 10);
+    assert_eq!(buf[19], 29);
+    assert_eq!(i2c.registers().wire.first(), Some(&Wire::Start(0x20, true)));
+    assert_eq!(i2c.registers().wire.last(), Some(&Wire::Stop));
+}
+
+#[test]
+fn test_write_read_uses_a_repeated_start() {
+    let mut i2c = i2c(vec![slave(Address::Seven(0x68))]);
+    let mut buf = [0; 3];
+    assert_eq!(i2c.write_read(Address::Seven(0x68), &[7], &mut buf), Ok(()));
+    assert_eq!(buf, [7, 8, 9]);
+    assert_eq!(i2c.registers().wire, [
+        Wire::Start(0x68, false), Wire::Byte(7), Wire::RepeatedStart(0x68, true),
+        Wire::Byte(7), Wire::Byte(8), Wire::Byte(9), Wire::Stop,
+    ]);
+}
+
+#[test]
+fn test_missing_slave_is_a_nack() {
+    let mut i2c = i2c(vec![slave(Address::Seven(0x68))]);
+    assert_eq!(i2c.write(Address::Seven(0x69), &[1, 2]), Err(Error::Nack));
+    assert_eq!(i2c.read(Address::Seven(0x69), &mut [0; 2]), Err(Error::Nack));
+    assert_eq!(i2c.write_read(Address::Seven(0x69), &[1], &mut [0; 2]), Err(Error::Nack));
+
+    // The controller is left ready for the next transfer.
+    i2c.registers().wire.clear();
+    assert_eq!(i2c.write(Address::Seven(0x68), &[1]), Ok(()));
+    assert_eq!(i2c.registers().wire, [Wire::Start(0x68, false), Wire::Byte(1), Wire::Stop]);
+}
+
+#[test]
+fn test_clock_stretching_times_out() {
+    let mut i2c = i2c(vec![slave(Address::Seven(0x68))]);
+    i2c.registers().stretch = true;
+    assert_eq!(i2c.write(Address::Seven(0x68), &[1]), Err(Error::ClockStretchTimeout));
+    assert_eq!(i2c.registers().status() & S_CLKT, 0);
+}
+
+#[test]
+fn test_stuck_controller_times_out() {
+    let mut i2c = i2c(vec![slave(Address::Seven(0x68))]);
+    i2c.set_timeout(50);
+    i2c.registers().hang = true;
+    assert_eq!(i2c.read(Address::Seven(0x68), &mut [0; 1]), Err(Error::Timeout));
+    assert_eq!(i2c.write(Address::Seven(0x68), &[1]), Err(Error::Timeout));
+
+    // The abandoned transfer was stopped.
+    let bsc = i2c.registers();
+    assert!(bsc.transfer.is_none());
+    assert!(bsc.tx.is_empty());
+}
+
+#[test]
+fn test_ten_bit_addresses() {
+    let mut i2c = i2c(vec![slave(Address::Ten(0x2A5)), slave(Address::Seven(0x50))]);
+    assert_eq!(i2c.write(Address::Ten(0x2A5), &[5, 0xEE]), Ok(()));
+    assert_eq!(i2c.registers().wire, [
+        Wire::Start(0x7A, false), Wire::Byte(0xA5), Wire::Byte(5), Wire::Byte(0xEE), Wire::Stop,
+    ]);
+    assert_eq!(i2c.registers().slaves[0].memory[5], 0xEE);
+
+    i2c.registers().wire.clear();
+    let mut buf = [0; 2];
+    assert_eq!(i2c.write_read(Address::Ten(0x2A5), &[4], &mut buf), Ok(()));
+    assert_eq!(buf, [4, 0xEE]);
+    assert_eq!(i2c.registers().wire, [
+        Wire::Start(0x7A, false), Wire::Byte(0xA5), Wire::Byte(4),
+        Wire::RepeatedStart(0x7A, true), Wire::Byte(4), Wire::Byte(0xEE), Wire::Stop,
+    ]);
+
+    // A read alone still selects the slave with a write first.
+    i2c.registers().wire.clear();
+    assert_eq!(i2c.read(Address::Ten(0x2A5), &mut buf), Ok(()));
+    assert_eq!(&i2c.registers().wire[..3], &[
+        Wire::Start(0x7A, false), Wire::Byte(0xA5), Wire::RepeatedStart(0x7A, true),
+    ]);
+
+    assert_eq!(i2c.write(Address::Ten(0x1A5), &[0]), Err(Error::Nack));
+}
+
+#[test]
+fn test_bad_transfers_are_refused() {
+    let mut i2c = i2c(vec![]);
+    assert_eq!(i2c.write(Address::Seven(0x80), &[0]), Err(Error::InvalidAddress));
+    assert_eq!(i2c.write(Address::Ten(0x400), &[0]), Err(Error::InvalidAddress));
+    assert_eq!(i2c.write_read(Address::Seven(0x50), &[0; 17], &mut [0; 1]), Err(Error::TooLong));
+    assert_eq!(i2c.write_read(Address::Ten(0x50), &[0; 16], &mut [0; 1]), Err(Error::TooLong));
+    assert!(i2c.registers().wire.is_empty());
+}
+
+#[test]
+fn test_clock_divider() {
+    let mut i2c = i2c(vec![]);
+    assert_eq!(i2c.set_clock(STANDARD_MODE, 250_000_000), 100_000);
+    assert_eq!(i2c.registers().clock_divider, 2500);
+    assert_eq!(i2c.registers().data_delay, 625 << 16 | 625);
+
+    // Rounded up to an even divider, so never faster than asked.
+    assert_eq!(i2c.set_clock(FAST_MODE, 250_000_000), 399_361);
+    assert_eq!(i2c.registers().clock_divider, 626);
+
+    assert_eq!(i2c.set_clock(1, 250_000_000), 7629);
+    assert_eq!(i2c.registers().clock_divider, 0x8000);
+}
+
+#[test]
+fn test_ds3231_over_i2c() {
+    let mut rtc = slave(Address::Seven(Ds3231::<I2c<MockBsc>>::ADDRESS));
+    rtc.memory = vec![0; 19];
+    let mut ds3231 = Ds3231::new(i2c(vec![rtc]));
+
+    let datetime = DateTime { year: 2026, month: 10, day: 19, hour: 21, minute: 5, second: 9 };
+    assert_eq!(ds3231.set(&datetime), Ok(()));
+    assert_eq!(ds3231.read(), Ok(datetime));
+}
@@ -1,15 +1,27 @@
+use std::fmt;
+
 use common::IO_BASE;
-use console::kprintln;
 use volatile::prelude::*;
 use volatile::{Volatile, ReadVolatile};
 
 const INT_BASE: usize = IO_BASE + 0xB000 + 0x200;
 
+/// The number of GPU interrupts, numbered 0 through 63.
+pub const NUM_GPU_IRQS: usize = 64;
+
+/// The number of ARM basic interrupts.
+pub const NUM_BASIC_IRQS: usize = 8;
+
+/// The number of interrupts the controller can signal.
+pub const NUM_IRQS: usize = NUM_GPU_IRQS + NUM_BASIC_IRQS;
+
+/// GPU interrupts with a well-known source.
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Interrupt {
     Timer1 = 1,
     Timer3 = 3,
     Usb = 9,
+    Aux = 29,
     Gpio0 = 49,
     Gpio1 = 50,
     Gpio2 = 51,
@@ -17,16 +29,93 @@ pub enum Interrupt {
     Uart = 57,
 }
 
-static INTERRUPTS: [Interrupt;  8] = [
-    Interrupt::Timer1,
-    Interrupt::Timer3,
-    Interrupt::Usb,
-    Interrupt::Gpio0,
-    Interrupt::Gpio1,
-    Interrupt::Gpio2,
-    Interrupt::Gpio3,
-    Interrupt::Uart
-];
+/// The ARM-side interrupts in the basic pending register.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Basic {
+    ArmTimer = 0,
+    ArmMailbox = 1,
+    ArmDoorbell0 = 2,
+    ArmDoorbell1 = 3,
+    Gpu0Halted = 4,
+    Gpu1Halted = 5,
+    IllegalAccess1 = 6,
+    IllegalAccess0 = 7,
+}
+
+/// Any interrupt the controller can signal. GPU interrupts keep their
+/// numbers, 0 through 63, and the ARM basic interrupts follow as 64 through
+/// 71.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Irq(u8);
+
+impl Irq {
+    /// Returns GPU interrupt `n`, if `n < NUM_GPU_IRQS`.
+    pub fn gpu(n: usize) -> Option<Irq> {
+        if n < NUM_GPU_IRQS { Some(Irq(n as u8)) } else { None }
+    }
+
+    /// Returns interrupt number `n`, if `n < NUM_IRQS`.
+    pub fn from_number(n: usize) -> Option<Irq> {
+        if n < NUM_IRQS { Some(Irq(n as u8)) } else { None }
+    }
+
+    /// This interrupt's number, less than `NUM_IRQS`.
+    pub fn number(&self) -> usize {
+        self.0 as usize
+    }
+}
+
+impl From<Interrupt> for Irq {
+    fn from(int: Interrupt) -> Irq {
+        Irq(int as u8)
+    }
+}
+
+impl From<Basic> for Irq {
+    fn from(basic: Basic) -> Irq {
+        Irq(NUM_GPU_IRQS as u8 + basic as u8)
+    }
+}
+
+impl fmt::Display for Irq {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.number() {
+            n if n < NUM_GPU_IRQS => write!(f, "gpu {}", n),
+            n => write!(f, "basic {}", n - NUM_GPU_IRQS),
+        }
+    }
+}
+
+/// The set of interrupts pending at one point in time. Iterating yields them
+/// in ascending order.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Pending(u128);
+
+impl Pending {
+    /// Returns `true` if no interrupt is pending.
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns `true` if `irq` is in the set.
+    pub fn contains<I: Into<Irq>>(&self, irq: I) -> bool {
+        self.0 & (1 << irq.into().number()) != 0
+    }
+}
+
+impl Iterator for Pending {
+    type Item = Irq;
+
+    fn next(&mut self) -> Option<Irq> {
+        if self.0 == 0 {
+            return None;
+        }
+
+        let n = self.0.trailing_zeros();
+        self.0 &= !(1 << n);
+        Some(Irq(n as u8))
+    }
+}
 
 #[repr(C)]
 #[allow(non_snake_case)]
@@ -58,40 +147,36 @@ impl Controller {
     }
 
     /// Enables the interrupt `int`.
-    pub fn enable(&mut self, int: Interrupt) {
-        if (int as u32) < 32 {
-            kprintln!("enabling int {} as u32", int as u32);
-            kprintln!("enabling int 0b{:b}", 0x01 << int as u32);
-            self.registers.ENABLE_IRQS_1.or_mask(0x01 << int as u32);
-        } else {
-            self.registers.ENABLE_IRQS_2.or_mask(0x01 << (32 - int as u32 ));
+    pub fn enable<I: Into<Irq>>(&mut self, int: I) {
+        // Writing a 1 enables an interrupt; 0 bits leave others unchanged.
+        match int.into().number() {
+            n if n < 32 => self.registers.ENABLE_IRQS_1.write(1 << n),
+            n if n < NUM_GPU_IRQS => self.registers.ENABLE_IRQS_2.write(1 << (n - 32)),
+            n => self.registers.ENABLE_BASIC_IRQS.write(1 << (n - NUM_GPU_IRQS)),
         }
     }
 
     /// Disables the interrupt `int`.
-    pub fn disable(&mut self, int: Interrupt) {
-        if (int as u32) < 32 {
-            self.registers.DISABLE_IRQS_1.or_mask(0x01 << int as u32);
-        } else {
-            self.registers.DISABLE_IRQS_2.or_mask(0x01 << (32 - int as u32));
+    pub fn disable<I: Into<Irq>>(&mut self, int: I) {
+        match int.into().number() {
+            n if n < 32 => self.registers.DISABLE_IRQS_1.write(1 << n),
+            n if n < NUM_GPU_IRQS => self.registers.DISABLE_IRQS_2.write(1 << (n - 32)),
+            n => self.registers.DISABLE_BASIC_IRQS.write(1 << (n - NUM_GPU_IRQS)),
         }
     }
 
     /// Returns `true` if `int` is pending. Otherwise, returns `false`.
-    pub fn is_pending(&self, int: Interrupt) -> bool {
-        if (int as u32) < 32 {
-            self.registers.IRQ_PENDING_1.has_mask(0x01 << int as u32)
-        } else {
-            self.registers.IRQ_PENDING_1.has_mask(0x01 << (32 - int as u32))
-        }
+    pub fn is_pending<I: Into<Irq>>(&self, int: I) -> bool {
+        self.pending().contains(int)
     }
 
-    pub fn first_pending(&self) -> Option<Interrupt> {
-        for i in INTERRUPTS.into_iter() {
-            if self.is_pending(*i) {
-                return Some(*i);
-            }
-        }
-        None
+    /// Returns every interrupt that is pending and enabled.
+    pub fn pending(&self) -> Pending {
+        // The basic pending register also summarizes and mirrors some GPU
+        // interrupts in its higher bits; only its ARM interrupts are used.
+        let basic = self.registers.IRQ_BASIC_PENDING.read() as u128 & 0xff;
+        let low = self.registers.IRQ_PENDING_1.read() as u128;
+        let high = self.registers.IRQ_PENDING_2.read() as u128;
+        Pending(low | high << 32 | basic << NUM_GPU_IRQS)
     }
 }
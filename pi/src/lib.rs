@@ -29,14 +29,26 @@ pub mod framebuffer;
 #[cfg(feature = "custom-std")]
 pub mod screen;
 
+pub mod aarch64;
+pub mod ansi;
 pub mod timer;
+pub mod generic_timer;
+pub mod watchdog;
+pub mod rtc;
 pub mod uart;
 pub mod gpio;
+pub mod i2c;
+pub mod spi;
+pub mod dma;
+pub mod pwm;
 pub mod mutex;
 pub mod common;
 pub mod allocator;
 pub mod raccoon;
 mod character_set;
+pub mod font;
+pub mod graphics;
 pub mod console;
 pub mod atags;
 pub mod interrupt;
+pub mod local_interrupt;
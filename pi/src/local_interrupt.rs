@@ -0,0 +1,130 @@
+use volatile::prelude::*;
+use volatile::{Volatile, WriteVolatile, ReadVolatile, Reserved};
+
+// Many thanks to the "QA7" document (BCM2836 ARM-local peripherals) for
+// register locations and layouts.
+
+/// The base address of the ARM-local peripherals.
+const LOCAL_BASE: usize = 0x40000000;
+
+/// The interrupt sources that can be routed to a single core, in the bit order
+/// of that core's IRQ source register.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LocalInterrupt {
+    CntPs = 0,
+    CntPns = 1,
+    CntHp = 2,
+    CntV = 3,
+    Mailbox0 = 4,
+    Mailbox1 = 5,
+    Mailbox2 = 6,
+    Mailbox3 = 7,
+    Gpu = 8,
+    Pmu = 9,
+    AxiOutstanding = 10,
+    LocalTimer = 11,
+}
+
+#[repr(C)]
+#[allow(non_snake_case)]
+struct Registers {
+    CONTROL: Volatile<u32>,
+    __r0: Reserved<u32>,
+    CORE_TIMER_PRESCALER: Volatile<u32>,
+    GPU_INT_ROUTING: Volatile<u32>,
+    PMU_INT_ROUTING_SET: Volatile<u32>,
+    PMU_INT_ROUTING_CLR: Volatile<u32>,
+    __r1: Reserved<u32>,
+    CORE_TIMER_LS: Volatile<u32>,
+    CORE_TIMER_MS: Volatile<u32>,
+    LOCAL_INT_ROUTING: Volatile<u32>,
+    __r2: Reserved<u32>,
+    AXI_COUNTERS: Volatile<u32>,
+    AXI_IRQ: Volatile<u32>,
+    LOCAL_TIMER_CONTROL: Volatile<u32>,
+    LOCAL_TIMER_WRITE: Volatile<u32>,
+    __r3: Reserved<u32>,
+    CORE_TIMER_INT_CONTROL: [Volatile<u32>; 4],
+    CORE_MAILBOX_INT_CONTROL: [Volatile<u32>; 4],
+    CORE_IRQ_SOURCE: [ReadVolatile<u32>; 4],
+    CORE_FIQ_SOURCE: [ReadVolatile<u32>; 4],
+    CORE_MAILBOX_SET: [[WriteVolatile<u32>; 4]; 4],
+    CORE_MAILBOX_CLEAR: [[Volatile<u32>; 4]; 4],
+}
+
+/// The interrupt controller local to one core. Used to route interrupts to
+/// that core and to exchange mailbox messages (inter-processor interrupts)
+/// with other cores.
+pub struct LocalController {
+    core: usize,
+    registers: &'static mut Registers
+}
+
+impl LocalController {
+    /// Returns a new handle to the local interrupt controller of `core`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `core` > `3`.
+    pub fn new(core: usize) -> LocalController {
+        if core > 3 {
+            panic!("LocalController::new(): core {} exceeds maximum of 3", core);
+        }
+
+        LocalController {
+            core,
+            registers: unsafe { &mut *(LOCAL_BASE as *mut Registers) },
+        }
+    }
+
+    /// Enables the IRQ for `mailbox` (0-3) on this core.
+    pub fn enable_mailbox(&mut self, mailbox: usize) {
+        self.registers.CORE_MAILBOX_INT_CONTROL[self.core].or_mask(1 << mailbox);
+    }
+
+    /// Disables the IRQ for `mailbox` (0-3) on this core.
+    pub fn disable_mailbox(&mut self, mailbox: usize) {
+        self.registers.CORE_MAILBOX_INT_CONTROL[self.core].and_mask(!(1 << mailbox));
+    }
+
+    /// Routes the generic timer interrupt `timer` (one of `CntPs`, `CntPns`,
+    /// `CntHp` or `CntV`) to this core as an IRQ.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `timer` isn't a generic timer interrupt.
+    pub fn enable_timer(&mut self, timer: LocalInterrupt) {
+        assert!((timer as u32) < 4, "not a generic timer: {:?}", timer);
+        self.registers.CORE_TIMER_INT_CONTROL[self.core].or_mask(1 << timer as u32);
+    }
+
+    /// Stops routing the generic timer interrupt `timer` to this core.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `timer` isn't a generic timer interrupt.
+    pub fn disable_timer(&mut self, timer: LocalInterrupt) {
+        assert!((timer as u32) < 4, "not a generic timer: {:?}", timer);
+        self.registers.CORE_TIMER_INT_CONTROL[self.core].and_mask(!(1 << timer as u32));
+    }
+
+    /// Returns `true` if `int` is pending on this core. Otherwise, returns
+    /// `false`.
+    pub fn is_pending(&self, int: LocalInterrupt) -> bool {
+        self.registers.CORE_IRQ_SOURCE[self.core].has_mask(1 << int as u32)
+    }
+
+    /// Sets the bits `message` in mailbox `mailbox` of core `target`. If that
+    /// mailbox's IRQ is enabled on `target`, it is interrupted.
+    pub fn send(&mut self, target: usize, mailbox: usize, message: u32) {
+        self.registers.CORE_MAILBOX_SET[target][mailbox].write(message);
+    }
+
+    /// Reads and clears this core's mailbox `mailbox`, returning the bits that
+    /// were set.
+    pub fn take(&mut self, mailbox: usize) -> u32 {
+        let message = self.registers.CORE_MAILBOX_CLEAR[self.core][mailbox].read();
+        self.registers.CORE_MAILBOX_CLEAR[self.core][mailbox].write(message);
+        message
+    }
+}
@@ -1,10 +1,9 @@
 use volatile::prelude::*;
 use volatile::{WriteVolatile, ReadVolatile};
 use common::IO_BASE;
-use console::kprintln;
-use timer::spin_sleep_ms;
+use timer;
 
-// Many thanks to 
+// Many thanks to
 // https://elinux.org/RPi_Framebuffer
 // and
 // https://github.com/raspberrypi/firmware/wiki/Mailbox
@@ -12,6 +11,10 @@ use timer::spin_sleep_ms;
 
 const MAILBOX_BASE: usize = IO_BASE + 0xB880;
 
+/// How long to wait for the mailbox to accept or answer a message, in
+/// microseconds. Changing clocks can take the firmware tens of milliseconds.
+const TIMEOUT: u64 = 500 * 1000;
+
 #[repr(u32)]
 #[derive(Clone, Copy, Debug)]
 pub enum Status {
@@ -27,12 +30,10 @@ pub enum Status {
 // 3 	VCHIQ interface
 // 4 	LEDs interface
 // 5 	Buttons interface
-// 6 	Touch screen interface 
-// 7    None/Reserved 
+// 6 	Touch screen interface
+// 7    None/Reserved
 // 8    Property Tags ARM to VC
-// 8    Property Tags VC to ARM
-// TODO: This probably makes more sense as a bitfield
-// because it's only represented in 4 bits
+// 9    Property Tags VC to ARM
 #[repr(u8)]
 #[derive(Clone, Copy, Debug)]
 pub enum Channel {
@@ -48,15 +49,20 @@ pub enum Channel {
     PropertyTagsVCTOARM = 9,
 }
 
+/// The mailbox didn't accept a message, or didn't answer one, within
+/// `TIMEOUT`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timeout;
+
 #[repr(C)]
 #[allow(non_snake_case)]
 struct Registers {
-// 0x00 	MAIL0_READ 	The read register for mailbox 0 
+// 0x00 	MAIL0_READ 	The read register for mailbox 0
 // 0x10 	MAIL0_PEEK 	Read from the mailbox without removing data from it.
 // 0x14 	MAIL0_SENDER 	Sender ID (bottom 2 bits only)
 // 0x18 	MAIL0_STATUS 	The status register for mailbox 0
 // 0x1C 	MAIL0_CONFIG 	The configuration register for mailbox 0
-// 0x20 	MAIL0_WRITE 	The write register for mailbox 0 (this is actually the read register for mailbox 1). 
+// 0x20 	MAIL0_WRITE 	The write register for mailbox 0 (this is actually the read register for mailbox 1).
     MAIL0_READ: ReadVolatile<u32>,
     r0: ReadVolatile<u32>,
     r1: ReadVolatile<u32>,
@@ -80,69 +86,59 @@ impl Mailbox {
             &mut *(MAILBOX_BASE as *mut Registers)
         };
 
-        let mb = Mailbox {
+        Mailbox {
             channel,
             registers
-        };
-        Mailbox::print_state(&mb);
-        mb
+        }
     }
 
+    /// Orders memory accesses around mailbox accesses, and tells the compiler
+    /// that memory the mailbox was given the address of may have changed.
     fn memory_barrier() {
         unsafe {
-            asm!("DSB SY" :::: "volatile");
-            asm!("DMB SY" :::: "volatile");
+            asm!("DSB SY" ::: "memory" : "volatile");
+            asm!("DMB SY" ::: "memory" : "volatile");
         }
     }
 
-    fn print_state(&self) {
-        kprintln!("MAIL0_PEEK = {:x}", self.registers.MAIL0_PEEK.read());
-        kprintln!("MAIL0_SENDER = {:x}", self.registers.MAIL0_SENDER.read());
-        kprintln!("MAIL0_STATUS = {:x}", self.registers.MAIL0_STATUS.read());
-    }
-
-    // status is the status to block until
-    fn block_while_status(&self, status: Status) -> Result<(), ()> {
+    /// Waits until `status` is clear, for at most `TIMEOUT`.
+    fn block_while_status(&self, status: Status) -> Result<(), Timeout> {
+        let start = timer::current_time();
         loop {
-            self.print_state();
             Mailbox::memory_barrier();
             if !self.registers.MAIL0_STATUS.has_mask(status as u32) {
-                break;
+                return Ok(());
+            }
+            if timer::current_time().wrapping_sub(start) > TIMEOUT {
+                return Err(Timeout);
             }
-            kprintln!("Blocking on status: {:?}({:x}) from mailbox", status, status as u32);
-            spin_sleep_ms(1000);
         }
-        Ok(())
     }
 
-    pub fn receive(&self) -> Result<(u32), ()> {
-        Mailbox::memory_barrier();
+    /// Waits for a message on this mailbox's channel and returns its data,
+    /// the upper 28 bits. Messages for other channels are discarded.
+    pub fn receive(&self) -> Result<u32, Timeout> {
+        let start = timer::current_time();
         loop {
             self.block_while_status(Status::MailEmpty)?;
 
-            Mailbox::memory_barrier();
-
             let received = self.registers.MAIL0_READ.read();
-            kprintln!("Received {:x} from mailbox", received);
-
+            Mailbox::memory_barrier();
             if received & 0xf == self.channel as u32 {
                 return Ok(received & !0xf);
             }
-            spin_sleep_ms(1000);
+            if timer::current_time().wrapping_sub(start) > TIMEOUT {
+                return Err(Timeout);
+            }
         }
     }
 
-    pub fn send(&mut self, data: u32) -> Result<(), ()> {
+    /// Sends `data`, whose lower 4 bits must be clear, on this mailbox's
+    /// channel.
+    pub fn send(&mut self, data: u32) -> Result<(), Timeout> {
+        Mailbox::memory_barrier();
         self.block_while_status(Status::MailFull)?;
-
-        kprintln!("Sending {:x} to mailbox on channel {:?}", data, self.channel);
-
-        unsafe {
-            kprintln!("Contents of data: {:?}", &(*(data as *mut [u8; 300]))[..]);
-        }
         self.registers.MAIL0_WRITE.write((self.channel as u32) | data);
-
-        kprintln!("Sent {:x}", (self.channel as u32) | data);
         Mailbox::memory_barrier();
         Ok(())
     }
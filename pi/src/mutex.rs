@@ -1,9 +1,36 @@
 use std::sync::atomic::{AtomicBool, AtomicUsize};
-use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::Ordering::{Acquire, Release, Relaxed};
 use std::cell::UnsafeCell;
 use std::ops::{DerefMut, Deref, Drop};
 use std::fmt;
 
+use aarch64;
+
+/// The owner value stored while a `Mutex` is unlocked.
+const NO_OWNER: usize = usize::max_value();
+
+/// The number of failed acquisition attempts `lock_irq` tolerates in debug
+/// builds before it reports a deadlock. `lock` has no such limit: its holder
+/// may be a preempted thread that keeps the lock for arbitrarily long.
+#[cfg(debug_assertions)]
+const DEADLOCK_SPINS: usize = 1 << 24;
+
+/// A spinlock protecting a value of type `T`.
+///
+/// The lock is acquired with an atomic compare-exchange using acquire
+/// ordering and released with a release store. The core holding the lock is
+/// recorded as its owner. The compare-exchange is built on exclusive loads
+/// and stores, which only work on cacheable memory: no core may lock before
+/// its MMU and caches are on.
+///
+/// Two flavors of guard are available. `lock` returns a `MutexGuard` that may
+/// be held across a preemption; it is what EL0 code should use. `lock_irq`
+/// masks IRQs on the current core for as long as the returned `IrqMutexGuard`
+/// lives, so the critical section can't be interrupted by a handler that tries
+/// to take the same lock. In debug builds both panic if the current core
+/// already owns the lock while IRQs are masked, so the holder can never run
+/// to release it; `lock_irq` also panics if it spins for an unreasonably long
+/// time.
 #[repr(align(32))]
 pub struct Mutex<T> {
     data: UnsafeCell<T>,
@@ -21,45 +48,135 @@ pub struct MutexGuard<'a, T: 'a> {
 impl<'a, T> !Send for MutexGuard<'a, T> { }
 unsafe impl<'a, T: Sync> Sync for MutexGuard<'a, T> { }
 
+/// A `MutexGuard` that additionally keeps IRQs masked on the current core
+/// until it is dropped.
+pub struct IrqMutexGuard<'a, T: 'a> {
+    guard: Option<MutexGuard<'a, T>>,
+    daif: u64
+}
+
+impl<'a, T> !Send for IrqMutexGuard<'a, T> { }
+unsafe impl<'a, T: Sync> Sync for IrqMutexGuard<'a, T> { }
+
 impl<T> Mutex<T> {
     pub const fn new(val: T) -> Mutex<T> {
         Mutex {
             lock: AtomicBool::new(false),
-            owner: AtomicUsize::new(usize::max_value()),
+            owner: AtomicUsize::new(NO_OWNER),
             data: UnsafeCell::new(val)
         }
     }
 }
 
 impl<T> Mutex<T> {
-    // Once MMU/cache is enabled, do the right thing here. For now, we don't
-    // need any real synchronization.
+    /// Attempts to acquire the lock without blocking. Returns `None` if the
+    /// lock is currently held by anyone, including the calling core.
     pub fn try_lock(&self) -> Option<MutexGuard<T>> {
-        let this = 0;
-        if !self.lock.load(Relaxed) || self.owner.load(Relaxed) == this {
-            self.lock.store(true, Relaxed);
-            self.owner.store(this, Relaxed);
+        if self.lock.compare_exchange(false, true, Acquire, Relaxed).is_ok() {
+            self.owner.store(aarch64::affinity(), Relaxed);
             Some(MutexGuard { lock: &self })
         } else {
             None
         }
     }
 
-    // Once MMU/cache is enabled, do the right thing here. For now, we don't
-    // need any real synchronization.
+    /// Spins until the lock is acquired.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, panics if the current core already holds the lock
+    /// while IRQs are masked. With IRQs unmasked, a holder on the current core
+    /// may be a preempted thread that will release it, so there is no limit
+    /// on how long this spins.
     #[inline(never)]
     pub fn lock(&self) -> MutexGuard<T> {
-        // Wait until we can "aquire" the lock, then "acquire" it.
+        self.acquire("lock", None)
+    }
+
+    /// Masks IRQs on the current core, then spins until the lock is acquired.
+    /// IRQs are restored to their previous state when the returned guard is
+    /// dropped.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, panics if the current core already holds the lock or
+    /// if the lock could not be acquired after a large number of attempts.
+    #[inline(never)]
+    pub fn lock_irq(&self) -> IrqMutexGuard<T> {
+        let daif = unsafe { aarch64::disable_irqs() };
+        let guard = self.acquire("lock_irq", Some(daif));
+        IrqMutexGuard { guard: Some(guard), daif }
+    }
+
+    /// Spins until the lock is acquired, running the debug checks documented
+    /// on the method `name`. `daif` is set only for `lock_irq`, which is the
+    /// only caller bounded by `DEADLOCK_SPINS`; IRQs are restored to it before
+    /// panicking.
+    #[inline(always)]
+    #[cfg_attr(not(debug_assertions), allow(unused_variables))]
+    fn acquire(&self, name: &str, daif: Option<u64>) -> MutexGuard<T> {
+        #[cfg(debug_assertions)]
+        let mut spins = 0;
+
         loop {
-            match self.try_lock() {
-                Some(guard) => return guard,
-                None => continue
+            if !self.lock.load(Relaxed) {
+                if let Some(guard) = self.try_lock() {
+                    return guard;
+                }
             }
+
+            aarch64::spin_hint();
+
+            #[cfg(debug_assertions)]
+            {
+                let core = aarch64::affinity();
+                let recursive = self.owner() == Some(core) && aarch64::irqs_masked();
+                if daif.is_some() {
+                    spins += 1;
+                }
+
+                if recursive || spins >= DEADLOCK_SPINS {
+                    if let Some(daif) = daif {
+                        unsafe { aarch64::restore_irqs(daif) };
+                    }
+
+                    match recursive {
+                        true => panic!("Mutex::{}(): recursive lock on core {}", name, core),
+                        false => panic!("Mutex::{}(): deadlock on core {}: held by core {:?}",
+                                        name, core, self.owner()),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns `true` if the lock is currently held.
+    pub fn is_locked(&self) -> bool {
+        self.lock.load(Relaxed)
+    }
+
+    /// Returns the core that currently holds the lock, if any.
+    pub fn owner(&self) -> Option<usize> {
+        match self.owner.load(Relaxed) {
+            NO_OWNER => None,
+            core => Some(core)
         }
     }
 
+    /// Releases the lock regardless of who holds it.
+    ///
+    /// # Safety
+    ///
+    /// Any outstanding guard for this lock becomes unsound. This is only meant
+    /// for paths that will never return to the holder, such as the panic
+    /// handler.
+    pub unsafe fn force_unlock(&self) {
+        self.unlock();
+    }
+
     fn unlock(&self) {
-        self.lock.store(false, Relaxed);
+        self.owner.store(NO_OWNER, Relaxed);
+        self.lock.store(false, Release);
     }
 }
 
@@ -83,6 +200,28 @@ impl<'a, T: 'a> Drop for MutexGuard<'a, T> {
     }
 }
 
+impl<'a, T: 'a> Deref for IrqMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<'a, T: 'a> DerefMut for IrqMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<'a, T: 'a> Drop for IrqMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        // Release the lock before unmasking IRQs.
+        self.guard.take();
+        unsafe { aarch64::restore_irqs(self.daif) }
+    }
+}
+
 impl<T: fmt::Debug> fmt::Debug for Mutex<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self.try_lock() {
@@ -1,25 +1,21 @@
-use volatile::prelude::*;
-use volatile::{Volatile};
-use console::kprintln;
-use stack_vec::StackVec;
-use mailbox::{Mailbox, Channel};
-
-#[repr(align(16))]
-pub struct TagBufferStorage {
-    buffer: [u8; (1 << 10)],
-}
+//! The mailbox property interface: asking the VideoCore firmware about the
+//! board and having it change clocks, voltages, power states, GPU memory and
+//! the display.
+//!
+//! A `Message` holds one or more tags, each a request with room for its
+//! response, which the firmware writes over the request. The functions at the
+//! bottom of this module send a single tag each and return its response as a
+//! typed value. Nothing here allocates.
 
-static mut TAG_BUFFER_STORAGE: TagBufferStorage = TagBufferStorage {
-    buffer: [0x00u8; (1 << 10)]
-};
+use std::{cmp, mem};
+use std::ops::BitOr;
 
-#[repr(align(16))]
-pub struct TagBuffer {
-    buffer: [Volatile<u8>; (1 << 10)],
-}
+use aarch64;
+use mailbox::{Mailbox, Channel};
+use mutex::Mutex;
 
 // From https://github.com/raspberrypi/firmware/wiki/Mailbox-property-interface
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[repr(u32)]
 pub enum PropertyId {
     GetFirmwareRevision = 0x00000001,
@@ -86,209 +82,610 @@ pub enum PropertyId {
     SetPalette = 0x0004800b,
     SetCursorInfo = 0x00008010,
     SetCursorState = 0x00008011,
-    Unimplemented = 0x00,
 }
 
-#[repr(C)]
-#[derive(Clone, Copy, Debug)]
-pub struct PropertyTag {
-    pub id: u32,
-    pub byte_length: u32,
-    pub tag_type: u32,
-    pub data: [u32; 32]
+/// The size of a message, in words: 1 KiB.
+pub const MESSAGE_WORDS: usize = 256;
+
+/// The code of a message being sent.
+const REQUEST: u32 = 0;
+/// The code of a message the firmware has answered.
+const RESPONSE_SUCCESS: u32 = 0x8000_0000;
+/// The code of a message the firmware couldn't parse.
+const RESPONSE_ERROR: u32 = 0x8000_0001;
+/// Set in a tag's code by the firmware when it has answered the tag. The
+/// other bits are the length of the response in bytes.
+const TAG_RESPONSE: u32 = 1 << 31;
+const END_TAG: u32 = 0;
+/// The words before a message's tags: its size and code.
+const MESSAGE_HEADER_WORDS: usize = 2;
+/// The words before a tag's value: its ID, value size and code.
+const TAG_HEADER_WORDS: usize = 3;
+
+/// Set in a voltage's ID in the response if there is no such voltage.
+const NO_SUCH_VOLTAGE: u32 = 0x8000_0000;
+/// Set in a power state if there is no such device.
+const NO_SUCH_DEVICE: u32 = 1 << 1;
+const POWER_ON: u32 = 1 << 0;
+const POWER_WAIT: u32 = 1 << 1;
+
+/// Only one message is in flight at a time: answers come back on a shared
+/// channel.
+static MAILBOX_LOCK: Mutex<()> = Mutex::new(());
+
+/// Releases the mailbox, whoever holds it. The screen needs it to show
+/// anything.
+///
+/// # Safety
+///
+/// As for `Mutex::force_unlock`: only for paths that never return to the
+/// holder.
+pub unsafe fn force_unlock() {
+    MAILBOX_LOCK.force_unlock();
+}
+
+/// An error sending a message or reading a response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The mailbox didn't accept the message or answer it in time.
+    Timeout,
+    /// The message doesn't fit in `MESSAGE_WORDS`.
+    TooLarge,
+    /// The firmware couldn't parse the message.
+    BadRequest,
+    /// The firmware answered with an unknown message code.
+    BadResponse,
+    /// The firmware didn't answer the tag, most likely because it doesn't
+    /// know it.
+    Unanswered,
+    /// The response is shorter than the tag's response should be.
+    ShortResponse,
+    /// The firmware says the clock, voltage or device asked about doesn't
+    /// exist.
+    NoSuchDevice,
 }
 
-pub fn u32_to_buffer(i: u32, buffer: &mut [Volatile<u8>]) {
-    buffer[3].write((i >> 24) as u8);
-    buffer[2].write((i >> 16) as u8);
-    buffer[1].write((i >> 8) as u8);
-    buffer[0].write(i as u8);
+/// Where a tag is in a `Message`, returned by `Message::push`.
+#[derive(Clone, Copy, Debug)]
+pub struct Slot {
+    offset: usize,
+    words: usize,
 }
 
-pub fn u32_from_buffer(buffer: &[Volatile<u8>]) -> u32 {
-    ((buffer[3].read() as u32) << 24) | ((buffer[2].read() as u32) << 16) | ((buffer[1].read() as u32) << 8) | (buffer[0].read() as u32)
+/// A property interface message, which the firmware answers in place. The
+/// buffer is aligned to 16 bytes, as the mailbox requires.
+#[repr(C, align(16))]
+pub struct Message {
+    buffer: [u32; MESSAGE_WORDS],
+    /// The words used so far, not counting the end tag.
+    len: usize,
 }
 
-impl Default for PropertyTag {
-    fn default() -> PropertyTag {
-        PropertyTag {
-            id: 0,
-            byte_length: 0,
-            tag_type: 0,
-            data: [0; 32]
+impl Message {
+    /// Returns an empty message.
+    pub fn new() -> Message {
+        Message { buffer: [0; MESSAGE_WORDS], len: MESSAGE_HEADER_WORDS }
+    }
+
+    /// Adds tag `id` with the value `request` and room for a response of
+    /// `response_words` words, returning where it is in the message.
+    pub fn push(&mut self, id: PropertyId, request: &[u32], response_words: usize)
+        -> Result<Slot, Error>
+    {
+        let words = cmp::max(request.len(), response_words);
+        let offset = self.len;
+        let value = offset + TAG_HEADER_WORDS;
+        // Leave room for the end tag.
+        if value + words + 1 > MESSAGE_WORDS {
+            return Err(Error::TooLarge);
+        }
+
+        self.buffer[offset] = id as u32;
+        self.buffer[offset + 1] = (words * 4) as u32;
+        self.buffer[offset + 2] = REQUEST;
+        self.buffer[value..value + request.len()].copy_from_slice(request);
+        for word in self.buffer[value + request.len()..value + words].iter_mut() {
+            *word = 0;
         }
+
+        self.len = value + words;
+        Ok(Slot { offset, words })
     }
-}
 
-impl PropertyTag {
-    pub fn new(property_id: PropertyId) -> PropertyTag {
-        match property_id {
-            PropertyId::AllocateBuffer => {
-                let mut data = [0x00; 32];
-                data[0] = 1;
-                PropertyTag {
-                    id: property_id as u32,
-                    byte_length: 8,
-                    tag_type: 0x00, // Request
-                    data
-                }
-            },
-            PropertyId::SetPhysicalWidthHeight => {
-                let mut data = [0x00; 32];
-                data[0] = 1024;
-                data[1] = 768;
-                PropertyTag {
-                    id: property_id as u32,
-                    byte_length: 8,
-                    tag_type: 0x00, // Request
-                    data
-                }
-            },
-            PropertyId::SetVirtualWidthHeight => {
-                let mut data = [0x00; 32];
-                data[0] = 1024;
-                data[1] = 768;
-                PropertyTag {
-                    id: property_id as u32,
-                    byte_length: 8,
-                    tag_type: 0x00, // Request
-                    data
-                }
-            },
-            PropertyId::SetDepth => {
-                let mut data = [0x00; 32];
-                data[0] = 24;
-                PropertyTag {
-                    id: property_id as u32,
-                    byte_length: 4,
-                    tag_type: 0x00, // Request
-                    data
-                }
-            },
-            PropertyId::GetPhysicalWidthHeight => {
-                let data = [0x00; 32];
-                PropertyTag {
-                    id: property_id as u32,
-                    byte_length: 8,
-                    tag_type: 0x00, // Request
-                    data
-                }
-            },
-            PropertyId::GetPitch => {
-                let data = [0x00; 32];
-                PropertyTag {
-                    id: property_id as u32,
-                    byte_length: 4,
-                    tag_type: 0x00, // Request
-                    data
-                }
-            },
-            PropertyId::GetDepth => {
-                let data = [0x00; 32];
-                PropertyTag {
-                    id: property_id as u32,
-                    byte_length: 4,
-                    tag_type: 0x00, // Request
-                    data
+    /// Sends the message and waits for the firmware to answer it.
+    pub fn send(&mut self) -> Result<(), Error> {
+        self.buffer[self.len] = END_TAG;
+        self.buffer[0] = ((self.len + 1) * 4) as u32;
+        self.buffer[1] = REQUEST;
+
+        let address = self.buffer.as_mut_ptr() as usize as u32;
+        // The firmware reads and answers the message in memory, behind the
+        // ARM's caches.
+        let size = mem::size_of_val(&self.buffer);
+        aarch64::clean_invalidate_dcache(address as usize, size);
+        {
+            // The holder mustn't be preempted: the clock code waits for the
+            // mailbox with IRQs masked.
+            let _lock = MAILBOX_LOCK.lock_irq();
+            let mut mailbox = Mailbox::new(Channel::PropertyTagsARMTOVC);
+            mailbox.send(address).map_err(|_| Error::Timeout)?;
+            loop {
+                // Skip stale answers to messages that timed out.
+                if mailbox.receive().map_err(|_| Error::Timeout)? == address {
+                    break;
                 }
-            },
-            _ => { // TODO: Remainder of property tags
-                unimplemented!()
             }
         }
+        aarch64::clean_invalidate_dcache(address as usize, size);
+
+        self.status()
     }
 
-    pub fn to_bytes(&self, buffer: &mut [Volatile<u8>]) -> usize {
-        let mut num_written = 0;
-        u32_to_buffer(self.id, &mut buffer[..]);
-        num_written += 4;
-        u32_to_buffer(self.byte_length, &mut buffer[num_written..]);
-        num_written += 4;
-        u32_to_buffer(self.tag_type, &mut buffer[num_written..]);
-        num_written += 4;
-        for i in 0..self.byte_length/4 {
-            u32_to_buffer(self.data[i as usize], &mut buffer[num_written..]);
-            num_written += 4;
+    /// Checks the message code the firmware answered with.
+    fn status(&self) -> Result<(), Error> {
+        match self.read(1) {
+            RESPONSE_SUCCESS => Ok(()),
+            RESPONSE_ERROR => Err(Error::BadRequest),
+            _ => Err(Error::BadResponse),
         }
-        num_written
     }
 
-    pub fn length(&self) -> usize {
-        self.byte_length as usize + 0x0c
+    /// Reads a word the firmware may have written.
+    fn read(&self, index: usize) -> u32 {
+        unsafe { ::std::ptr::read_volatile(&self.buffer[index]) }
     }
 
-    pub fn from_bytes(buffer: &[Volatile<u8>]) -> Self {
-        let mut num_read = 0;
-        let id = u32_from_buffer(&buffer[num_read..]);
-        num_read += 4;
-        let byte_length = u32_from_buffer(&buffer[num_read..]);
-        num_read += 4;
-        let tag_type = u32_from_buffer(&buffer[num_read..]);
-        num_read += 4;
-
-        let mut data = [0x00; 32];
-        for i in 0..byte_length / 4 {
-            data[i as usize] =  u32_from_buffer(&buffer[num_read..]);
-            num_read += 4;
+    /// Copies the response to the tag at `slot` into `response`, which must
+    /// be no longer than the room the tag was given. Returns the number of
+    /// words the firmware answered with, which may be more than `response`
+    /// holds.
+    pub fn response(&self, slot: Slot, response: &mut [u32]) -> Result<usize, Error> {
+        let code = self.read(slot.offset + 2);
+        if code & TAG_RESPONSE == 0 {
+            return Err(Error::Unanswered);
+        }
+
+        let words = ((code & !TAG_RESPONSE) as usize + 3) / 4;
+        let available = cmp::min(cmp::min(words, slot.words), response.len());
+        for (i, word) in response[..available].iter_mut().enumerate() {
+            *word = self.read(slot.offset + TAG_HEADER_WORDS + i);
         }
 
-        PropertyTag {
-            id,
-            byte_length,
-            tag_type,
-            data
+        match available < response.len() {
+            true => Err(Error::ShortResponse),
+            false => Ok(words),
         }
     }
 }
 
-pub fn send_tags<'a>(tags: &'a mut StackVec<'a, PropertyTag>) -> &'a mut StackVec<'a, PropertyTag> {
+/// Sends tag `id` with the value `request` on its own and fills `response`
+/// with the firmware's answer.
+pub fn query(id: PropertyId, request: &[u32], response: &mut [u32]) -> Result<(), Error> {
+    let mut message = Message::new();
+    let slot = message.push(id, request, response.len())?;
+    message.send()?;
+    message.response(slot, response).map(|_| ())
+}
+
+/// Sends tag `id` with the value `request` and returns the first word of the
+/// response.
+fn query_word(id: PropertyId, request: &[u32]) -> Result<u32, Error> {
+    let mut response = [0; 1];
+    query(id, request, &mut response)?;
+    Ok(response[0])
+}
+
+/// Sends tag `id` about the thing `request[0]` identifies and returns the
+/// second word of the response, checking the first names the same thing.
+fn query_about(id: PropertyId, request: &[u32]) -> Result<u32, Error> {
+    let mut response = [0; 2];
+    query(id, request, &mut response)?;
+    match response[0] == request[0] {
+        true => Ok(response[1]),
+        false => Err(Error::NoSuchDevice),
+    }
+}
+
+/// A clock the firmware controls.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Clock {
+    Emmc = 1,
+    Uart = 2,
+    Arm = 3,
+    Core = 4,
+    V3d = 5,
+    H264 = 6,
+    Isp = 7,
+    Sdram = 8,
+    Pixel = 9,
+    Pwm = 10,
+    Emmc2 = 12,
+}
+
+impl Clock {
+    /// Every clock, in ID order.
+    pub const ALL: [Clock; 11] = [
+        Clock::Emmc, Clock::Uart, Clock::Arm, Clock::Core, Clock::V3d, Clock::H264,
+        Clock::Isp, Clock::Sdram, Clock::Pixel, Clock::Pwm, Clock::Emmc2,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Clock::Emmc => "emmc",
+            Clock::Uart => "uart",
+            Clock::Arm => "arm",
+            Clock::Core => "core",
+            Clock::V3d => "v3d",
+            Clock::H264 => "h264",
+            Clock::Isp => "isp",
+            Clock::Sdram => "sdram",
+            Clock::Pixel => "pixel",
+            Clock::Pwm => "pwm",
+            Clock::Emmc2 => "emmc2",
+        }
+    }
+
+    /// Returns the clock called `name`, as returned by `name()`.
+    pub fn from_name(name: &str) -> Option<Clock> {
+        Clock::ALL.iter().cloned().find(|clock| clock.name() == name)
+    }
+}
+
+/// A voltage the firmware controls.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Voltage {
+    Core = 1,
+    SdramC = 2,
+    SdramP = 3,
+    SdramI = 4,
+}
+
+/// A device the firmware can power on and off.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Device {
+    SdCard = 0,
+    Uart0 = 1,
+    Uart1 = 2,
+    UsbHcd = 3,
+    I2c0 = 4,
+    I2c1 = 5,
+    I2c2 = 6,
+    Spi = 7,
+    Ccp2tx = 8,
+}
+
+/// A block of memory: its base address and size in bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub base: u32,
+    pub size: u32,
+}
+
+/// Flags for `allocate_memory`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryFlags(pub u32);
+
+impl MemoryFlags {
+    /// The GPU may discard the memory while it isn't locked.
+    pub const DISCARDABLE: MemoryFlags = MemoryFlags(1 << 0);
+    /// Memory accessed through the GPU's L1 and L2 caches.
+    pub const NORMAL: MemoryFlags = MemoryFlags(0);
+    /// Memory accessed uncached, through the `0xC` bus alias.
+    pub const DIRECT: MemoryFlags = MemoryFlags(1 << 2);
+    /// Memory accessed non-allocating in L2, through the `0x8` bus alias.
+    pub const COHERENT: MemoryFlags = MemoryFlags(2 << 2);
+    /// Zeroes the memory when it is allocated.
+    pub const ZERO: MemoryFlags = MemoryFlags(1 << 4);
+    /// Doesn't initialize the memory at all.
+    pub const NO_INIT: MemoryFlags = MemoryFlags(1 << 5);
+    /// The memory is likely to stay locked for a long time.
+    pub const HINT_PERMALOCK: MemoryFlags = MemoryFlags(1 << 6);
+}
+
+impl BitOr for MemoryFlags {
+    type Output = MemoryFlags;
+
+    fn bitor(self, other: MemoryFlags) -> MemoryFlags {
+        MemoryFlags(self.0 | other.0)
+    }
+}
+
+/// A handle to GPU memory returned by `allocate_memory`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryHandle(pub u32);
+
+pub fn firmware_revision() -> Result<u32, Error> {
+    query_word(PropertyId::GetFirmwareRevision, &[])
+}
+
+pub fn board_model() -> Result<u32, Error> {
+    query_word(PropertyId::GetBoardModel, &[])
+}
+
+/// Returns the board revision code, which identifies the model, memory size
+/// and manufacturer.
+pub fn board_revision() -> Result<u32, Error> {
+    query_word(PropertyId::GetBoardRevision, &[])
+}
+
+pub fn board_serial() -> Result<u64, Error> {
+    let mut response = [0; 2];
+    query(PropertyId::GetBoardSerial, &[], &mut response)?;
+    Ok(response[0] as u64 | (response[1] as u64) << 32)
+}
+
+/// Returns the Ethernet MAC address, in network byte order.
+pub fn board_mac() -> Result<[u8; 6], Error> {
+    let mut response = [0; 2];
+    query(PropertyId::GetBoardMac, &[], &mut response)?;
+    let (low, high) = (response[0], response[1]);
+    Ok([low as u8, (low >> 8) as u8, (low >> 16) as u8, (low >> 24) as u8,
+        high as u8, (high >> 8) as u8])
+}
+
+/// Returns the memory the ARM cores can use.
+pub fn arm_memory() -> Result<MemoryRegion, Error> {
+    let mut response = [0; 2];
+    query(PropertyId::GetArmMemory, &[], &mut response)?;
+    Ok(MemoryRegion { base: response[0], size: response[1] })
+}
+
+/// Returns the memory reserved for the GPU.
+pub fn vc_memory() -> Result<MemoryRegion, Error> {
+    let mut response = [0; 2];
+    query(PropertyId::GetVcMemory, &[], &mut response)?;
+    Ok(MemoryRegion { base: response[0], size: response[1] })
+}
+
+/// Returns `true` if `clock` is running.
+pub fn clock_state(clock: Clock) -> Result<bool, Error> {
+    let state = query_about(PropertyId::GetClockState, &[clock as u32, 0])?;
+    match state & NO_SUCH_DEVICE {
+        0 => Ok(state & POWER_ON != 0),
+        _ => Err(Error::NoSuchDevice),
+    }
+}
+
+/// Starts or stops `clock`, returning whether it is running.
+pub fn set_clock_state(clock: Clock, on: bool) -> Result<bool, Error> {
+    let state = query_about(PropertyId::SetClockState, &[clock as u32, on as u32])?;
+    match state & NO_SUCH_DEVICE {
+        0 => Ok(state & POWER_ON != 0),
+        _ => Err(Error::NoSuchDevice),
+    }
+}
+
+/// Returns the rate `clock` runs at, in Hz.
+pub fn clock_rate(clock: Clock) -> Result<u32, Error> {
+    nonzero(query_about(PropertyId::GetClockRate, &[clock as u32, 0]))
+}
 
-    let mut mailbox = Mailbox::new(Channel::PropertyTagsARMTOVC);
+/// Returns the fastest `clock` may run, in Hz.
+pub fn max_clock_rate(clock: Clock) -> Result<u32, Error> {
+    nonzero(query_about(PropertyId::GetMaxClockRate, &[clock as u32, 0]))
+}
 
-    let tag_buffer: &mut TagBuffer = unsafe { &mut *(((&mut TAG_BUFFER_STORAGE.buffer as *mut [u8]) as *mut u8) as *mut TagBuffer)};
+/// Returns the slowest `clock` may run, in Hz.
+pub fn min_clock_rate(clock: Clock) -> Result<u32, Error> {
+    nonzero(query_about(PropertyId::GetMinClockRate, &[clock as u32, 0]))
+}
 
-    kprintln!("tag_buffer.buffer[0] = {:?}", tag_buffer.buffer[0].read());
-    kprintln!("tag_buffer.buffer[1] = {:?}", tag_buffer.buffer[1].read());
-    kprintln!("tag_buffer.buffer[2] = {:?}", tag_buffer.buffer[2].read());
-    kprintln!("Totally safe territory...");
-    kprintln!("Empty buffer: {:x?}", &tag_buffer.buffer[..300]);
+/// Sets `clock` to `rate` Hz, within its limits, and returns the rate it
+/// was set to. Unless `skip_turbo` is set, setting the ARM clock above its
+/// minimum also raises the voltage and the other clocks, as turbo does.
+pub fn set_clock_rate(clock: Clock, rate: u32, skip_turbo: bool) -> Result<u32, Error> {
+    let request = [clock as u32, rate, skip_turbo as u32];
+    nonzero(query_about(PropertyId::SetClockRate, &request))
+}
 
-    let mut index = 8;
-    for tag in tags.iter() {
-        index += tag.to_bytes(&mut tag_buffer.buffer[index..]);
-        kprintln!("Added tag {}", index);
+/// A rate of zero means there's no such clock.
+fn nonzero(rate: Result<u32, Error>) -> Result<u32, Error> {
+    match rate {
+        Ok(0) => Err(Error::NoSuchDevice),
+        rate => rate,
     }
+}
 
-    u32_to_buffer(0, &mut tag_buffer.buffer[index..]); // End tag
-    index += 4;
-    u32_to_buffer(index as u32, &mut tag_buffer.buffer); // Size
-    u32_to_buffer(0, &mut tag_buffer.buffer[4..]); // Request code
+/// Returns `true` if turbo is on: the clocks and voltage at their highest.
+pub fn turbo() -> Result<bool, Error> {
+    query_about(PropertyId::GetTurbo, &[0, 0]).map(|level| level != 0)
+}
 
-    kprintln!("{:x?}", &tag_buffer.buffer[..300]);
-    mailbox.send((((&mut tag_buffer.buffer as *mut [Volatile<u8>]) as *mut [u8]) as *mut u8) as u32).expect("Error sending tags in mailbox");
+pub fn set_turbo(on: bool) -> Result<bool, Error> {
+    query_about(PropertyId::SetTurbo, &[0, on as u32]).map(|level| level != 0)
+}
 
-    let mailbox_response = mailbox.receive().expect("Error in mailbox reception");
+/// Returns `voltage`, in microvolts.
+pub fn voltage(voltage: Voltage) -> Result<u32, Error> {
+    query_voltage(PropertyId::GetVoltage, voltage, 0)
+}
 
-    kprintln!("Received {:x} from mailbox in framebuffer", mailbox_response);
+pub fn max_voltage(voltage: Voltage) -> Result<u32, Error> {
+    query_voltage(PropertyId::GetMaxVoltage, voltage, 0)
+}
 
-    kprintln!("{:x?}", &tag_buffer.buffer[..300]);
+pub fn min_voltage(voltage: Voltage) -> Result<u32, Error> {
+    query_voltage(PropertyId::GetMinVoltage, voltage, 0)
+}
 
-    let mut bytes_read = 0;
-    let buffer_size = u32_from_buffer(&tag_buffer.buffer[bytes_read..]);
-    bytes_read += 4;
-    let _response = u32_from_buffer(&tag_buffer.buffer[bytes_read..]);
-    bytes_read += 4;
+/// Sets `voltage` to `microvolts`, within its limits, and returns what it
+/// was set to.
+pub fn set_voltage(voltage: Voltage, microvolts: u32) -> Result<u32, Error> {
+    query_voltage(PropertyId::SetVoltage, voltage, microvolts)
+}
 
-    tags.truncate(0);
+fn query_voltage(id: PropertyId, voltage: Voltage, value: u32) -> Result<u32, Error> {
+    let mut response = [0; 2];
+    query(id, &[voltage as u32, value], &mut response)?;
+    match response[0] == voltage as u32 && response[1] != NO_SUCH_VOLTAGE {
+        true => Ok(response[1]),
+        false => Err(Error::NoSuchDevice),
+    }
+}
+
+/// Returns the SoC's temperature, in thousandths of a degree Celsius.
+pub fn temperature() -> Result<u32, Error> {
+    query_about(PropertyId::GetTemperature, &[0, 0])
+}
 
-    while bytes_read < buffer_size as usize {
-        let tag = PropertyTag::from_bytes(&tag_buffer.buffer[bytes_read..]);
-        kprintln!("Received tag: {:x?}", &tag);
-        tags.push(tag).expect("error pushing tag to stackvec");
-        bytes_read += tag.length();
+/// Returns the temperature, in thousandths of a degree Celsius, above which
+/// the firmware throttles the clocks.
+pub fn max_temperature() -> Result<u32, Error> {
+    query_about(PropertyId::GetMaxtemperature, &[0, 0])
+}
+
+/// Returns `true` if `device` is powered on.
+pub fn power_state(device: Device) -> Result<bool, Error> {
+    let state = query_about(PropertyId::GetPowerstate, &[device as u32, 0])?;
+    match state & NO_SUCH_DEVICE {
+        0 => Ok(state & POWER_ON != 0),
+        _ => Err(Error::NoSuchDevice),
     }
+}
 
-    tags.pop();
+/// Powers `device` on or off, returning whether it is on. If `wait` is set,
+/// the firmware waits for the device to become stable before answering.
+pub fn set_power_state(device: Device, on: bool, wait: bool) -> Result<bool, Error> {
+    let state = match (on, wait) {
+        (true, true) => POWER_ON | POWER_WAIT,
+        (true, false) => POWER_ON,
+        (false, true) => POWER_WAIT,
+        (false, false) => 0,
+    };
+    let state = query_about(PropertyId::SetPowerState, &[device as u32, state])?;
+    match state & NO_SUCH_DEVICE {
+        0 => Ok(state & POWER_ON != 0),
+        _ => Err(Error::NoSuchDevice),
+    }
+}
+
+/// Returns how long `device` takes to become stable after being powered on,
+/// in microseconds.
+pub fn power_timing(device: Device) -> Result<u32, Error> {
+    query_about(PropertyId::GetTiming, &[device as u32, 0])
+}
+
+/// Allocates `size` bytes of GPU memory aligned to `alignment` bytes.
+pub fn allocate_memory(size: u32, alignment: u32, flags: MemoryFlags)
+    -> Result<MemoryHandle, Error>
+{
+    match query_word(PropertyId::AllocateMemory, &[size, alignment, flags.0])? {
+        0 => Err(Error::NoSuchDevice),
+        handle => Ok(MemoryHandle(handle)),
+    }
+}
+
+/// Locks `handle` in place and returns its bus address.
+pub fn lock_memory(handle: MemoryHandle) -> Result<u32, Error> {
+    match query_word(PropertyId::LockMemory, &[handle.0])? {
+        0 => Err(Error::NoSuchDevice),
+        address => Ok(address),
+    }
+}
 
-    tags
+/// Unlocks `handle`, after which the GPU may move it.
+pub fn unlock_memory(handle: MemoryHandle) -> Result<(), Error> {
+    match query_word(PropertyId::UnlockMemory, &[handle.0])? {
+        0 => Ok(()),
+        _ => Err(Error::NoSuchDevice),
+    }
+}
+
+/// Frees `handle`.
+pub fn release_memory(handle: MemoryHandle) -> Result<(), Error> {
+    match query_word(PropertyId::ReleaseMemory, &[handle.0])? {
+        0 => Ok(()),
+        _ => Err(Error::NoSuchDevice),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers the tag at `slot` as the firmware would, with `value` and a
+    /// response length of `bytes`.
+    fn answer(message: &mut Message, slot: Slot, value: &[u32], bytes: u32) {
+        message.buffer[1] = RESPONSE_SUCCESS;
+        message.buffer[slot.offset + 2] = TAG_RESPONSE | bytes;
+        let start = slot.offset + TAG_HEADER_WORDS;
+        message.buffer[start..start + value.len()].copy_from_slice(value);
+    }
+
+    #[test]
+    fn test_push() {
+        let mut message = Message::new();
+        let slot = message.push(PropertyId::GetClockRate, &[3], 2).unwrap();
+        assert_eq!((slot.offset, slot.words), (2, 2));
+        assert_eq!(&message.buffer[2..7], &[0x00030002, 8, REQUEST, 3, 0]);
+
+        // A longer request than response sizes the tag by the request.
+        let slot = message.push(PropertyId::SetClockRate, &[3, 600_000_000, 0], 2).unwrap();
+        assert_eq!((slot.offset, slot.words), (7, 3));
+        assert_eq!(&message.buffer[7..13], &[0x00038002, 12, REQUEST, 3, 600_000_000, 0]);
+        assert_eq!(message.len, 13);
+    }
+
+    #[test]
+    fn test_push_too_large() {
+        let mut message = Message::new();
+        // The header, the tag's header and the end tag leave this much room.
+        let room = MESSAGE_WORDS - MESSAGE_HEADER_WORDS - TAG_HEADER_WORDS - 1;
+        assert_eq!(message.push(PropertyId::GetCommandline, &[], room + 1).err(),
+                   Some(Error::TooLarge));
+        assert_eq!(message.len, MESSAGE_HEADER_WORDS);
+        assert!(message.push(PropertyId::GetCommandline, &[], room).is_ok());
+        assert_eq!(message.push(PropertyId::GetBoardModel, &[], 0).err(), Some(Error::TooLarge));
+    }
+
+    #[test]
+    fn test_response() {
+        let mut message = Message::new();
+        let slot = message.push(PropertyId::GetBoardSerial, &[], 2).unwrap();
+        answer(&mut message, slot, &[0x1234, 0x5678], 8);
+        assert_eq!(message.status(), Ok(()));
+
+        let mut response = [0; 2];
+        assert_eq!(message.response(slot, &mut response), Ok(2));
+        assert_eq!(response, [0x1234, 0x5678]);
+
+        // The firmware may report more than there was room for.
+        answer(&mut message, slot, &[0x1234, 0x5678], 16);
+        assert_eq!(message.response(slot, &mut response), Ok(4));
+    }
+
+    #[test]
+    fn test_response_unanswered() {
+        let mut message = Message::new();
+        let slot = message.push(PropertyId::GetBoardSerial, &[], 2).unwrap();
+        message.buffer[1] = RESPONSE_SUCCESS;
+        assert_eq!(message.response(slot, &mut [0; 2]), Err(Error::Unanswered));
+    }
+
+    #[test]
+    fn test_response_short() {
+        let mut message = Message::new();
+        let slot = message.push(PropertyId::GetBoardSerial, &[], 2).unwrap();
+        answer(&mut message, slot, &[0x1234], 4);
+
+        let mut response = [0; 2];
+        assert_eq!(message.response(slot, &mut response), Err(Error::ShortResponse));
+        assert_eq!(response[0], 0x1234);
+    }
+
+    #[test]
+    fn test_response_codes() {
+        let mut message = Message::new();
+        message.buffer[1] = RESPONSE_SUCCESS;
+        assert_eq!(message.status(), Ok(()));
+        message.buffer[1] = RESPONSE_ERROR;
+        assert_eq!(message.status(), Err(Error::BadRequest));
+        message.buffer[1] = REQUEST;
+        assert_eq!(message.status(), Err(Error::BadResponse));
+        message.buffer[1] = 0x8000_0002;
+        assert_eq!(message.status(), Err(Error::BadResponse));
+    }
 }
@@ -0,0 +1,260 @@
+//! PCM audio through the headphone jack.
+//!
+//! The jack's left and right channels hang off PWM0 and PWM1 through a low
+//! pass filter, so a channel's duty cycle sets the speaker's position. Each
+//! sample becomes the data of one PWM period, with the range chosen so that
+//! a period lasts one sample. A DMA channel feeds the PWM FIFO from two
+//! buffers in turn, paced by the FIFO's data requests, while the other
+//! buffer is refilled.
+
+use std::cmp;
+use std::sync::atomic::{compiler_fence, Ordering};
+use std::time::Duration;
+
+use dma::{self, ControlBlock, Dreq};
+use timer;
+use super::{Channel, Pwm, PLLD_HZ};
+
+/// The rate of the PWM clock while playing audio, in Hz.
+const CLOCK_HZ: u32 = PLLD_HZ / 4;
+
+/// The pins of the headphone jack's left and right channels.
+const LEFT_PIN: u8 = 40;
+const RIGHT_PIN: u8 = 41;
+
+/// The sample rate `beep` plays at, in Hz.
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+/// The slowest and fastest sample rates played, in Hz.
+const MIN_SAMPLE_RATE: u32 = 4000;
+const MAX_SAMPLE_RATE: u32 = 192_000;
+
+/// The frames in each of the two buffers DMA plays from.
+const BUFFER_FRAMES: usize = 2048;
+
+/// The bytes of FIFO data in a frame: a word for each channel.
+const FRAME_BYTES: usize = 8;
+
+/// Slack added to every wait for the DMA channel, in microseconds.
+const SLACK_US: u64 = 100 * 1000;
+
+/// The amplitude of the square wave `beep` plays: a quarter of full scale.
+const TONE_AMPLITUDE: i16 = i16::max_value() / 4;
+
+/// A left and right sample.
+pub type Frame = (i16, i16);
+
+/// An error setting up or playing audio.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// Every DMA channel is claimed.
+    NoDmaChannel,
+    /// The sample rate is outside what can be played.
+    BadSampleRate,
+    /// The DMA channel stalled or failed.
+    Dma(dma::Error),
+}
+
+/// Returns the PWM data that outputs `sample` with a range of `range`.
+fn level(sample: i16, range: u32) -> u32 {
+    ((sample as i32 + 0x8000) as u64 * range as u64 >> 16) as u32
+}
+
+/// A square wave.
+#[derive(Debug, Clone)]
+pub struct Tone {
+    hz: u32,
+    sample_rate: u32,
+    /// How far into the wave's period the next frame is, in units of
+    /// `1 / sample_rate` periods.
+    phase: u32,
+    remaining: u64,
+}
+
+impl Tone {
+    /// Returns `duration` of a `hz` square wave sampled at `sample_rate`.
+    pub fn new(hz: u32, duration: Duration, sample_rate: u32) -> Tone {
+        let micros = duration.as_secs() * 1000 * 1000 + (duration.subsec_nanos() / 1000) as u64;
+        Tone {
+            hz: cmp::min(hz, sample_rate / 2),
+            sample_rate,
+            phase: 0,
+            remaining: micros * sample_rate as u64 / (1000 * 1000),
+        }
+    }
+}
+
+impl Iterator for Tone {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        let sample = match self.phase < self.sample_rate / 2 {
+            true => TONE_AMPLITUDE,
+            false => -TONE_AMPLITUDE,
+        };
+        self.phase = (self.phase + self.hz) % self.sample_rate;
+        Some((sample, sample))
+    }
+}
+
+/// The headphone jack, claiming the PWM controller and a DMA channel.
+pub struct Audio {
+    pwm: Pwm,
+    dma: dma::Channel,
+    sample_rate: u32,
+    range: u32,
+}
+
+impl Audio {
+    /// Routes the PWM channels to the headphone jack and sets them up to play
+    /// `sample_rate` samples a second.
+    pub fn new(sample_rate: u32) -> Result<Audio, Error> {
+        if sample_rate < MIN_SAMPLE_RATE || sample_rate > MAX_SAMPLE_RATE {
+            return Err(Error::BadSampleRate);
+        }
+        let dma = dma::Channel::claim().ok_or(Error::NoDmaChannel)?;
+
+        let mut pwm = Pwm::new(CLOCK_HZ);
+        pwm.attach(LEFT_PIN);
+        pwm.attach(RIGHT_PIN);
+        let range = pwm.clock() / sample_rate;
+        pwm.set_range(Channel::Pwm0, range);
+        pwm.set_range(Channel::Pwm1, range);
+        Ok(Audio { pwm, dma, sample_rate, range })
+    }
+
+    /// The sample rate, in Hz.
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Plays a `hz` square wave for `duration`.
+    pub fn beep(&mut self, hz: u32, duration: Duration) -> Result<(), Error> {
+        let tone = Tone::new(hz, duration, self.sample_rate);
+        self.play(tone)
+    }
+
+    /// Plays `frames`, returning once the last has been played.
+    pub fn play<I: IntoIterator<Item = Frame>>(&mut self, frames: I) -> Result<(), Error> {
+        let mut frames = frames.into_iter();
+        let ti = dma::TI_WAIT_RESP | dma::TI_SRC_INC | dma::TI_DEST_DREQ | Dreq::Pwm.permap()
+            | dma::TI_NO_WIDE_BURSTS;
+        let fifo = self.pwm.fifo_bus_address();
+
+        let mut buffers = [vec![0u32; BUFFER_FRAMES * 2], vec![0u32; BUFFER_FRAMES * 2]];
+        let mut blocks = Box::new([
+            ControlBlock::new(ti, dma::bus_address(buffers[0].as_ptr()), fifo, 0),
+            ControlBlock::new(ti, dma::bus_address(buffers[1].as_ptr()), fifo, 0),
+        ]);
+        let addresses = [dma::bus_address(&blocks[0]), dma::bus_address(&blocks[1])];
+        blocks[0].next = addresses[1];
+        blocks[1].next = addresses[0];
+
+        // The buffer holding the last frames, once they've been reached.
+        let mut last = None;
+        for index in 0..2 {
+            if last.is_none() && !self.fill(&mut buffers[index], &mut blocks[index], &mut frames) {
+                last = Some(index);
+            }
+        }
+        compiler_fence(Ordering::SeqCst);
+
+        let buffer_us = BUFFER_FRAMES as u64 * 1000 * 1000 / self.sample_rate as u64;
+        self.pwm.start_fifo();
+        // The buffers and blocks outlive the transfer: the channel is done or
+        // stopped before this function returns.
+        unsafe { self.dma.start(&blocks[0]); }
+
+        let mut refill = 0;
+        let mut result = Ok(());
+        while last.is_none() {
+            // Wait for the channel to move on to the other buffer.
+            let start = timer::current_time();
+            while self.dma.current_block() == addresses[refill] {
+                if timer::current_time().wrapping_sub(start) > buffer_us + SLACK_US {
+                    self.dma.reset();
+                    result = Err(Error::Dma(dma::Error::Timeout));
+                    break;
+                }
+            }
+            if result.is_err() || !self.dma.is_active() {
+                break;
+            }
+
+            if !self.fill(&mut buffers[refill], &mut blocks[refill], &mut frames) {
+                last = Some(refill);
+            }
+            compiler_fence(Ordering::SeqCst);
+            refill ^= 1;
+        }
+
+        if result.is_ok() {
+            result = self.dma.wait(2 * buffer_us + SLACK_US).map_err(Error::Dma);
+        }
+        self.pwm.stop_fifo(SLACK_US);
+        result
+    }
+
+    /// Fills `buffer` with the next frames and sets `block` to play them.
+    /// Returns `false` if they were the last, in which case `block` stops
+    /// the channel. Both are cleaned for the channel to read.
+    fn fill<I>(&self, buffer: &mut [u32], block: &mut ControlBlock, frames: &mut I) -> bool
+        where I: Iterator<Item = Frame>
+    {
+        let mut count = 0;
+        for (slot, (left, right)) in buffer.chunks_mut(2).zip(frames.by_ref()) {
+            slot[0] = level(left, self.range);
+            slot[1] = level(right, self.range);
+            count += 1;
+            if count == BUFFER_FRAMES {
+                break;
+            }
+        }
+
+        let more = count == BUFFER_FRAMES;
+        if more {
+            block.length = (count * FRAME_BYTES) as u32;
+        } else {
+            // End on a frame of silence, so the chain is never empty.
+            buffer[count * 2] = level(0, self.range);
+            buffer[count * 2 + 1] = level(0, self.range);
+            block.length = ((count + 1) * FRAME_BYTES) as u32;
+            block.next = 0;
+        }
+
+        dma::clean(&*buffer);
+        dma::clean(&*block);
+        more
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::{level, Tone, TONE_AMPLITUDE};
+
+    #[test]
+    fn test_sample_levels() {
+        assert_eq!(level(i16::min_value(), 2834), 0);
+        assert_eq!(level(0, 2834), 1417);
+        assert_eq!(level(i16::max_value(), 2834), 2833);
+    }
+
+    #[test]
+    fn test_tone() {
+        let frames: Vec<_> = Tone::new(1000, Duration::from_millis(10), 8000).collect();
+        assert_eq!(frames.len(), 80);
+        let high = (TONE_AMPLITUDE, TONE_AMPLITUDE);
+        let low = (-TONE_AMPLITUDE, -TONE_AMPLITUDE);
+        assert_eq!(&frames[..8], &[high, high, high, high, low, low, low, low]);
+
+        // Tones above the Nyquist rate are clamped to it.
+        let frames: Vec<_> = Tone::new(10000, Duration::from_millis(1), 8000).collect();
+        assert_eq!(&frames[..4], &[high, low, high, low]);
+    }
+}
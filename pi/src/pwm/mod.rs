@@ -0,0 +1,292 @@
+//! PWM driver for the BCM2837's two-channel PWM controller and its clock.
+//!
+//! Both channels count at the rate of the PWM clock, which comes from the
+//! clock manager. Each channel's output is high for `data` ticks of every
+//! `range`; in balanced mode the high ticks are spread over the period, in
+//! mark-space mode they come first, which is what servos expect. The
+//! channels can also take their data from a shared FIFO, fed by DMA, which
+//! is how `audio` plays samples.
+
+pub mod audio;
+pub mod wav;
+
+#[cfg(test)]
+mod tests;
+
+use std::cmp;
+use std::time::Duration;
+
+use common::IO_BASE;
+use dma;
+use gpio::{Function, Gpio};
+use timer;
+use volatile::prelude::*;
+use volatile::Volatile;
+
+/// The base address of the PWM controller's registers.
+const PWM_BASE: usize = IO_BASE + 0x20C000;
+
+/// The clock manager's control and divisor registers for the PWM clock.
+const CM_PWMCTL: usize = IO_BASE + 0x1010A0;
+const CM_PWMDIV: usize = IO_BASE + 0x1010A4;
+
+/// Every write to a clock manager register must carry this password.
+const CM_PASSWORD: u32 = 0x5A << 24;
+/// `CM_PWMCTL`: the clock's source.
+const CM_SRC_OSCILLATOR: u32 = 1;
+const CM_SRC_PLLD: u32 = 6;
+/// `CM_PWMCTL`: runs the clock.
+const CM_ENAB: u32 = 1 << 4;
+/// `CM_PWMCTL`: the clock is running.
+const CM_BUSY: u32 = 1 << 7;
+
+/// The largest integer divisor the clock manager takes.
+const MAX_DIVISOR: u32 = 0xFFF;
+
+/// How long to wait for the clock manager to stop the clock, in
+/// microseconds.
+const CLOCK_TIMEOUT_US: u64 = 10 * 1000;
+
+/// The rate of the crystal oscillator, in Hz.
+pub const OSCILLATOR_HZ: u32 = 19_200_000;
+
+/// The rate of PLLD, in Hz.
+pub const PLLD_HZ: u32 = 500_000_000;
+
+/// `CTL`: channel 1's bits; channel 2's are the same, 8 bits up.
+const CTL_PWEN: u32 = 1 << 0;
+const CTL_USEF: u32 = 1 << 5;
+const CTL_MSEN: u32 = 1 << 7;
+/// `CTL`: clears the FIFO.
+const CTL_CLRF: u32 = 1 << 6;
+
+/// `STA`: the FIFO is empty.
+const STA_EMPT: u32 = 1 << 1;
+/// `STA`: the error flags. Write 1 to clear.
+const STA_ERRORS: u32 = 0b1_0011_1100;
+
+/// `DMAC`: the FIFO raises DMA requests when it holds this few words...
+const DMAC_DREQ: u32 = 7;
+/// `DMAC`: ...and panic requests when it holds this few.
+const DMAC_PANIC: u32 = 7 << 8;
+/// `DMAC`: enables DMA requests.
+const DMAC_ENAB: u32 = 1 << 31;
+
+#[repr(C)]
+#[allow(non_snake_case)]
+struct Registers {
+    CTL: Volatile<u32>,
+    STA: Volatile<u32>,
+    DMAC: Volatile<u32>,
+    __r0: Volatile<u32>,
+    RNG1: Volatile<u32>,
+    DAT1: Volatile<u32>,
+    FIF1: Volatile<u32>,
+    __r1: Volatile<u32>,
+    RNG2: Volatile<u32>,
+    DAT2: Volatile<u32>,
+}
+
+/// One of the controller's two outputs.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Channel {
+    Pwm0,
+    Pwm1,
+}
+
+impl Channel {
+    /// How far this channel's bits are shifted up in `CTL`.
+    fn shift(&self) -> u32 {
+        match *self {
+            Channel::Pwm0 => 0,
+            Channel::Pwm1 => 8,
+        }
+    }
+}
+
+/// How a channel spreads its high ticks over the period.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// High for the first `data` ticks of the period: the usual PWM signal.
+    MarkSpace,
+    /// High ticks spread evenly over the period, which is easier to filter
+    /// into a level.
+    Balanced,
+}
+
+/// Returns the channel GPIO pin `pin` can output, and the function that
+/// routes it there. The headphone jack is on pins 40 and 41.
+pub fn pin_channel(pin: u8) -> Option<(Channel, Function)> {
+    match pin {
+        12 | 40 => Some((Channel::Pwm0, Function::Alt0)),
+        13 | 41 | 45 => Some((Channel::Pwm1, Function::Alt0)),
+        18 => Some((Channel::Pwm0, Function::Alt5)),
+        19 => Some((Channel::Pwm1, Function::Alt5)),
+        _ => None,
+    }
+}
+
+/// Returns the clock source, as its `CM_PWMCTL` bits and rate, and the
+/// integer divisor that give the fastest rate no faster than `hz`.
+fn clock_source(hz: u32) -> (u32, u32, u32) {
+    let hz = cmp::max(hz, 1);
+    // The oscillator divides more finely at low rates.
+    let (source, source_hz) = match hz <= OSCILLATOR_HZ / 2 {
+        true => (CM_SRC_OSCILLATOR, OSCILLATOR_HZ),
+        false => (CM_SRC_PLLD, PLLD_HZ),
+    };
+    let divisor = (source_hz + hz - 1) / hz;
+    (source, source_hz, cmp::min(cmp::max(divisor, 2), MAX_DIVISOR))
+}
+
+/// Returns the number of ticks of a `clock_hz` clock in `duration`.
+fn ticks(clock_hz: u32, duration: Duration) -> u32 {
+    let clock_hz = clock_hz as u64;
+    let ticks = duration.as_secs().saturating_mul(clock_hz)
+        .saturating_add(duration.subsec_nanos() as u64 * clock_hz / (1000 * 1000 * 1000));
+    cmp::min(ticks, u32::max_value() as u64) as u32
+}
+
+/// The PWM controller. Both channels share the clock, so there is one
+/// handle for the two.
+pub struct Pwm {
+    registers: &'static mut Registers,
+    clock_hz: u32,
+}
+
+impl Pwm {
+    /// Returns a handle to the controller with its clock running at the
+    /// fastest rate no faster than `clock_hz`, and both channels stopped.
+    pub fn new(clock_hz: u32) -> Pwm {
+        let mut pwm = Pwm {
+            registers: unsafe { &mut *(PWM_BASE as *mut Registers) },
+            clock_hz: 0,
+        };
+        pwm.registers.CTL.write(0);
+        pwm.set_clock(clock_hz);
+        pwm
+    }
+
+    /// The rate of the PWM clock, in Hz.
+    pub fn clock(&self) -> u32 {
+        self.clock_hz
+    }
+
+    /// Restarts the PWM clock at the fastest rate no faster than `hz`, and
+    /// returns the rate set. Ranges and data are in ticks of this clock.
+    pub fn set_clock(&mut self, hz: u32) -> u32 {
+        let (source, source_hz, divisor) = clock_source(hz);
+        let control = unsafe { &mut *(CM_PWMCTL as *mut Volatile<u32>) };
+        let division = unsafe { &mut *(CM_PWMDIV as *mut Volatile<u32>) };
+
+        // The clock may only be changed while it is stopped.
+        control.write(CM_PASSWORD | (control.read() & !CM_ENAB & 0xFFFFFF));
+        let start = timer::current_time();
+        while control.read() & CM_BUSY != 0 {
+            if timer::current_time().wrapping_sub(start) > CLOCK_TIMEOUT_US {
+                break;
+            }
+        }
+
+        division.write(CM_PASSWORD | divisor << 12);
+        control.write(CM_PASSWORD | source);
+        control.write(CM_PASSWORD | source | CM_ENAB);
+        self.clock_hz = source_hz / divisor;
+        self.clock_hz
+    }
+
+    /// Routes GPIO pin `pin` to the channel it can output, and returns that
+    /// channel. Returns `None` if the pin can't output PWM.
+    pub fn attach(&mut self, pin: u8) -> Option<Channel> {
+        let (channel, function) = pin_channel(pin)?;
+        let _pin = Gpio::new(pin).into_alt(function);
+        Some(channel)
+    }
+
+    /// Sets the length of `channel`'s period, in ticks of the PWM clock.
+    pub fn set_range(&mut self, channel: Channel, range: u32) {
+        match channel {
+            Channel::Pwm0 => self.registers.RNG1.write(range),
+            Channel::Pwm1 => self.registers.RNG2.write(range),
+        }
+    }
+
+    /// Returns the length of `channel`'s period, in ticks of the PWM clock.
+    pub fn range(&self, channel: Channel) -> u32 {
+        match channel {
+            Channel::Pwm0 => self.registers.RNG1.read(),
+            Channel::Pwm1 => self.registers.RNG2.read(),
+        }
+    }
+
+    /// Sets how many ticks of each period `channel` is high for.
+    pub fn set_data(&mut self, channel: Channel, data: u32) {
+        match channel {
+            Channel::Pwm0 => self.registers.DAT1.write(data),
+            Channel::Pwm1 => self.registers.DAT2.write(data),
+        }
+    }
+
+    /// Sets `channel`'s period to `period`.
+    pub fn set_period(&mut self, channel: Channel, period: Duration) {
+        let range = ticks(self.clock_hz, period);
+        self.set_range(channel, range);
+    }
+
+    /// Makes `channel` high for `pulse` of every period.
+    pub fn set_pulse(&mut self, channel: Channel, pulse: Duration) {
+        let data = ticks(self.clock_hz, pulse);
+        self.set_data(channel, data);
+    }
+
+    /// Makes `channel` high for `percent` percent of every period.
+    pub fn set_duty(&mut self, channel: Channel, percent: u32) {
+        let range = self.range(channel) as u64;
+        let data = range * cmp::min(percent, 100) as u64 / 100;
+        self.set_data(channel, data as u32);
+    }
+
+    /// Starts `channel` outputting its data in `mode`.
+    pub fn enable(&mut self, channel: Channel, mode: Mode) {
+        let shift = channel.shift();
+        let mut control = self.registers.CTL.read() & !((CTL_USEF | CTL_MSEN) << shift);
+        if mode == Mode::MarkSpace {
+            control |= CTL_MSEN << shift;
+        }
+        self.registers.CTL.write(control | CTL_PWEN << shift);
+    }
+
+    /// Stops `channel`; its output stays low.
+    pub fn disable(&mut self, channel: Channel) {
+        let control = self.registers.CTL.read();
+        self.registers.CTL.write(control & !(CTL_PWEN << channel.shift()));
+    }
+
+    /// Starts both channels taking their data, in turn, from the FIFO, in
+    /// balanced mode, with DMA requests raised as it empties.
+    fn start_fifo(&mut self) {
+        self.registers.CTL.write(CTL_CLRF);
+        self.registers.STA.write(STA_ERRORS);
+        self.registers.DMAC.write(DMAC_ENAB | DMAC_PANIC | DMAC_DREQ);
+        let both = (CTL_PWEN | CTL_USEF) << Channel::Pwm0.shift()
+            | (CTL_PWEN | CTL_USEF) << Channel::Pwm1.shift();
+        self.registers.CTL.write(both);
+    }
+
+    /// Stops both channels taking data from the FIFO, once it has drained.
+    fn stop_fifo(&mut self, timeout_us: u64) {
+        let start = timer::current_time();
+        while self.registers.STA.read() & STA_EMPT == 0 {
+            if timer::current_time().wrapping_sub(start) > timeout_us {
+                break;
+            }
+        }
+        self.registers.DMAC.write(0);
+        self.registers.CTL.write(CTL_CLRF);
+    }
+
+    /// The bus address of the FIFO, for DMA.
+    fn fifo_bus_address(&self) -> u32 {
+        dma::peripheral_bus_address(&self.registers.FIF1 as *const _ as usize)
+    }
+}
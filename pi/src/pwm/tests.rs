@@ -0,0 +1,101 @@
+use std::time::Duration;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use gpio::Function;
+use pwm::wav::{Error, Wav};
+use pwm::{clock_source, pin_channel, ticks, Channel, CM_SRC_OSCILLATOR, CM_SRC_PLLD};
+
+/// Returns a WAV file with a `fmt ` chunk of `format`, `channels`,
+/// `sample_rate` and `bits` and a `data` chunk of `data`, after a chunk the
+/// parser must skip.
+fn wav(format: u16, channels: u16, sample_rate: u32, bits: u16, data: &[u8]) -> Vec<u8> {
+    let mut fmt = [0; 16];
+    LittleEndian::write_u16(&mut fmt[0..], format);
+    LittleEndian::write_u16(&mut fmt[2..], channels);
+    LittleEndian::write_u32(&mut fmt[4..], sample_rate);
+    let block_align = channels * bits / 8;
+    LittleEndian::write_u32(&mut fmt[8..], sample_rate * block_align as u32);
+    LittleEndian::write_u16(&mut fmt[12..], block_align);
+    LittleEndian::write_u16(&mut fmt[14..], bits);
+
+    let mut file = b"RIFF\0\0\0\0WAVE".to_vec();
+    for &(id, body) in [(&b"LIST"[..], &b"odd"[..]), (b"fmt ", &fmt), (b"data", data)].iter() {
+        let mut size = [0; 4];
+        LittleEndian::write_u32(&mut size, body.len() as u32);
+        file.extend_from_slice(id);
+        file.extend_from_slice(&size);
+        file.extend_from_slice(body);
+        if body.len() % 2 == 1 && id != b"data" {
+            file.push(0);
+        }
+    }
+    let riff_size = file.len() as u32 - 8;
+    LittleEndian::write_u32(&mut file[4..], riff_size);
+    file
+}
+
+#[test]
+fn test_pins() {
+    assert_eq!(pin_channel(12), Some((Channel::Pwm0, Function::Alt0)));
+    assert_eq!(pin_channel(13), Some((Channel::Pwm1, Function::Alt0)));
+    assert_eq!(pin_channel(18), Some((Channel::Pwm0, Function::Alt5)));
+    assert_eq!(pin_channel(19), Some((Channel::Pwm1, Function::Alt5)));
+    assert_eq!(pin_channel(40), Some((Channel::Pwm0, Function::Alt0)));
+    assert_eq!(pin_channel(41), Some((Channel::Pwm1, Function::Alt0)));
+    assert_eq!(pin_channel(17), None);
+}
+
+#[test]
+fn test_clock_divisors() {
+    assert_eq!(clock_source(1000 * 1000), (CM_SRC_OSCILLATOR, 19_200_000, 20));
+    assert_eq!(clock_source(9_600_000), (CM_SRC_OSCILLATOR, 19_200_000, 2));
+    assert_eq!(clock_source(125_000_000), (CM_SRC_PLLD, 500_000_000, 4));
+    assert_eq!(clock_source(300_000_000), (CM_SRC_PLLD, 500_000_000, 2));
+    assert_eq!(clock_source(1), (CM_SRC_OSCILLATOR, 19_200_000, 0xFFF));
+}
+
+#[test]
+fn test_ticks_in_durations() {
+    assert_eq!(ticks(1000 * 1000, Duration::from_millis(20)), 20_000);
+    assert_eq!(ticks(19_200_000, Duration::new(0, 1_500_000)), 28_800);
+    assert_eq!(ticks(500_000_000, Duration::from_secs(100)), u32::max_value());
+}
+
+#[test]
+fn test_wav_stereo_16_bit() {
+    let mut data = [0; 8];
+    for (i, &sample) in [1, -2, i16::max_value(), i16::min_value()].iter().enumerate() {
+        LittleEndian::write_i16(&mut data[i * 2..], sample);
+    }
+    let file = wav(1, 2, 22050, 16, &data);
+
+    let wav = Wav::parse(&file).unwrap();
+    assert_eq!((wav.channels(), wav.sample_rate(), wav.bits_per_sample()), (2, 22050, 16));
+    assert_eq!(wav.len(), 2);
+    assert_eq!(wav.frames().collect::<Vec<_>>(),
+               [(1, -2), (i16::max_value(), i16::min_value())]);
+}
+
+#[test]
+fn test_wav_mono_8_bit() {
+    let file = wav(1, 1, 8000, 8, &[0x80, 0xFF, 0x00]);
+    let wav = Wav::parse(&file).unwrap();
+    assert_eq!(wav.frames().collect::<Vec<_>>(),
+               [(0, 0), (0x7F00, 0x7F00), (-0x8000, -0x8000)]);
+    assert_eq!(wav.duration(), Duration::new(0, 375 * 1000));
+}
+
+#[test]
+fn test_wav_errors() {
+    assert_eq!(Wav::parse(b"BM"), Err(Error::Unrecognized));
+    assert_eq!(Wav::parse(b"RIFF\0\0"), Err(Error::Truncated));
+    assert_eq!(Wav::parse(b"RIFF\0\0\0\0AVI LIST"), Err(Error::Unrecognized));
+
+    let file = wav(1, 2, 44100, 16, &[0; 16]);
+    assert_eq!(Wav::parse(&file[..file.len() - 1]), Err(Error::Truncated));
+    assert_eq!(Wav::parse(&wav(3, 2, 44100, 32, &[0; 16])), Err(Error::Unsupported));
+    assert_eq!(Wav::parse(&wav(1, 2, 44100, 24, &[0; 18])), Err(Error::Unsupported));
+    assert_eq!(Wav::parse(&wav(1, 0, 44100, 16, &[])), Err(Error::Invalid));
+    assert_eq!(Wav::parse(&wav(1, 2, 0, 16, &[])), Err(Error::Invalid));
+}
@@ -0,0 +1,176 @@
+//! WAV files holding uncompressed PCM samples.
+
+use std::cmp;
+use std::time::Duration;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::audio::Frame;
+
+/// An error parsing a WAV file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The file isn't a RIFF WAVE file.
+    Unrecognized,
+    /// The file ends before a chunk does, or has no `fmt ` or `data` chunk.
+    Truncated,
+    /// The samples aren't 8- or 16-bit PCM.
+    Unsupported,
+    /// The format describes samples that can't exist, such as ones with no
+    /// channels.
+    Invalid,
+}
+
+const RIFF_MAGIC: &[u8] = b"RIFF";
+const WAVE_MAGIC: &[u8] = b"WAVE";
+const RIFF_HEADER_SIZE: usize = 12;
+const CHUNK_HEADER_SIZE: usize = 8;
+const FMT_CHUNK: &[u8] = b"fmt ";
+const DATA_CHUNK: &[u8] = b"data";
+const FMT_SIZE: usize = 16;
+const FORMAT_PCM: u16 = 1;
+
+/// A parsed WAV file, borrowing its samples.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Wav<'a> {
+    channels: u16,
+    sample_rate: u32,
+    bits_per_sample: u16,
+    data: &'a [u8],
+}
+
+impl<'a> Wav<'a> {
+    /// Parses the WAV file `data`.
+    pub fn parse(data: &'a [u8]) -> Result<Wav<'a>, Error> {
+        if !data.starts_with(RIFF_MAGIC) {
+            return Err(Error::Unrecognized);
+        }
+        if data.len() < RIFF_HEADER_SIZE {
+            return Err(Error::Truncated);
+        }
+        if &data[8..12] != WAVE_MAGIC {
+            return Err(Error::Unrecognized);
+        }
+
+        let mut format = None;
+        let mut chunks = &data[RIFF_HEADER_SIZE..];
+        while chunks.len() >= CHUNK_HEADER_SIZE {
+            let id = &chunks[..4];
+            let size = LittleEndian::read_u32(&chunks[4..]) as usize;
+            let body = &chunks[CHUNK_HEADER_SIZE..];
+            if body.len() < size {
+                return Err(Error::Truncated);
+            }
+            let body = &body[..size];
+
+            if id == FMT_CHUNK {
+                if size < FMT_SIZE {
+                    return Err(Error::Invalid);
+                }
+                format = Some((
+                    LittleEndian::read_u16(&body[0..]),
+                    LittleEndian::read_u16(&body[2..]),
+                    LittleEndian::read_u32(&body[4..]),
+                    LittleEndian::read_u16(&body[12..]),
+                    LittleEndian::read_u16(&body[14..]),
+                ));
+            } else if id == DATA_CHUNK {
+                let (tag, channels, sample_rate, block_align, bits_per_sample) = match format {
+                    Some(format) => format,
+                    None => return Err(Error::Truncated),
+                };
+                if tag != FORMAT_PCM || (bits_per_sample != 8 && bits_per_sample != 16) {
+                    return Err(Error::Unsupported);
+                }
+                let frame_size = channels as u32 * bits_per_sample as u32 / 8;
+                if channels == 0 || sample_rate == 0 || block_align as u32 != frame_size {
+                    return Err(Error::Invalid);
+                }
+                return Ok(Wav { channels, sample_rate, bits_per_sample, data: body });
+            }
+
+            // Chunks are padded to an even length.
+            let padded = size + size % 2;
+            chunks = &chunks[cmp::min(CHUNK_HEADER_SIZE + padded, chunks.len())..];
+        }
+        Err(Error::Truncated)
+    }
+
+    /// The number of channels.
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    /// The number of frames a second.
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// The size of each sample, 8 or 16 bits.
+    pub fn bits_per_sample(&self) -> u16 {
+        self.bits_per_sample
+    }
+
+    /// The number of frames: samples of every channel.
+    pub fn len(&self) -> usize {
+        self.data.len() / self.frame_size()
+    }
+
+    /// Returns `true` if there are no frames.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// How long the samples take to play.
+    pub fn duration(&self) -> Duration {
+        let frames = self.len() as u64;
+        let rate = self.sample_rate as u64;
+        Duration::new(frames / rate, (frames % rate * 1000 * 1000 * 1000 / rate) as u32)
+    }
+
+    /// Returns the frames as 16-bit stereo. Mono is played on both sides, and
+    /// channels past the first two are dropped.
+    pub fn frames(&self) -> Frames<'a> {
+        Frames { wav: self.clone(), offset: 0 }
+    }
+
+    fn frame_size(&self) -> usize {
+        self.channels as usize * self.bits_per_sample as usize / 8
+    }
+
+    /// Returns sample `channel` of the frame at `frame`, as 16 bits.
+    fn sample(&self, frame: &[u8], channel: usize) -> i16 {
+        match self.bits_per_sample {
+            // 8-bit samples are unsigned.
+            8 => ((frame[channel] as i16) - 0x80) << 8,
+            _ => LittleEndian::read_i16(&frame[channel * 2..]),
+        }
+    }
+}
+
+/// An iterator over a WAV file's frames, as 16-bit stereo.
+#[derive(Debug, Clone)]
+pub struct Frames<'a> {
+    wav: Wav<'a>,
+    offset: usize,
+}
+
+impl<'a> Iterator for Frames<'a> {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        let size = self.wav.frame_size();
+        if self.offset + size > self.wav.data.len() {
+            return None;
+        }
+
+        let frame = &self.wav.data[self.offset..self.offset + size];
+        self.offset += size;
+        let left = self.wav.sample(frame, 0);
+        let right = match self.wav.channels {
+            1 => left,
+            _ => self.wav.sample(frame, 1),
+        };
+        Some((left, right))
+    }
+}
@@ -0,0 +1,375 @@
+//! Calendar time and the DS3231 real-time clock.
+
+use std::fmt;
+use std::mem;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Seconds in a day.
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// The `fn() -> DateTime` that file times come from, or 0 if none is set.
+static TIME_SOURCE: AtomicUsize = AtomicUsize::new(0);
+
+/// A UTC date and time with one second resolution, from the year 1970 on.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1 to 12.
+    pub month: u8,
+    /// 1 to the length of the month.
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+fn is_leap_year(year: u16) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+impl DateTime {
+    /// Returns the date and time `secs` seconds after the Unix epoch.
+    pub fn from_unix(secs: u64) -> DateTime {
+        // Count from 0000-03-01 so that leap days fall at the end of a year
+        // (ref: Howard Hinnant, "chrono-Compatible Low-Level Date Algorithms").
+        let days = secs / SECS_PER_DAY + 719_468;
+        let time = secs % SECS_PER_DAY;
+
+        let era = days / 146_097;
+        let day_of_era = days % 146_097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524
+                           - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+        let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+
+    /// Returns the number of seconds from the Unix epoch to this date and
+    /// time.
+    pub fn to_unix(&self) -> u64 {
+        let year = self.year as u64 - if self.month <= 2 { 1 } else { 0 };
+        let month = self.month as u64;
+        let era = year / 400;
+        let year_of_era = year % 400;
+        let month_index = if month > 2 { month - 3 } else { month + 9 };
+        let day_of_year = (153 * month_index + 2) / 5 + self.day as u64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+
+        days * SECS_PER_DAY + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+    }
+
+    /// Returns `true` if every field is in range and the date is no earlier
+    /// than 1970.
+    pub fn is_valid(&self) -> bool {
+        self.year >= 1970
+            && self.month >= 1 && self.month <= 12
+            && self.day >= 1 && self.day <= days_in_month(self.year, self.month)
+            && self.hour < 24 && self.minute < 60 && self.second < 60
+    }
+
+    /// The day of the week, from 1 for Monday to 7 for Sunday.
+    pub fn weekday(&self) -> u8 {
+        // 1970-01-01 was a Thursday.
+        ((self.to_unix() / SECS_PER_DAY + 3) % 7 + 1) as u8
+    }
+
+    /// The date packed as in a FAT directory entry: years since 1980, month
+    /// and day. Dates before 1980 are clamped to 1980-01-01.
+    pub fn fat_date(&self) -> u16 {
+        if self.year < 1980 {
+            return 1 << 5 | 1;
+        }
+
+        ((self.year - 1980) as u16) << 9 | (self.month as u16) << 5 | self.day as u16
+    }
+
+    /// The time packed as in a FAT directory entry, with two second
+    /// resolution.
+    pub fn fat_time(&self) -> u16 {
+        (self.hour as u16) << 11 | (self.minute as u16) << 5 | (self.second / 2) as u16
+    }
+
+    /// The date and time packed as in a FAT directory entry.
+    pub fn fat_timestamp(&self) -> FatTimestamp {
+        FatTimestamp { date: self.fat_date(), time: self.fat_time() }
+    }
+}
+
+/// A date and time as a FAT directory entry stores them.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FatTimestamp {
+    pub date: u16,
+    pub time: u16,
+}
+
+/// Makes `source` the clock that the file system reads the time from when it
+/// stamps a file's creation or modification time.
+pub fn set_time_source(source: fn() -> DateTime) {
+    TIME_SOURCE.store(source as usize, Ordering::Release);
+}
+
+/// Returns the current time from the source set with `set_time_source`, packed
+/// for a FAT directory entry. Until one is set, this is the earliest FAT
+/// time: 1980-01-01 00:00:00.
+pub fn fat_now() -> FatTimestamp {
+    match TIME_SOURCE.load(Ordering::Acquire) {
+        0 => FatTimestamp { date: 1 << 5 | 1, time: 0 },
+        source => {
+            let source: fn() -> DateTime = unsafe { mem::transmute(source) };
+            source().fat_timestamp()
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+               self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+/// Error returned when a string isn't a date and time in the form
+/// `YYYY-MM-DD HH:MM:SS`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ParseError;
+
+/// Parses one field of a date or time, which must be there and fit in a `T`.
+fn field<T: FromStr>(part: Option<&str>) -> Result<T, ParseError> {
+    part.ok_or(ParseError)?.parse().map_err(|_| ParseError)
+}
+
+impl FromStr for DateTime {
+    type Err = ParseError;
+
+    /// Parses `YYYY-MM-DD HH:MM:SS`. A `T` may separate the date and time
+    /// instead of a space, and the time may be omitted for midnight.
+    fn from_str(s: &str) -> Result<DateTime, ParseError> {
+        let s = s.trim();
+        let (date, time) = match s.find(|c: char| c == ' ' || c == 'T') {
+            Some(i) => (&s[..i], s[i + 1..].trim()),
+            None => (s, "00:00:00"),
+        };
+
+        let (mut date, mut time) = (date.split('-'), time.split(':'));
+        let datetime = DateTime {
+            year: field(date.next())?,
+            month: field(date.next())?,
+            day: field(date.next())?,
+            hour: field(time.next())?,
+            minute: field(time.next())?,
+            second: field(time.next().or(Some("0")))?,
+        };
+
+        if date.next().is_some() || time.next().is_some() || !datetime.is_valid() {
+            return Err(ParseError);
+        }
+
+        Ok(datetime)
+    }
+}
+
+/// An I2C bus that a real-time clock chip is attached to.
+pub trait Bus {
+    type Error;
+
+    /// Writes `data` to the device at 7-bit address `addr`.
+    fn write(&mut self, addr: u8, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Writes `data` to the device at `addr`, then reads `buf.len()` bytes
+    /// back from it without releasing the bus in between.
+    fn write_read(&mut self, addr: u8, data: &[u8], buf: &mut [u8]) -> Result<(), Self::Error>;
+}
+
+/// Errors reading or setting a real-time clock.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error<E> {
+    /// The bus transfer failed.
+    Bus(E),
+    /// The clock holds no valid time, for instance because its battery ran
+    /// out.
+    Invalid,
+}
+
+fn from_bcd(bcd: u8) -> u8 {
+    (bcd >> 4) * 10 + (bcd & 0xF)
+}
+
+fn to_bcd(value: u8) -> u8 {
+    (value / 10) << 4 | value % 10
+}
+
+/// A Maxim DS3231 real-time clock, kept in 24-hour mode.
+pub struct Ds3231<B> {
+    bus: B,
+}
+
+impl<B: Bus> Ds3231<B> {
+    /// The DS3231's fixed I2C address.
+    pub const ADDRESS: u8 = 0x68;
+
+    /// Returns a handle to the DS3231 on `bus`.
+    pub fn new(bus: B) -> Ds3231<B> {
+        Ds3231 { bus }
+    }
+
+    /// Reads the current date and time.
+    pub fn read(&mut self) -> Result<DateTime, Error<B::Error>> {
+        let mut regs = [0u8; 7];
+        self.bus.write_read(Self::ADDRESS, &[0], &mut regs).map_err(Error::Bus)?;
+
+        let hour = match regs[2] & 0x40 {
+            // 12-hour mode: bit 5 is PM.
+            0x40 => from_bcd(regs[2] & 0x1F) % 12 + if regs[2] & 0x20 != 0 { 12 } else { 0 },
+            _ => from_bcd(regs[2] & 0x3F),
+        };
+
+        let century = if regs[5] & 0x80 != 0 { 100 } else { 0 };
+        let datetime = DateTime {
+            second: from_bcd(regs[0] & 0x7F),
+            minute: from_bcd(regs[1] & 0x7F),
+            hour,
+            day: from_bcd(regs[4] & 0x3F),
+            month: from_bcd(regs[5] & 0x1F),
+            year: 2000 + century + from_bcd(regs[6]) as u16,
+        };
+
+        match datetime.is_valid() {
+            true => Ok(datetime),
+            false => Err(Error::Invalid),
+        }
+    }
+
+    /// Sets the clock to `datetime`, which must be between 2000 and 2199.
+    pub fn set(&mut self, datetime: &DateTime) -> Result<(), Error<B::Error>> {
+        if !datetime.is_valid() || datetime.year < 2000 || datetime.year >= 2200 {
+            return Err(Error::Invalid);
+        }
+
+        let years = datetime.year - 2000;
+        let century = if years >= 100 { 0x80 } else { 0 };
+        let regs = [
+            0,
+            to_bcd(datetime.second),
+            to_bcd(datetime.minute),
+            to_bcd(datetime.hour),
+            datetime.weekday(),
+            to_bcd(datetime.day),
+            century | to_bcd(datetime.month),
+            to_bcd((years % 100) as u8),
+        ];
+        self.bus.write(Self::ADDRESS, &regs).map_err(Error::Bus)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datetime(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+        DateTime { year, month, day, hour, minute, second }
+    }
+
+    #[test]
+    fn test_unix_conversions() {
+        let table = [
+            (0, datetime(1970, 1, 1, 0, 0, 0)),
+            (951_782_400, datetime(2000, 2, 29, 0, 0, 0)),
+            (1_234_567_890, datetime(2009, 2, 13, 23, 31, 30)),
+            (4_107_542_399, datetime(2100, 2, 28, 23, 59, 59)),
+            (4_107_542_400, datetime(2100, 3, 1, 0, 0, 0)),
+        ];
+
+        for &(secs, expected) in table.iter() {
+            assert_eq!(DateTime::from_unix(secs), expected);
+            assert_eq!(expected.to_unix(), secs);
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("2009-02-13 23:31:30".parse(), Ok(datetime(2009, 2, 13, 23, 31, 30)));
+        assert_eq!("2009-02-13T23:31".parse(), Ok(datetime(2009, 2, 13, 23, 31, 0)));
+        assert_eq!("2009-02-13".parse(), Ok(datetime(2009, 2, 13, 0, 0, 0)));
+        assert_eq!("2009-02-29".parse::<DateTime>(), Err(ParseError));
+        assert_eq!("2009-13-01 00:00:00".parse::<DateTime>(), Err(ParseError));
+        assert_eq!("2024-257-01".parse::<DateTime>(), Err(ParseError));
+        assert_eq!("2024-01-257".parse::<DateTime>(), Err(ParseError));
+        assert_eq!("yesterday".parse::<DateTime>(), Err(ParseError));
+    }
+
+    #[test]
+    fn test_fat_and_weekday() {
+        let dt = datetime(2009, 2, 13, 23, 31, 30);
+        assert_eq!(dt.weekday(), 5);
+        assert_eq!(dt.fat_date(), 29 << 9 | 2 << 5 | 13);
+        assert_eq!(dt.fat_time(), 23 << 11 | 31 << 5 | 15);
+        assert_eq!(datetime(1970, 1, 1, 0, 0, 0).fat_date(), 1 << 5 | 1);
+    }
+
+    #[test]
+    fn test_fat_time_source() {
+        assert_eq!(fat_now(), FatTimestamp { date: 1 << 5 | 1, time: 0 });
+
+        fn source() -> DateTime {
+            datetime(2009, 2, 13, 23, 31, 30)
+        }
+        set_time_source(source);
+        assert_eq!(fat_now(), source().fat_timestamp());
+        assert_eq!(fat_now(), FatTimestamp { date: 29 << 9 | 2 << 5 | 13,
+                                             time: 23 << 11 | 31 << 5 | 15 });
+    }
+
+    struct MockBus([u8; 8]);
+
+    impl Bus for MockBus {
+        type Error = ();
+
+        fn write(&mut self, _addr: u8, data: &[u8]) -> Result<(), ()> {
+            self.0[..data.len()].copy_from_slice(data);
+            Ok(())
+        }
+
+        fn write_read(&mut self, _addr: u8, data: &[u8], buf: &mut [u8]) -> Result<(), ()> {
+            let start = data[0] as usize + 1;
+            buf.copy_from_slice(&self.0[start..start + buf.len()]);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_ds3231_round_trip() {
+        let mut rtc = Ds3231::new(MockBus([0; 8]));
+        let dt = datetime(2026, 10, 19, 21, 5, 9);
+        rtc.set(&dt).unwrap();
+        assert_eq!(rtc.bus.0, [0, 0x09, 0x05, 0x21, 1, 0x19, 0x10, 0x26]);
+        assert_eq!(rtc.read(), Ok(dt));
+
+        // 12-hour mode, 9 PM.
+        rtc.bus.0[3] = 0x40 | 0x20 | 0x09;
+        assert_eq!(rtc.read().map(|dt| dt.hour), Ok(21));
+
+        assert_eq!(Ds3231::new(MockBus([0; 8])).read(), Err(Error::Invalid));
+    }
+}
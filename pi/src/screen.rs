@@ -1,14 +1,71 @@
-use framebuffer::{Color, Position, Pixel, Framebuffer};
-use character_set::{ascii_to_glyph};
+use std::{cmp, fmt};
+
+use ansi::{Action, Csi, Parser};
+use font::{Font, Utf8Decoder};
+use framebuffer::{Color, Framebuffer, Mode};
+use graphics::Rect;
 use mutex::Mutex;
 
+/// The scale the terminal starts at. With the built-in font's 6x10 cells this
+/// gives 85x38 cells at 1024x768.
+const DEFAULT_SCALE: usize = 2;
+
+/// The widest row of a glyph, in bytes, that can be drawn: 256 pixels at
+/// 32 bits per pixel.
+const ROW_BUFFER_SIZE: usize = 1024;
+
+const TAB_WIDTH: usize = 8;
+
+const fn rgb(red: u8, green: u8, blue: u8) -> Color {
+    Color { red, green, blue }
+}
+
+/// The 8 ANSI colors, then their bright variants.
+const PALETTE: [Color; 16] = [
+    rgb(0x00, 0x00, 0x00), rgb(0xaa, 0x00, 0x00), rgb(0x00, 0xaa, 0x00), rgb(0xaa, 0x55, 0x00),
+    rgb(0x00, 0x00, 0xaa), rgb(0xaa, 0x00, 0xaa), rgb(0x00, 0xaa, 0xaa), rgb(0xaa, 0xaa, 0xaa),
+    rgb(0x55, 0x55, 0x55), rgb(0xff, 0x55, 0x55), rgb(0x55, 0xff, 0x55), rgb(0xff, 0xff, 0x55),
+    rgb(0x55, 0x55, 0xff), rgb(0xff, 0x55, 0xff), rgb(0x55, 0xff, 0xff), rgb(0xff, 0xff, 0xff),
+];
+
+const DEFAULT_FOREGROUND: usize = 7;
+const DEFAULT_BACKGROUND: usize = 0;
+
 /// A global singleton allowing read/write access to the screen.
+///
+/// The screen is a text terminal: a grid of character cells with a cursor
+/// that understands the common ANSI/VT100 escape sequences for colors, cursor
+/// movement and erasing. Everything printed with `kprint!` appears on it.
+///
+/// The framebuffer is double buffered when the firmware allows it. Drawing
+/// goes to the hidden page; the screen's own methods present it when they
+/// are done, and anyone drawing on `framebuffer` directly calls `present`.
 pub struct Screen {
     inner: Option<Framebuffer>,
-    position: Position,
-    // TODO: make methods for this
-    pub color: Color,
-    pub hue: f32,
+    /// Set if the firmware couldn't provide a framebuffer; output is dropped.
+    unavailable: bool,
+    /// The part of the framebuffer the terminal is confined to, if not all of
+    /// it.
+    viewport: Option<Rect>,
+    parser: Parser,
+    utf8: Utf8Decoder,
+    /// The font characters are drawn in; the built-in one until another is
+    /// set.
+    font: Option<Font>,
+    scale: usize,
+    columns: usize,
+    rows: usize,
+    /// The cursor's column. It may be `columns` after the last column was
+    /// written, in which case the next character wraps to the next line.
+    column: usize,
+    row: usize,
+    saved: (usize, usize),
+    foreground: usize,
+    background: usize,
+    bold: bool,
+    reverse: bool,
+    cursor_visible: bool,
+    cursor_drawn: bool,
 }
 
 /// Global `SCREEN` singleton.
@@ -17,160 +74,446 @@ pub static SCREEN: Mutex<Screen> = Mutex::new(Screen::new());
 impl Screen {
     /// Creates a new instance of `Screen`.
     const fn new() -> Screen {
-        Screen { 
+        Screen {
             inner: None,
-            position: Position {
-                x: 0,
-                y: 0,
-            },
-            color: Color {
-                red: 0x00,
-                green: 0xff,
-                blue: 0x00,
-            },
-            hue: 0f32,
+            unavailable: false,
+            viewport: None,
+            parser: Parser::new(),
+            utf8: Utf8Decoder::new(),
+            font: None,
+            scale: DEFAULT_SCALE,
+            columns: 1,
+            rows: 1,
+            column: 0,
+            row: 0,
+            saved: (0, 0),
+            foreground: DEFAULT_FOREGROUND,
+            background: DEFAULT_BACKGROUND,
+            bold: false,
+            reverse: false,
+            cursor_visible: true,
+            cursor_drawn: false,
         }
     }
 
-    /// Initializes the screen if it's not already initialized.
-    #[inline]
-    fn initialize(&mut self) {
-        if self.inner.is_none() {
-            self.inner = Some(Framebuffer::new().expect("error creating new framebuffer"));
+    /// Returns the framebuffer, initializing it as needed, or `None` if there
+    /// is none.
+    pub fn framebuffer(&mut self) -> Option<&mut Framebuffer> {
+        if self.inner.is_none() && !self.unavailable {
+            match Framebuffer::new(Mode { buffers: 2, ..Mode::default() }) {
+                Ok(framebuffer) => {
+                    self.inner = Some(framebuffer);
+                    if self.font.is_none() {
+                        self.font = Some(Font::builtin());
+                    }
+                    self.resize();
+                    self.clear();
+                }
+                Err(()) => self.unavailable = true,
+            }
         }
+
+        self.inner.as_mut()
     }
 
     /// Returns a mutable borrow to the inner `Framebuffer`, initializing it as
     /// needed.
     pub fn inner(&mut self) -> &mut Framebuffer {
-        self.initialize();
-        self.inner.as_mut().unwrap()
+        self.framebuffer().expect("no framebuffer available")
+    }
+
+    /// Displays what has been drawn on the framebuffer since it was last
+    /// presented.
+    pub fn present(&mut self) {
+        if let Some(framebuffer) = self.inner.as_mut() {
+            let _ = framebuffer.present();
+        }
+    }
+
+    /// Switches the screen to `mode`, clearing it.
+    pub fn set_mode(&mut self, mode: Mode) -> Result<(), ()> {
+        self.inner = Some(Framebuffer::new(mode)?);
+        self.unavailable = false;
+        self.cursor_drawn = false;
+        self.resize();
+        self.clear();
+        Ok(())
+    }
+
+    /// Sets the font characters are drawn in, and how many times larger than
+    /// the font's glyphs they are drawn, clearing the screen.
+    pub fn set_font(&mut self, font: Font, scale: usize) {
+        self.hide_cursor();
+        self.font = Some(font);
+        self.scale = cmp::max(scale, 1);
+        self.resize();
+        self.clear();
+    }
+
+    /// Confines the terminal to `viewport`, or lets it use the whole
+    /// framebuffer if `None`, clearing it. The rest of the framebuffer is left
+    /// to others to draw on.
+    pub fn set_viewport(&mut self, viewport: Option<Rect>) {
+        self.hide_cursor();
+        self.viewport = viewport;
+        self.resize();
+        self.clear();
+    }
+
+    /// Returns the left, top, width and height of the part of the framebuffer
+    /// the terminal draws in, all zero if there is no framebuffer.
+    fn area(&self) -> (usize, usize, usize, usize) {
+        let (width, height) = match self.inner {
+            Some(ref framebuffer) => (framebuffer.width, framebuffer.height),
+            None => return (0, 0, 0, 0),
+        };
+
+        match self.viewport {
+            Some(viewport) => {
+                let area = viewport.intersect(&Rect::new(0, 0, width as u32, height as u32));
+                (area.x as usize, area.y as usize, area.width as usize, area.height as usize)
+            }
+            None => (0, 0, width, height),
+        }
+    }
+
+    /// Returns the number of columns and rows of the character grid.
+    pub fn size(&self) -> (usize, usize) {
+        (self.columns, self.rows)
+    }
+
+    fn cell_size(&self) -> (usize, usize) {
+        match self.font {
+            Some(ref font) => (font.width() * self.scale, font.height() * self.scale),
+            None => (self.scale, self.scale),
+        }
+    }
+
+    fn resize(&mut self) {
+        let (cell_width, cell_height) = self.cell_size();
+        let (_, _, width, height) = self.area();
+        if self.inner.is_some() {
+            self.columns = cmp::max(width / cell_width, 1);
+            self.rows = cmp::max(height / cell_height, 1);
+        }
+        self.column = cmp::min(self.column, self.columns - 1);
+        self.row = cmp::min(self.row, self.rows - 1);
     }
 
+    /// Clears the screen and moves the cursor to the top left.
     pub fn clear(&mut self) {
-        self.inner().clear();
-        self.position = Default::default();
+        self.hide_cursor();
+        let background = self.colors().1;
+        if self.framebuffer().is_some() {
+            let (left, top, width, height) = self.area();
+            self.inner().fill_rect(left, top, width, height, background);
+        }
+        self.column = 0;
+        self.row = 0;
+        self.show_cursor();
+        self.present();
     }
 
-    pub fn draw_char(&mut self, c: u8) {
-        self.draw_char_scale(c, 1);
+    /// Writes `byte`, which may be part of an escape sequence.
+    pub fn write_byte(&mut self, byte: u8) {
+        self.hide_cursor();
+        self.process(byte);
+        self.show_cursor();
+        self.present();
     }
 
-    pub fn draw_char_scale(&mut self, c: u8, scale: usize) {
-        if c == 0x0d || c == 0x0a {
-            self.position.y = self.position.y.wrapping_add(10 * scale);
-            let default_position: Position = Default::default();
-            self.position.x = default_position.x;
-            return;
+    /// Draws `s` in characters `scale` times their usual size, starting on a
+    /// fresh line, and moves the cursor to the line after it. Escape
+    /// sequences aren't interpreted. Characters that don't fit are dropped.
+    /// A `scale` of 0 is taken as 1.
+    pub fn draw_string_scale(&mut self, s: &str, scale: usize) {
+        let scale = cmp::max(scale, 1);
+        self.hide_cursor();
+        if self.column != 0 {
+            self.new_line();
         }
 
-        self.hue += 0.05f32;
-        if self.hue > 1f32 {
-            self.hue -= 1f32;
+        let cell_height = self.cell_size().1;
+        let font_height = cell_height / self.scale;
+        let lines = cmp::min((font_height * scale + cell_height - 1) / cell_height, self.rows);
+        while self.row + lines > self.rows {
+            self.scroll();
+            self.row -= 1;
         }
 
-        match ascii_to_glyph(c) {
-            Some(glyph) => {
+        let (foreground, background) = self.colors();
+        let font_width = self.cell_size().0 / self.scale;
+        let (left, top, width, _) = self.area();
+        let y = top + self.row * cell_height;
+        for (i, c) in s.chars().take(width / (font_width * scale)).enumerate() {
+            self.draw_glyph(left + i * font_width * scale, y, c, scale, foreground, background);
+        }
 
-                let mut pixel = Pixel {
-                    position: self.position,
-                    color: Screen::hsl_to_rgb(self.hue, 1f32, 0.5f32),
-                };
+        self.row += lines - 1;
+        self.new_line();
+        self.show_cursor();
+        self.present();
+    }
 
-                if pixel.position.y >= self.inner().height - 10 * scale {
-                    self.position.y = 0;
-                }
+    fn process(&mut self, byte: u8) {
+        match self.parser.advance(byte) {
+            Some(Action::Print(byte)) => {
+                let mut utf8 = self.utf8;
+                utf8.push(byte, |c| self.put(c));
+                self.utf8 = utf8;
+            }
+            Some(Action::Control(byte)) => self.control(byte),
+            Some(Action::Escape(b'7')) => self.saved = (self.column, self.row),
+            Some(Action::Escape(b'8')) => self.restore(),
+            Some(Action::Escape(b'c')) => {
+                self.reset_attributes();
+                self.cursor_visible = true;
+                self.clear();
+            }
+            Some(Action::Csi(csi)) => self.csi(&csi),
+            Some(Action::Escape(_)) | None => {}
+        }
+    }
 
-                for row in glyph.iter() {
-                    for i in 0..5 {
-                        if row << i & 0b10000u8 != 0u8 {
-                            for j in 0..scale {
-                                for k in 0..scale {
-                                    self.inner().draw_pixel(&Pixel {
-                                        position: Position {
-                                            x: pixel.position.x + j,
-                                            y: pixel.position.y + k,
-                                        },
-                                        color: pixel.color
-                                    });
-                                }
-                            }
-                        } else {
-                            for j in 0..scale {
-                                for k in 0..scale {
-                                    self.inner().draw_pixel(&Pixel {
-                                        position: Position {
-                                            x: pixel.position.x + j,
-                                            y: pixel.position.y + k,
-                                        },
-                                        color: Default::default()
-                                    });
-                                }
-                            }
-                        }
-                        pixel.position.x = pixel.position.x.wrapping_add(scale);
-                    }
-                    pixel.position.x = self.position.x;
-                    pixel.position.y = pixel.position.y.wrapping_add(scale);
-                }
+    /// Returns the foreground and background colors for new characters.
+    fn colors(&self) -> (Color, Color) {
+        let foreground = match self.bold && self.foreground < 8 {
+            true => PALETTE[self.foreground + 8],
+            false => PALETTE[self.foreground],
+        };
+        let background = PALETTE[self.background];
+        match self.reverse {
+            true => (background, foreground),
+            false => (foreground, background),
+        }
+    }
 
-                self.position.x = self.position.x.wrapping_add(6 * scale);
+    fn reset_attributes(&mut self) {
+        self.foreground = DEFAULT_FOREGROUND;
+        self.background = DEFAULT_BACKGROUND;
+        self.bold = false;
+        self.reverse = false;
+    }
 
-                if self.position.x > self.inner().width {
-                    self.position.x = 0;
-                    self.position.y = self.position.y.wrapping_add(10 * scale);
-                }
-            },
-            None => {}
+    /// Draws the glyph for `c` with its top left corner at (`x`, `y`),
+    /// `scale` times the font's size. Characters the font has no glyph for are
+    /// drawn as `?`.
+    fn draw_glyph(&mut self, x: usize, y: usize, c: char, scale: usize,
+                  foreground: Color, background: Color) {
+        if self.framebuffer().is_none() {
+            return;
+        }
+
+        let (framebuffer, font) = match (self.inner.as_mut(), self.font.as_ref()) {
+            (Some(framebuffer), Some(font)) => (framebuffer, font),
+            _ => return,
+        };
+
+        let glyph = font.glyph(c).or_else(|| font.glyph('?'));
+        let bytes = framebuffer.depth.bytes();
+        let (foreground, background) = (framebuffer.depth.encode(foreground),
+                                         framebuffer.depth.encode(background));
+        let width = cmp::min(font.width() * scale, ROW_BUFFER_SIZE / bytes);
+
+        // Encode each row of the glyph once, then copy it `scale` times.
+        let mut row = [0u8; ROW_BUFFER_SIZE];
+        for glyph_y in 0..font.height() {
+            for (glyph_x, pixel) in row[..width * bytes].chunks_mut(bytes).enumerate() {
+                let color = match glyph.map_or(false, |g| g.is_set(glyph_x / scale, glyph_y)) {
+                    true => &foreground,
+                    false => &background,
+                };
+                pixel.copy_from_slice(&color[..bytes]);
+            }
+            framebuffer.blit(x, y + glyph_y * scale, width, scale, &row, 0);
         }
     }
 
-    pub fn draw_string_scale(&mut self, s: &str, scale: usize) {
-        for c in s.bytes() {
-            self.draw_char_scale(c, scale);
+    fn put(&mut self, c: char) {
+        if self.column >= self.columns {
+            self.new_line();
         }
+
+        let (cell_width, cell_height) = self.cell_size();
+        let (foreground, background) = self.colors();
+        let (left, top, _, _) = self.area();
+        let (x, y) = (left + self.column * cell_width, top + self.row * cell_height);
+        let scale = self.scale;
+        self.draw_glyph(x, y, c, scale, foreground, background);
+        self.column += 1;
     }
 
-    pub fn draw_string(&mut self, s: &str) {
-        self.draw_string_scale(s, 1);
+    fn control(&mut self, byte: u8) {
+        match byte {
+            // Like a terminal with `onlcr` set, a line feed also returns.
+            b'\n' => self.new_line(),
+            b'\r' => self.column = 0,
+            0x08 => self.column = cmp::min(self.column, self.columns - 1).saturating_sub(1),
+            b'\t' => self.column = cmp::min((self.column / TAB_WIDTH + 1) * TAB_WIDTH,
+                                            self.columns - 1),
+            _ => {}
+        }
     }
 
-    pub fn hsl_to_rgb(h: f32, s: f32, l: f32) -> Color {
-        let (r, g, b) = if s == 0f32 {
-            (l, l, l)
+    fn new_line(&mut self) {
+        self.column = 0;
+        if self.row + 1 >= self.rows {
+            self.scroll();
         } else {
-            let q: f32 = if l < 0.5f32 { l * (1f32 + s) } else { l + s - l * s };
-            let p: f32 = 2f32 * l - q;
-            (
-                Screen::hue_to_rgb(p, q, h + 1f32/3f32),
-                Screen::hue_to_rgb(p, q, h),
-                Screen::hue_to_rgb(p, q, h - 1f32/3f32),
-            )
-        };
+            self.row += 1;
+        }
+    }
+
+    /// Scrolls the screen up by a line, clearing the bottom line.
+    fn scroll(&mut self) {
+        let (cell_width, cell_height) = self.cell_size();
+        let (left, top, _, _) = self.area();
+        let (width, height) = (self.columns * cell_width, (self.rows - 1) * cell_height);
+        if let Some(framebuffer) = self.framebuffer() {
+            framebuffer.copy_rect(left, top + cell_height, width, height, left, top);
+        }
+
+        let bottom = self.rows - 1;
+        let columns = self.columns;
+        self.erase(bottom, 0, columns);
+    }
 
-        Color {
-            red: (r * 255f32) as u8,
-            green: (g * 255f32) as u8,
-            blue: (b * 255f32) as u8,
+    /// Erases the columns `from` up to `to` on `row`.
+    fn erase(&mut self, row: usize, from: usize, to: usize) {
+        let (cell_width, cell_height) = self.cell_size();
+        let background = self.colors().1;
+        let (left, top, _, _) = self.area();
+        if let Some(framebuffer) = self.framebuffer() {
+            framebuffer.fill_rect(left + from * cell_width, top + row * cell_height,
+                                  to.saturating_sub(from) * cell_width, cell_height, background);
         }
     }
 
-    pub fn hue_to_rgb(p: f32, q: f32, mut t: f32) -> f32 {
-        if t < 0f32 {
-            t += 1f32;
+    /// Erases the rows `from` up to `to`.
+    fn erase_rows(&mut self, from: usize, to: usize) {
+        let columns = self.columns;
+        for row in from..to {
+            self.erase(row, 0, columns);
         }
-        if t > 1f32 {
-            t -= 1f32;
+    }
+
+    fn restore(&mut self) {
+        let (column, row) = self.saved;
+        self.column = cmp::min(column, self.columns - 1);
+        self.row = cmp::min(row, self.rows - 1);
+    }
+
+    fn csi(&mut self, csi: &Csi) {
+        let (columns, rows) = (self.columns, self.rows);
+        let column = cmp::min(self.column, columns - 1);
+        let n = csi.param(0, 1) as usize;
+        match (csi.private, csi.command) {
+            (false, b'A') => self.row = self.row.saturating_sub(n),
+            (false, b'B') => self.row = cmp::min(self.row + n, rows - 1),
+            (false, b'C') => self.column = cmp::min(column + n, columns - 1),
+            (false, b'D') => self.column = column.saturating_sub(n),
+            (false, b'E') => {
+                self.row = cmp::min(self.row + n, rows - 1);
+                self.column = 0;
+            }
+            (false, b'F') => {
+                self.row = self.row.saturating_sub(n);
+                self.column = 0;
+            }
+            (false, b'G') => self.column = cmp::min(n, columns) - 1,
+            (false, b'd') => self.row = cmp::min(n, rows) - 1,
+            (false, b'H') | (false, b'f') => {
+                self.row = cmp::min(n, rows) - 1;
+                self.column = cmp::min(csi.param(1, 1) as usize, columns) - 1;
+            }
+            (false, b'J') => match csi.param(0, 0) {
+                0 => {
+                    let row = self.row;
+                    self.erase(row, column, columns);
+                    self.erase_rows(row + 1, rows);
+                }
+                1 => {
+                    let row = self.row;
+                    self.erase_rows(0, row);
+                    self.erase(row, 0, column + 1);
+                }
+                _ => self.erase_rows(0, rows),
+            },
+            (false, b'K') => {
+                let row = self.row;
+                match csi.param(0, 0) {
+                    0 => self.erase(row, column, columns),
+                    1 => self.erase(row, 0, column + 1),
+                    _ => self.erase(row, 0, columns),
+                }
+            }
+            (false, b'm') => self.select_graphic_rendition(csi.params()),
+            (false, b's') => self.saved = (self.column, self.row),
+            (false, b'u') => self.restore(),
+            (true, b'h') if csi.param(0, 0) == 25 => self.cursor_visible = true,
+            (true, b'l') if csi.param(0, 0) == 25 => self.cursor_visible = false,
+            _ => {}
         }
-        if t < 1f32 / 6f32 {
-            return p + (q - p) * 6f32 * t;
+    }
+
+    fn select_graphic_rendition(&mut self, params: &[u16]) {
+        if params.is_empty() {
+            self.reset_attributes();
         }
-        if t < 1f32 / 2f32 {
-            return q;
+
+        for &param in params {
+            match param {
+                0 => self.reset_attributes(),
+                1 => self.bold = true,
+                7 => self.reverse = true,
+                22 => self.bold = false,
+                27 => self.reverse = false,
+                30...37 => self.foreground = (param - 30) as usize,
+                39 => self.foreground = DEFAULT_FOREGROUND,
+                40...47 => self.background = (param - 40) as usize,
+                49 => self.background = DEFAULT_BACKGROUND,
+                90...97 => self.foreground = (param - 90) as usize + 8,
+                100...107 => self.background = (param - 100) as usize + 8,
+                _ => {}
+            }
         }
-        if t < 2f32 / 3f32 {
-            return p + (q - p) * (2f32 /3f32 - t) * 6f32;
+    }
+
+    /// Inverts the bottom of the cursor's cell, drawing or erasing the cursor.
+    fn toggle_cursor(&mut self) {
+        let (cell_width, cell_height) = self.cell_size();
+        let (left, top, _, _) = self.area();
+        let x = left + cmp::min(self.column, self.columns - 1) * cell_width;
+        let y = top + self.row * cell_height;
+        let scale = self.scale;
+        if let Some(framebuffer) = self.framebuffer() {
+            framebuffer.invert_rect(x, y + cell_height - scale, cell_width, scale);
+        }
+    }
+
+    fn hide_cursor(&mut self) {
+        if self.cursor_drawn {
+            self.toggle_cursor();
+            self.cursor_drawn = false;
+        }
+    }
+
+    fn show_cursor(&mut self) {
+        if self.cursor_visible && !self.cursor_drawn {
+            self.toggle_cursor();
+            self.cursor_drawn = true;
+        }
+    }
+}
+
+impl fmt::Write for Screen {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.hide_cursor();
+        for byte in s.bytes() {
+            self.process(byte);
         }
-        return p;
+        self.show_cursor();
+        self.present();
+        Ok(())
     }
 }
@@ -0,0 +1,477 @@
+//! SPI master driver for the BCM2837's SPI0 controller.
+//!
+//! Transfers are full duplex: every byte clocked out clocks one in. Short
+//! transfers are polled through the FIFO. Once DMA is enabled with
+//! `enable_dma`, transfers of `DMA_THRESHOLD` bytes or more are handed to a
+//! pair of DMA channels instead, one feeding the FIFO and one draining it.
+//!
+//! The auxiliary "mini" SPI1 and SPI2 controllers have a different register
+//! set and no DMA, and aren't supported.
+//!
+//! The driver reaches the controller through the `Registers` trait, so the
+//! transfer state machine can be tested on the host against a mock.
+
+#[cfg(test)]
+mod tests;
+
+use std::cmp;
+
+use common::IO_BASE;
+use dma::{self, ControlBlock, Dreq};
+use gpio::{Function, Gpio, Output};
+use timer;
+use uart::DEFAULT_CORE_CLOCK;
+use volatile::prelude::*;
+use volatile::Volatile;
+
+/// The base address of SPI0's registers.
+const SPI0_BASE: usize = IO_BASE + 0x204000;
+
+/// The clock rate `Spi::new` starts at, in Hz.
+pub const DEFAULT_CLOCK: u32 = 1000 * 1000;
+
+/// The depth of the controller's FIFOs, in bytes.
+pub const FIFO_SIZE: usize = 64;
+
+/// The length, in bytes, from which transfers use DMA once it's enabled.
+pub const DMA_THRESHOLD: usize = 2 * FIFO_SIZE;
+
+/// The longest transfer DMA can describe, in bytes. Longer ones are polled.
+const MAX_DMA_TRANSFER: usize = 0xFFFF;
+
+/// How long a transfer may take before it is abandoned, in microseconds, if
+/// the controller never reports it done.
+const DEFAULT_TIMEOUT_US: u64 = 100 * 1000;
+
+/// The pins SPI0 uses, all alternative function 0: CE1, CE0, MISO, MOSI and
+/// SCLK.
+const PINS: [u8; 5] = [7, 8, 9, 10, 11];
+
+/// `CS`: the chip select line driven during transfers.
+const CS_CS: u32 = 0b11 << 0;
+/// `CS`: clock phase.
+const CS_CPHA: u32 = 1 << 2;
+/// `CS`: clock polarity.
+const CS_CPOL: u32 = 1 << 3;
+/// `CS`: clears both FIFOs.
+const CS_CLEAR: u32 = 0b11 << 4;
+/// `CS`: the selected chip select line is active high.
+const CS_CSPOL: u32 = 1 << 6;
+/// `CS`: a transfer is active; the chip select line is asserted.
+const CS_TA: u32 = 1 << 7;
+/// `CS`: the FIFOs raise DMA requests.
+const CS_DMAEN: u32 = 1 << 8;
+/// `CS`: clears `TA` automatically at the end of a DMA transfer.
+const CS_ADCS: u32 = 1 << 11;
+/// `CS`: the transfer is complete.
+const CS_DONE: u32 = 1 << 16;
+/// `CS`: the receive FIFO holds data.
+const CS_RXD: u32 = 1 << 17;
+/// `CS`: the transmit FIFO has room.
+const CS_TXD: u32 = 1 << 18;
+/// `CS`: chip select line 0 is active high.
+const CS_CSPOL0: u32 = 1 << 21;
+
+/// A SPI0 register.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Register {
+    /// `CS`: control and status.
+    Control,
+    /// `FIFO`: the transmit and receive FIFOs.
+    Fifo,
+    /// `CLK`: the core clock divider giving SCLK.
+    ClockDivider,
+    /// `DLEN`: the length of a DMA transfer.
+    DataLength,
+}
+
+/// Access to the SPI controller's registers, and the time.
+pub trait Registers {
+    fn read(&mut self, register: Register) -> u32;
+
+    fn write(&mut self, register: Register, value: u32);
+
+    /// The bus address of the FIFO register, if DMA can reach it.
+    fn fifo_bus_address(&self) -> Option<u32> {
+        None
+    }
+
+    /// The current time in microseconds, for transfer timeouts.
+    fn now(&self) -> u64 {
+        timer::current_time()
+    }
+}
+
+#[repr(C)]
+#[allow(non_snake_case)]
+struct SpiRegisters {
+    CS: Volatile<u32>,
+    FIFO: Volatile<u32>,
+    CLK: Volatile<u32>,
+    DLEN: Volatile<u32>,
+    LTOH: Volatile<u32>,
+    DC: Volatile<u32>,
+}
+
+/// SPI0's registers.
+pub struct Mmio {
+    registers: &'static mut SpiRegisters,
+}
+
+impl Registers for Mmio {
+    fn read(&mut self, register: Register) -> u32 {
+        let registers = &self.registers;
+        match register {
+            Register::Control => registers.CS.read(),
+            Register::Fifo => registers.FIFO.read(),
+            Register::ClockDivider => registers.CLK.read(),
+            Register::DataLength => registers.DLEN.read(),
+        }
+    }
+
+    fn write(&mut self, register: Register, value: u32) {
+        let registers = &mut self.registers;
+        match register {
+            Register::Control => registers.CS.write(value),
+            Register::Fifo => registers.FIFO.write(value),
+            Register::ClockDivider => registers.CLK.write(value),
+            Register::DataLength => registers.DLEN.write(value),
+        }
+    }
+
+    fn fifo_bus_address(&self) -> Option<u32> {
+        Some(dma::peripheral_bus_address(&self.registers.FIFO as *const _ as usize))
+    }
+}
+
+/// The clock polarity and phase.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// The clock idles low; data is sampled on the rising edge.
+    Mode0,
+    /// The clock idles low; data is sampled on the falling edge.
+    Mode1,
+    /// The clock idles high; data is sampled on the falling edge.
+    Mode2,
+    /// The clock idles high; data is sampled on the rising edge.
+    Mode3,
+}
+
+impl Mode {
+    fn bits(&self) -> u32 {
+        match *self {
+            Mode::Mode0 => 0,
+            Mode::Mode1 => CS_CPHA,
+            Mode::Mode2 => CS_CPOL,
+            Mode::Mode3 => CS_CPOL | CS_CPHA,
+        }
+    }
+}
+
+/// The line that selects the slave a transfer is for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ChipSelect {
+    /// CE0, on GPIO 8.
+    Ce0,
+    /// CE1, on GPIO 7.
+    Ce1,
+    /// Any other GPIO pin, driven by the driver around each transfer.
+    Pin(u8),
+}
+
+/// The level a chip select line is asserted at.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveLow,
+    ActiveHigh,
+}
+
+/// An error in a SPI transfer.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The transmit and receive buffers have different lengths.
+    LengthMismatch,
+    /// The controller didn't finish the transfer in time.
+    Timeout,
+    /// The DMA channels failed to feed or drain the FIFO.
+    Dma(dma::Error),
+}
+
+/// The DMA channels that feed and drain the FIFO.
+struct Dma {
+    tx: dma::Channel,
+    rx: dma::Channel,
+}
+
+/// A SPI master on SPI0.
+pub struct Spi<R = Mmio> {
+    registers: R,
+    /// The `CS` bits for the mode, chip select line and polarities.
+    control: u32,
+    /// The chip select pin driven by the driver, if the slave isn't on CE0
+    /// or CE1, and whether it is active high.
+    pin: Option<(Gpio<Output>, bool)>,
+    dma: Option<Dma>,
+    timeout_us: u64,
+}
+
+impl Spi<Mmio> {
+    /// Sets up SPI0's pins and returns a master on it, in mode 0 on CE0 at
+    /// `DEFAULT_CLOCK` for the default core clock.
+    pub fn new() -> Spi<Mmio> {
+        for &pin in PINS.iter() {
+            let _pin = Gpio::new(pin).into_alt(Function::Alt0);
+        }
+
+        let registers = unsafe { &mut *(SPI0_BASE as *mut SpiRegisters) };
+        let mut spi = Spi::with_registers(Mmio { registers });
+        spi.set_clock(DEFAULT_CLOCK, DEFAULT_CORE_CLOCK);
+        spi
+    }
+
+    /// Claims two DMA channels for transfers of `DMA_THRESHOLD` bytes or
+    /// more. Returns `false` if there aren't two free.
+    pub fn enable_dma(&mut self) -> bool {
+        if self.dma.is_none() {
+            if let (Some(tx), Some(rx)) = (dma::Channel::claim(), dma::Channel::claim()) {
+                self.dma = Some(Dma { tx, rx });
+            }
+        }
+        self.dma.is_some()
+    }
+
+    /// Releases the DMA channels; every transfer is polled from now on.
+    pub fn disable_dma(&mut self) {
+        self.dma = None;
+    }
+}
+
+impl<R: Registers> Spi<R> {
+    /// Returns a master on the controller behind `registers`, in mode 0 on
+    /// CE0, with its FIFOs cleared.
+    pub fn with_registers(registers: R) -> Spi<R> {
+        let mut spi = Spi {
+            registers,
+            control: 0,
+            pin: None,
+            dma: None,
+            timeout_us: DEFAULT_TIMEOUT_US,
+        };
+        spi.registers.write(Register::Control, CS_CLEAR);
+        spi
+    }
+
+    /// Returns the registers this master drives.
+    pub fn registers(&mut self) -> &mut R {
+        &mut self.registers
+    }
+
+    /// Sets SCLK to the fastest rate no faster than `hz`, given a core clock
+    /// of `core_clock` Hz. Returns the rate set.
+    pub fn set_clock(&mut self, hz: u32, core_clock: u32) -> u32 {
+        // The divider is rounded up to an even number; 0 means 65536.
+        let hz = cmp::max(hz, 1) as u64;
+        let divider = ((core_clock as u64 + hz - 1) / hz) as u32;
+        let divider = cmp::min(cmp::max(divider + divider % 2, 2), 0x10000);
+        self.registers.write(Register::ClockDivider, divider & 0xFFFF);
+        core_clock / divider
+    }
+
+    /// Sets the clock polarity and phase.
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.control = self.control & !(CS_CPOL | CS_CPHA) | mode.bits();
+    }
+
+    /// Makes transfers go to the slave on `chip_select`, asserted at
+    /// `polarity`. A chip select pin other than CE0 and CE1 is made an
+    /// output and deasserted.
+    pub fn select(&mut self, chip_select: ChipSelect, polarity: Polarity) {
+        let active_high = polarity == Polarity::ActiveHigh;
+        self.pin = None;
+        self.control &= !(CS_CS | CS_CSPOL);
+
+        let line = match chip_select {
+            ChipSelect::Ce0 => 0,
+            ChipSelect::Ce1 => 1,
+            ChipSelect::Pin(pin) => {
+                let mut pin = Gpio::new(pin).into_output();
+                set_level(&mut pin, !active_high);
+                self.pin = Some((pin, active_high));
+                // Neither hardware line is driven.
+                self.control |= CS_CS;
+                return;
+            }
+        };
+
+        // Each line's polarity sets its idle level, so it's kept when
+        // switching to another line.
+        self.control |= line;
+        match active_high {
+            true => self.control |= CS_CSPOL | CS_CSPOL0 << line,
+            false => self.control &= !(CS_CSPOL0 << line),
+        }
+    }
+
+    /// Sets how long a transfer may take, in microseconds, before it is
+    /// abandoned with `Timeout`.
+    pub fn set_timeout(&mut self, us: u64) {
+        self.timeout_us = us;
+    }
+
+    /// Sends `tx` while receiving as many bytes into `rx`.
+    pub fn transfer(&mut self, tx: &[u8], rx: &mut [u8]) -> Result<(), Error> {
+        if tx.len() != rx.len() {
+            return Err(Error::LengthMismatch);
+        }
+        self.exchange(tx.len(), |i| tx[i], |i, byte| rx[i] = byte)
+    }
+
+    /// Sends `buf`, replacing each byte with the one received in its place.
+    pub fn transfer_in_place(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        let tx = buf.to_vec();
+        self.exchange(tx.len(), |i| tx[i], |i, byte| buf[i] = byte)
+    }
+
+    /// Sends `data`, ignoring what is received.
+    pub fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.exchange(data.len(), |i| data[i], |_, _| ())
+    }
+
+    /// Receives `buf.len()` bytes, sending zeroes.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        let len = buf.len();
+        self.exchange(len, |_| 0, |i, byte| buf[i] = byte)
+    }
+
+    /// Sends `data`, then receives `buf.len()` bytes while sending zeroes,
+    /// without deasserting chip select in between.
+    pub fn write_read(&mut self, data: &[u8], buf: &mut [u8]) -> Result<(), Error> {
+        let skip = data.len();
+        let len = skip + buf.len();
+        self.exchange(len, |i| data.get(i).cloned().unwrap_or(0), |i, byte| {
+            if i >= skip {
+                buf[i - skip] = byte;
+            }
+        })
+    }
+
+    /// Clocks `len` bytes with chip select asserted, sending `tx(i)` as the
+    /// `i`th byte and passing the `i`th byte received to `rx`.
+    fn exchange<T, F>(&mut self, len: usize, tx: T, rx: F) -> Result<(), Error>
+        where T: FnMut(usize) -> u8, F: FnMut(usize, u8)
+    {
+        if len == 0 {
+            return Ok(());
+        }
+
+        self.assert_pin(true);
+        let use_dma = len >= DMA_THRESHOLD && len <= MAX_DMA_TRANSFER && self.dma.is_some();
+        let result = match (use_dma, self.registers.fifo_bus_address()) {
+            (true, Some(fifo)) => self.exchange_dma(fifo, len, tx, rx),
+            _ => self.exchange_polled(len, tx, rx),
+        };
+
+        // Ending the transfer deasserts the hardware chip select lines.
+        self.registers.write(Register::Control, self.control | CS_CLEAR);
+        self.assert_pin(false);
+        result
+    }
+
+    fn exchange_polled<T, F>(&mut self, len: usize, mut tx: T, mut rx: F) -> Result<(), Error>
+        where T: FnMut(usize) -> u8, F: FnMut(usize, u8)
+    {
+        self.registers.write(Register::Control, self.control | CS_CLEAR | CS_TA);
+
+        let start = self.registers.now();
+        let (mut sent, mut received) = (0, 0);
+        loop {
+            // Never let more bytes be in flight than the receive FIFO holds.
+            while sent < len && sent - received < FIFO_SIZE && self.status() & CS_TXD != 0 {
+                self.registers.write(Register::Fifo, tx(sent) as u32);
+                sent += 1;
+            }
+            while received < sent && self.status() & CS_RXD != 0 {
+                rx(received, self.registers.read(Register::Fifo) as u8);
+                received += 1;
+            }
+            if received == len && self.status() & CS_DONE != 0 {
+                return Ok(());
+            }
+            self.check_timeout(start)?;
+        }
+    }
+
+    /// Has the DMA channels feed `len` bytes to the FIFO and drain as many
+    /// from it at `fifo`. The bytes are packed four to a word, after a word
+    /// giving the length and the `CS` bits to start with.
+    fn exchange_dma<T, F>(&mut self, fifo: u32, len: usize, mut tx: T, mut rx: F)
+        -> Result<(), Error>
+        where T: FnMut(usize) -> u8, F: FnMut(usize, u8)
+    {
+        let words = (len + 3) / 4;
+        let mut tx_words = vec![0u32; 1 + words];
+        tx_words[0] = (len as u32) << 16 | (self.control | CS_TA) & 0xFF;
+        for i in 0..len {
+            tx_words[1 + i / 4] |= (tx(i) as u32) << (i % 4 * 8);
+        }
+        let mut rx_words = vec![0u32; words];
+
+        let tx_block = ControlBlock::new(
+            dma::TI_WAIT_RESP | dma::TI_SRC_INC | dma::TI_DEST_DREQ | Dreq::SpiTx.permap(),
+            dma::bus_address(tx_words.as_ptr()), fifo, (4 + len) as u32);
+        let rx_block = ControlBlock::new(
+            dma::TI_DEST_INC | dma::TI_SRC_DREQ | Dreq::SpiRx.permap(),
+            fifo, dma::bus_address(rx_words.as_mut_ptr()), len as u32);
+        dma::clean(&tx_words[..]);
+        dma::clean(&tx_block);
+        dma::clean(&rx_block);
+        dma::invalidate(&mut rx_words[..]);
+
+        self.registers.write(Register::Control, self.control | CS_CLEAR | CS_DMAEN | CS_ADCS);
+        let timeout_us = self.timeout_us;
+        let dma = self.dma.as_mut().unwrap();
+        // The blocks and buffers outlive the transfer: both channels are done
+        // or stopped by the time `wait` returns.
+        let result = unsafe {
+            dma.rx.start(&rx_block);
+            dma.tx.start(&tx_block);
+            dma.rx.wait(timeout_us).and_then(|_| dma.tx.wait(timeout_us))
+        };
+        if result.is_err() {
+            dma.rx.reset();
+            dma.tx.reset();
+        }
+        result.map_err(Error::Dma)?;
+
+        dma::invalidate(&mut rx_words[..]);
+        for i in 0..len {
+            rx(i, (rx_words[i / 4] >> (i % 4 * 8)) as u8);
+        }
+        Ok(())
+    }
+
+    /// Drives the driver-managed chip select pin, if there is one.
+    fn assert_pin(&mut self, asserted: bool) {
+        if let Some((ref mut pin, active_high)) = self.pin {
+            set_level(pin, asserted == active_high);
+        }
+    }
+
+    fn status(&mut self) -> u32 {
+        self.registers.read(Register::Control)
+    }
+
+    fn check_timeout(&self, start: u64) -> Result<(), Error> {
+        match self.registers.now().wrapping_sub(start) > self.timeout_us {
+            true => Err(Error::Timeout),
+            false => Ok(()),
+        }
+    }
+}
+
+fn set_level(pin: &mut Gpio<Output>, high: bool) {
+    match high {
+        true => pin.set(),
+        false => pin.clear(),
+    }
+}
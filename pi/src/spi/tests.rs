@@ -0,0 +1,212 @@
+use std::cell::Cell;
+use std::collections::VecDeque;
+
+use super::*;
+
+/// What one transfer, from `TA` being set to it being cleared, put on the
+/// wire.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Transaction {
+    /// The `CS` bits the transfer started with.
+    control: u32,
+    sent: Vec<u8>,
+}
+
+/// SPI0 simulated at the register level, with a slave that answers each byte
+/// with the next of `responses`, or `0xFF` once they run out. Every read of
+/// `CS` clocks out one byte, if there is one to send and room to receive it.
+struct MockSpi {
+    control: u32,
+    clock_divider: u32,
+    tx: VecDeque<u8>,
+    rx: VecDeque<u8>,
+    responses: VecDeque<u8>,
+    transactions: Vec<Transaction>,
+    /// The most bytes the receive FIFO ever held.
+    rx_high_water: usize,
+    /// The controller never clocks anything out.
+    hang: bool,
+    time: Cell<u64>,
+}
+
+impl MockSpi {
+    fn new(responses: &[u8]) -> MockSpi {
+        MockSpi {
+            control: 0,
+            clock_divider: 0,
+            tx: VecDeque::new(),
+            rx: VecDeque::new(),
+            responses: responses.iter().cloned().collect(),
+            transactions: vec![],
+            rx_high_water: 0,
+            hang: false,
+            time: Cell::new(0),
+        }
+    }
+
+    fn active(&self) -> bool {
+        self.control & CS_TA != 0
+    }
+
+    fn tick(&mut self) {
+        if self.hang || !self.active() || self.rx.len() >= FIFO_SIZE {
+            return;
+        }
+        if let Some(byte) = self.tx.pop_front() {
+            self.transactions.last_mut().unwrap().sent.push(byte);
+            self.rx.push_back(self.responses.pop_front().unwrap_or(0xFF));
+            self.rx_high_water = cmp::max(self.rx_high_water, self.rx.len());
+        }
+    }
+}
+
+impl Registers for MockSpi {
+    fn read(&mut self, register: Register) -> u32 {
+        match register {
+            Register::Control => {
+                self.tick();
+                let mut status = self.control;
+                if self.active() && self.tx.is_empty() && !self.hang { status |= CS_DONE; }
+                if !self.rx.is_empty() { status |= CS_RXD; }
+                if self.tx.len() < FIFO_SIZE { status |= CS_TXD; }
+                status
+            }
+            Register::Fifo => self.rx.pop_front().unwrap_or(0) as u32,
+            Register::ClockDivider => self.clock_divider,
+            Register::DataLength => 0,
+        }
+    }
+
+    fn write(&mut self, register: Register, value: u32) {
+        match register {
+            Register::Control => {
+                if value & CS_CLEAR != 0 {
+                    self.tx.clear();
+                    self.rx.clear();
+                }
+                if value & CS_TA != 0 && !self.active() {
+                    let control = value & !CS_CLEAR;
+                    self.transactions.push(Transaction { control, sent: vec![] });
+                }
+                self.control = value & !CS_CLEAR;
+            }
+            Register::Fifo => {
+                if self.active() && self.tx.len() < FIFO_SIZE {
+                    self.tx.push_back(value as u8);
+                }
+            }
+            Register::ClockDivider => self.clock_divider = value,
+            Register::DataLength => (),
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.time.set(self.time.get() + 1);
+        self.time.get()
+    }
+}
+
+fn spi(responses: &[u8]) -> Spi<MockSpi> {
+    Spi::with_registers(MockSpi::new(responses))
+}
+
+#[test]
+fn test_transfer_is_full_duplex() {
+    let mut spi = spi(&[9, 8, 7]);
+    let mut rx = [0; 3];
+    assert_eq!(spi.transfer(&[1, 2, 3], &mut rx), Ok(()));
+    assert_eq!(rx, [9, 8, 7]);
+
+    let mock = spi.registers();
+    assert_eq!(mock.transactions, [Transaction { control: CS_TA, sent: vec![1, 2, 3] }]);
+    assert!(!mock.active());
+}
+
+#[test]
+fn test_long_transfers_keep_the_fifo_from_overflowing() {
+    let responses: Vec<u8> = (0..200).map(|i| i as u8).collect();
+    let mut spi = spi(&responses);
+    let mut buf: Vec<u8> = (0..200).map(|i| !i as u8).collect();
+    assert_eq!(spi.transfer_in_place(&mut buf), Ok(()));
+    assert_eq!(buf, responses);
+
+    let mock = spi.registers();
+    assert_eq!(mock.transactions.len(), 1);
+    assert_eq!(mock.transactions[0].sent, (0..200).map(|i| !i as u8).collect::<Vec<_>>());
+    assert!(mock.rx_high_water <= FIFO_SIZE);
+}
+
+#[test]
+fn test_write_read_holds_chip_select() {
+    let mut spi = spi(&[0xFF, 0xFF, 0x12, 0x34]);
+    let mut buf = [0; 2];
+    assert_eq!(spi.write_read(&[0x03, 0x80], &mut buf), Ok(()));
+    assert_eq!(buf, [0x12, 0x34]);
+    assert_eq!(spi.registers().transactions.len(), 1);
+    assert_eq!(spi.registers().transactions[0].sent, [0x03, 0x80, 0, 0]);
+}
+
+#[test]
+fn test_write_and_read() {
+    let mut spi = spi(&[0, 0, 5, 6]);
+    assert_eq!(spi.write(&[0xAA, 0x55]), Ok(()));
+    let mut buf = [0; 2];
+    assert_eq!(spi.read(&mut buf), Ok(()));
+    assert_eq!(buf, [5, 6]);
+
+    let transactions = &spi.registers().transactions;
+    assert_eq!(transactions.len(), 2);
+    assert_eq!(transactions[0].sent, [0xAA, 0x55]);
+    assert_eq!(transactions[1].sent, [0, 0]);
+}
+
+#[test]
+fn test_mode_and_chip_select() {
+    let mut spi = spi(&[]);
+    spi.set_mode(Mode::Mode3);
+    spi.select(ChipSelect::Ce1, Polarity::ActiveHigh);
+    assert_eq!(spi.write(&[1]), Ok(()));
+
+    spi.set_mode(Mode::Mode1);
+    spi.select(ChipSelect::Ce0, Polarity::ActiveLow);
+    assert_eq!(spi.write(&[2]), Ok(()));
+
+    let transactions = &spi.registers().transactions;
+    assert_eq!(transactions[0].control, CS_TA | CS_CPOL | CS_CPHA | 1 | CS_CSPOL | CS_CSPOL0 << 1);
+    // CE1 is still active high, so it idles low while CE0 is selected.
+    assert_eq!(transactions[1].control, CS_TA | CS_CPHA | CS_CSPOL0 << 1);
+}
+
+#[test]
+fn test_errors() {
+    let mut spi = spi(&[]);
+    assert_eq!(spi.transfer(&[1, 2], &mut [0; 3]), Err(Error::LengthMismatch));
+    assert!(spi.registers().transactions.is_empty());
+
+    assert_eq!(spi.write(&[]), Ok(()));
+    assert!(spi.registers().transactions.is_empty());
+
+    spi.set_timeout(50);
+    spi.registers().hang = true;
+    assert_eq!(spi.write(&[1, 2, 3]), Err(Error::Timeout));
+    assert!(!spi.registers().active());
+    assert!(spi.registers().tx.is_empty());
+}
+
+#[test]
+fn test_clock_divider() {
+    let mut spi = spi(&[]);
+    assert_eq!(spi.set_clock(DEFAULT_CLOCK, 250_000_000), DEFAULT_CLOCK);
+    assert_eq!(spi.registers().clock_divider, 250);
+
+    // Rounded up to an even divider, so never faster than asked.
+    assert_eq!(spi.set_clock(3_000_000, 250_000_000), 2_976_190);
+    assert_eq!(spi.registers().clock_divider, 84);
+
+    assert_eq!(spi.set_clock(125_000_000, 250_000_000), 125_000_000);
+    assert_eq!(spi.set_clock(200_000_000, 250_000_000), 125_000_000);
+    assert_eq!(spi.registers().clock_divider, 2);
+
+    assert_eq!(spi.set_clock(1, 250_000_000), 3814);
+    assert_eq!(spi.registers().clock_divider, 0);
+}
@@ -13,11 +13,28 @@ const MU_REG_BASE: usize = IO_BASE + 0x215040;
 /// The `AUXENB` register from page 9 of the BCM2837 documentation.
 const AUX_ENABLES: *mut Volatile<u8> = (IO_BASE + 0x215004) as *mut Volatile<u8>;
 
+/// The baud rate the mini UART runs at.
+pub const BAUD_RATE: u32 = 115200;
+
+/// The core (VPU) clock rate the firmware boots with, in Hz, which the mini
+/// UART's baud rate is derived from.
+pub const DEFAULT_CORE_CLOCK: u32 = 250 * 1000 * 1000;
+
 /// Enum representing bit fields of the `AUX_MU_LSR_REG` register.
 #[repr(u8)]
 enum LsrStatus {
     DataReady = 1,
     TxAvailable = 1 << 5,
+    TxIdle = 1 << 6,
+}
+
+/// Returns the `AUX_MU_BAUD_REG` divider giving the baud rate closest to
+/// `baud` when the core clock runs at `core_clock` Hz. The baud rate is
+/// `core_clock / (8 * (divider + 1))`.
+pub fn baud_divider(core_clock: u32, baud: u32) -> u16 {
+    let divisor = 8 * baud as u64;
+    let rounded = (core_clock as u64 + divisor / 2) / divisor;
+    ::std::cmp::min(::std::cmp::max(rounded, 1) - 1, u16::max_value() as u64) as u16
 }
 
 #[repr(C)]
@@ -54,8 +71,9 @@ pub struct MiniUart {
 impl MiniUart {
     /// Initializes the mini UART by enabling it as an auxiliary peripheral,
     /// setting the data size to 8 bits, setting the BAUD rate to ~115200 (baud
-    /// divider of 270), setting GPIO pins 14 and 15 to alternative function 5
-    /// (TXD1/RDXD1), and finally enabling the UART transmitter and receiver.
+    /// divider of 270 at the default core clock), setting GPIO pins 14 and 15
+    /// to alternative function 5 (TXD1/RDXD1), and finally enabling the UART
+    /// transmitter and receiver.
     ///
     /// By default, reads will never time out. To set a read timeout, use
     /// `set_read_timeout()`.
@@ -70,9 +88,7 @@ impl MiniUart {
         };
 
         // Set to 115200 baud (divider 270)
-        registers.AUX_MU_BAUD_REG.write(270);
-        // Set to 9600 baud
-        // registers.AUX_MU_BAUD_REG.write(3240);
+        registers.AUX_MU_BAUD_REG.write(baud_divider(DEFAULT_CORE_CLOCK, BAUD_RATE));
 
         // Set to 8 bit size
         registers.AUX_MU_LCR_REG.or_mask(0b11);
@@ -91,6 +107,20 @@ impl MiniUart {
         self.timeout = Some(milliseconds);
     }
 
+    /// Blocks until every byte written has been sent.
+    pub fn flush_tx(&mut self) {
+        while !self.registers.AUX_MU_LSR_REG.has_mask(LsrStatus::TxIdle as u8) {}
+    }
+
+    /// Recomputes the baud divider for a core clock of `core_clock` Hz, which
+    /// must be called whenever the core clock changes to keep the baud rate
+    /// at `BAUD_RATE`. Bytes still being sent when the clock changes are
+    /// garbled, so callers should `flush_tx()` before changing it.
+    pub fn set_core_clock(&mut self, core_clock: u32) {
+        self.flush_tx();
+        self.registers.AUX_MU_BAUD_REG.write(baud_divider(core_clock, BAUD_RATE));
+    }
+
     /// Write the byte `byte`. This method blocks until there is space available
     /// in the output FIFO.
     pub fn write_byte(&mut self, byte: u8) {
@@ -102,6 +132,14 @@ impl MiniUart {
         }
     }
 
+    /// Enables the receive interrupt, raised on the `Aux` IRQ while the receive
+    /// FIFO holds data. Contrary to the BCM2837 documentation, bit 0 of
+    /// `AUX_MU_IER_REG` enables the receive interrupt, and bits 2 and 3 must
+    /// be set for any interrupt to be raised.
+    pub fn enable_rx_interrupt(&mut self) {
+        self.registers.AUX_MU_IER_REG.or_mask(0b1101);
+    }
+
     /// Returns `true` if there is at least one byte ready to be read. If this
     /// method returns `true`, a subsequent call to `read_byte` is guaranteed to
     /// return immediately. This method does not block.
@@ -136,6 +174,14 @@ impl MiniUart {
         }
     }
 
+    /// Reads a byte if one is ready to be read. This method does not block.
+    pub fn try_read_byte(&self) -> Option<u8> {
+        match self.has_byte() {
+            true => Some(self.registers.AUX_MU_IO_REG.read()),
+            false => None
+        }
+    }
+
     /// Reads a byte. Blocks indefinitely until a byte is ready to be read.
     pub fn read_byte(&self) -> u8 {
         loop {
@@ -202,7 +248,31 @@ mod uart_io {
         }
 
         fn flush(&mut self) -> io::Result<()> {
+            self.flush_tx();
             Ok(())
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_baud_divider_matches_the_boot_divider() {
+        assert_eq!(baud_divider(DEFAULT_CORE_CLOCK, BAUD_RATE), 270);
+        assert_eq!(baud_divider(DEFAULT_CORE_CLOCK, 9600), 3254);
+    }
+
+    #[test]
+    fn test_baud_divider_follows_the_core_clock() {
+        assert_eq!(baud_divider(400 * 1000 * 1000, BAUD_RATE), 433);
+        assert_eq!(baud_divider(500 * 1000 * 1000, BAUD_RATE), 542);
+    }
+
+    #[test]
+    fn test_baud_divider_saturates() {
+        assert_eq!(baud_divider(1, BAUD_RATE), 0);
+        assert_eq!(baud_divider(u32::max_value(), 1), u16::max_value());
+    }
+}
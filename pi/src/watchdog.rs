@@ -0,0 +1,139 @@
+use std::time::Duration;
+
+use common::IO_BASE;
+use volatile::prelude::*;
+use volatile::Volatile;
+
+/// The base address of the power management watchdog registers.
+const PM_REG_BASE: usize = IO_BASE + 0x100000 + 0x1C;
+
+/// Every write to a power management register must carry this password.
+const PASSWORD: u32 = 0x5A << 24;
+
+/// `RSTC.WRCFG`: what happens when the watchdog expires.
+const RSTC_WRCFG_MASK: u32 = 0b11 << 4;
+const RSTC_WRCFG_FULL_RESET: u32 = 0b10 << 4;
+
+/// The `RSTC` value that stops the watchdog.
+const RSTC_RESET: u32 = 0x102;
+
+/// The bits of `RSTS` holding the partition to boot from: every other bit of
+/// the low 12.
+const RSTS_PARTITION_MASK: u32 = 0x555;
+
+/// The partition that tells the firmware to halt rather than boot.
+const HALT_PARTITION: u8 = 63;
+
+/// The watchdog counts down in units of 1/65536 of a second.
+const TICKS_PER_SEC: u64 = 1 << 16;
+
+/// The largest value of the 20-bit watchdog counter.
+const MAX_TICKS: u64 = 0xFFFFF;
+
+#[repr(C)]
+#[allow(non_snake_case)]
+struct Registers {
+    RSTC: Volatile<u32>,
+    RSTS: Volatile<u32>,
+    WDOG: Volatile<u32>,
+}
+
+/// The power management watchdog. Once started, it resets the board unless
+/// it is petted before the timeout runs out.
+pub struct Watchdog {
+    registers: &'static mut Registers,
+    timeout: u32,
+}
+
+impl Watchdog {
+    /// Returns a new handle to the watchdog. The timeout defaults to the
+    /// longest possible, just under 16 seconds.
+    pub fn new() -> Watchdog {
+        Watchdog {
+            registers: unsafe { &mut *(PM_REG_BASE as *mut Registers) },
+            timeout: MAX_TICKS as u32,
+        }
+    }
+
+    fn arm(&mut self, ticks: u32) {
+        let rstc = self.registers.RSTC.read() & !RSTC_WRCFG_MASK;
+        self.registers.WDOG.write(PASSWORD | ticks);
+        self.registers.RSTC.write(PASSWORD | rstc | RSTC_WRCFG_FULL_RESET);
+    }
+
+    /// Starts the watchdog to reset the board `timeout` from now, unless it
+    /// is petted or stopped first. Timeouts are capped at just under 16
+    /// seconds.
+    pub fn start(&mut self, timeout: Duration) {
+        let ticks = timeout.as_secs() * TICKS_PER_SEC
+            + timeout.subsec_nanos() as u64 * TICKS_PER_SEC / 1_000_000_000;
+        self.timeout = ::std::cmp::min(::std::cmp::max(ticks, 1), MAX_TICKS) as u32;
+        let timeout = self.timeout;
+        self.arm(timeout);
+    }
+
+    /// Restarts the countdown with the timeout last passed to `start`.
+    pub fn pet(&mut self) {
+        let timeout = self.timeout;
+        self.arm(timeout);
+    }
+
+    /// Stops the watchdog.
+    pub fn stop(&mut self) {
+        self.registers.RSTC.write(PASSWORD | RSTC_RESET);
+    }
+
+    /// Returns the time left before the watchdog resets the board.
+    pub fn time_remaining(&self) -> Duration {
+        let ticks = (self.registers.WDOG.read() as u64) & MAX_TICKS;
+        Duration::new(ticks / TICKS_PER_SEC,
+                      ((ticks % TICKS_PER_SEC) * 1_000_000_000 / TICKS_PER_SEC) as u32)
+    }
+
+    /// Resets the board almost immediately, after which the firmware boots
+    /// from `partition`. Partition 0 is the usual boot.
+    pub fn reset(&mut self, partition: u8) -> ! {
+        // Spread the six partition bits over the even bits of `RSTS`.
+        let spread = (0..6).fold(0, |bits, i| bits | ((partition as u32 >> i) & 1) << (2 * i));
+        let rsts = self.registers.RSTS.read() & !RSTS_PARTITION_MASK;
+        self.registers.RSTS.write(PASSWORD | rsts | spread);
+
+        self.arm(10);
+        loop {}
+    }
+
+    /// Reboots the board.
+    pub fn reboot(&mut self) -> ! {
+        self.reset(0)
+    }
+
+    /// Powers the board off, as far as it can be: the firmware is reset into
+    /// its halt partition and stops with the chip idle. Only a power cycle
+    /// boots the board again.
+    pub fn power_off(&mut self) -> ! {
+        self.reset(HALT_PARTITION)
+    }
+}
+
+/// Halts the calling core without resetting the board, so its state can still
+/// be inspected with a debugger. The watchdog is stopped first so it doesn't
+/// reset the board later.
+pub fn halt() -> ! {
+    Watchdog::new().stop();
+    loop {
+        #[cfg(not(test))]
+        unsafe {
+            asm!("wfe" :::: "volatile");
+        }
+    }
+}
+
+/// Reboots the board.
+pub fn reboot() -> ! {
+    Watchdog::new().reboot()
+}
+
+/// Powers the board off; see `Watchdog::power_off`.
+pub fn power_off() -> ! {
+    Watchdog::new().power_off()
+}
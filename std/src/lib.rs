@@ -378,6 +378,8 @@ pub use core::char;
 pub use core::u128;
 #[stable(feature = "core_hint", since = "1.27.0")]
 pub use core::hint;
+#[stable(feature = "duration_core", since = "1.25.0")]
+pub use core::time;
 
 pub mod ascii;
 pub mod collections;